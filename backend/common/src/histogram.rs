@@ -0,0 +1,112 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+/// A simple histogram, counting how many of the values that we observe fall
+/// into each of a fixed set of buckets. This mirrors the shape of a Prometheus
+/// histogram, so that it's easy to expose.
+#[derive(Debug, Clone, Default)]
+pub struct Histogram {
+    /// The upper bound of each bucket, in increasing order.
+    bounds: Box<[f64]>,
+    /// How many values fell into each bucket. Has an extra
+    /// entry on the end for values larger than every bound.
+    counts: Box<[u64]>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    /// Create a new histogram, given the upper bound of each bucket. The
+    /// bounds will be sorted if they aren't already.
+    pub fn new(bounds: &[f64]) -> Self {
+        let mut bounds = bounds.to_vec();
+        bounds.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+        Histogram {
+            counts: vec![0; bounds.len() + 1].into_boxed_slice(),
+            bounds: bounds.into_boxed_slice(),
+            sum: 0.0,
+            count: 0,
+        }
+    }
+
+    /// Record a value.
+    pub fn observe(&mut self, val: f64) {
+        let idx = self
+            .bounds
+            .iter()
+            .position(|&bound| val <= bound)
+            .unwrap_or(self.bounds.len());
+        // A default histogram has no buckets at all:
+        if let Some(count) = self.counts.get_mut(idx) {
+            *count += 1;
+        }
+        self.sum += val;
+        self.count += 1;
+    }
+
+    /// Iterate over the upper bound of each bucket and the number of values less
+    /// than or equal to it, as Prometheus expects. The final `+Inf` bucket is not
+    /// included; its value is always [`Histogram::count()`].
+    pub fn cumulative_buckets(&self) -> impl Iterator<Item = (f64, u64)> + '_ {
+        self.bounds
+            .iter()
+            .zip(self.counts.iter())
+            .scan(0, |total, (&bound, &count)| {
+                *total += count;
+                Some((bound, *total))
+            })
+    }
+
+    /// The sum of every value observed.
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    /// How many values have been observed.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn values_land_in_the_right_buckets() {
+        let mut h = Histogram::new(&[1.0, 0.1, 10.0]);
+
+        h.observe(0.05);
+        h.observe(0.1);
+        h.observe(5.0);
+        h.observe(100.0);
+
+        let buckets: Vec<_> = h.cumulative_buckets().collect();
+        assert_eq!(buckets, vec![(0.1, 2), (1.0, 2), (10.0, 3)]);
+        assert_eq!(h.count(), 4);
+        assert!((h.sum() - 105.15).abs() < 1e-9);
+    }
+
+    #[test]
+    fn default_histogram_just_counts() {
+        let mut h = Histogram::default();
+
+        h.observe(1.0);
+
+        assert_eq!(h.cumulative_buckets().count(), 0);
+        assert_eq!(h.count(), 1);
+    }
+}
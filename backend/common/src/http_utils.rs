@@ -14,41 +14,304 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
-use futures::io::{BufReader, BufWriter};
+use futures::io::{AsyncRead, AsyncWrite, BufReader, BufWriter};
 use hyper::server::conn::AddrStream;
 use hyper::{Body, Request, Response, Server};
+use pin_project_lite::pin_project;
+use soketto::extension::deflate::Deflate;
+use soketto::extension::{Extension, Param};
 use std::future::Future;
 use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{Context, Poll};
 use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
 
-/// A convenience function to start up a Hyper server and handle requests.
-pub async fn start_server<H, F>(addr: SocketAddr, handler: H) -> Result<(), anyhow::Error>
+/// Whether a server is ready to have traffic sent its way. This starts off not ready; the
+/// server is marked as ready once all of its listeners are listening for connections, and
+/// can be marked as not ready again while it's shutting down. Checking it is cheap.
+#[derive(Clone, Debug)]
+pub struct Readiness(Arc<ReadinessInner>);
+
+#[derive(Debug)]
+struct ReadinessInner {
+    ready: AtomicBool,
+    listeners_to_bind: AtomicUsize,
+}
+
+impl Default for Readiness {
+    fn default() -> Self {
+        Readiness::new()
+    }
+}
+
+impl Readiness {
+    /// Readiness for a server with a single listener.
+    pub fn new() -> Self {
+        Readiness::for_listeners(1)
+    }
+
+    /// Readiness for a server with the given number of listeners, each of which is handed
+    /// a clone of this by [`start_server`]. It's only ready once all of them are listening.
+    pub fn for_listeners(listeners: usize) -> Self {
+        Readiness(Arc::new(ReadinessInner {
+            ready: AtomicBool::new(listeners == 0),
+            listeners_to_bind: AtomicUsize::new(listeners),
+        }))
+    }
+
+    /// Note that another of the server's listeners is listening for connections, marking
+    /// the server as ready if it was the last one.
+    pub fn listener_bound(&self) {
+        let last =
+            self.0
+                .listeners_to_bind
+                .fetch_update(Ordering::AcqRel, Ordering::Acquire, |n| n.checked_sub(1));
+        if last == Ok(1) {
+            self.set_ready(true);
+        }
+    }
+
+    /// Is the server ready for traffic?
+    pub fn is_ready(&self) -> bool {
+        self.0.ready.load(Ordering::Relaxed)
+    }
+
+    /// Mark the server as ready for traffic, or not.
+    pub fn set_ready(&self, ready: bool) {
+        self.0.ready.store(ready, Ordering::Relaxed);
+    }
+
+    /// Respond to a readiness check: 200 if we're ready for traffic, and 503 if not.
+    pub fn response(&self) -> Response<Body> {
+        if self.is_ready() {
+            Response::new("OK".into())
+        } else {
+            Response::builder()
+                .status(503)
+                .body("Not ready".into())
+                .unwrap()
+        }
+    }
+}
+
+/// The path that a server's endpoints are mounted under, for when it sits behind a reverse
+/// proxy which hands it requests without stripping the path off first. By default this is
+/// empty, and endpoints are mounted at the root.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BasePath(Arc<str>);
+
+impl BasePath {
+    /// The path of a request relative to the base path, or `None` if it isn't under it.
+    pub fn strip<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let rest = path.strip_prefix(&*self.0)?;
+        if rest.is_empty() || rest.starts_with('/') {
+            Some(rest)
+        } else {
+            None
+        }
+    }
+
+    /// The path to route a request to: its path relative to the base path, without any
+    /// trailing '/'. Health checks are answered outside of the base path too, so that
+    /// whatever's checking on us doesn't need to know it. Anything else outside of the
+    /// base path is routed to "", which nothing is served at.
+    pub fn route<'a>(&self, path: &'a str) -> &'a str {
+        let path = path.trim_end_matches('/');
+        match self.strip(path) {
+            Some(path) => path,
+            None if matches!(path, "/health" | "/healthz" | "/readyz") => path,
+            None => "",
+        }
+    }
+}
+
+impl std::str::FromStr for BasePath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.contains(|c: char| matches!(c, '?' | '#') || c.is_whitespace()) {
+            anyhow::bail!("Base paths cannot contain '?', '#' or whitespace");
+        }
+        // "telemetry", "/telemetry" and "/telemetry/" are all the same thing:
+        let s = s.trim_matches('/');
+        if s.is_empty() {
+            return Ok(BasePath::default());
+        }
+        Ok(BasePath(format!("/{}", s).into()))
+    }
+}
+
+/// A convenience function to start up a Hyper server and handle requests. If some TLS
+/// configuration is given, connections to the server must use TLS. `ready` is marked as
+/// ready once we're listening for connections.
+pub async fn start_server<H, F>(
+    addr: SocketAddr,
+    tls: Option<Arc<crate::tls::ServerConfig>>,
+    ready: Readiness,
+    handler: H,
+) -> Result<(), anyhow::Error>
 where
     H: Clone + Send + Sync + 'static + FnMut(SocketAddr, Request<Body>) -> F,
     F: Send + 'static + Future<Output = Result<Response<Body>, anyhow::Error>>,
 {
+    if let Some(tls) = tls {
+        return start_tls_server(addr, tls, ready, handler).await;
+    }
+
     let service = hyper::service::make_service_fn(move |addr: &AddrStream| {
         let mut handler = handler.clone();
         let addr = addr.remote_addr();
-        async move { Ok::<_, hyper::Error>(hyper::service::service_fn(move |r| handler(addr, r))) }
+        async move {
+            Ok::<_, hyper::Error>(hyper::service::service_fn(move |r| {
+                crate::logging::scope([("ip", addr.ip().to_string())], handler(addr, r))
+            }))
+        }
     });
     let server = Server::bind(&addr).serve(service);
 
     log::info!("listening on http://{}", server.local_addr());
+    ready.listener_bound();
     server.await?;
 
     Ok(())
 }
 
-type WsStream = BufReader<BufWriter<Compat<hyper::upgrade::Upgraded>>>;
+async fn start_tls_server<H, F>(
+    addr: SocketAddr,
+    tls: Arc<crate::tls::ServerConfig>,
+    ready: Readiness,
+    handler: H,
+) -> Result<(), anyhow::Error>
+where
+    H: Clone + Send + Sync + 'static + FnMut(SocketAddr, Request<Body>) -> F,
+    F: Send + 'static + Future<Output = Result<Response<Body>, anyhow::Error>>,
+{
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    let acceptor = tokio_rustls::TlsAcceptor::from(tls);
+
+    log::info!("listening on https://{}", listener.local_addr()?);
+    ready.listener_bound();
+    loop {
+        let (socket, addr) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                // Likely a transient problem such as running out of file descriptors,
+                // so keep accepting connections rather than giving up entirely:
+                log::warn!("Error accepting connection: {}", e);
+                continue;
+            }
+        };
+        let acceptor = acceptor.clone();
+        let mut handler = handler.clone();
+        tokio::spawn(crate::logging::scope(
+            [("ip", addr.ip().to_string())],
+            async move {
+                // The handshake happens here rather than in the accept loop, so that
+                // a slow client can't hold up everybody else:
+                let stream = match acceptor.accept(socket).await {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log::debug!("TLS handshake failed: {}", e);
+                        return;
+                    }
+                };
+                let service = hyper::service::service_fn(move |r| {
+                    crate::logging::scope([("ip", addr.ip().to_string())], handler(addr, r))
+                });
+                if let Err(e) = hyper::server::conn::Http::new()
+                    .serve_connection(stream, service)
+                    .with_upgrades()
+                    .await
+                {
+                    log::debug!("Error serving connection: {}", e);
+                }
+            },
+        ));
+    }
+}
+
+type WsStream = BufReader<BufWriter<CountBytes<Compat<hyper::upgrade::Upgraded>>>>;
 pub type WsSender = soketto::connection::Sender<WsStream>;
 pub type WsReceiver = soketto::connection::Receiver<WsStream>;
 
+/// Handed to the websocket handler if the client agreed to compress messages, so that
+/// it can see how much was actually written to and read from the connection.
+#[derive(Clone, Debug)]
+pub struct WsCompression {
+    bytes_written: Arc<AtomicU64>,
+    bytes_read: Arc<AtomicU64>,
+}
+
+impl WsCompression {
+    /// How many bytes, after compression, have been written to the connection so far.
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written.load(Ordering::Relaxed)
+    }
+
+    /// How many bytes, before decompression, have been read from the connection so far.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+}
+
+/// Options to configure the websocket connections that we accept.
+#[derive(Clone, Copy, Debug)]
+pub struct WsOpts {
+    /// The largest message that we'll accept, in bytes. If the other end tries to send
+    /// us anything bigger, we'll see an error instead of buffering it, and can close
+    /// the connection.
+    pub max_message_size: usize,
+}
+
+impl Default for WsOpts {
+    fn default() -> Self {
+        WsOpts {
+            // The same limit that Soketto applies by default:
+            max_message_size: 256 * 1024 * 1024,
+        }
+    }
+}
+
 /// A convenience function to upgrade a Hyper request into a Soketto Websocket.
-pub fn upgrade_to_websocket<H, F>(req: Request<Body>, on_upgrade: H) -> hyper::Response<Body>
+pub fn upgrade_to_websocket<H, F>(
+    req: Request<Body>,
+    opts: WsOpts,
+    on_upgrade: H,
+) -> hyper::Response<Body>
 where
     H: 'static + Send + FnOnce(WsSender, WsReceiver) -> F,
     F: Send + Future<Output = ()>,
+{
+    upgrade(req, opts, false, move |ws_send, ws_recv, _| {
+        on_upgrade(ws_send, ws_recv)
+    })
+}
+
+/// Like [`upgrade_to_websocket`], except that if the client offers to use the
+/// "permessage-deflate" extension in its handshake, messages are compressed.
+pub fn upgrade_to_compressed_websocket<H, F>(
+    req: Request<Body>,
+    opts: WsOpts,
+    on_upgrade: H,
+) -> hyper::Response<Body>
+where
+    H: 'static + Send + FnOnce(WsSender, WsReceiver, Option<WsCompression>) -> F,
+    F: Send + Future<Output = ()>,
+{
+    upgrade(req, opts, true, on_upgrade)
+}
+
+fn upgrade<H, F>(
+    req: Request<Body>,
+    opts: WsOpts,
+    allow_deflate: bool,
+    on_upgrade: H,
+) -> hyper::Response<Body>
+where
+    H: 'static + Send + FnOnce(WsSender, WsReceiver, Option<WsCompression>) -> F,
+    F: Send + Future<Output = ()>,
 {
     if !is_upgrade_request(&req) {
         return basic_response(400, "Expecting WebSocket upgrade headers");
@@ -80,17 +343,30 @@ where
     let mut accept_key_buf = [0; 32];
     let accept_key = generate_websocket_accept_key(key.as_bytes(), &mut accept_key_buf);
 
+    // Compress messages if we're allowed to and the client wants us to:
+    let deflate = if allow_deflate {
+        negotiate_deflate(req.headers())
+    } else {
+        None
+    };
+
     // Tell the client that we accept the upgrade-to-WS request:
-    let response = Response::builder()
+    let mut response = Response::builder()
         .status(hyper::StatusCode::SWITCHING_PROTOCOLS)
         .header(hyper::header::CONNECTION, "upgrade")
         .header(hyper::header::UPGRADE, "websocket")
-        .header("Sec-WebSocket-Accept", accept_key)
+        .header("Sec-WebSocket-Accept", accept_key);
+    if let Some(deflate) = &deflate {
+        response = response.header("Sec-WebSocket-Extensions", extension_header(deflate));
+    }
+    let response = response
         .body(Body::empty())
         .expect("bug: failed to build response");
 
-    // Spawn our handler to work with the WS connection:
-    tokio::spawn(async move {
+    // Spawn our handler to work with the WS connection, logging with the same
+    // fields as the request that it was upgraded from:
+    let log_fields = crate::logging::current_fields();
+    tokio::spawn(crate::logging::scope(log_fields, async move {
         // Get our underlying TCP stream:
         let stream = match hyper::upgrade::on(req).await {
             Ok(stream) => stream,
@@ -101,19 +377,148 @@ where
         };
 
         // Start a Soketto server with it:
-        let server =
-            soketto::handshake::Server::new(BufReader::new(BufWriter::new(stream.compat())));
+        let bytes_written = Arc::new(AtomicU64::new(0));
+        let bytes_read = Arc::new(AtomicU64::new(0));
+        let stream = CountBytes {
+            inner: stream.compat(),
+            bytes_written: bytes_written.clone(),
+            bytes_read: bytes_read.clone(),
+        };
+        let mut server = soketto::handshake::Server::new(BufReader::new(BufWriter::new(stream)));
 
-        // Get hold of a way to send and receive messages:
-        let (sender, receiver) = server.into_builder().finish();
+        let compression = deflate.map(|deflate| {
+            server.add_extension(Box::new(deflate));
+            WsCompression {
+                bytes_written,
+                bytes_read,
+            }
+        });
+
+        // Get hold of a way to send and receive messages. Frames and messages that
+        // are too large are rejected as soon as we see how big they claim to be:
+        let mut builder = server.into_builder();
+        builder.set_max_message_size(opts.max_message_size);
+        builder.set_max_frame_size(opts.max_message_size);
+        let (sender, receiver) = builder.finish();
 
         // Pass these to our when-upgraded handler:
-        on_upgrade(sender, receiver).await;
-    });
+        on_upgrade(sender, receiver, compression).await;
+    }));
 
     response
 }
 
+/// The subprotocols that a client offers to speak in the "Sec-WebSocket-Protocol" headers of
+/// its upgrade request, in order of preference. To accept one of them, the response to the
+/// request should name it in a "Sec-WebSocket-Protocol" header of its own.
+pub fn offered_protocols(headers: &hyper::HeaderMap) -> impl Iterator<Item = &str> {
+    headers
+        .get_all("Sec-WebSocket-Protocol")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|protocol| !protocol.is_empty())
+}
+
+/// Look through the extensions offered in the "Sec-WebSocket-Extensions" header, and hand
+/// back a configured deflate extension for the first "permessage-deflate" offer we can accept.
+fn negotiate_deflate(headers: &hyper::HeaderMap) -> Option<Deflate> {
+    let offers = headers
+        .get_all("Sec-WebSocket-Extensions")
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','));
+
+    for offer in offers {
+        let mut parts = offer.split(';').map(str::trim);
+        if parts.next() != Some("permessage-deflate") {
+            continue;
+        }
+        let params: Vec<Param> = parts
+            .filter(|part| !part.is_empty())
+            .map(|part| {
+                let (name, value) = match part.split_once('=') {
+                    Some((name, value)) => (name.trim(), Some(value.trim().trim_matches('"'))),
+                    None => (part, None),
+                };
+                let mut param = Param::new(name);
+                param.set_value(value);
+                param
+            })
+            .collect();
+
+        // The extension is only enabled if it's happy with the parameters offered:
+        let mut deflate = Deflate::new(soketto::Mode::Server);
+        if deflate.configure(&params).is_ok() && deflate.is_enabled() {
+            return Some(deflate);
+        }
+    }
+    None
+}
+
+/// The value of the "Sec-WebSocket-Extensions" header we reply with to accept an extension.
+fn extension_header(extension: &dyn Extension) -> String {
+    let mut header = extension.name().to_owned();
+    for param in extension.params() {
+        header.push_str("; ");
+        header.push_str(param.name());
+        if let Some(value) = param.value() {
+            header.push('=');
+            header.push_str(value);
+        }
+    }
+    header
+}
+
+pin_project! {
+    /// Keeps count of how many bytes have been written to and read from the wrapped stream.
+    pub struct CountBytes<S> {
+        #[pin]
+        inner: S,
+        bytes_written: Arc<AtomicU64>,
+        bytes_read: Arc<AtomicU64>,
+    }
+}
+
+impl<S: AsyncRead> AsyncRead for CountBytes<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        let res = this.inner.poll_read(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            this.bytes_read.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+}
+
+impl<S: AsyncWrite> AsyncWrite for CountBytes<S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.project();
+        let res = this.inner.poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = res {
+            this.bytes_written.fetch_add(n as u64, Ordering::Relaxed);
+        }
+        res
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.project().inner.poll_close(cx)
+    }
+}
+
 /// A helper to return a basic HTTP response with a code and text body.
 fn basic_response(code: u16, msg: impl AsRef<str>) -> Response<Body> {
     Response::builder()
@@ -170,3 +575,83 @@ fn header_contains_value(
     }
     false
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn offer(value: &str) -> hyper::HeaderMap {
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("Sec-WebSocket-Extensions", value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn servers_are_only_ready_once_every_listener_is_bound() {
+        let ready = Readiness::for_listeners(2);
+        assert!(!ready.is_ready());
+
+        ready.listener_bound();
+        assert!(!ready.is_ready());
+
+        ready.listener_bound();
+        assert!(ready.is_ready());
+
+        // Shutting down makes us unready again:
+        ready.set_ready(false);
+        assert!(!ready.is_ready());
+    }
+
+    #[test]
+    fn deflate_is_negotiated_if_offered() {
+        let deflate = negotiate_deflate(&offer(
+            "permessage-deflate; client_max_window_bits; server_max_window_bits=10",
+        ))
+        .expect("deflate should be accepted");
+        assert_eq!(
+            extension_header(&deflate),
+            "permessage-deflate; server_max_window_bits=10"
+        );
+
+        // We skip over offers that we can't accept:
+        let deflate = negotiate_deflate(&offer(
+            "permessage-deflate; unknown_param, permessage-deflate",
+        ))
+        .expect("second offer should be accepted");
+        assert_eq!(extension_header(&deflate), "permessage-deflate");
+
+        assert!(negotiate_deflate(&offer("x-webkit-deflate-frame")).is_none());
+        assert!(negotiate_deflate(&hyper::HeaderMap::new()).is_none());
+    }
+
+    #[test]
+    fn paths_are_stripped_of_the_base_path() {
+        let base: BasePath = "/telemetry/".parse().unwrap();
+        assert_eq!(base, "telemetry".parse().unwrap());
+        assert_eq!(base.strip("/telemetry/feed"), Some("/feed"));
+        assert_eq!(base.strip("/telemetry"), Some(""));
+        assert_eq!(base.strip("/telemetryfeed"), None);
+        assert_eq!(base.strip("/feed"), None);
+        assert_eq!(base.route("/telemetry/feed/"), "/feed");
+        assert_eq!(base.route("/healthz"), "/healthz");
+        assert_eq!(base.route("/feed"), "");
+
+        // Without a base path, everything is mounted at the root:
+        let base: BasePath = "/".parse().unwrap();
+        assert_eq!(base, BasePath::default());
+        assert_eq!(base.strip("/feed"), Some("/feed"));
+
+        assert!("/telemetry?foo".parse::<BasePath>().is_err());
+    }
+
+    #[test]
+    fn offered_protocols_are_split_up() {
+        let mut headers = hyper::HeaderMap::new();
+        headers.append("Sec-WebSocket-Protocol", "one, two ,".parse().unwrap());
+        headers.append("Sec-WebSocket-Protocol", "three".parse().unwrap());
+        let protocols: Vec<&str> = offered_protocols(&headers).collect();
+        assert_eq!(protocols, vec!["one", "two", "three"]);
+
+        assert_eq!(offered_protocols(&hyper::HeaderMap::new()).count(), 0);
+    }
+}
@@ -64,4 +64,9 @@ pub enum FromTelemetryCore {
 pub enum MuteReason {
     Overquota,
     ChainNotAllowed,
+    /// The node has connected again, so we've stopped listening to this connection.
+    Duplicate,
+    /// An operator has asked for the node to be disconnected, or it's gone for too
+    /// long without telling us anything.
+    Disconnected,
 }
@@ -15,14 +15,20 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 pub mod byte_size;
+pub mod histogram;
 pub mod http_utils;
 pub mod id_type;
 pub mod internal_messages;
+pub mod ip_range;
+pub mod logging;
 pub mod node_message;
 pub mod node_types;
 pub mod ready_chunks_all;
+pub mod real_ip;
 pub mod rolling_total;
+pub mod secret_token;
 pub mod time;
+pub mod tls;
 pub mod ws_client;
 
 mod assign_id;
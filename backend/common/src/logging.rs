@@ -0,0 +1,199 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A logger that writes out one JSON object per line, for when our logs are
+//! being collected up by something that would rather not parse free text.
+//!
+//! Anything logged can have extra fields (like the IP address of the connection
+//! it's about) attached to it, by running the code doing the logging inside
+//! [`scope`] or [`with_fields`]. This way, the `log::` calls themselves don't
+//! need to change.
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::io::Write;
+use std::str::FromStr;
+
+/// Fields to attach to log lines, in the order that they were added. A field
+/// added later replaces any earlier one with the same name.
+pub type LogFields = Vec<(&'static str, String)>;
+
+tokio::task_local! {
+    static TASK_FIELDS: LogFields;
+}
+
+thread_local! {
+    static THREAD_FIELDS: RefCell<LogFields> = const { RefCell::new(Vec::new()) };
+}
+
+/// How log lines are written out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines.
+    Text,
+    /// One JSON object per line, with the time (in ms since the unix epoch),
+    /// level, target and message, as well as any fields in scope.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(anyhow::anyhow!(
+                "Unknown log format '{}'; expected 'text' or 'json'",
+                s
+            )),
+        }
+    }
+}
+
+/// The fields that are currently in scope for this task and thread.
+pub fn current_fields() -> LogFields {
+    let mut fields = TASK_FIELDS.try_with(|f| f.clone()).unwrap_or_default();
+    THREAD_FIELDS.with(|f| fields.extend(f.borrow().iter().cloned()));
+    fields
+}
+
+/// Run a future, attaching the fields given (as well as any that are already
+/// in scope) to anything that it logs. Tasks spawned from within the future
+/// don't inherit these fields.
+pub async fn scope<F: Future>(
+    fields: impl IntoIterator<Item = (&'static str, String)>,
+    fut: F,
+) -> F::Output {
+    let mut all_fields = current_fields();
+    all_fields.extend(fields);
+    TASK_FIELDS.scope(all_fields, fut).await
+}
+
+/// Like [`scope`], but for synchronous code.
+pub fn with_fields<R>(
+    fields: impl IntoIterator<Item = (&'static str, String)>,
+    f: impl FnOnce() -> R,
+) -> R {
+    // Take the fields back out again when we're done, even if `f` panics:
+    struct Reset(usize);
+    impl Drop for Reset {
+        fn drop(&mut self) {
+            THREAD_FIELDS.with(|f| f.borrow_mut().truncate(self.0));
+        }
+    }
+
+    let _reset = THREAD_FIELDS.with(|f| {
+        let mut f = f.borrow_mut();
+        let reset = Reset(f.len());
+        f.extend(fields);
+        reset
+    });
+    f()
+}
+
+/// Logs everything at or above the given level to stdout, as JSON.
+pub struct JsonLogger {
+    level: log::LevelFilter,
+}
+
+impl JsonLogger {
+    pub fn new(level: log::LevelFilter) -> Self {
+        JsonLogger { level }
+    }
+
+    /// Make this the global logger.
+    pub fn init(self) -> Result<(), log::SetLoggerError> {
+        log::set_max_level(self.level);
+        log::set_boxed_logger(Box::new(self))
+    }
+}
+
+impl log::Log for JsonLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format_record(record, crate::time::now(), current_fields());
+        let _ = writeln!(std::io::stdout().lock(), "{}", line);
+    }
+
+    fn flush(&self) {
+        let _ = std::io::stdout().flush();
+    }
+}
+
+fn format_record(record: &log::Record, time: u64, fields: LogFields) -> String {
+    let mut obj = serde_json::Map::new();
+    obj.insert("time".into(), time.into());
+    obj.insert("level".into(), record.level().as_str().into());
+    obj.insert("target".into(), record.target().into());
+    obj.insert("message".into(), record.args().to_string().into());
+    for (key, value) in fields {
+        obj.insert(key.into(), value.into());
+    }
+    serde_json::Value::Object(obj).to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn format_now(args: std::fmt::Arguments) -> serde_json::Value {
+        let record = log::Record::builder()
+            .args(args)
+            .level(log::Level::Warn)
+            .target("telemetry_core::find_location")
+            .build();
+        let line = format_record(&record, 1000, current_fields());
+        serde_json::from_str(&line).unwrap()
+    }
+
+    #[test]
+    fn records_are_formatted_as_json() {
+        let value = format_now(format_args!("Couldn't find location for \"{}\"", "1.2.3.4"));
+        assert_eq!(
+            value,
+            serde_json::json!({
+                "time": 1000,
+                "level": "WARN",
+                "target": "telemetry_core::find_location",
+                "message": "Couldn't find location for \"1.2.3.4\"",
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn fields_in_scope_are_included() {
+        let value = scope([("ip", "1.2.3.4".to_string())], async {
+            with_fields([("node_id", "1".to_string())], || {
+                // Inner fields replace outer ones of the same name:
+                with_fields([("ip", "5.6.7.8".to_string())], || {
+                    format_now(format_args!("A"))
+                })
+            })
+        })
+        .await;
+        assert_eq!(value["ip"], "5.6.7.8");
+        assert_eq!(value["node_id"], "1");
+
+        // Nothing is left in scope afterwards:
+        assert!(current_fields().is_empty());
+    }
+}
@@ -19,7 +19,7 @@
 //! able to serialize these messages to bincode, and various serde attribtues aren't compatible
 //! with this, hence this separate internal representation.
 
-use crate::node_types::{Block, BlockHash, BlockNumber, NodeDetails};
+use crate::node_types::{Block, BlockHash, BlockNumber, NodeDetails, SyncMethod};
 use serde::{Deserialize, Serialize};
 
 pub type NodeMessageId = u64;
@@ -61,6 +61,8 @@ pub enum Payload {
     NotifyFinalized(Finalized),
     AfgAuthoritySet(AfgAuthoritySet),
     HwBench(NodeHwBench),
+    SystemVersion(SystemVersion),
+    TimedBlockImport(TimedBlockImport),
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -69,7 +71,7 @@ pub struct SystemConnected {
     pub node: NodeDetails,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
 pub struct SystemInterval {
     pub peers: Option<u64>,
     pub txcount: Option<u64>,
@@ -79,6 +81,29 @@ pub struct SystemInterval {
     pub finalized_hash: Option<BlockHash>,
     pub block: Option<Block>,
     pub used_state_cache_size: Option<f32>,
+    pub cpu: Option<f32>,
+    pub memory: Option<u64>,
+    pub disk_usage: Option<u64>,
+    pub target_height: Option<BlockNumber>,
+    /// How the node is syncing; this can change while it's connected, for instance
+    /// once it's done warp syncing.
+    pub sync_method: Option<SyncMethod>,
+}
+
+/// Sent by nodes when their version or the version of their runtime changes
+/// (for instance after a runtime upgrade), without them reconnecting.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SystemVersion {
+    pub version: Box<str>,
+    pub spec_version: Option<u32>,
+}
+
+/// A block import from a node that also told us how long it took to import the block.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct TimedBlockImport {
+    pub block: Block,
+    /// How long the node took to import the block, in ms.
+    pub import_time: u64,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -103,9 +128,24 @@ pub struct NodeHwBench {
 }
 
 impl Payload {
+    /// The name of this type of payload, as given in the "msg" field of the
+    /// JSON that nodes send.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Payload::SystemConnected(_) => "system.connected",
+            Payload::SystemInterval(_) => "system.interval",
+            Payload::BlockImport(_) | Payload::TimedBlockImport(_) => "block.import",
+            Payload::NotifyFinalized(_) => "notify.finalized",
+            Payload::AfgAuthoritySet(_) => "afg.authority_set",
+            Payload::HwBench(_) => "sysinfo.hwbench",
+            Payload::SystemVersion(_) => "system.version",
+        }
+    }
+
     pub fn best_block(&self) -> Option<&Block> {
         match self {
             Payload::BlockImport(block) => Some(block),
+            Payload::TimedBlockImport(TimedBlockImport { block, .. }) => Some(block),
             Payload::SystemInterval(SystemInterval { block, .. }) => block.as_ref(),
             _ => None,
         }
@@ -154,13 +194,17 @@ mod tests {
                     name: "foo".into(),
                     implementation: "foo".into(),
                     version: "foo".into(),
+                    spec_version: Some(9180),
                     target_arch: Some("x86_64".into()),
                     target_os: Some("linux".into()),
                     target_env: Some("env".into()),
                     validator: None,
+                    authority: false,
+                    operator: None,
                     network_id: ArrayString::new(),
                     startup_time: None,
                     sysinfo: None,
+                    sync_method: None,
                 },
             }),
         });
@@ -170,14 +214,8 @@ mod tests {
     fn bincode_can_serialize_and_deserialize_node_message_system_interval() {
         bincode_can_serialize_and_deserialize(NodeMessage::V1 {
             payload: Payload::SystemInterval(SystemInterval {
-                peers: None,
-                txcount: None,
-                bandwidth_upload: None,
-                bandwidth_download: None,
-                finalized_height: None,
-                finalized_hash: None,
-                block: None,
-                used_state_cache_size: None,
+                sync_method: Some(SyncMethod::Warp),
+                ..Default::default()
             }),
         });
     }
@@ -192,6 +230,19 @@ mod tests {
         });
     }
 
+    #[test]
+    fn bincode_can_serialize_and_deserialize_node_message_timed_block_import() {
+        bincode_can_serialize_and_deserialize(NodeMessage::V1 {
+            payload: Payload::TimedBlockImport(TimedBlockImport {
+                block: Block {
+                    hash: BlockHash([0; 32]),
+                    height: 0,
+                },
+                import_time: 120,
+            }),
+        });
+    }
+
     #[test]
     fn bincode_can_serialize_and_deserialize_node_message_notify_finalized() {
         bincode_can_serialize_and_deserialize(NodeMessage::V1 {
@@ -213,6 +264,16 @@ mod tests {
         });
     }
 
+    #[test]
+    fn bincode_can_serialize_and_deserialize_node_message_system_version() {
+        bincode_can_serialize_and_deserialize(NodeMessage::V1 {
+            payload: Payload::SystemVersion(SystemVersion {
+                version: "foo".into(),
+                spec_version: None,
+            }),
+        });
+    }
+
     #[test]
     fn bincode_block_zero() {
         let raw = Block::zero();
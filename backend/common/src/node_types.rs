@@ -35,13 +35,51 @@ pub struct NodeDetails {
     pub name: Box<str>,
     pub implementation: Box<str>,
     pub version: Box<str>,
+    /// The spec version of the runtime that the node is running, if it's told us.
+    #[serde(default)]
+    pub spec_version: Option<u32>,
     pub validator: Option<Box<str>>,
+    /// Is the node an authority (ie a validator) on its chain?
+    #[serde(default)]
+    pub authority: bool,
+    /// A label naming whoever runs the node, if they've chosen to give one.
+    #[serde(default)]
+    pub operator: Option<Box<str>>,
     pub network_id: NetworkId,
     pub startup_time: Option<Box<str>>,
     pub target_os: Option<Box<str>>,
     pub target_arch: Option<Box<str>>,
     pub target_env: Option<Box<str>>,
     pub sysinfo: Option<NodeSysInfo>,
+    /// How the node is syncing, if it's told us.
+    #[serde(default)]
+    pub sync_method: Option<SyncMethod>,
+}
+
+/// How a node is syncing with its chain.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMethod {
+    /// Downloading and executing every block.
+    Full,
+    /// Downloading every block, but only the state at the latest finalized block.
+    Fast,
+    /// Downloading proofs of finality to jump to the latest finalized block, and only
+    /// then catching up on the blocks before it.
+    Warp,
+}
+
+impl SyncMethod {
+    /// The sync method with the name given, as nodes name them. Nodes can name
+    /// methods we don't know about yet, in which case we have nothing to go on.
+    pub fn from_name(name: &str) -> Option<SyncMethod> {
+        match name {
+            "full" => Some(SyncMethod::Full),
+            "fast" | "fast-unsafe" => Some(SyncMethod::Fast),
+            "warp" => Some(SyncMethod::Warp),
+            _ => None,
+        }
+    }
 }
 
 /// Hardware and software information for the node.
@@ -112,6 +150,52 @@ impl<'de> Deserialize<'de> for NodeStats {
     }
 }
 
+/// The resources that a node reports that it's using. Each of these is
+/// `None` until the node reports it.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub struct NodeResourceUsage {
+    /// CPU usage, as a percentage.
+    pub cpu: Option<f32>,
+    /// Memory used, in bytes.
+    pub memory: Option<u64>,
+    /// Disk space used, in bytes.
+    pub disk_usage: Option<u64>,
+}
+
+impl NodeResourceUsage {
+    /// Has the node told us about any of its resource usage?
+    pub fn is_empty(&self) -> bool {
+        self.cpu.is_none() && self.memory.is_none() && self.disk_usage.is_none()
+    }
+}
+
+impl Serialize for NodeResourceUsage {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        let mut tup = serializer.serialize_tuple(3)?;
+        tup.serialize_element(&self.cpu)?;
+        tup.serialize_element(&self.memory)?;
+        tup.serialize_element(&self.disk_usage)?;
+        tup.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for NodeResourceUsage {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let (cpu, memory, disk_usage) = <_>::deserialize(deserializer)?;
+        Ok(NodeResourceUsage {
+            cpu,
+            memory,
+            disk_usage,
+        })
+    }
+}
+
 /// Node IO details.
 #[derive(Default)]
 pub struct NodeIO {
@@ -172,11 +256,18 @@ impl Serialize for NodeHardware {
 }
 
 /// Node location details
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Default)]
 pub struct NodeLocation {
     pub latitude: f32,
     pub longitude: f32,
     pub city: Box<str>,
+    /// The number of the autonomous system that the node is in, if known.
+    pub asn: Option<u32>,
+    /// The name of the network (ie the ISP or hosting provider) that the node is on, if known.
+    pub network: Option<Box<str>>,
+    /// Where this location came from (eg the geolocation provider that found it), if known.
+    /// This is only for our own debugging, and isn't sent to feeds.
+    pub source: Option<Box<str>>,
 }
 
 impl Serialize for NodeLocation {
@@ -184,10 +275,12 @@ impl Serialize for NodeLocation {
     where
         S: Serializer,
     {
-        let mut tup = serializer.serialize_tuple(3)?;
+        let mut tup = serializer.serialize_tuple(5)?;
         tup.serialize_element(&self.latitude)?;
         tup.serialize_element(&self.longitude)?;
         tup.serialize_element(&&*self.city)?;
+        tup.serialize_element(&self.asn)?;
+        tup.serialize_element(&self.network)?;
         tup.end()
     }
 }
@@ -197,12 +290,46 @@ impl<'de> Deserialize<'de> for NodeLocation {
     where
         D: serde::Deserializer<'de>,
     {
-        let (latitude, longitude, city) = <(f32, f32, Box<str>)>::deserialize(deserializer)?;
-        Ok(NodeLocation {
-            latitude,
-            longitude,
-            city,
-        })
+        // Locations used to be just (latitude, longitude, city), so we
+        // accept those as well as the newer format with network details.
+        struct LocationVisitor;
+
+        impl<'de> serde::de::Visitor<'de> for LocationVisitor {
+            type Value = NodeLocation;
+
+            fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                f.write_str("a location tuple of 3 or 5 elements")
+            }
+
+            fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+            where
+                A: serde::de::SeqAccess<'de>,
+            {
+                use serde::de::Error;
+                let latitude = seq
+                    .next_element()?
+                    .ok_or_else(|| A::Error::invalid_length(0, &self))?;
+                let longitude = seq
+                    .next_element()?
+                    .ok_or_else(|| A::Error::invalid_length(1, &self))?;
+                let city = seq
+                    .next_element()?
+                    .ok_or_else(|| A::Error::invalid_length(2, &self))?;
+                let asn = seq.next_element()?.flatten();
+                let network = seq.next_element()?.flatten();
+
+                Ok(NodeLocation {
+                    latitude,
+                    longitude,
+                    city,
+                    asn,
+                    network,
+                    source: None,
+                })
+            }
+        }
+
+        deserializer.deserialize_seq(LocationVisitor)
     }
 }
 
@@ -258,3 +385,42 @@ impl<'de> Deserialize<'de> for BlockDetails {
         })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn node_location_accepts_old_format() {
+        let loc: NodeLocation = serde_json::from_str(r#"[1.5,2.5,"Berlin"]"#).unwrap();
+        assert_eq!(&*loc.city, "Berlin");
+        assert_eq!(loc.asn, None);
+
+        let loc = NodeLocation {
+            asn: Some(15169),
+            network: Some("Google LLC".into()),
+            ..loc
+        };
+        let json = serde_json::to_string(&loc).unwrap();
+        assert_eq!(json, r#"[1.5,2.5,"Berlin",15169,"Google LLC"]"#);
+        assert_eq!(serde_json::from_str::<NodeLocation>(&json).unwrap(), loc);
+
+        // Where the location came from isn't sent to feeds:
+        let sourced = NodeLocation {
+            source: Some("maxmind".into()),
+            ..loc
+        };
+        assert_eq!(serde_json::to_string(&sourced).unwrap(), json);
+    }
+
+    #[test]
+    fn node_stats_round_trip() {
+        let stats = NodeStats {
+            peers: 0,
+            txcount: 12,
+        };
+        let json = serde_json::to_string(&stats).unwrap();
+        assert_eq!(json, "[0,12]");
+        assert_eq!(serde_json::from_str::<NodeStats>(&json).unwrap(), stats);
+    }
+}
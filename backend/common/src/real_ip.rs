@@ -16,6 +16,10 @@
 
 use std::net::{IpAddr, SocketAddr};
 
+use hyper::header::HeaderName;
+
+use crate::ip_range::IpRange;
+
 /**
 Extract the "real" IP address of the connection by looking at headers
 set by proxies (this is inspired by Actix Web's implementation of the feature).
@@ -42,11 +46,90 @@ pub fn real_ip(addr: SocketAddr, headers: &hyper::HeaderMap) -> (IpAddr, Source)
     pick_best_ip_from_options(forwarded, forwarded_for, real_ip, addr)
 }
 
+/// Which headers to believe when working out the real IP address of a connection.
+#[derive(Debug, Clone, Default)]
+pub struct RealIpOpts {
+    /// If given, only this header is looked at, rather than each of the usual ones in turn.
+    pub header: Option<HeaderName>,
+    /// If not empty, headers are only believed on connections from these ranges, and any
+    /// addresses in these ranges that proxies have added to them are skipped over.
+    pub trusted_proxies: Vec<IpRange>,
+}
+
+impl RealIpOpts {
+    fn is_trusted(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies.iter().any(|range| range.contains(ip))
+    }
+}
+
+/**
+Like [`real_ip`], but configurable. With no options given, this does the same thing.
+
+If a header is given, only that header is looked at. If trusted proxies are given, headers
+are ignored unless the connection comes from one of them. In that case, the addresses that
+each proxy added are looked at from last to first, and the first address that isn't one of
+our trusted proxies is the one we want. Earlier addresses could have been made up by the client.
+*/
+pub fn real_ip_with_opts(
+    addr: SocketAddr,
+    headers: &hyper::HeaderMap,
+    opts: &RealIpOpts,
+) -> (IpAddr, Source) {
+    let sources = match &opts.header {
+        Some(name) => vec![(name.as_str(), Source::Header(name.clone()))],
+        None if opts.trusted_proxies.is_empty() => return real_ip(addr, headers),
+        None => vec![
+            ("forwarded", Source::ForwardedHeader),
+            ("x-forwarded-for", Source::XForwardedForHeader),
+            ("x-real-ip", Source::XRealIpHeader),
+        ],
+    };
+
+    let trust_all = opts.trusted_proxies.is_empty();
+    if !trust_all && !opts.is_trusted(addr.ip()) {
+        return (addr.ip(), Source::SocketAddr);
+    }
+
+    for (name, source) in sources {
+        let value = match headers.get(name).and_then(header_as_str) {
+            Some(value) => value,
+            None => continue,
+        };
+        let addrs: Vec<IpAddr> = if name == "forwarded" {
+            value
+                .split(',')
+                .filter_map(get_addr_from_forwarded_element)
+                .filter_map(parse_addr)
+                .collect()
+        } else {
+            value.split(',').filter_map(parse_addr).collect()
+        };
+
+        let ip = if trust_all {
+            addrs.first()
+        } else {
+            // If every address is a trusted proxy, the first is as close as we can get.
+            addrs
+                .iter()
+                .rev()
+                .find(|ip| !opts.is_trusted(**ip))
+                .or_else(|| addrs.first())
+        };
+        if let Some(&ip) = ip {
+            return (ip, source);
+        }
+    }
+
+    (addr.ip(), Source::SocketAddr)
+}
+
 /// The source of the address returned
 pub enum Source {
     ForwardedHeader,
     XForwardedForHeader,
     XRealIpHeader,
+    /// A header that we were asked to look at specifically.
+    Header(HeaderName),
     SocketAddr,
 }
 
@@ -56,6 +139,7 @@ impl std::fmt::Display for Source {
             Source::ForwardedHeader => write!(f, "'Forwarded' header"),
             Source::XForwardedForHeader => write!(f, "'X-Forwarded-For' header"),
             Source::XRealIpHeader => write!(f, "'X-Real-Ip' header"),
+            Source::Header(name) => write!(f, "'{}' header", name),
             Source::SocketAddr => write!(f, "Socket address"),
         }
     }
@@ -95,16 +179,7 @@ fn pick_best_ip_from_options(
                 Some((addr, Source::XRealIpHeader))
             })
         })
-        .and_then(|(ip, source)| {
-            // Try parsing assuming it may have a port first,
-            // and then assuming it doesn't.
-            let addr = ip
-                .parse::<SocketAddr>()
-                .map(|s| s.ip())
-                .or_else(|_| ip.parse::<IpAddr>())
-                .ok()?;
-            Some((addr, source))
-        })
+        .and_then(|(ip, source)| Some((parse_addr(ip)?, source)))
         // Fall back to local IP address if the above fails
         .unwrap_or((addr.ip(), Source::SocketAddr));
 
@@ -126,9 +201,12 @@ fn pick_best_ip_from_options(
 /// Forwarded: for=192.0.2.43, for=198.51.100.17
 /// ```
 fn get_first_addr_from_forwarded_header(value: &str) -> Option<&str> {
-    let first_values = value.split(',').next()?;
+    get_addr_from_forwarded_element(value.split(',').next()?)
+}
 
-    for pair in first_values.split(';') {
+/// Find the "for" address in one of the comma separated elements of a Forwarded header.
+fn get_addr_from_forwarded_element(element: &str) -> Option<&str> {
+    for pair in element.split(';') {
         let mut parts = pair.trim().splitn(2, '=');
         let key = parts.next()?;
         let value = parts.next()?;
@@ -151,6 +229,15 @@ fn get_first_addr_from_x_forwarded_for_header(value: &str) -> Option<&str> {
     value.split(",").map(|val| val.trim()).next()
 }
 
+/// Parse an address given in a header, which may or may not have a port.
+fn parse_addr(ip: &str) -> Option<IpAddr> {
+    let ip = ip.trim();
+    ip.parse::<SocketAddr>()
+        .map(|s| s.ip())
+        .or_else(|_| ip.parse::<IpAddr>())
+        .ok()
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -176,4 +263,40 @@ mod test {
             );
         }
     }
+
+    #[test]
+    fn headers_are_only_believed_from_trusted_proxies() {
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert(
+            "x-forwarded-for",
+            "1.1.1.1, 2.2.2.2, 10.0.0.2".parse().unwrap(),
+        );
+        headers.insert("x-real-ip", "3.3.3.3".parse().unwrap());
+        let opts = RealIpOpts {
+            header: None,
+            trusted_proxies: vec!["10.0.0.0/8".parse().unwrap()],
+        };
+        let ip = |addr: &str, opts: &RealIpOpts| {
+            real_ip_with_opts(addr.parse().unwrap(), &headers, opts)
+                .0
+                .to_string()
+        };
+
+        // The client could have made up 1.1.1.1, so we take the address that our
+        // trusted proxy (10.0.0.2) was connected to by:
+        assert_eq!(ip("10.0.0.1:1234", &opts), "2.2.2.2");
+        // Connections that aren't from a trusted proxy don't get a say:
+        assert_eq!(ip("4.4.4.4:1234", &opts), "4.4.4.4");
+
+        // Only the header we're told to look at is looked at:
+        let opts = RealIpOpts {
+            header: Some(HeaderName::from_static("x-real-ip")),
+            ..opts
+        };
+        assert_eq!(ip("10.0.0.1:1234", &opts), "3.3.3.3");
+
+        // Without any trusted proxies, headers are believed from anybody, as before:
+        let opts = RealIpOpts::default();
+        assert_eq!(ip("4.4.4.4:1234", &opts), "1.1.1.1");
+    }
 }
@@ -0,0 +1,102 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use anyhow::{anyhow, Error};
+
+/// A secret that a client must give in order to be let in. It's used for:
+///
+/// - The token that shards hand to the telemetry core when they connect, so that the core
+///   knows to accept the nodes that they tell it about. This is sent in the query string
+///   of the URL that shards connect to.
+/// - The token that feeds must give to connect to the core, which is sent as part of the
+///   name of a websocket subprotocol that they offer.
+/// - The token that operators must give to use the core's admin endpoints, which is sent
+///   in an "Authorization: Bearer" header.
+///
+/// Since it can end up in any of those places, only characters which don't need escaping
+/// in a URL and are allowed in a subprotocol name and a header are allowed.
+#[derive(Clone)]
+pub struct SecretToken(Box<str>);
+
+impl SecretToken {
+    /// The token, as it should be sent.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// Does the token given match this one? The time taken to compare the two
+    /// depends only on their lengths, so that the comparison doesn't leak how
+    /// much of the given token was correct.
+    pub fn matches(&self, given: &str) -> bool {
+        let expected = self.0.as_bytes();
+        let given = given.as_bytes();
+        if expected.len() != given.len() {
+            return false;
+        }
+        let diff = expected
+            .iter()
+            .zip(given)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b));
+        diff == 0
+    }
+}
+
+impl std::str::FromStr for SecretToken {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(anyhow!("Tokens cannot be empty"));
+        }
+        let is_url_safe = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.' | '~');
+        if !s.chars().all(is_url_safe) {
+            return Err(anyhow!(
+                "Tokens can only contain the characters A-Z, a-z, 0-9, '-', '_', '.' and '~'"
+            ));
+        }
+        Ok(SecretToken(s.into()))
+    }
+}
+
+// Don't print the token itself anywhere by accident:
+impl std::fmt::Debug for SecretToken {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("SecretToken(<redacted>)")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tokens_must_be_url_safe() {
+        assert!("abc-DEF_123.~".parse::<SecretToken>().is_ok());
+        assert!("".parse::<SecretToken>().is_err());
+        assert!("abc&def".parse::<SecretToken>().is_err());
+        assert!("abc def".parse::<SecretToken>().is_err());
+    }
+
+    #[test]
+    fn tokens_only_match_themselves() {
+        let token: SecretToken = "secret".parse().unwrap();
+        assert!(token.matches("secret"));
+        assert!(!token.matches("secreT"));
+        assert!(!token.matches("secret2"));
+        assert!(!token.matches(""));
+        assert_eq!(format!("{:?}", token), "SecretToken(<redacted>)");
+    }
+}
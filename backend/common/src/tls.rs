@@ -0,0 +1,132 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Loading the certificates and keys needed to speak TLS ourselves, rather than
+//! relying on a proxy in front of us to do it.
+
+use anyhow::Context;
+use std::path::Path;
+use std::sync::Arc;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::RootCertStore;
+
+pub use tokio_rustls::rustls::{ClientConfig, ServerConfig};
+
+/// Configuration for accepting TLS connections, given a PEM encoded certificate chain
+/// and the PEM encoded private key that goes with it.
+pub fn server_config(cert_path: &Path, key_path: &Path) -> anyhow::Result<Arc<ServerConfig>> {
+    let certs = load_certs(cert_path)?;
+    let key = load_private_key(key_path)?;
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .with_context(|| format!("Invalid TLS certificate in {}", cert_path.display()))?;
+    Ok(Arc::new(config))
+}
+
+/// Configuration for making TLS connections. We trust the usual web root certificates,
+/// as well as any PEM encoded certificates in the file given (which is handy if the
+/// server we're connecting to has a self-signed certificate).
+pub fn client_config(ca_cert_path: Option<&Path>) -> anyhow::Result<Arc<ClientConfig>> {
+    let mut roots = RootCertStore::empty();
+    roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned());
+    if let Some(ca_cert_path) = ca_cert_path {
+        for cert in load_certs(ca_cert_path)? {
+            roots
+                .add(cert)
+                .with_context(|| format!("Invalid CA certificate in {}", ca_cert_path.display()))?;
+        }
+    }
+    let config = ClientConfig::builder()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    Ok(Arc::new(config))
+}
+
+fn load_certs(path: &Path) -> anyhow::Result<Vec<CertificateDer<'static>>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Couldn't open certificate file {}", path.display()))?;
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(file))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("Couldn't read certificates from {}", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("No certificates found in {}", path.display());
+    }
+    Ok(certs)
+}
+
+fn load_private_key(path: &Path) -> anyhow::Result<PrivateKeyDer<'static>> {
+    let file = std::fs::File::open(path)
+        .with_context(|| format!("Couldn't open private key file {}", path.display()))?;
+    rustls_pemfile::private_key(&mut std::io::BufReader::new(file))
+        .with_context(|| format!("Couldn't read private key from {}", path.display()))?
+        .ok_or_else(|| anyhow::anyhow!("No private key found in {}", path.display()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio_rustls::rustls::pki_types::ServerName;
+
+    fn test_cert_path(name: &str) -> std::path::PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("test_certs")
+            .join(name)
+    }
+
+    #[tokio::test]
+    async fn clients_can_talk_to_servers_with_self_signed_certs() {
+        let server_config = server_config(
+            &test_cert_path("localhost.crt"),
+            &test_cert_path("localhost.key"),
+        )
+        .unwrap();
+        let client_config = client_config(Some(&test_cert_path("localhost.crt"))).unwrap();
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (socket, _) = listener.accept().await.unwrap();
+            let acceptor = tokio_rustls::TlsAcceptor::from(server_config);
+            let mut stream = acceptor.accept(socket).await.unwrap();
+            let mut buf = [0; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let socket = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let connector = tokio_rustls::TlsConnector::from(client_config);
+        let server_name = ServerName::try_from("localhost").unwrap();
+        let mut stream = connector.connect(server_name, socket).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        server.await.unwrap();
+    }
+
+    #[test]
+    fn missing_keys_are_an_error() {
+        let err = server_config(
+            &test_cert_path("localhost.crt"),
+            &test_cert_path("localhost.crt"),
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("No private key found"));
+    }
+}
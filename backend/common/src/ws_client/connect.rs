@@ -15,9 +15,15 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 use super::on_close::OnClose;
 use futures::{channel, StreamExt};
+use soketto::extension::deflate::Deflate;
 use soketto::handshake::{Client, ServerResponse};
+use std::pin::Pin;
 use std::sync::Arc;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
 use tokio::net::TcpStream;
+use tokio_rustls::client::TlsStream;
+use tokio_rustls::rustls::pki_types::ServerName;
 use tokio_util::compat::TokioAsyncReadCompatExt;
 
 use super::{
@@ -26,20 +32,71 @@ use super::{
 };
 
 /// The send side of a Soketto WebSocket connection
-pub type RawSender = soketto::connection::Sender<tokio_util::compat::Compat<tokio::net::TcpStream>>;
+pub type RawSender = soketto::connection::Sender<tokio_util::compat::Compat<Stream>>;
 
 /// The receive side of a Soketto WebSocket connection
-pub type RawReceiver =
-    soketto::connection::Receiver<tokio_util::compat::Compat<tokio::net::TcpStream>>;
+pub type RawReceiver = soketto::connection::Receiver<tokio_util::compat::Compat<Stream>>;
+
+/// The underlying connection; plain TCP for `ws://` URIs, or TLS for `wss://` ones.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+impl AsyncRead for Stream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_read(cx, buf),
+            Stream::Tls(s) => Pin::new(s).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for Stream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_write(cx, buf),
+            Stream::Tls(s) => Pin::new(s).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_flush(cx),
+            Stream::Tls(s) => Pin::new(s).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        match self.get_mut() {
+            Stream::Plain(s) => Pin::new(s).poll_shutdown(cx),
+            Stream::Tls(s) => Pin::new(s).poll_shutdown(cx),
+        }
+    }
+}
 
 /// A websocket connection. From this, we can either expose the raw connection
 /// or expose a cancel-safe interface to it.
 pub struct Connection {
     tx: RawSender,
     rx: RawReceiver,
+    protocol: Option<String>,
 }
 
 impl Connection {
+    /// The subprotocol that the server accepted in its handshake response, if any.
+    pub fn protocol(&self) -> Option<&str> {
+        self.protocol.as_deref()
+    }
+
     /// Get hold of the raw send/receive interface for this connection.
     /// These are not cancel-safe, but can be more performant than the
     /// cancel-safe channel based interface.
@@ -223,21 +280,74 @@ pub enum ConnectError {
     ConnectionFailedRedirect { status_code: u16 },
     #[error("Connection rejected (status code: {status_code})")]
     ConnectionFailedRejected { status_code: u16 },
+    #[error("Invalid TLS server name: {0}")]
+    InvalidServerName(String),
+    #[error("TLS error: {0}")]
+    Tls(#[from] anyhow::Error),
+}
+
+/// Options to configure a websocket connection.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectOpts {
+    /// Offer to compress messages using the "permessage-deflate" extension. Messages
+    /// are only compressed if the server agrees to this in its handshake response.
+    pub compress: bool,
+    /// How to make `wss://` connections. If this isn't given, we trust the usual web
+    /// root certificates (see [`crate::tls::client_config`]).
+    pub tls: Option<Arc<crate::tls::ClientConfig>>,
+    /// Subprotocols to offer to speak, in order of preference. The server can accept
+    /// one of these in its handshake response (see [`Connection::protocol`]).
+    pub protocols: Vec<String>,
 }
 
 /// Establish a websocket connection that you can send and receive messages from.
 pub async fn connect(uri: &http::Uri) -> Result<Connection, ConnectError> {
+    connect_with_opts(uri, ConnectOpts::default()).await
+}
+
+/// Like [`connect`], but with some options to configure the connection.
+pub async fn connect_with_opts(
+    uri: &http::Uri,
+    opts: ConnectOpts,
+) -> Result<Connection, ConnectError> {
     let host = uri.host().unwrap_or("127.0.0.1");
-    let port = uri.port_u16().unwrap_or(80);
-    let path = uri.path();
+    let is_tls = uri.scheme_str() == Some("wss");
+    let port = uri.port_u16().unwrap_or(if is_tls { 443 } else { 80 });
+    let path = uri
+        .path_and_query()
+        .map(|path| path.as_str())
+        .unwrap_or("/");
 
     let socket = TcpStream::connect((host, port)).await?;
     socket.set_nodelay(true).expect("socket set_nodelay failed");
+    let socket = if is_tls {
+        let tls = match opts.tls {
+            Some(tls) => tls,
+            None => crate::tls::client_config(None)?,
+        };
+        let server_name = ServerName::try_from(host.to_owned())
+            .map_err(|_| ConnectError::InvalidServerName(host.to_owned()))?;
+        let stream = tokio_rustls::TlsConnector::from(tls)
+            .connect(server_name, socket)
+            .await?;
+        Stream::Tls(Box::new(stream))
+    } else {
+        Stream::Plain(socket)
+    };
 
     // Establish a WS connection:
     let mut client = Client::new(socket.compat(), host, &path);
-    let (ws_to_connection, ws_from_connection) = match client.handshake().await? {
-        ServerResponse::Accepted { .. } => client.into_builder().finish(),
+    if opts.compress {
+        client.add_extension(Box::new(Deflate::new(soketto::Mode::Client)));
+    }
+    for protocol in &opts.protocols {
+        client.add_protocol(protocol);
+    }
+    let (ws_to_connection, ws_from_connection, protocol) = match client.handshake().await? {
+        ServerResponse::Accepted { protocol } => {
+            let (tx, rx) = client.into_builder().finish();
+            (tx, rx, protocol)
+        }
         ServerResponse::Redirect { status_code, .. } => {
             return Err(ConnectError::ConnectionFailedRedirect { status_code })
         }
@@ -249,5 +359,6 @@ pub async fn connect(uri: &http::Uri) -> Result<Connection, ConnectError> {
     Ok(Connection {
         tx: ws_to_connection,
         rx: ws_from_connection,
+        protocol,
     })
 }
@@ -23,6 +23,8 @@ mod receiver;
 /// The channel based send interface
 mod sender;
 
-pub use connect::{connect, ConnectError, Connection, RawReceiver, RawSender};
+pub use connect::{
+    connect, connect_with_opts, ConnectError, ConnectOpts, Connection, RawReceiver, RawSender,
+};
 pub use receiver::{Receiver, RecvError, RecvMessage};
 pub use sender::{SendError, Sender, SentMessage};
@@ -0,0 +1,101 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Limits how quickly new connections are accepted. When the core restarts, every shard
+/// tries to reconnect at once; this lets a second's worth of them in straight away, and
+/// tells the rest when to come back. Each connection turned away is given its own slot
+/// to come back in (plus a little jitter), so that they arrive spread out rather than
+/// all retrying together again.
+#[derive(Clone)]
+pub struct AcceptRateLimiter {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    per_second: f64,
+    /// How many connections we can accept right now. This tops back up over time.
+    tokens: f64,
+    last_refill: Instant,
+    /// The slot that the last connection we turned away was told to come back in.
+    last_slot: Instant,
+}
+
+impl AcceptRateLimiter {
+    pub fn new(per_second: u32, now: Instant) -> Self {
+        let per_second = per_second.max(1) as f64;
+        AcceptRateLimiter {
+            inner: Arc::new(Mutex::new(Inner {
+                per_second,
+                tokens: per_second,
+                last_refill: now,
+                last_slot: now,
+            })),
+        }
+    }
+
+    /// Accept a new connection if we can, or otherwise hand back how long it should wait
+    /// before trying again.
+    pub fn try_accept(&self, now: Instant) -> Result<(), Duration> {
+        let mut inner = self.inner.lock().unwrap();
+
+        let elapsed = now.saturating_duration_since(inner.last_refill);
+        inner.tokens =
+            (inner.tokens + elapsed.as_secs_f64() * inner.per_second).min(inner.per_second);
+        inner.last_refill = now;
+
+        if inner.tokens >= 1.0 {
+            inner.tokens -= 1.0;
+            return Ok(());
+        }
+
+        let interval = Duration::from_secs_f64(1.0 / inner.per_second);
+        let slot = inner.last_slot.max(now) + interval;
+        inner.last_slot = slot;
+        let jitter = interval.mul_f64(rand::random::<f64>());
+        Err(slot - now + jitter)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn connections_beyond_the_rate_are_spread_out() {
+        let now = Instant::now();
+        let limiter = AcceptRateLimiter::new(2, now);
+
+        // A second's worth are accepted straight away:
+        assert!(limiter.try_accept(now).is_ok());
+        assert!(limiter.try_accept(now).is_ok());
+
+        // The rest are each given a later slot to come back in, plus up to a slot of jitter:
+        for n in 1..=4 {
+            let wait = limiter.try_accept(now).unwrap_err();
+            assert!(wait >= Duration::from_millis(500 * n));
+            assert!(wait < Duration::from_millis(500 * (n + 1)));
+        }
+
+        // Once enough time has passed, connections are accepted again:
+        assert!(limiter.try_accept(now + Duration::from_millis(500)).is_ok());
+        assert!(limiter
+            .try_accept(now + Duration::from_millis(500))
+            .is_err());
+    }
+}
@@ -15,11 +15,14 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use super::inner_loop;
-use crate::find_location::find_location;
-use crate::state::NodeId;
+use crate::feed_sink::FeedSinkHandle;
+use crate::find_location::{find_location, LocatorOpts};
+use crate::state::{
+    ChainListsSnapshot, PersistedState, QuotasSnapshot, StateExport, StateOpts, StateSnapshot,
+};
 use common::id_type;
+use common::node_types::BlockHash;
 use futures::{future, Sink, SinkExt};
-use std::net::Ipv4Addr;
 use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
 
@@ -30,28 +33,55 @@ id_type! {
     pub struct ConnId(u64)
 }
 
+/// How often we try to add nodes that are waiting for their turn.
+const QUEUED_NODE_ADD_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// How often we look for disconnected nodes that have run out of time to reconnect.
+const DISCONNECTED_NODE_EXPIRY_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// How often we look for chains that have had no nodes for too long.
+const EMPTY_CHAIN_REMOVAL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How often we look for nodes that haven't sent anything for too long.
+const IDLE_NODE_REMOVAL_INTERVAL: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// How often we look for node updates that were held back and can now be processed.
+const SAMPLED_UPDATE_INTERVAL: std::time::Duration = std::time::Duration::from_millis(100);
+
+/// How often we tell feeds about the average block time of each chain.
+const CHAIN_BLOCK_TIME_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
 #[derive(Clone)]
 pub struct Aggregator(Arc<AggregatorInternal>);
 
 /// Options to configure the aggregator loop(s)
 #[derive(Debug, Clone)]
 pub struct AggregatorOpts {
-    /// Any node from these chains is muted
-    pub denylist: Vec<String>,
     /// If our incoming message queue exceeds this length, we start
     /// dropping non-essential messages.
     pub max_queue_len: usize,
-    /// How many nodes from third party chains are allowed to connect
-    /// before we prevent connections from them.
-    pub max_third_party_nodes: usize,
+    /// If more than this many messages are waiting to be sent to a feed, it's
+    /// too slow to keep up and is disconnected.
+    pub max_feed_queue_len: Option<usize>,
+    /// How many nodes any one shard can add each second. Any more than this are queued
+    /// and added once they can be. By default, there's no limit.
+    pub max_node_adds_per_second: Option<u32>,
+    /// If given, feeds are split between this many tasks, which send messages out to them
+    /// in parallel. By default, the aggregator loop sends messages to every feed itself.
+    pub feed_workers: Option<usize>,
+    /// If given, feed messages are published to this sink as well as being sent to feeds.
+    pub feed_sink: Option<FeedSinkHandle>,
+    /// If given, every message that shards send us is recorded here.
+    #[cfg(feature = "recording")]
+    pub shard_recorder: Option<crate::recording::ShardRecorder>,
+    /// Which nodes are we willing to accept?
+    pub state: StateOpts,
+    /// How should we go about locating nodes?
+    pub locator: LocatorOpts,
 }
 
 struct AggregatorInternal {
-    /// Shards that connect are each assigned a unique connection ID.
-    /// This helps us know who to send messages back to (especially in
-    /// conjunction with the `ShardNodeId` that messages will come with).
-    shard_conn_id: AtomicU64,
-    /// Feeds that connect have their own unique connection ID, too.
+    /// Feeds that connect are each assigned a unique connection ID.
     feed_conn_id: AtomicU64,
     /// Send messages in to the aggregator from the outside via this. This is
     /// stored here so that anybody holding an `Aggregator` handle can
@@ -65,48 +95,103 @@ impl Aggregator {
         let (tx_to_aggregator, rx_from_external) = flume::unbounded();
 
         // Kick off a locator task to locate nodes, which hands back a channel to make location requests
-        let tx_to_locator =
-            find_location(tx_to_aggregator.clone().into_sink().with(|(node_id, msg)| {
-                future::ok::<_, flume::SendError<_>>(inner_loop::ToAggregator::FromFindLocation(
-                    node_id, msg,
-                ))
-            }));
+        let (tx_to_locator, locator_metrics) = find_location(
+            tx_to_aggregator
+                .clone()
+                .into_sink()
+                .with(|(node_id, ip, msg)| {
+                    future::ok::<_, flume::SendError<_>>(
+                        inner_loop::ToAggregator::FromFindLocation(node_id, ip, msg),
+                    )
+                }),
+            opts.locator,
+        )?;
+
+        let reconnect_grace_period = opts.state.reconnect_grace_period;
+        let empty_chain_ttl = opts.state.empty_chain_ttl;
+        let node_idle_timeout = opts.state.node_idle_timeout;
+        let min_update_interval = opts.state.min_update_interval;
 
-        // Handle any incoming messages in our handler loop:
-        tokio::spawn(Aggregator::handle_messages(
-            rx_from_external,
+        // Handle any incoming messages in our handler loop. This is created here rather than
+        // in the task, so that any feed workers that it needs are spawned alongside it:
+        let mut inner_loop = inner_loop::InnerLoop::new(
             tx_to_locator,
+            locator_metrics,
+            opts.state,
             opts.max_queue_len,
-            opts.denylist,
-            opts.max_third_party_nodes,
-        ));
+            opts.max_feed_queue_len,
+            opts.max_node_adds_per_second,
+            opts.feed_workers,
+        );
+        if let Some(sink) = opts.feed_sink {
+            inner_loop.set_feed_sink(sink);
+        }
+        tokio::spawn(Aggregator::handle_messages(rx_from_external, inner_loop));
+
+        // Nodes that were queued because their shard added them too quickly are added
+        // a little at a time:
+        if opts.max_node_adds_per_second.is_some() {
+            Aggregator::spawn_ticker(&tx_to_aggregator, QUEUED_NODE_ADD_INTERVAL, || {
+                inner_loop::ToAggregator::AddQueuedNodes
+            });
+        }
+        if reconnect_grace_period.is_some() {
+            Aggregator::spawn_ticker(&tx_to_aggregator, DISCONNECTED_NODE_EXPIRY_INTERVAL, || {
+                inner_loop::ToAggregator::ExpireDisconnectedNodes
+            });
+        }
+        if empty_chain_ttl.is_some() {
+            Aggregator::spawn_ticker(&tx_to_aggregator, EMPTY_CHAIN_REMOVAL_INTERVAL, || {
+                inner_loop::ToAggregator::RemoveEmptyChains
+            });
+        }
+        if node_idle_timeout.is_some() {
+            Aggregator::spawn_ticker(&tx_to_aggregator, IDLE_NODE_REMOVAL_INTERVAL, || {
+                inner_loop::ToAggregator::RemoveIdleNodes
+            });
+        }
+        if min_update_interval.is_some() {
+            Aggregator::spawn_ticker(&tx_to_aggregator, SAMPLED_UPDATE_INTERVAL, || {
+                inner_loop::ToAggregator::ProcessSampledUpdates
+            });
+        }
+        Aggregator::spawn_ticker(&tx_to_aggregator, CHAIN_BLOCK_TIME_INTERVAL, || {
+            inner_loop::ToAggregator::BroadcastChainBlockTimes
+        });
 
         // Return a handle to our aggregator:
         Ok(Aggregator(Arc::new(AggregatorInternal {
-            shard_conn_id: AtomicU64::new(1),
             feed_conn_id: AtomicU64::new(1),
             tx_to_aggregator,
         })))
     }
 
+    /// Send the aggregator a message every so often, until it goes away.
+    fn spawn_ticker(
+        tx_to_aggregator: &flume::Sender<inner_loop::ToAggregator>,
+        period: std::time::Duration,
+        msg: fn() -> inner_loop::ToAggregator,
+    ) {
+        let tx_to_aggregator = tx_to_aggregator.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                if tx_to_aggregator.send_async(msg()).await.is_err() {
+                    return;
+                }
+            }
+        });
+    }
+
     /// This is spawned into a separate task and handles any messages coming
     /// in to the aggregator. If nobody is holding the tx side of the channel
     /// any more, this task will gracefully end.
     async fn handle_messages(
         rx_from_external: flume::Receiver<inner_loop::ToAggregator>,
-        tx_to_aggregator: flume::Sender<(NodeId, Ipv4Addr)>,
-        max_queue_len: usize,
-        denylist: Vec<String>,
-        max_third_party_nodes: usize,
+        inner_loop: inner_loop::InnerLoop,
     ) {
-        inner_loop::InnerLoop::new(
-            tx_to_aggregator,
-            denylist,
-            max_queue_len,
-            max_third_party_nodes,
-        )
-        .handle(rx_from_external)
-        .await;
+        inner_loop.handle(rx_from_external).await;
     }
 
     /// Gather metrics from our aggregator loop
@@ -120,24 +205,152 @@ impl Aggregator {
         Ok(metrics)
     }
 
+    /// Take a snapshot of the chains and nodes that our aggregator loop knows about. Operators
+    /// are told about every chain, and which shard each node is connected through.
+    pub async fn gather_snapshot(
+        &self,
+        genesis_hash: Option<BlockHash>,
+        for_operators: bool,
+    ) -> anyhow::Result<StateSnapshot> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherSnapshot(genesis_hash, for_operators, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let snapshot = rx.recv_async().await?;
+        Ok(snapshot)
+    }
+
+    /// Obtain the parts of the state that we'd like to write to disk.
+    pub async fn gather_persisted_state(&self) -> anyhow::Result<PersistedState> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherPersistedState(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let persisted = rx.recv_async().await?;
+        Ok(persisted)
+    }
+
+    /// Obtain the denylist and allowlist that our aggregator loop is using.
+    pub async fn gather_chain_lists(&self) -> anyhow::Result<ChainListsSnapshot> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherChainLists(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let chain_lists = rx.recv_async().await?;
+        Ok(chain_lists)
+    }
+
+    /// Obtain the quotas that our aggregator loop is using, and how close chains are to them.
+    pub async fn gather_quotas(&self) -> anyhow::Result<QuotasSnapshot> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherQuotas(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let quotas = rx.recv_async().await?;
+        Ok(quotas)
+    }
+
+    /// Obtain everything that our aggregator loop knows about, to be exported.
+    pub async fn gather_export(&self) -> anyhow::Result<StateExport> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::GatherExport(tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let export = rx.recv_async().await?;
+        Ok(export)
+    }
+
+    /// Restore state that was previously written to disk. Restored nodes will be
+    /// removed when [`Aggregator::expire_restored_nodes`] is called, unless they've
+    /// reconnected by then.
+    pub fn restore_state(&self, persisted: PersistedState) -> anyhow::Result<()> {
+        self.0
+            .tx_to_aggregator
+            .send(inner_loop::ToAggregator::RestoreState(persisted))?;
+        Ok(())
+    }
+
+    /// Remove any restored nodes that haven't reconnected.
+    pub fn expire_restored_nodes(&self) -> anyhow::Result<()> {
+        self.0
+            .tx_to_aggregator
+            .send(inner_loop::ToAggregator::ExpireRestoredNodes)?;
+        Ok(())
+    }
+
+    /// Replace the list of chains that aren't allowed to connect, removing any
+    /// nodes already connected to them.
+    pub fn set_denylist(&self, denylist: Vec<String>) -> anyhow::Result<()> {
+        self.0
+            .tx_to_aggregator
+            .send(inner_loop::ToAggregator::SetDenylist(denylist))?;
+        Ok(())
+    }
+
+    /// Disconnect the node known to feeds of the chain with this genesis hash as `node_id`.
+    /// Hands back false if there's no such node.
+    pub async fn disconnect_node(
+        &self,
+        genesis_hash: BlockHash,
+        node_id: usize,
+    ) -> anyhow::Result<bool> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::DisconnectNode(genesis_hash, node_id, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let found = rx.recv_async().await?;
+        Ok(found)
+    }
+
+    /// Stop or start sending updates to feeds subscribed to the chain with this genesis hash.
+    /// Hands back false if there's no such chain to pause, or if it wasn't paused to resume.
+    pub async fn set_chain_paused(
+        &self,
+        genesis_hash: BlockHash,
+        paused: bool,
+    ) -> anyhow::Result<bool> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::SetChainPaused(genesis_hash, paused, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let changed = rx.recv_async().await?;
+        Ok(changed)
+    }
+
+    /// Disconnect the shard with the connection ID given, and remove all of its nodes.
+    /// Hands back false if there's no such shard.
+    pub async fn disconnect_shard(&self, shard_conn_id: ConnId) -> anyhow::Result<bool> {
+        let (tx, rx) = flume::unbounded();
+        let msg = inner_loop::ToAggregator::DisconnectShard(shard_conn_id, tx);
+
+        self.0.tx_to_aggregator.send_async(msg).await?;
+
+        let found = rx.recv_async().await?;
+        Ok(found)
+    }
+
     /// Return a sink that a shard can send messages into to be handled by the aggregator.
+    /// Messages are passed along with the connection ID given, which should be unique to
+    /// this shard connection.
     pub fn subscribe_shard(
         &self,
+        shard_conn_id: ConnId,
     ) -> impl Sink<inner_loop::FromShardWebsocket, Error = anyhow::Error> + Send + Sync + Unpin + 'static
     {
-        // Assign a unique aggregator-local ID to each connection that subscribes, and pass
-        // that along with every message to the aggregator loop:
-        let shard_conn_id = self
-            .0
-            .shard_conn_id
-            .fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         let tx_to_aggregator = self.0.tx_to_aggregator.clone();
 
         // Calling `send` on this Sink requires Unpin. There may be a nicer way than this,
         // but pinning by boxing is the easy solution for now:
         Box::pin(tx_to_aggregator.into_sink().with(move |msg| async move {
             Ok(inner_loop::ToAggregator::FromShardWebsocket(
-                shard_conn_id.into(),
+                shard_conn_id,
                 msg,
             ))
         }))
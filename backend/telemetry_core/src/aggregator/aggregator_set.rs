@@ -1,10 +1,18 @@
-use super::aggregator::{Aggregator, AggregatorOpts};
+use super::aggregator::{Aggregator, AggregatorOpts, ConnId};
 use super::inner_loop;
+use crate::state::{
+    ChainListsSnapshot, PersistedState, QuotasSnapshot, StateExport, StateSnapshot,
+};
+use common::node_types::BlockHash;
 use common::EitherSink;
 use futures::{Sink, SinkExt};
 use inner_loop::{FromShardWebsocket, Metrics};
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// How long a snapshot handed out to the public is reused for before we take another.
+const PUBLIC_SNAPSHOT_MAX_AGE: Duration = Duration::from_secs(1);
 
 #[derive(Clone)]
 pub struct AggregatorSet(Arc<AggregatorSetInner>);
@@ -12,7 +20,15 @@ pub struct AggregatorSet(Arc<AggregatorSetInner>);
 pub struct AggregatorSetInner {
     aggregators: Vec<Aggregator>,
     next_idx: AtomicUsize,
+    /// Shards that connect are each assigned a unique connection ID. Every
+    /// aggregator hears about every shard, and knows it by this same ID.
+    shard_conn_id: AtomicU64,
     metrics: Mutex<Vec<Metrics>>,
+    /// The last snapshot handed out to the public, and when it was taken.
+    public_snapshot: tokio::sync::Mutex<Option<(Instant, Arc<StateSnapshot>)>>,
+    /// Records every message that shards send us, if we've been asked to.
+    #[cfg(feature = "recording")]
+    shard_recorder: Option<crate::recording::ShardRecorder>,
 }
 
 impl AggregatorSet {
@@ -23,17 +39,29 @@ impl AggregatorSet {
     ) -> anyhow::Result<AggregatorSet> {
         assert_ne!(num_aggregators, 0, "You must have 1 or more aggregator");
 
-        let aggregators = futures::future::try_join_all(
-            (0..num_aggregators).map(|_| Aggregator::spawn(opts.clone())),
-        )
+        let aggregators = futures::future::try_join_all((0..num_aggregators).map(|idx| {
+            // Every aggregator sends out the same messages about chains, so only the
+            // first one needs to publish them to the feed sink:
+            let mut opts = opts.clone();
+            if idx > 0 {
+                opts.feed_sink = None;
+            }
+            Aggregator::spawn(opts)
+        }))
         .await?;
 
         let initial_metrics = (0..num_aggregators).map(|_| Metrics::default()).collect();
+        #[cfg(feature = "recording")]
+        let shard_recorder = opts.shard_recorder.clone();
 
         let this = AggregatorSet(Arc::new(AggregatorSetInner {
             aggregators,
             next_idx: AtomicUsize::new(0),
+            shard_conn_id: AtomicU64::new(1),
             metrics: Mutex::new(initial_metrics),
+            public_snapshot: tokio::sync::Mutex::new(None),
+            #[cfg(feature = "recording")]
+            shard_recorder,
         }));
 
         // Start asking for metrics:
@@ -82,15 +110,186 @@ impl AggregatorSet {
         self.0.metrics.lock().unwrap().clone()
     }
 
-    /// Return a sink that a shard can send messages into to be handled by all aggregators.
+    /// Take a snapshot of the current state. Every internal aggregator knows about
+    /// every node, so we just ask whichever is next in line.
+    pub async fn gather_snapshot(
+        &self,
+        genesis_hash: Option<BlockHash>,
+        for_operators: bool,
+    ) -> anyhow::Result<StateSnapshot> {
+        let last_val = self.0.next_idx.fetch_add(1, Ordering::Relaxed);
+        let this_idx = (last_val + 1) % self.0.aggregators.len();
+
+        self.0.aggregators[this_idx]
+            .gather_snapshot(genesis_hash, for_operators)
+            .await
+    }
+
+    /// Obtain a snapshot of the chains that feeds are being told about, to hand out to the
+    /// public. Anybody can ask for this as often as they like, so rather than interrupting
+    /// the aggregators each time, the same snapshot is handed out until it's
+    /// [`PUBLIC_SNAPSHOT_MAX_AGE`] old.
+    pub async fn public_snapshot(
+        &self,
+        genesis_hash: Option<BlockHash>,
+    ) -> anyhow::Result<Arc<StateSnapshot>> {
+        let snapshot = {
+            // Anybody else asking while we take a new snapshot waits for it to arrive:
+            let mut cached = self.0.public_snapshot.lock().await;
+            match &*cached {
+                Some((taken_at, snapshot)) if taken_at.elapsed() < PUBLIC_SNAPSHOT_MAX_AGE => {
+                    Arc::clone(snapshot)
+                }
+                _ => {
+                    let snapshot = Arc::new(self.gather_snapshot(None, false).await?);
+                    *cached = Some((Instant::now(), Arc::clone(&snapshot)));
+                    snapshot
+                }
+            }
+        };
+
+        Ok(match genesis_hash {
+            Some(genesis_hash) => Arc::new(StateSnapshot {
+                chains: snapshot
+                    .chains
+                    .iter()
+                    .filter(|chain| chain.genesis_hash == genesis_hash)
+                    .cloned()
+                    .collect(),
+            }),
+            None => snapshot,
+        })
+    }
+
+    /// Obtain the state that we'd like to write to disk. Every internal aggregator
+    /// knows about every node, so any of them can tell us this.
+    pub async fn gather_persisted_state(&self) -> anyhow::Result<PersistedState> {
+        self.0.aggregators[0].gather_persisted_state().await
+    }
+
+    /// Obtain the denylist and allowlist in use. Every internal aggregator is given the
+    /// same lists, so any of them can tell us this.
+    pub async fn gather_chain_lists(&self) -> anyhow::Result<ChainListsSnapshot> {
+        self.0.aggregators[0].gather_chain_lists().await
+    }
+
+    /// Obtain the quotas in use, and how close each chain is to its own. Every internal
+    /// aggregator is given the same quotas and knows about every node, so any of them can
+    /// tell us this.
+    pub async fn gather_quotas(&self) -> anyhow::Result<QuotasSnapshot> {
+        self.0.aggregators[0].gather_quotas().await
+    }
+
+    /// Obtain everything we know about every chain and node, to be exported. As with
+    /// [`AggregatorSet::gather_snapshot`], any internal aggregator can tell us this.
+    pub async fn gather_export(&self) -> anyhow::Result<StateExport> {
+        self.0.aggregators[0].gather_export().await
+    }
+
+    /// Restore previously persisted state into every internal aggregator.
+    pub fn restore_state(&self, persisted: PersistedState) -> anyhow::Result<()> {
+        for a in &self.0.aggregators {
+            a.restore_state(persisted.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Remove any restored nodes that haven't reconnected from every internal aggregator.
+    pub fn expire_restored_nodes(&self) -> anyhow::Result<()> {
+        for a in &self.0.aggregators {
+            a.expire_restored_nodes()?;
+        }
+        Ok(())
+    }
+
+    /// Update the denylist used by every internal aggregator.
+    pub fn set_denylist(&self, denylist: Vec<String>) -> anyhow::Result<()> {
+        for a in &self.0.aggregators {
+            a.set_denylist(denylist.clone())?;
+        }
+        Ok(())
+    }
+
+    /// Disconnect a node from every internal aggregator. Hands back false if there's no
+    /// such node.
+    pub async fn disconnect_node(
+        &self,
+        genesis_hash: BlockHash,
+        node_id: usize,
+    ) -> anyhow::Result<bool> {
+        let found = futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.disconnect_node(genesis_hash, node_id)),
+        )
+        .await?;
+        Ok(found.into_iter().any(|found| found))
+    }
+
+    /// Pause or resume updates to feeds of a chain in every internal aggregator. Hands back
+    /// false if there's no such chain to pause, or if it wasn't paused to resume.
+    pub async fn set_chain_paused(
+        &self,
+        genesis_hash: BlockHash,
+        paused: bool,
+    ) -> anyhow::Result<bool> {
+        let changed = futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.set_chain_paused(genesis_hash, paused)),
+        )
+        .await?;
+        Ok(changed.into_iter().any(|changed| changed))
+    }
+
+    /// Disconnect a shard from every internal aggregator. Hands back false if there's no
+    /// such shard.
+    pub async fn disconnect_shard(&self, shard_conn_id: ConnId) -> anyhow::Result<bool> {
+        let found = futures::future::try_join_all(
+            self.0
+                .aggregators
+                .iter()
+                .map(|a| a.disconnect_shard(shard_conn_id)),
+        )
+        .await?;
+        Ok(found.into_iter().any(|found| found))
+    }
+
+    /// Return a sink that a shard can send messages into to be handled by all aggregators,
+    /// along with the ID that the shard connection is known by.
     pub fn subscribe_shard(
         &self,
+    ) -> (
+        ConnId,
+        impl Sink<inner_loop::FromShardWebsocket, Error = anyhow::Error> + Send + Sync + Unpin + 'static,
+    ) {
+        let shard_conn_id = ConnId::from(self.0.shard_conn_id.fetch_add(1, Ordering::Relaxed));
+        let sink = self.subscribe_shard_with_id(shard_conn_id);
+
+        // Note down everything that the shard sends us on the way past, if we're recording:
+        #[cfg(feature = "recording")]
+        let sink = match self.0.shard_recorder.clone() {
+            Some(recorder) => EitherSink::a(sink.with(move |msg: FromShardWebsocket| {
+                recorder.record(shard_conn_id.into(), &msg);
+                futures::future::ok::<_, anyhow::Error>(msg)
+            })),
+            None => EitherSink::b(sink),
+        };
+
+        (shard_conn_id, sink)
+    }
+
+    fn subscribe_shard_with_id(
+        &self,
+        shard_conn_id: ConnId,
     ) -> impl Sink<inner_loop::FromShardWebsocket, Error = anyhow::Error> + Send + Sync + Unpin + 'static
     {
         // Special case 1 aggregator to avoid the extra indirection and so on
         // if we don't actually need it.
         if self.0.aggregators.len() == 1 {
-            let sub = self.0.aggregators[0].subscribe_shard();
+            let sub = self.0.aggregators[0].subscribe_shard(shard_conn_id);
             return EitherSink::a(sub);
         }
 
@@ -98,7 +297,7 @@ impl AggregatorSet {
             .0
             .aggregators
             .iter()
-            .map(|a| a.subscribe_shard())
+            .map(|a| a.subscribe_shard(shard_conn_id))
             .collect();
 
         let (tx, rx) = flume::unbounded::<FromShardWebsocket>();
@@ -0,0 +1,377 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use super::aggregator::ConnId;
+use super::inner_loop::ToFeedWebsocket;
+use crate::feed_sink::FeedSinkHandle;
+use common::node_types::BlockHash;
+use common::MultiMapUnique;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+/// Something to do with the feeds that a [`FeedGroup`] looks after.
+#[derive(Clone, Debug)]
+enum FeedCommand {
+    Add(ConnId, flume::Sender<ToFeedWebsocket>),
+    Remove(ConnId),
+    Subscribe(ConnId, BlockHash),
+    Unsubscribe(ConnId),
+    SendTo(ConnId, ToFeedWebsocket),
+    SendToChain(BlockHash, ToFeedWebsocket),
+    SendToAll(ToFeedWebsocket),
+    /// Update the [`FeedGroupCounts`] to reflect how things are now.
+    RefreshCounts,
+}
+
+/// Figures about a [`FeedGroup`]. The number of feeds and queued messages are only
+/// brought up to date when a [`FeedCommand::RefreshCounts`] is handled.
+#[derive(Default, Debug)]
+struct FeedGroupCounts {
+    feeds: AtomicUsize,
+    queued_messages: AtomicUsize,
+    dropped_feeds: AtomicU64,
+}
+
+/// Some feed connections, and the chain that each is subscribed to.
+struct FeedGroup {
+    channels: HashMap<ConnId, flume::Sender<ToFeedWebsocket>>,
+    chain_to_feed_conn_ids: MultiMapUnique<BlockHash, ConnId>,
+    /// How many messages can be waiting to be sent to a feed before we give up on it.
+    max_feed_queue_len: Option<usize>,
+    counts: Arc<FeedGroupCounts>,
+}
+
+impl FeedGroup {
+    fn new(max_feed_queue_len: Option<usize>) -> Self {
+        FeedGroup {
+            channels: HashMap::new(),
+            chain_to_feed_conn_ids: MultiMapUnique::new(),
+            max_feed_queue_len,
+            counts: Arc::new(FeedGroupCounts::default()),
+        }
+    }
+
+    fn handle(&mut self, cmd: FeedCommand) {
+        match cmd {
+            FeedCommand::Add(feed_conn_id, channel) => {
+                self.channels.insert(feed_conn_id, channel);
+            }
+            FeedCommand::Remove(feed_conn_id) => {
+                self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
+                self.channels.remove(&feed_conn_id);
+            }
+            FeedCommand::Subscribe(feed_conn_id, genesis_hash) => {
+                if self.channels.contains_key(&feed_conn_id) {
+                    self.chain_to_feed_conn_ids
+                        .insert(genesis_hash, feed_conn_id);
+                }
+            }
+            FeedCommand::Unsubscribe(feed_conn_id) => {
+                self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
+            }
+            FeedCommand::SendTo(feed_conn_id, message) => {
+                if let Some(chan) = self.channels.get(&feed_conn_id) {
+                    let _ = chan.send(message);
+                }
+            }
+            FeedCommand::SendToChain(genesis_hash, message) => {
+                let mut too_slow = Vec::new();
+                if let Some(feeds) = self.chain_to_feed_conn_ids.get_values(&genesis_hash) {
+                    for &feed_id in feeds {
+                        if let Some(chan) = self.channels.get(&feed_id) {
+                            if matches!(self.max_feed_queue_len, Some(max) if chan.len() >= max) {
+                                too_slow.push(feed_id);
+                            } else {
+                                let _ = chan.send(message.clone());
+                            }
+                        }
+                    }
+                }
+                self.drop_slow_feeds(too_slow);
+            }
+            FeedCommand::SendToAll(message) => {
+                let mut too_slow = Vec::new();
+                for (&feed_id, chan) in self.channels.iter() {
+                    if matches!(self.max_feed_queue_len, Some(max) if chan.len() >= max) {
+                        too_slow.push(feed_id);
+                    } else {
+                        let _ = chan.send(message.clone());
+                    }
+                }
+                self.drop_slow_feeds(too_slow);
+            }
+            FeedCommand::RefreshCounts => {
+                let queued_messages = self.channels.values().map(|c| c.len()).sum();
+                self.counts
+                    .feeds
+                    .store(self.channels.len(), Ordering::Relaxed);
+                self.counts
+                    .queued_messages
+                    .store(queued_messages, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Stop sending messages to feeds that have too many messages queued up already. Dropping
+    /// our side of the channel closes the feed connection once it's sent what's left, and the
+    /// aggregator hears that it's disconnected as usual.
+    fn drop_slow_feeds(&mut self, feed_conn_ids: Vec<ConnId>) {
+        for feed_conn_id in feed_conn_ids {
+            log::warn!(
+                "Disconnecting feed {:?}; too many messages are waiting to be sent to it",
+                feed_conn_id
+            );
+            self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
+            self.channels.remove(&feed_conn_id);
+            self.counts.dropped_feeds.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// How many feeds are connected, how many messages are waiting to be sent to them, and
+/// how many have been disconnected for being too slow. Also, how many messages a feed sink
+/// (if there is one) has been unable to keep up with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FeedCounts {
+    pub connected_feeds: usize,
+    pub queued_messages: usize,
+    pub dropped_feeds: u64,
+    pub dropped_sink_messages: u64,
+}
+
+/// Sends messages out to feeds. Either this is done in the aggregator loop itself, or the
+/// feeds are split between some worker tasks (by hashing their connection IDs), which each
+/// send out messages to their own share of them. Every message to a given feed goes via the
+/// same worker, so they arrive in the order that they were sent.
+pub struct FeedBroadcaster {
+    groups: FeedGroups,
+    counts: Vec<Arc<FeedGroupCounts>>,
+    /// The feeds that we've been given and not yet asked to remove.
+    feed_conn_ids: HashSet<ConnId>,
+    /// If given, messages for every feed and for chain subscribers are published here too.
+    sink: Option<FeedSinkHandle>,
+}
+
+enum FeedGroups {
+    Inline(FeedGroup),
+    Workers(Vec<flume::Sender<FeedCommand>>),
+}
+
+impl FeedBroadcaster {
+    /// Send messages to feeds from the aggregator loop if `num_workers` is `None`, or else
+    /// spawn that many worker tasks to send them (this must be called from within a
+    /// tokio runtime if so).
+    pub fn new(max_feed_queue_len: Option<usize>, num_workers: Option<usize>) -> Self {
+        match num_workers {
+            None => {
+                let group = FeedGroup::new(max_feed_queue_len);
+                FeedBroadcaster {
+                    counts: vec![Arc::clone(&group.counts)],
+                    groups: FeedGroups::Inline(group),
+                    feed_conn_ids: HashSet::new(),
+                    sink: None,
+                }
+            }
+            Some(num_workers) => {
+                let mut counts = Vec::with_capacity(num_workers);
+                let mut workers = Vec::with_capacity(num_workers);
+                for _ in 0..num_workers.max(1) {
+                    let mut group = FeedGroup::new(max_feed_queue_len);
+                    let (tx, rx) = flume::unbounded();
+                    counts.push(Arc::clone(&group.counts));
+                    workers.push(tx);
+                    // This ends once the broadcaster, and so every sender, is dropped:
+                    tokio::spawn(async move {
+                        while let Ok(cmd) = rx.recv_async().await {
+                            group.handle(cmd);
+                        }
+                    });
+                }
+                FeedBroadcaster {
+                    groups: FeedGroups::Workers(workers),
+                    counts,
+                    feed_conn_ids: HashSet::new(),
+                    sink: None,
+                }
+            }
+        }
+    }
+
+    /// Publish the messages sent to every feed, or to the feeds of any chain, to this sink
+    /// as well. Publishing happens on the sink's own task, and never holds up the feeds.
+    pub fn set_sink(&mut self, sink: FeedSinkHandle) {
+        self.sink = Some(sink);
+    }
+
+    /// Start sending messages to a new feed.
+    pub fn add(&mut self, feed_conn_id: ConnId, channel: flume::Sender<ToFeedWebsocket>) {
+        self.feed_conn_ids.insert(feed_conn_id);
+        self.send_to_group_of(feed_conn_id, FeedCommand::Add(feed_conn_id, channel));
+    }
+
+    /// Forget about a feed, which closes its connection if it's still open.
+    pub fn remove(&mut self, feed_conn_id: ConnId) {
+        self.feed_conn_ids.remove(&feed_conn_id);
+        self.send_to_group_of(feed_conn_id, FeedCommand::Remove(feed_conn_id));
+    }
+
+    /// Have we been given this feed (and not asked to remove it since)?
+    pub fn contains(&self, feed_conn_id: ConnId) -> bool {
+        self.feed_conn_ids.contains(&feed_conn_id)
+    }
+
+    /// Send chain updates to this feed from now on, instead of those for any chain that it
+    /// was subscribed to before.
+    pub fn subscribe(&mut self, feed_conn_id: ConnId, genesis_hash: BlockHash) {
+        self.send_to_group_of(
+            feed_conn_id,
+            FeedCommand::Subscribe(feed_conn_id, genesis_hash),
+        );
+    }
+
+    /// Stop sending chain updates to this feed.
+    pub fn unsubscribe(&mut self, feed_conn_id: ConnId) {
+        self.send_to_group_of(feed_conn_id, FeedCommand::Unsubscribe(feed_conn_id));
+    }
+
+    /// Send a message to a single feed.
+    pub fn send_to(&mut self, feed_conn_id: ConnId, message: ToFeedWebsocket) {
+        self.send_to_group_of(feed_conn_id, FeedCommand::SendTo(feed_conn_id, message));
+    }
+
+    /// Send a message to every feed subscribed to the chain with this genesis hash.
+    pub fn send_to_chain(&mut self, genesis_hash: BlockHash, message: ToFeedWebsocket) {
+        if let (Some(sink), ToFeedWebsocket::Bytes(bytes)) = (&self.sink, &message) {
+            sink.publish_to_chain(&genesis_hash, bytes.clone());
+        }
+        self.send_to_all_groups(FeedCommand::SendToChain(genesis_hash, message));
+    }
+
+    /// Send a message to every feed.
+    pub fn send_to_all(&mut self, message: ToFeedWebsocket) {
+        if let (Some(sink), ToFeedWebsocket::Bytes(bytes)) = (&self.sink, &message) {
+            sink.publish_to_all(bytes.clone());
+        }
+        self.send_to_all_groups(FeedCommand::SendToAll(message));
+    }
+
+    /// How things stand with our feeds. When feeds are split between workers, the number of
+    /// feeds and queued messages are as of the last time this was called.
+    pub fn counts(&mut self) -> FeedCounts {
+        self.send_to_all_groups(FeedCommand::RefreshCounts);
+        let mut totals = FeedCounts::default();
+        for counts in &self.counts {
+            totals.connected_feeds += counts.feeds.load(Ordering::Relaxed);
+            totals.queued_messages += counts.queued_messages.load(Ordering::Relaxed);
+            totals.dropped_feeds += counts.dropped_feeds.load(Ordering::Relaxed);
+        }
+        totals.dropped_sink_messages = self.sink.as_ref().map_or(0, |sink| sink.dropped());
+        totals
+    }
+
+    fn send_to_group_of(&mut self, feed_conn_id: ConnId, cmd: FeedCommand) {
+        match &mut self.groups {
+            FeedGroups::Inline(group) => group.handle(cmd),
+            FeedGroups::Workers(workers) => {
+                let mut hasher = DefaultHasher::new();
+                feed_conn_id.hash(&mut hasher);
+                let idx = hasher.finish() as usize % workers.len();
+                let _ = workers[idx].send(cmd);
+            }
+        }
+    }
+
+    fn send_to_all_groups(&mut self, cmd: FeedCommand) {
+        match &mut self.groups {
+            FeedGroups::Inline(group) => group.handle(cmd),
+            FeedGroups::Workers(workers) => {
+                for worker in workers.iter() {
+                    let _ = worker.send(cmd.clone());
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn bytes(s: &'static str) -> ToFeedWebsocket {
+        ToFeedWebsocket::Bytes(bytes::Bytes::from_static(s.as_bytes()))
+    }
+
+    fn received(rx: &flume::Receiver<ToFeedWebsocket>) -> Vec<bytes::Bytes> {
+        rx.drain()
+            .map(|msg| match msg {
+                ToFeedWebsocket::Bytes(bytes) => bytes,
+                ToFeedWebsocket::Snapshot(_) => panic!("Expected bytes"),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn feeds_split_between_workers_get_messages_in_order() {
+        let mut broadcaster = FeedBroadcaster::new(None, Some(4));
+        let chain_one = BlockHash::from_low_u64_be(1);
+        let chain_two = BlockHash::from_low_u64_be(2);
+
+        let feeds: Vec<_> = (0..20)
+            .map(|id| {
+                let (tx, rx) = flume::unbounded();
+                broadcaster.add(ConnId::from(id), tx);
+                if id % 2 == 0 {
+                    broadcaster.subscribe(ConnId::from(id), chain_one);
+                } else {
+                    broadcaster.subscribe(ConnId::from(id), chain_two);
+                }
+                rx
+            })
+            .collect();
+
+        broadcaster.send_to_all(bytes("all"));
+        broadcaster.send_to_chain(chain_one, bytes("one"));
+        broadcaster.send_to(ConnId::from(3), bytes("three"));
+        broadcaster.send_to_chain(chain_two, bytes("two"));
+        broadcaster.remove(ConnId::from(5));
+        broadcaster.send_to_all(bytes("last"));
+
+        // The workers send messages out in their own time:
+        let all_sent = || {
+            feeds[0].len() == 3
+                && feeds[3].len() == 4
+                && feeds[4].len() == 3
+                && feeds[5].is_disconnected()
+        };
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while !all_sent() {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("messages should have been sent to the feeds");
+
+        assert_eq!(received(&feeds[0]), vec!["all", "one", "last"]);
+        assert_eq!(received(&feeds[3]), vec!["all", "three", "two", "last"]);
+        assert_eq!(received(&feeds[4]), vec!["all", "one", "last"]);
+        assert_eq!(received(&feeds[5]), vec!["all", "two"]);
+        assert!(feeds[5].is_disconnected());
+        assert!(!broadcaster.contains(ConnId::from(5)));
+    }
+}
@@ -15,35 +15,91 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 use super::aggregator::ConnId;
+use super::feed_broadcast::FeedBroadcaster;
+use super::node_add_queue::{NodeAddQueue, QueuedNode};
 use crate::feed_message::{self, FeedMessageSerializer};
-use crate::find_location;
-use crate::state::{self, NodeId, State};
+use crate::feed_sink::FeedSinkHandle;
+use crate::find_location::{self, LocatorMetrics, LocatorMetricsSnapshot};
+use crate::state::{
+    self, ChainListsSnapshot, NodeId, PersistedState, QuotasSnapshot, ShardSnapshot, State,
+    StateExport, StateOpts, StateSnapshot,
+};
 use bimap::BiMap;
 use common::{
+    histogram::Histogram,
     internal_messages::{self, MuteReason, ShardNodeId},
-    node_message,
-    node_types::BlockHash,
+    logging, node_message,
+    node_types::{BlockHash, NodeLocation},
     time, MultiMapUnique,
 };
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::{
     atomic::{AtomicU64, Ordering},
     Arc,
 };
+use std::time::Instant;
 use std::{
-    net::{IpAddr, Ipv4Addr},
+    net::{IpAddr, SocketAddr},
     str::FromStr,
 };
 
+/// The upper bounds (in seconds) of the buckets that we use to record how long
+/// it takes to handle node messages. Most take well under a millisecond.
+const NODE_MESSAGE_TIMING_BUCKETS: &[f64] = &[
+    0.00001, 0.00005, 0.0001, 0.0005, 0.001, 0.005, 0.01, 0.05, 0.1, 0.5, 1.0,
+];
+
 /// Incoming messages come via subscriptions, and end up looking like this.
 #[derive(Clone, Debug)]
 pub enum ToAggregator {
     FromShardWebsocket(ConnId, FromShardWebsocket),
     FromFeedWebsocket(ConnId, FromFeedWebsocket),
-    FromFindLocation(NodeId, find_location::Location),
+    FromFindLocation(NodeId, IpAddr, find_location::Location),
     /// Hand back some metrics. The provided sender is expected not to block when
     /// a message is sent into it.
     GatherMetrics(flume::Sender<Metrics>),
+    /// Replace the list of chains that nodes aren't allowed to connect to.
+    SetDenylist(Vec<String>),
+    /// Hand back a snapshot of the current state, optionally only for the chain with
+    /// the given genesis hash. If the flag is set, the snapshot is for operators, and
+    /// includes every chain and which shard each node is connected through. As with
+    /// `GatherMetrics`, sending shouldn't block.
+    GatherSnapshot(Option<BlockHash>, bool, flume::Sender<StateSnapshot>),
+    /// Hand back the parts of the state that we'd like to write to disk.
+    GatherPersistedState(flume::Sender<PersistedState>),
+    /// Hand back the denylist and allowlist that are currently in use.
+    GatherChainLists(flume::Sender<ChainListsSnapshot>),
+    /// Hand back the quotas that are currently in use, and how close chains are to them.
+    GatherQuotas(flume::Sender<QuotasSnapshot>),
+    /// Hand back everything we know about every chain and node, to be exported.
+    GatherExport(flume::Sender<StateExport>),
+    /// Restore state that was written to disk before we last shut down.
+    RestoreState(PersistedState),
+    /// Remove any restored nodes which haven't reconnected yet.
+    ExpireRestoredNodes,
+    /// Disconnect the node known to feeds of the chain with the given genesis hash by
+    /// the ID given, handing back whether there was such a node.
+    DisconnectNode(BlockHash, usize, flume::Sender<bool>),
+    /// Disconnect the shard with the connection ID given, and remove all of its nodes,
+    /// handing back whether there was such a shard.
+    DisconnectShard(ConnId, flume::Sender<bool>),
+    /// Add any nodes that were queued up because their shard was adding them too quickly.
+    AddQueuedNodes,
+    /// Remove any disconnected nodes which haven't reconnected within the grace period.
+    ExpireDisconnectedNodes,
+    /// Remove any chains which have had no nodes for too long.
+    RemoveEmptyChains,
+    /// Remove any nodes which haven't sent us anything for too long.
+    RemoveIdleNodes,
+    /// Stop (if true) or start again (if false) sending updates to feeds subscribed to the
+    /// chain with the given genesis hash. Hands back false if there's no such chain to pause,
+    /// or if the chain wasn't paused when asked to resume it.
+    SetChainPaused(BlockHash, bool, flume::Sender<bool>),
+    /// Tell feeds about the average block time of any chains where it's changed.
+    BroadcastChainBlockTimes,
+    /// Process any node updates that were held back because their nodes were sending them
+    /// too often, and which are now due.
+    ProcessSampledUpdates,
 }
 
 /// An incoming shard connection can send these messages to the aggregator.
@@ -53,6 +109,8 @@ pub enum FromShardWebsocket {
     /// so that we have a way to communicate back to it.
     Initialize {
         channel: flume::Sender<ToShardWebsocket>,
+        /// The address that the shard connected from, if it's a real shard.
+        addr: Option<SocketAddr>,
     },
     /// Tell the aggregator about a new node.
     Add {
@@ -66,6 +124,12 @@ pub enum FromShardWebsocket {
         local_id: ShardNodeId,
         payload: node_message::Payload,
     },
+    /// Tell the aggregator where a node is, rather than having it look up the node's IP
+    /// address. This is used when mirroring nodes from another core, which has already done so.
+    Located {
+        local_id: ShardNodeId,
+        location: find_location::Location,
+    },
     /// Tell the aggregator that a node has been removed when it disconnects.
     Remove { local_id: ShardNodeId },
     /// The shard is disconnected.
@@ -95,6 +159,9 @@ pub enum FromFeedWebsocket {
     /// The feed can subscribe to a chain to receive
     /// messages relating to it.
     Subscribe { chain: BlockHash },
+    /// The feed no longer wants to hear about the chain it
+    /// subscribed to, if it's this one.
+    Unsubscribe { chain: BlockHash },
     /// An explicit ping message.
     Ping { value: Box<str> },
     /// The feed is disconnected.
@@ -113,6 +180,10 @@ pub struct Metrics {
     /// How many messages are currently queued up in internal channels
     /// waiting to be sent out to feeds.
     pub total_messages_to_feeds: usize,
+    /// How many feeds have been disconnected because too many messages were queued up for them.
+    pub dropped_feeds: u64,
+    /// How many feed messages weren't published to the feed sink because it couldn't keep up.
+    pub dropped_feed_sink_messages: u64,
     /// How many messages are currently queued waiting to be handled by this aggregator.
     pub current_messages_to_aggregator: usize,
     /// The total number of messages sent to the aggregator.
@@ -125,6 +196,58 @@ pub struct Metrics {
     pub connected_feeds: usize,
     /// How many shards are currently connected to this aggregator.
     pub connected_shards: usize,
+    /// How many chains are currently known to this aggregator.
+    pub connected_chains: usize,
+    /// A rough estimate of how many bytes the nodes and chains known to this aggregator take up.
+    pub estimated_state_bytes: usize,
+    /// How many nodes have been turned away because they'd have needed a new chain, and
+    /// this aggregator already had as many chains as it's allowed.
+    pub nodes_over_chain_limit: u64,
+    /// How we're getting on locating nodes.
+    pub locator: LocatorMetricsSnapshot,
+    /// How many nodes have come and gone on each chain.
+    pub chain_churn: Vec<ChainChurn>,
+    /// How long it's taking to handle updates from nodes, for each type of payload.
+    pub node_message_timings: Vec<NodeMessageTimings>,
+    /// How many nodes are waiting to be added, because their shards are adding them too quickly.
+    pub queued_node_additions: usize,
+}
+
+/// How many nodes have been added to and removed from some chain. These count up from
+/// when the chain was first seen, and start over if it's removed and comes back again.
+#[derive(Clone, Debug)]
+pub struct ChainChurn {
+    pub genesis_hash: BlockHash,
+    pub label: Box<str>,
+    pub nodes_added: u64,
+    pub nodes_removed: u64,
+    /// How many nodes were turned away because the chain was at its quota.
+    pub nodes_over_quota: u64,
+}
+
+/// How long it took to handle each stage of the updates that nodes have sent with
+/// some type of payload, in seconds.
+#[derive(Clone, Debug)]
+pub struct NodeMessageTimings {
+    /// The type of payload, like "system.interval".
+    pub payload: &'static str,
+    /// How long updates waited to be handled once they reached the aggregator.
+    pub queued: Histogram,
+    /// How long it took to apply updates to the state.
+    pub state: Histogram,
+    /// How long it took to serialize the resulting feed messages and send them out.
+    pub feeds: Histogram,
+}
+
+impl NodeMessageTimings {
+    fn new(payload: &'static str) -> Self {
+        NodeMessageTimings {
+            payload,
+            queued: Histogram::new(NODE_MESSAGE_TIMING_BUCKETS),
+            state: Histogram::new(NODE_MESSAGE_TIMING_BUCKETS),
+            feeds: Histogram::new(NODE_MESSAGE_TIMING_BUCKETS),
+        }
+    }
 }
 
 // The frontend sends text based commands; parse them into these messages:
@@ -142,6 +265,9 @@ impl FromStr for FromFeedWebsocket {
             "subscribe" => Ok(FromFeedWebsocket::Subscribe {
                 chain: value.parse()?,
             }),
+            "unsubscribe" => Ok(FromFeedWebsocket::Unsubscribe {
+                chain: value.parse()?,
+            }),
             _ => return Err(anyhow::anyhow!("Command {} not recognised", cmd)),
         }
     }
@@ -151,6 +277,20 @@ impl FromStr for FromFeedWebsocket {
 #[derive(Clone, Debug)]
 pub enum ToFeedWebsocket {
     Bytes(bytes::Bytes),
+    /// Everything about the nodes on a chain that the feed has just subscribed to, in
+    /// batches. These are sent one after the other, unless the feed asked for them to be
+    /// gzipped into a single message.
+    Snapshot(Vec<bytes::Bytes>),
+}
+
+impl ToFeedWebsocket {
+    #[cfg(test)]
+    fn into_batches(self) -> Vec<bytes::Bytes> {
+        match self {
+            ToFeedWebsocket::Bytes(bytes) => vec![bytes],
+            ToFeedWebsocket::Snapshot(batches) => batches,
+        }
+    }
 }
 
 /// Instances of this are responsible for handling incoming and
@@ -163,44 +303,87 @@ pub struct InnerLoop {
     node_ids: BiMap<NodeId, (ConnId, ShardNodeId)>,
 
     /// Keep track of how to send messages out to feeds.
-    feed_channels: HashMap<ConnId, flume::Sender<ToFeedWebsocket>>,
+    feeds: FeedBroadcaster,
     /// Keep track of how to send messages out to shards.
     shard_channels: HashMap<ConnId, flume::Sender<ToShardWebsocket>>,
+    /// The addresses that shards connected from, where we know them.
+    shard_addrs: HashMap<ConnId, SocketAddr>,
 
     /// Which feeds are subscribed to a given chain?
     chain_to_feed_conn_ids: MultiMapUnique<BlockHash, ConnId>,
 
     /// Send messages here to make geographical location requests.
-    tx_to_locator: flume::Sender<(NodeId, Ipv4Addr)>,
+    tx_to_locator: flume::Sender<(NodeId, IpAddr)>,
+    /// The address that we last asked for the location of for each node. IDs are reused
+    /// once nodes go away, so this tells us which locations are still wanted.
+    located_ips: HashMap<NodeId, IpAddr>,
+    /// Metrics about how we're getting on locating nodes.
+    locator_metrics: Arc<LocatorMetrics>,
+    /// Locations that we've found for nodes (by their ID on the chain) but not yet told feeds
+    /// about, for each chain. These are sent out in batches, so that we don't send a message
+    /// per node when lots of nodes are located at once.
+    pending_locations: HashMap<BlockHash, Vec<(usize, Arc<NodeLocation>)>>,
 
     /// How big can the queue of messages coming in to the aggregator get before messages
     /// are prioritised and dropped to try and get back on track.
     max_queue_len: usize,
+    /// How long it's taking to handle updates from nodes, by payload type.
+    node_message_timings: BTreeMap<&'static str, NodeMessageTimings>,
+    /// The number of nodes and chains that feeds were last told about.
+    network_stats: (usize, usize),
+    /// The average block time of each chain that feeds were last told about.
+    chain_block_times: HashMap<BlockHash, u64>,
+    /// Chains that an operator has asked us to stop sending updates about for now. Feeds
+    /// subscribed to them are sent everything afresh once they're resumed.
+    paused_chains: HashSet<BlockHash>,
+    /// How many nodes each shard can add per second, if there's a limit.
+    max_node_adds_per_second: Option<u32>,
+    /// Nodes waiting to be added, for each shard that's been adding them too quickly.
+    node_add_queues: HashMap<ConnId, NodeAddQueue>,
 }
 
 impl InnerLoop {
     /// Create a new inner loop handler with the various state it needs.
     pub fn new(
-        tx_to_locator: flume::Sender<(NodeId, Ipv4Addr)>,
-        denylist: Vec<String>,
+        tx_to_locator: flume::Sender<(NodeId, IpAddr)>,
+        locator_metrics: Arc<LocatorMetrics>,
+        state_opts: StateOpts,
         max_queue_len: usize,
-        max_third_party_nodes: usize,
+        max_feed_queue_len: Option<usize>,
+        max_node_adds_per_second: Option<u32>,
+        feed_workers: Option<usize>,
     ) -> Self {
         InnerLoop {
-            node_state: State::new(denylist, max_third_party_nodes),
+            node_state: State::new(state_opts),
             node_ids: BiMap::new(),
-            feed_channels: HashMap::new(),
+            feeds: FeedBroadcaster::new(max_feed_queue_len, feed_workers),
             shard_channels: HashMap::new(),
+            shard_addrs: HashMap::new(),
             chain_to_feed_conn_ids: MultiMapUnique::new(),
             tx_to_locator,
+            located_ips: HashMap::new(),
+            locator_metrics,
+            pending_locations: HashMap::new(),
             max_queue_len,
+            node_message_timings: BTreeMap::new(),
+            network_stats: (0, 0),
+            chain_block_times: HashMap::new(),
+            paused_chains: HashSet::new(),
+            max_node_adds_per_second,
+            node_add_queues: HashMap::new(),
         }
     }
 
+    /// Publish feed messages to this sink as well as sending them to feeds.
+    pub fn set_feed_sink(&mut self, sink: FeedSinkHandle) {
+        self.feeds.set_sink(sink);
+    }
+
     /// Start handling and responding to incoming messages.
     pub async fn handle(mut self, rx_from_external: flume::Receiver<ToAggregator>) {
         let max_queue_len = self.max_queue_len;
-        let (metered_tx, metered_rx) = flume::unbounded();
+        // Messages are stamped with when they arrived, so that we can see how long they queue for:
+        let (metered_tx, metered_rx) = flume::unbounded::<(Instant, ToAggregator)>();
 
         // Keep count of the number of dropped/total messages for the sake of metric reporting
         let dropped_messages = Arc::new(AtomicU64::new(0));
@@ -212,7 +395,22 @@ impl InnerLoop {
         let dropped_messages2 = Arc::clone(&dropped_messages);
         let total_messages2 = Arc::clone(&total_messages);
         tokio::spawn(async move {
-            while let Ok(msg) = metered_rx.recv_async().await {
+            while let Ok((received_at, msg)) = metered_rx.recv_async().await {
+                if let ToAggregator::FromShardWebsocket(
+                    _,
+                    FromShardWebsocket::Update { payload, .. },
+                ) = &msg
+                {
+                    self.node_message_timings(payload.kind())
+                        .queued
+                        .observe(received_at.elapsed().as_secs_f64());
+                }
+
+                // Feeds must hear about any locations we're holding on to before anything
+                // else happens (the node they're about might be removed, for instance):
+                if !matches!(msg, ToAggregator::FromFindLocation(..)) {
+                    self.broadcast_pending_locations();
+                }
                 match msg {
                     ToAggregator::FromFeedWebsocket(feed_conn_id, msg) => {
                         self.handle_from_feed(feed_conn_id, msg)
@@ -220,8 +418,8 @@ impl InnerLoop {
                     ToAggregator::FromShardWebsocket(shard_conn_id, msg) => {
                         self.handle_from_shard(shard_conn_id, msg)
                     }
-                    ToAggregator::FromFindLocation(node_id, location) => {
-                        self.handle_from_find_location(node_id, location)
+                    ToAggregator::FromFindLocation(node_id, ip, location) => {
+                        self.handle_located_ip(node_id, ip, location)
                     }
                     ToAggregator::GatherMetrics(tx) => self.handle_gather_metrics(
                         tx,
@@ -229,6 +427,53 @@ impl InnerLoop {
                         dropped_messages2.load(Ordering::Relaxed),
                         total_messages2.load(Ordering::Relaxed),
                     ),
+                    ToAggregator::SetDenylist(denylist) => self.handle_set_denylist(denylist),
+                    ToAggregator::GatherSnapshot(genesis_hash, for_operators, tx) => {
+                        let _ = tx.send(self.gather_snapshot(genesis_hash.as_ref(), for_operators));
+                    }
+                    ToAggregator::GatherPersistedState(tx) => {
+                        let _ = tx.send(PersistedState::new(&self.node_state));
+                    }
+                    ToAggregator::GatherChainLists(tx) => {
+                        let _ = tx.send(ChainListsSnapshot::new(&self.node_state));
+                    }
+                    ToAggregator::GatherQuotas(tx) => {
+                        let _ = tx.send(QuotasSnapshot::new(&self.node_state));
+                    }
+                    ToAggregator::GatherExport(tx) => {
+                        let _ = tx.send(StateExport::new(&self.node_state));
+                    }
+                    ToAggregator::RestoreState(persisted) => {
+                        self.node_state.restore(persisted);
+                    }
+                    ToAggregator::ExpireRestoredNodes => {
+                        let node_ids = self.node_state.take_restored();
+                        self.remove_nodes_and_broadcast_result(node_ids);
+                    }
+                    ToAggregator::DisconnectNode(genesis_hash, node_id, tx) => {
+                        let _ = tx.send(self.handle_disconnect_node(genesis_hash, node_id));
+                    }
+                    ToAggregator::DisconnectShard(shard_conn_id, tx) => {
+                        let _ = tx.send(self.handle_disconnect_shard(shard_conn_id));
+                    }
+                    ToAggregator::AddQueuedNodes => self.add_queued_nodes(),
+                    ToAggregator::ExpireDisconnectedNodes => {
+                        let node_ids = self.node_state.take_expired_disconnected(Instant::now());
+                        self.remove_nodes_and_broadcast_result(node_ids);
+                    }
+                    ToAggregator::RemoveEmptyChains => self.remove_empty_chains(),
+                    ToAggregator::RemoveIdleNodes => self.remove_idle_nodes(time::now()),
+                    ToAggregator::SetChainPaused(genesis_hash, paused, tx) => {
+                        let _ = tx.send(self.handle_set_chain_paused(genesis_hash, paused));
+                    }
+                    ToAggregator::BroadcastChainBlockTimes => self.broadcast_chain_block_times(),
+                    ToAggregator::ProcessSampledUpdates => {
+                        self.process_sampled_updates(Instant::now())
+                    }
+                }
+                // Nothing else to batch them up with right now, so send them out:
+                if metered_rx.is_empty() {
+                    self.broadcast_pending_locations();
                 }
             }
         });
@@ -251,7 +496,7 @@ impl InnerLoop {
                 }
             }
 
-            if let Err(e) = metered_tx.send(msg) {
+            if let Err(e) = metered_tx.send((Instant::now(), msg)) {
                 log::error!("Cannot send message into aggregator: {}", e);
                 break;
             }
@@ -271,57 +516,415 @@ impl InnerLoop {
         let subscribed_feeds = self.chain_to_feed_conn_ids.num_values();
         let chains_subscribed_to = self.chain_to_feed_conn_ids.num_keys();
         let connected_shards = self.shard_channels.len();
-        let connected_feeds = self.feed_channels.len();
-        let total_messages_to_feeds: usize = self.feed_channels.values().map(|c| c.len()).sum();
+        let feed_counts = self.feeds.counts();
+        let connected_chains = self.node_state.chain_count();
+        // The node ID map stores each pair twice, once for lookups in each direction:
+        let estimated_state_bytes = self.node_state.estimated_size_bytes()
+            + 2 * connected_nodes * std::mem::size_of::<(NodeId, (ConnId, ShardNodeId))>();
+        let chain_churn = self
+            .node_state
+            .iter_chains()
+            .map(|chain| ChainChurn {
+                genesis_hash: chain.genesis_hash(),
+                label: chain.label().into(),
+                nodes_added: chain.nodes_added(),
+                nodes_removed: chain.nodes_removed(),
+                nodes_over_quota: chain.nodes_over_quota(),
+            })
+            .collect();
 
         // Ignore error sending; assume the receiver stopped caring and dropped the channel:
         let _ = rx.send(Metrics {
             timestamp_unix_ms,
             chains_subscribed_to,
             subscribed_feeds,
-            total_messages_to_feeds,
+            total_messages_to_feeds: feed_counts.queued_messages,
+            dropped_feeds: feed_counts.dropped_feeds,
+            dropped_feed_sink_messages: feed_counts.dropped_sink_messages,
             current_messages_to_aggregator,
             total_messages_to_aggregator,
             dropped_messages_to_aggregator,
             connected_nodes,
-            connected_feeds,
+            connected_feeds: feed_counts.connected_feeds,
             connected_shards,
+            connected_chains,
+            estimated_state_bytes,
+            nodes_over_chain_limit: self.node_state.nodes_over_chain_limit(),
+            locator: self.locator_metrics.snapshot(),
+            chain_churn,
+            node_message_timings: self.node_message_timings.values().cloned().collect(),
+            queued_node_additions: self.node_add_queues.values().map(|q| q.len()).sum(),
         });
     }
 
-    /// Handle messages that come from the node geographical locator.
+    /// The timings for node updates with the type of payload given.
+    fn node_message_timings(&mut self, payload: &'static str) -> &mut NodeMessageTimings {
+        self.node_message_timings
+            .entry(payload)
+            .or_insert_with(|| NodeMessageTimings::new(payload))
+    }
+
+    /// Update the denylist, removing any connected nodes whose chain is now denied.
+    fn handle_set_denylist(&mut self, denylist: Vec<String>) {
+        let denied_node_ids = self.node_state.set_denylist(denylist);
+        if denied_node_ids.is_empty() {
+            return;
+        }
+        log::info!(
+            "Removing {} nodes from chains that are now denied",
+            denied_node_ids.len()
+        );
+
+        // Ask the shards to stop sending us anything about these nodes:
+        for node_id in &denied_node_ids {
+            if let Some(&(shard_conn_id, local_id)) = self.node_ids.get_by_left(node_id) {
+                if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                    let _ = shard_conn.send(ToShardWebsocket::Mute {
+                        local_id,
+                        reason: MuteReason::ChainNotAllowed,
+                    });
+                }
+            }
+        }
+
+        self.remove_nodes_and_broadcast_result(denied_node_ids);
+    }
+
+    /// Remove a node that an operator has asked us to get rid of, and ask its shard to
+    /// stop sending us anything more about it.
+    fn handle_disconnect_node(&mut self, genesis_hash: BlockHash, feed_node_id: usize) -> bool {
+        let node_id = match self.node_state.find_node_id(&genesis_hash, feed_node_id) {
+            Some(node_id) => node_id,
+            None => return false,
+        };
+        log::info!(
+            "Disconnecting node {} from chain {:?}",
+            feed_node_id,
+            genesis_hash
+        );
+
+        self.mute_node(node_id, MuteReason::Disconnected);
+        self.remove_nodes_and_broadcast_result([node_id]);
+        true
+    }
+
+    /// Ask the shard that a node is connected to to stop sending us anything more about it.
+    fn mute_node(&mut self, node_id: NodeId, reason: MuteReason) {
+        if let Some(&(shard_conn_id, local_id)) = self.node_ids.get_by_left(&node_id) {
+            if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                let _ = shard_conn.send(ToShardWebsocket::Mute { local_id, reason });
+            }
+        }
+    }
+
+    /// Pause or resume updates to the feeds subscribed to a chain. The state of the chain
+    /// carries on being updated while it's paused, and when it's resumed, its feeds are
+    /// subscribed to it again so that they're sent everything that's changed in one go.
+    fn handle_set_chain_paused(&mut self, genesis_hash: BlockHash, paused: bool) -> bool {
+        if paused {
+            if self
+                .node_state
+                .get_chain_by_genesis_hash(&genesis_hash)
+                .is_none()
+            {
+                return false;
+            }
+            log::info!("Pausing feed updates for chain {:?}", genesis_hash);
+            self.paused_chains.insert(genesis_hash);
+            return true;
+        }
+
+        if !self.paused_chains.remove(&genesis_hash) {
+            return false;
+        }
+        log::info!("Resuming feed updates for chain {:?}", genesis_hash);
+        let feed_conn_ids: Vec<ConnId> = self
+            .chain_to_feed_conn_ids
+            .get_values(&genesis_hash)
+            .map(|feeds| feeds.iter().copied().collect())
+            .unwrap_or_default();
+        for feed_conn_id in feed_conn_ids {
+            self.handle_from_feed(
+                feed_conn_id,
+                FromFeedWebsocket::Subscribe {
+                    chain: genesis_hash,
+                },
+            );
+        }
+        true
+    }
+
+    /// Take a snapshot of the state. Operators are told about every chain, and which shard
+    /// each node is connected through (which only we know, so it's filled in here). Anybody
+    /// else is only told about the chains that feeds are currently being told about.
+    fn gather_snapshot(
+        &self,
+        genesis_hash: Option<&BlockHash>,
+        for_operators: bool,
+    ) -> StateSnapshot {
+        let mut snapshot = StateSnapshot::new(&self.node_state, genesis_hash);
+        if !for_operators {
+            snapshot.chains.retain(|chain| {
+                self.node_state
+                    .is_chain_advertised(&chain.genesis_hash, chain.node_count)
+                    && !self.paused_chains.contains(&chain.genesis_hash)
+            });
+            return snapshot;
+        }
+
+        let mut shards: HashMap<(BlockHash, usize), ShardSnapshot> = HashMap::new();
+        for (node_id, (shard_conn_id, _)) in self.node_ids.iter() {
+            let chain = match self.node_state.get_chain_by_node_id(*node_id) {
+                Some(chain) => chain,
+                None => continue,
+            };
+            if genesis_hash.is_some_and(|hash| *hash != chain.genesis_hash()) {
+                continue;
+            }
+            let shard = ShardSnapshot {
+                id: u64::from(*shard_conn_id),
+                addr: self.shard_addrs.get(shard_conn_id).copied(),
+            };
+            let chain_node_id: usize = node_id.get_chain_node_id().into();
+            shards.insert((chain.genesis_hash(), chain_node_id), shard);
+        }
+        for chain in &mut snapshot.chains {
+            for node in &mut chain.nodes {
+                node.shard = shards.remove(&(chain.genesis_hash, node.id));
+            }
+        }
+        snapshot
+    }
+
+    /// Close the connection to a shard that an operator has asked us to get rid of.
+    fn handle_disconnect_shard(&mut self, shard_conn_id: ConnId) -> bool {
+        // The connection is closed once nothing is left to send messages to it:
+        if self.shard_channels.remove(&shard_conn_id).is_none() {
+            return false;
+        }
+        log::info!("Disconnecting shard {}", u64::from(shard_conn_id));
+        self.shard_addrs.remove(&shard_conn_id);
+        self.node_add_queues.remove(&shard_conn_id);
+        self.remove_shard_nodes(shard_conn_id);
+        true
+    }
+
+    /// Handle a location that the locator has found for a node's address. By the time that
+    /// it arrives, the node may have gone away and its ID been given to a node connecting
+    /// from elsewhere (perhaps the same node, having moved), so the location is only used
+    /// if it's for the address we last asked about.
+    fn handle_located_ip(
+        &mut self,
+        node_id: NodeId,
+        ip: IpAddr,
+        location: find_location::Location,
+    ) {
+        if self.located_ips.get(&node_id) != Some(&ip) {
+            return;
+        }
+        self.handle_from_find_location(node_id, location);
+    }
+
+    /// Handle messages that come from the node geographical locator. Feeds are told
+    /// about locations in batches; see [`Self::broadcast_pending_locations`].
     fn handle_from_find_location(&mut self, node_id: NodeId, location: find_location::Location) {
         self.node_state
             .update_node_location(node_id, location.clone());
 
-        if let Some(loc) = location {
-            let mut feed_message_serializer = FeedMessageSerializer::new();
-            feed_message_serializer.push(feed_message::LocatedNode(
-                node_id.get_chain_node_id().into(),
-                loc.latitude,
-                loc.longitude,
-                &loc.city,
-            ));
+        let loc = match location {
+            Some(loc) => loc,
+            None => return,
+        };
+        let chain_genesis_hash = match self.node_state.get_chain_by_node_id(node_id) {
+            Some(chain) => chain.genesis_hash(),
+            None => return,
+        };
 
-            let chain_genesis_hash = self
-                .node_state
-                .get_chain_by_node_id(node_id)
-                .map(|chain| chain.genesis_hash());
+        let pending = self
+            .pending_locations
+            .entry(chain_genesis_hash)
+            .or_default();
+        pending.push((node_id.get_chain_node_id().into(), loc));
 
-            if let Some(chain_genesis_hash) = chain_genesis_hash {
+        // Don't let any one message get too big:
+        if pending.len() >= self.node_state.feed_chunk_size() {
+            let node_clusters = self.node_state.node_clusters();
+            if let Some(locations) = self.pending_locations.remove(&chain_genesis_hash) {
                 self.finalize_and_broadcast_to_chain_feeds(
                     &chain_genesis_hash,
-                    feed_message_serializer,
+                    serialize_locations(&locations, node_clusters),
                 );
             }
         }
     }
 
+    /// Tell feeds about any node locations that we've not sent out yet.
+    fn broadcast_pending_locations(&mut self) {
+        let node_clusters = self.node_state.node_clusters();
+        for (genesis_hash, locations) in std::mem::take(&mut self.pending_locations) {
+            self.finalize_and_broadcast_to_chain_feeds(
+                &genesis_hash,
+                serialize_locations(&locations, node_clusters),
+            );
+        }
+    }
+
+    /// Add a node that a shard has told us about, and tell feeds about it.
+    fn add_node(&mut self, shard_conn_id: ConnId, local_id: ShardNodeId, queued: QueuedNode) {
+        let QueuedNode {
+            ip,
+            node,
+            genesis_hash,
+        } = queued;
+        let log_fields = [("genesis_hash", format!("{:?}", genesis_hash))];
+        let add_node_result =
+            logging::with_fields(log_fields, || self.node_state.add_node(genesis_hash, node));
+        match add_node_result {
+            state::AddNodeResult::ChainOnDenyList | state::AddNodeResult::ChainNotAllowed => {
+                if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                    let _ = shard_conn.send(ToShardWebsocket::Mute {
+                        local_id,
+                        reason: MuteReason::ChainNotAllowed,
+                    });
+                }
+            }
+            state::AddNodeResult::ChainOverQuota | state::AddNodeResult::TooManyChains => {
+                if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
+                    let _ = shard_conn.send(ToShardWebsocket::Mute {
+                        local_id,
+                        reason: MuteReason::Overquota,
+                    });
+                }
+            }
+            state::AddNodeResult::NodeAddedToChain(details) => {
+                let node_id = details.id;
+                let replaced_node_id = details.replaced_node_id;
+
+                // If this node replaced an older connection from the same node, we won't
+                // want to hear anything more from that connection:
+                if let Some(replaced_node_id) = replaced_node_id {
+                    if let Some((_, (old_shard_conn_id, old_local_id))) =
+                        self.node_ids.remove_by_left(&replaced_node_id)
+                    {
+                        if let Some(shard_conn) = self.shard_channels.get_mut(&old_shard_conn_id) {
+                            let _ = shard_conn.send(ToShardWebsocket::Mute {
+                                local_id: old_local_id,
+                                reason: MuteReason::Duplicate,
+                            });
+                        }
+                    }
+                }
+
+                // Record ID <-> (shardId,localId) for future messages:
+                self.node_ids.insert(node_id, (shard_conn_id, local_id));
+
+                // Don't hold onto details too long because we want &mut self later:
+                let new_chain_label = details.new_chain_label.to_owned();
+                let chain_node_count = details.chain_node_count;
+                let has_chain_label_changed = details.has_chain_label_changed;
+
+                // Tell chain subscribers about the node we've just added:
+                let mut feed_messages_for_chain = FeedMessageSerializer::new();
+                if let Some(replaced_node_id) = replaced_node_id {
+                    feed_messages_for_chain.push(feed_message::RemovedNode(
+                        replaced_node_id.get_chain_node_id().into(),
+                    ));
+                }
+                feed_messages_for_chain.push(feed_message::AddedNode(
+                    node_id.get_chain_node_id().into(),
+                    &details.node,
+                ));
+                if let Some(method) = details.node.details().sync_method {
+                    feed_messages_for_chain.push(feed_message::NodeSyncMethod(
+                        node_id.get_chain_node_id().into(),
+                        method,
+                    ));
+                }
+                self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_messages_for_chain);
+                // Tell everybody about the new node count and potential rename, if the chain
+                // has enough nodes for them to hear about it:
+                let prev_chain_node_count =
+                    chain_node_count - usize::from(replaced_node_id.is_none());
+                let was_advertised = self
+                    .node_state
+                    .is_chain_advertised(&genesis_hash, prev_chain_node_count);
+                let is_advertised = self
+                    .node_state
+                    .is_chain_advertised(&genesis_hash, chain_node_count);
+                let mut feed_messages_for_all = FeedMessageSerializer::new();
+                if is_advertised {
+                    if has_chain_label_changed && was_advertised {
+                        feed_messages_for_all.push(feed_message::RemovedChain(genesis_hash));
+                    }
+                    feed_messages_for_all.push(feed_message::AddedChain(
+                        &new_chain_label,
+                        genesis_hash,
+                        chain_node_count,
+                    ));
+                }
+                // The first node on a chain may have just created it, or the chain may only
+                // just have enough nodes for feeds to hear about it:
+                if is_advertised && (chain_node_count == 1 || !was_advertised) {
+                    if let Some(chain) = self.node_state.get_chain_by_genesis_hash(&genesis_hash) {
+                        feed_messages_for_all.push(feed_message::ChainFirstSeen(
+                            genesis_hash,
+                            chain.first_seen(),
+                        ));
+                    }
+                }
+                self.push_network_stats(&mut feed_messages_for_all);
+                self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
+
+                // Ask for the grographical location of the node. Nodes that we're mirroring
+                // from another core have no address to look up, and are located by it instead.
+                // A node's address can't change without it reconnecting, so this is also how
+                // nodes that move are located again (the locator's cache means that those
+                // which come back from the same address aren't looked up again).
+                if let Some(replaced_node_id) = replaced_node_id {
+                    self.located_ips.remove(&replaced_node_id);
+                }
+                if ip.is_unspecified() {
+                    self.located_ips.remove(&node_id);
+                } else {
+                    self.located_ips.insert(node_id, ip);
+                    let _ = self.tx_to_locator.send((node_id, ip));
+                }
+            }
+        }
+    }
+
+    fn is_node_queued(&self, shard_conn_id: ConnId, local_id: ShardNodeId) -> bool {
+        self.node_add_queues
+            .get(&shard_conn_id)
+            .is_some_and(|queue| queue.contains(local_id))
+    }
+
+    /// Add any queued up nodes that can now be added.
+    fn add_queued_nodes(&mut self) {
+        let now = Instant::now();
+        let ready: Vec<_> = self
+            .node_add_queues
+            .iter_mut()
+            .flat_map(|(&shard_conn_id, queue)| {
+                queue
+                    .take_ready(now)
+                    .into_iter()
+                    .map(move |(local_id, queued)| (shard_conn_id, local_id, queued))
+            })
+            .collect();
+        for (shard_conn_id, local_id, queued) in ready {
+            self.add_node(shard_conn_id, local_id, queued);
+        }
+    }
+
     /// Handle messages coming from shards.
     fn handle_from_shard(&mut self, shard_conn_id: ConnId, msg: FromShardWebsocket) {
         match msg {
-            FromShardWebsocket::Initialize { channel } => {
+            FromShardWebsocket::Initialize { channel, addr } => {
                 self.shard_channels.insert(shard_conn_id, channel);
+                if let Some(addr) = addr {
+                    self.shard_addrs.insert(shard_conn_id, addr);
+                }
             }
             FromShardWebsocket::Add {
                 local_id,
@@ -329,65 +932,32 @@ impl InnerLoop {
                 node,
                 genesis_hash,
             } => {
-                match self.node_state.add_node(genesis_hash, node) {
-                    state::AddNodeResult::ChainOnDenyList => {
-                        if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
-                            let _ = shard_conn.send(ToShardWebsocket::Mute {
-                                local_id,
-                                reason: MuteReason::ChainNotAllowed,
-                            });
-                        }
-                    }
-                    state::AddNodeResult::ChainOverQuota => {
-                        if let Some(shard_conn) = self.shard_channels.get_mut(&shard_conn_id) {
-                            let _ = shard_conn.send(ToShardWebsocket::Mute {
-                                local_id,
-                                reason: MuteReason::Overquota,
-                            });
-                        }
-                    }
-                    state::AddNodeResult::NodeAddedToChain(details) => {
-                        let node_id = details.id;
-
-                        // Record ID <-> (shardId,localId) for future messages:
-                        self.node_ids.insert(node_id, (shard_conn_id, local_id));
-
-                        // Don't hold onto details too long because we want &mut self later:
-                        let new_chain_label = details.new_chain_label.to_owned();
-                        let chain_node_count = details.chain_node_count;
-                        let has_chain_label_changed = details.has_chain_label_changed;
-
-                        // Tell chain subscribers about the node we've just added:
-                        let mut feed_messages_for_chain = FeedMessageSerializer::new();
-                        feed_messages_for_chain.push(feed_message::AddedNode(
-                            node_id.get_chain_node_id().into(),
-                            &details.node,
-                        ));
-                        self.finalize_and_broadcast_to_chain_feeds(
-                            &genesis_hash,
-                            feed_messages_for_chain,
-                        );
-                        // Tell everybody about the new node count and potential rename:
-                        let mut feed_messages_for_all = FeedMessageSerializer::new();
-                        if has_chain_label_changed {
-                            feed_messages_for_all.push(feed_message::RemovedChain(genesis_hash));
-                        }
-                        feed_messages_for_all.push(feed_message::AddedChain(
-                            &new_chain_label,
-                            genesis_hash,
-                            chain_node_count,
-                        ));
-                        self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
-
-                        // Ask for the grographical location of the node.
-                        // Currently we only geographically locate IPV4 addresses so ignore IPV6.
-                        if let IpAddr::V4(ip_v4) = ip {
-                            let _ = self.tx_to_locator.send((node_id, ip_v4));
-                        }
+                let queued = QueuedNode {
+                    ip,
+                    node,
+                    genesis_hash,
+                };
+                // If nodes are being added too quickly, they wait their turn:
+                let ready = match self.max_node_adds_per_second {
+                    None => Some(queued),
+                    Some(per_second) => {
+                        let now = Instant::now();
+                        self.node_add_queues
+                            .entry(shard_conn_id)
+                            .or_insert_with(|| NodeAddQueue::new(per_second, now))
+                            .push(local_id, queued, now)
                     }
+                };
+                if let Some(queued) = ready {
+                    self.add_node(shard_conn_id, local_id, queued);
                 }
             }
             FromShardWebsocket::Remove { local_id } => {
+                if let Some(queue) = self.node_add_queues.get_mut(&shard_conn_id) {
+                    if queue.remove(local_id) {
+                        return;
+                    }
+                }
                 let node_id = match self.node_ids.remove_by_right(&(shard_conn_id, local_id)) {
                     Some((node_id, _)) => node_id,
                     None => {
@@ -399,11 +969,18 @@ impl InnerLoop {
                         return;
                     }
                 };
-                self.remove_nodes_and_broadcast_result(Some(node_id));
+                self.disconnect_nodes(vec![node_id]);
+            }
+            FromShardWebsocket::Located { local_id, location } => {
+                if let Some(&node_id) = self.node_ids.get_by_right(&(shard_conn_id, local_id)) {
+                    self.handle_from_find_location(node_id, location);
+                }
             }
             FromShardWebsocket::Update { local_id, payload } => {
                 let node_id = match self.node_ids.get_by_right(&(shard_conn_id, local_id)) {
                     Some(id) => *id,
+                    // We'll hear about nodes that are still queued up again soon enough:
+                    None if self.is_node_queued(shard_conn_id, local_id) => return,
                     None => {
                         log::error!(
                             "Cannot find ID for node with shard/connectionId of {:?}/{:?}",
@@ -414,78 +991,187 @@ impl InnerLoop {
                     }
                 };
 
-                let mut feed_message_serializer = FeedMessageSerializer::new();
-                self.node_state
-                    .update_node(node_id, payload, &mut feed_message_serializer);
-
-                if let Some(chain) = self.node_state.get_chain_by_node_id(node_id) {
-                    let genesis_hash = chain.genesis_hash();
-                    self.finalize_and_broadcast_to_chain_feeds(
-                        &genesis_hash,
-                        feed_message_serializer,
-                    );
-                }
+                self.update_node(node_id, payload, Instant::now());
             }
             FromShardWebsocket::Disconnected => {
                 self.shard_channels.remove(&shard_conn_id);
+                self.shard_addrs.remove(&shard_conn_id);
+                self.node_add_queues.remove(&shard_conn_id);
+                let node_ids = self.shard_node_ids(shard_conn_id);
+                self.disconnect_nodes(node_ids);
+            }
+        }
+    }
 
-                // Find all nodes associated with this shard connection ID:
-                let node_ids_to_remove: Vec<NodeId> = self
-                    .node_ids
-                    .iter()
-                    .filter(|(_, &(this_shard_conn_id, _))| shard_conn_id == this_shard_conn_id)
-                    .map(|(&node_id, _)| node_id)
-                    .collect();
+    /// Apply an update to a node, and tell feeds subscribed to its chain about anything
+    /// that's changed.
+    fn update_node(&mut self, node_id: NodeId, payload: node_message::Payload, now: Instant) {
+        let genesis_hash = match self.node_state.get_chain_by_node_id(node_id) {
+            Some(chain) => chain.genesis_hash(),
+            None => return,
+        };
+
+        let mut feed_message_serializer = FeedMessageSerializer::new();
+        let log_fields = [
+            (
+                "node_id",
+                usize::from(node_id.get_chain_node_id()).to_string(),
+            ),
+            ("genesis_hash", format!("{:?}", genesis_hash)),
+        ];
+        let payload_kind = payload.kind();
+        let started_at = Instant::now();
+        logging::with_fields(log_fields, || {
+            self.node_state
+                .update_node(node_id, payload, &mut feed_message_serializer, now)
+        });
+        let updated_at = Instant::now();
+        self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_message_serializer);
+        let broadcast_at = Instant::now();
 
-                // ... and remove them:
-                self.remove_nodes_and_broadcast_result(node_ids_to_remove);
+        let timings = self.node_message_timings(payload_kind);
+        timings
+            .state
+            .observe((updated_at - started_at).as_secs_f64());
+        timings
+            .feeds
+            .observe((broadcast_at - updated_at).as_secs_f64());
+    }
+
+    /// Apply any node updates that were held back, now that enough time has passed.
+    fn process_sampled_updates(&mut self, now: Instant) {
+        for (node_id, payload) in self.node_state.take_sampled_updates(now) {
+            self.update_node(node_id, payload, now);
+        }
+    }
+
+    /// Remove all of the nodes that we heard about from some shard connection.
+    fn remove_shard_nodes(&mut self, shard_conn_id: ConnId) {
+        let node_ids_to_remove = self.shard_node_ids(shard_conn_id);
+        self.remove_nodes_and_broadcast_result(node_ids_to_remove);
+    }
+
+    /// Find all nodes associated with this shard connection ID.
+    fn shard_node_ids(&self, shard_conn_id: ConnId) -> Vec<NodeId> {
+        self.node_ids
+            .iter()
+            .filter(|(_, &(this_shard_conn_id, _))| shard_conn_id == this_shard_conn_id)
+            .map(|(&node_id, _)| node_id)
+            .collect()
+    }
+
+    /// The connections to these nodes have gone away. If we're giving nodes a chance to
+    /// reconnect, feeds are told that they're stale for now, and otherwise they're removed.
+    fn disconnect_nodes(&mut self, node_ids: Vec<NodeId>) {
+        let now = Instant::now();
+        let mut to_remove = Vec::new();
+        let mut stale_per_chain: HashMap<BlockHash, FeedMessageSerializer> = HashMap::new();
+        for node_id in node_ids {
+            self.node_ids.remove_by_left(&node_id);
+            if !self.node_state.mark_disconnected(node_id, now) {
+                to_remove.push(node_id);
+                continue;
+            }
+            if let Some(chain) = self.node_state.get_chain_by_node_id(node_id) {
+                stale_per_chain
+                    .entry(chain.genesis_hash())
+                    .or_insert_with(FeedMessageSerializer::new)
+                    .push(feed_message::StaleNode(node_id.get_chain_node_id().into()));
             }
         }
+        for (genesis_hash, feed_serializer) in stale_per_chain {
+            self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_serializer);
+        }
+        self.remove_nodes_and_broadcast_result(to_remove);
     }
 
     /// Handle messages coming from feeds.
     fn handle_from_feed(&mut self, feed_conn_id: ConnId, msg: FromFeedWebsocket) {
         match msg {
             FromFeedWebsocket::Initialize { channel } => {
-                self.feed_channels.insert(feed_conn_id, channel.clone());
+                self.feeds.add(feed_conn_id, channel);
 
                 // Tell the new feed subscription some basic things to get it going:
                 let mut feed_serializer = FeedMessageSerializer::new();
-                feed_serializer.push(feed_message::Version(32));
-                for chain in self.node_state.iter_chains() {
+                feed_serializer.push(feed_message::Version(feed_message::FEED_VERSION));
+                for chain in self.node_state.iter_chains_by_node_count() {
+                    if !self
+                        .node_state
+                        .is_chain_advertised(&chain.genesis_hash(), chain.node_count())
+                    {
+                        continue;
+                    }
                     feed_serializer.push(feed_message::AddedChain(
                         chain.label(),
                         chain.genesis_hash(),
                         chain.node_count(),
                     ));
+                    feed_serializer.push(feed_message::ChainFirstSeen(
+                        chain.genesis_hash(),
+                        chain.first_seen(),
+                    ));
+                }
+                feed_serializer.push(feed_message::NetworkStats(
+                    self.node_state.node_count(),
+                    self.node_state.chain_count(),
+                ));
+                for (genesis_hash, average_block_time) in &self.chain_block_times {
+                    feed_serializer.push(feed_message::ChainBlockTime(
+                        *genesis_hash,
+                        *average_block_time,
+                    ));
                 }
 
                 // Send this to the channel that subscribed:
                 if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = channel.send(ToFeedWebsocket::Bytes(bytes));
+                    self.feeds
+                        .send_to(feed_conn_id, ToFeedWebsocket::Bytes(bytes));
                 }
             }
             FromFeedWebsocket::Ping { value } => {
-                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
-                    Some(chan) => chan,
-                    None => return,
-                };
+                if !self.feeds.contains(feed_conn_id) {
+                    return;
+                }
 
                 // Pong!
                 let mut feed_serializer = FeedMessageSerializer::new();
                 feed_serializer.push(feed_message::Pong(&value));
                 if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                    self.feeds
+                        .send_to(feed_conn_id, ToFeedWebsocket::Bytes(bytes));
+                }
+            }
+            FromFeedWebsocket::Unsubscribe { chain } => {
+                if !self.feeds.contains(feed_conn_id) {
+                    return;
+                }
+
+                // Leave the subscription alone if it's to some other chain:
+                let is_subscribed = self
+                    .chain_to_feed_conn_ids
+                    .get_values(&chain)
+                    .is_some_and(|feeds| feeds.contains(&feed_conn_id));
+                if !is_subscribed {
+                    return;
+                }
+                self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
+                self.feeds.unsubscribe(feed_conn_id);
+
+                let mut feed_serializer = FeedMessageSerializer::new();
+                feed_serializer.push(feed_message::UnsubscribedFrom(chain));
+                if let Some(bytes) = feed_serializer.into_finalized() {
+                    self.feeds
+                        .send_to(feed_conn_id, ToFeedWebsocket::Bytes(bytes));
                 }
             }
             FromFeedWebsocket::Subscribe { chain } => {
-                let feed_channel = match self.feed_channels.get_mut(&feed_conn_id) {
-                    Some(chan) => chan,
-                    None => return,
-                };
+                if !self.feeds.contains(feed_conn_id) {
+                    return;
+                }
 
                 // Unsubscribe from previous chain if subscribed to one:
                 let old_genesis_hash = self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
+                self.feeds.unsubscribe(feed_conn_id);
 
                 // Get old chain if there was one:
                 let node_state = &self.node_state;
@@ -515,22 +1201,28 @@ impl InnerLoop {
                     new_chain.finalized_block().hash,
                 ));
                 feed_serializer.push(feed_message::ChainStatsUpdate(new_chain.stats()));
+                feed_serializer.push(new_chain.propagation_update());
+                if let Some(gap) = new_chain.finalization_stall() {
+                    feed_serializer.push(feed_message::FinalizationStall(true, gap));
+                }
                 if let Some(bytes) = feed_serializer.into_finalized() {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                    self.feeds
+                        .send_to(feed_conn_id, ToFeedWebsocket::Bytes(bytes));
                 }
 
                 // If many (eg 10k) nodes are connected, serializing all of their info takes time.
                 // So, parallelise this with Rayon, but we still send out messages for each node in order
                 // (which is helpful for the UI as it tries to maintain a sorted list of nodes). The chunk
                 // size is the max number of node info we fit into 1 message; smaller messages allow the UI
-                // to react a little faster and not have to wait for a larger update to come in. A chunk size
-                // of 64 means each message is ~32k.
+                // to react a little faster and not have to wait for a larger update to come in. The default
+                // chunk size of 64 means each message is ~32k.
+                let chunk_size = self.node_state.feed_chunk_size();
                 use rayon::prelude::*;
                 let all_feed_messages: Vec<_> = new_chain
                     .nodes_slice()
                     .par_iter()
                     .enumerate()
-                    .chunks(64)
+                    .chunks(chunk_size)
                     .filter_map(|nodes| {
                         let mut feed_serializer = FeedMessageSerializer::new();
                         for (node_id, node) in nodes
@@ -543,26 +1235,49 @@ impl InnerLoop {
                                 node.finalized().height,
                                 node.finalized().hash,
                             ));
+                            if let Some(usage) = node.resource_usage() {
+                                feed_serializer
+                                    .push(feed_message::NodeResourceUsageUpdate(node_id, usage));
+                            }
+                            if let Some(target) = node.sync_target() {
+                                feed_serializer.push(feed_message::NodeSyncState(
+                                    node_id,
+                                    node.best().height,
+                                    target,
+                                ));
+                            }
+                            if let Some(import_time) = node.block_import_time() {
+                                feed_serializer
+                                    .push(feed_message::NodeBlockImportTime(node_id, import_time));
+                            }
+                            if node.implausible_height() {
+                                feed_serializer.push(feed_message::NodeAnomaly(node_id, true));
+                            }
                             if node.stale() {
                                 feed_serializer.push(feed_message::StaleNode(node_id));
                             }
+                            if let Some(method) = node.details().sync_method {
+                                feed_serializer.push(feed_message::NodeSyncMethod(node_id, method));
+                            }
                         }
                         feed_serializer.into_finalized()
                     })
                     .collect();
-                for bytes in all_feed_messages {
-                    let _ = feed_channel.send(ToFeedWebsocket::Bytes(bytes));
+                if !all_feed_messages.is_empty() {
+                    self.feeds
+                        .send_to(feed_conn_id, ToFeedWebsocket::Snapshot(all_feed_messages));
                 }
 
                 // Actually make a note of the new chain subsciption:
                 let new_genesis_hash = new_chain.genesis_hash();
                 self.chain_to_feed_conn_ids
                     .insert(new_genesis_hash, feed_conn_id);
+                self.feeds.subscribe(feed_conn_id, new_genesis_hash);
             }
             FromFeedWebsocket::Disconnected => {
                 // The feed has disconnected; clean up references to it:
                 self.chain_to_feed_conn_ids.remove_value(&feed_conn_id);
-                self.feed_channels.remove(&feed_conn_id);
+                self.feeds.remove(feed_conn_id);
             }
         }
     }
@@ -580,64 +1295,174 @@ impl InnerLoop {
             }
         }
 
-        // Remove the nodes for each chain
+        // Remove the nodes for each chain, in the same order that feeds are told about
+        // chains in the first place (rather than whatever order the HashMap gives us):
+        let mut node_ids_per_chain: Vec<_> = node_ids_per_chain.into_iter().collect();
+        node_ids_per_chain.sort_by_cached_key(|(genesis_hash, _)| {
+            let node_count = self
+                .node_state
+                .get_chain_by_genesis_hash(genesis_hash)
+                .map_or(0, |chain| chain.node_count());
+            (std::cmp::Reverse(node_count), *genesis_hash)
+        });
         let mut feed_messages_for_all = FeedMessageSerializer::new();
-        for (chain_label, node_ids) in node_ids_per_chain {
+        for (genesis_hash, node_ids) in node_ids_per_chain {
+            let was_advertised = self
+                .node_state
+                .get_chain_by_genesis_hash(&genesis_hash)
+                .is_some_and(|chain| {
+                    self.node_state
+                        .is_chain_advertised(&genesis_hash, chain.node_count())
+                });
             let mut feed_messages_for_chain = FeedMessageSerializer::new();
+            let mut any_removed = false;
+            let mut has_chain_label_changed = false;
             for node_id in node_ids {
-                self.remove_node(
-                    node_id,
-                    &mut feed_messages_for_chain,
-                    &mut feed_messages_for_all,
-                );
+                if let Some(removed) = self.remove_node(node_id, &mut feed_messages_for_chain) {
+                    has_chain_label_changed |= removed.has_chain_label_changed;
+                    any_removed = true;
+                }
+            }
+            self.finalize_and_broadcast_to_chain_feeds(&genesis_hash, feed_messages_for_chain);
+            if !any_removed {
+                continue;
+            }
+
+            // Tell everybody about the chain once we're done removing nodes from it, rather
+            // than once per node, so that a rename part way through doesn't leave feeds with
+            // a label or node count that's only true for a moment. We look at what's left of
+            // the chain in the state to decide what to say, so that feeds never hear about a
+            // chain that has gone, and always hear about one that still has nodes (if it
+            // still has enough of them to be advertised).
+            match self.node_state.get_chain_by_genesis_hash(&genesis_hash) {
+                Some(chain)
+                    if self
+                        .node_state
+                        .is_chain_advertised(&genesis_hash, chain.node_count()) =>
+                {
+                    if has_chain_label_changed {
+                        feed_messages_for_all.push(feed_message::RemovedChain(genesis_hash));
+                    }
+                    feed_messages_for_all.push(feed_message::AddedChain(
+                        chain.label(),
+                        genesis_hash,
+                        chain.node_count(),
+                    ));
+                }
+                _ if was_advertised => {
+                    feed_messages_for_all.push(feed_message::RemovedChain(genesis_hash));
+                }
+                _ => {}
+            }
+        }
+        self.push_network_stats(&mut feed_messages_for_all);
+        self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
+    }
+
+    /// Remove any chains which have had no nodes for too long, and tell everybody that
+    /// they've gone.
+    fn remove_empty_chains(&mut self) {
+        let removed = self.node_state.remove_empty_chains(Instant::now());
+        if removed.is_empty() {
+            return;
+        }
+        log::info!(
+            "Removing {} chains that have had no nodes for too long",
+            removed.len()
+        );
+        let mut feed_messages_for_all = FeedMessageSerializer::new();
+        for genesis_hash in removed {
+            feed_messages_for_all.push(feed_message::RemovedChain(genesis_hash));
+        }
+        self.push_network_stats(&mut feed_messages_for_all);
+        self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
+    }
+
+    /// Remove any connected nodes which haven't sent us anything for too long, and ask their
+    /// shards to stop sending us anything more about them. `now` is a unix timestamp in ms.
+    fn remove_idle_nodes(&mut self, now: u64) {
+        // Nodes without a connection (restored nodes, and those waiting to reconnect) are
+        // removed once they've had their chance to come back instead:
+        let node_ids: Vec<NodeId> = self
+            .node_state
+            .idle_nodes(now)
+            .into_iter()
+            .filter(|node_id| self.node_ids.contains_left(node_id))
+            .collect();
+        if node_ids.is_empty() {
+            return;
+        }
+        log::info!(
+            "Removing {} nodes that haven't sent anything for too long",
+            node_ids.len()
+        );
+        for &node_id in &node_ids {
+            self.mute_node(node_id, MuteReason::Disconnected);
+        }
+        self.remove_nodes_and_broadcast_result(node_ids);
+    }
+
+    /// Tell every feed about the average block time of each chain, if it's changed since
+    /// they were last told. Chains work this out over their last few best blocks.
+    fn broadcast_chain_block_times(&mut self) {
+        let mut feed_messages_for_all = FeedMessageSerializer::new();
+        let mut chain_block_times = HashMap::with_capacity(self.chain_block_times.len());
+        for chain in self.node_state.iter_chains() {
+            let genesis_hash = chain.genesis_hash();
+            let average_block_time = match chain.average_block_time() {
+                Some(average_block_time) => average_block_time,
+                None => continue,
+            };
+            if self.chain_block_times.get(&genesis_hash) != Some(&average_block_time) {
+                feed_messages_for_all.push(feed_message::ChainBlockTime(
+                    genesis_hash,
+                    average_block_time,
+                ));
             }
-            self.finalize_and_broadcast_to_chain_feeds(&chain_label, feed_messages_for_chain);
+            chain_block_times.insert(genesis_hash, average_block_time);
         }
+        self.chain_block_times = chain_block_times;
         self.finalize_and_broadcast_to_all_feeds(feed_messages_for_all);
     }
 
-    /// Remove a single node by its ID, pushing any messages we'd want to send
-    /// out to feeds onto the provided feed serializers. Doesn't actually send
-    /// anything to the feeds; just updates state as needed.
+    /// Tell feeds how many nodes and chains there are in total, if that's changed since
+    /// they were last told.
+    fn push_network_stats(&mut self, feed_serializer: &mut FeedMessageSerializer) {
+        let network_stats = (self.node_state.node_count(), self.node_state.chain_count());
+        if network_stats != self.network_stats {
+            self.network_stats = network_stats;
+            feed_serializer.push(feed_message::NetworkStats(network_stats.0, network_stats.1));
+        }
+    }
+
+    /// Remove a single node by its ID, pushing any messages we'd want to send out to
+    /// feeds subscribed to its chain onto the provided serializer. Details about the
+    /// chain after the removal are handed back so that everybody can be told about it.
     fn remove_node(
         &mut self,
         node_id: NodeId,
         feed_for_chain: &mut FeedMessageSerializer,
-        feed_for_all: &mut FeedMessageSerializer,
-    ) {
+    ) -> Option<state::RemovedNode> {
         // Remove our top level association (this may already have been done).
         self.node_ids.remove_by_left(&node_id);
+        self.located_ips.remove(&node_id);
 
         let removed_details = match self.node_state.remove_node(node_id) {
             Some(remove_details) => remove_details,
             None => {
                 log::error!("Could not find node {:?}", node_id);
-                return;
+                return None;
             }
         };
 
-        // The chain has been removed (no nodes left in it, or it was renamed):
-        if removed_details.chain_node_count == 0 || removed_details.has_chain_label_changed {
-            feed_for_all.push(feed_message::RemovedChain(
-                removed_details.chain_genesis_hash,
-            ));
-        }
-
-        // If the chain still exists, tell everybody about the new label or updated node count:
-        if removed_details.chain_node_count != 0 {
-            feed_for_all.push(feed_message::AddedChain(
-                &removed_details.new_chain_label,
-                removed_details.chain_genesis_hash,
-                removed_details.chain_node_count,
-            ));
-        }
-
         // Assuming the chain hasn't gone away, tell chain subscribers about the node removal
         if removed_details.chain_node_count != 0 {
             feed_for_chain.push(feed_message::RemovedNode(
                 node_id.get_chain_node_id().into(),
             ));
         }
+
+        Some(removed_details)
     }
 
     /// Finalize a [`FeedMessageSerializer`] and broadcast the result to feeds for the chain.
@@ -653,13 +1478,11 @@ impl InnerLoop {
 
     /// Send a message to all chain feeds.
     fn broadcast_to_chain_feeds(&mut self, genesis_hash: &BlockHash, message: ToFeedWebsocket) {
-        if let Some(feeds) = self.chain_to_feed_conn_ids.get_values(genesis_hash) {
-            for &feed_id in feeds {
-                if let Some(chan) = self.feed_channels.get_mut(&feed_id) {
-                    let _ = chan.send(message.clone());
-                }
-            }
+        // Feeds of paused chains catch up on everything they've missed once it's resumed:
+        if self.paused_chains.contains(genesis_hash) {
+            return;
         }
+        self.feeds.send_to_chain(*genesis_hash, message);
     }
 
     /// Finalize a [`FeedMessageSerializer`] and broadcast the result to all feeds
@@ -671,8 +1494,1662 @@ impl InnerLoop {
 
     /// Send a message to everybody.
     fn broadcast_to_all_feeds(&mut self, message: ToFeedWebsocket) {
-        for chan in self.feed_channels.values_mut() {
-            let _ = chan.send(message.clone());
+        self.feeds.send_to_all(message);
+    }
+}
+
+/// Describe the locations of some nodes (given by their ID on the chain) to feeds. If we're
+/// asked to, nodes located close enough together are described as a [`feed_message::NodeCluster`]
+/// rather than one at a time.
+fn serialize_locations(
+    locations: &[(usize, Arc<NodeLocation>)],
+    node_clusters: Option<state::NodeClusterOpts>,
+) -> FeedMessageSerializer {
+    let mut serializer = FeedMessageSerializer::new();
+    let mut clustered = vec![false; locations.len()];
+
+    if let Some(opts) = node_clusters {
+        let coords: Vec<_> = locations
+            .iter()
+            .map(|(_, loc)| (loc.latitude, loc.longitude))
+            .collect();
+        for group in state::group_nearby(&coords, opts.radius_km) {
+            if group.len() < opts.min_nodes.max(2) {
+                continue;
+            }
+            let node_ids: Vec<usize> = group.iter().map(|&idx| locations[idx].0).collect();
+            let latitude = group.iter().map(|&idx| coords[idx].0).sum::<f32>() / group.len() as f32;
+            let longitude =
+                group.iter().map(|&idx| coords[idx].1).sum::<f32>() / group.len() as f32;
+            serializer.push(feed_message::NodeCluster(
+                latitude,
+                longitude,
+                &locations[group[0]].1.city,
+                node_ids.len(),
+                &node_ids,
+            ));
+            for idx in group {
+                clustered[idx] = true;
+            }
+        }
+    }
+
+    for ((node_id, loc), _) in locations
+        .iter()
+        .zip(clustered)
+        .filter(|(_, clustered)| !clustered)
+    {
+        serializer.push(feed_message::LocatedNode(
+            *node_id,
+            loc.latitude,
+            loc.longitude,
+            &loc.city,
+            loc.asn,
+            loc.network.as_deref(),
+        ));
+    }
+    serializer
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::node_details;
+    use common::node_types::{NetworkId, NodeDetails};
+    use test_utils::feed_message_de::FeedMessage;
+
+    fn node(name: &str, chain: &str) -> NodeDetails {
+        NodeDetails {
+            chain: chain.into(),
+            ..node_details(name)
+        }
+    }
+
+    fn add_node(inner: &mut InnerLoop, shard: ConnId, local_id: usize, node: NodeDetails) {
+        inner.handle_from_shard(
+            shard,
+            FromShardWebsocket::Add {
+                local_id: ShardNodeId::from(local_id),
+                ip: "127.0.0.1".parse().unwrap(),
+                node,
+                genesis_hash: BlockHash::from_low_u64_be(1),
+            },
+        );
+    }
+
+    /// An inner loop to test with, which uses the state options given and otherwise the
+    /// defaults. Anything that it asks the locator to locate is sent to the receiver
+    /// handed back.
+    fn test_inner_loop(state_opts: StateOpts) -> (InnerLoop, flume::Receiver<(NodeId, IpAddr)>) {
+        let (tx_to_locator, rx_to_locator) = flume::unbounded();
+        let locator_metrics = Arc::new(LocatorMetrics::new([]));
+        let inner = InnerLoop::new(
+            tx_to_locator,
+            locator_metrics,
+            state_opts,
+            0,
+            None,
+            None,
+            None,
+        );
+        (inner, rx_to_locator)
+    }
+
+    /// Connect a feed, handing back the receiver that it's sent messages on.
+    fn connect_feed(inner: &mut InnerLoop, feed: ConnId) -> flume::Receiver<ToFeedWebsocket> {
+        let (tx_to_feed, rx_to_feed) = flume::unbounded();
+        inner.handle_from_feed(
+            feed,
+            FromFeedWebsocket::Initialize {
+                channel: tx_to_feed,
+            },
+        );
+        rx_to_feed
+    }
+
+    /// Connect a shard, handing back the receiver that it's sent messages on.
+    fn connect_shard(inner: &mut InnerLoop, shard: ConnId) -> flume::Receiver<ToShardWebsocket> {
+        let (tx_to_shard, rx_to_shard) = flume::unbounded();
+        inner.handle_from_shard(
+            shard,
+            FromShardWebsocket::Initialize {
+                channel: tx_to_shard,
+                addr: None,
+            },
+        );
+        rx_to_shard
+    }
+
+    fn feed_messages(rx: &flume::Receiver<ToFeedWebsocket>) -> Vec<FeedMessage> {
+        rx.try_iter()
+            .flat_map(ToFeedWebsocket::into_batches)
+            .flat_map(|bytes| FeedMessage::from_bytes(&bytes).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn chain_is_relabelled_once_when_removing_many_nodes() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(0));
+
+        let shard1 = ConnId::from(1);
+        let shard2 = ConnId::from(2);
+        for shard in [shard1, shard2] {
+            let _rx_to_shard = connect_shard(&mut inner, shard);
+        }
+
+        // The chain is labelled "A" to begin with, and then nodes disagreeing with that
+        // are added and removed on another shard:
+        add_node(&mut inner, shard1, 0, node("1", "A"));
+        add_node(&mut inner, shard1, 1, node("2", "A"));
+        add_node(&mut inner, shard2, 0, node("3", "B"));
+        add_node(&mut inner, shard2, 1, node("4", "B"));
+        add_node(&mut inner, shard2, 2, node("5", "C"));
+        inner.handle_from_shard(
+            shard2,
+            FromShardWebsocket::Remove {
+                local_id: ShardNodeId::from(2),
+            },
+        );
+        add_node(&mut inner, shard2, 3, node("6", "C"));
+        feed_messages(&rx_to_feed);
+
+        // Removing both "A" nodes at once renames the chain part way through. Feeds
+        // should just hear about the final label and node count:
+        inner.handle_from_shard(shard1, FromShardWebsocket::Disconnected);
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        assert_eq!(
+            feed_messages(&rx_to_feed),
+            vec![
+                FeedMessage::RemovedChain { genesis_hash },
+                FeedMessage::AddedChain {
+                    name: "B".to_owned(),
+                    genesis_hash,
+                    node_count: 3,
+                },
+                FeedMessage::NetworkStats {
+                    total_nodes: 3,
+                    total_chains: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn feeds_are_told_when_empty_chains_are_removed() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts {
+            pinned_chains: vec![genesis_hash],
+            empty_chain_ttl: Some(std::time::Duration::ZERO),
+            ..StateOpts::default()
+        });
+
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(0));
+        let shard = ConnId::from(1);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+
+        // The pinned chain stays around once its node has gone:
+        add_node(&mut inner, shard, 0, node("1", "A"));
+        inner.handle_from_shard(shard, FromShardWebsocket::Disconnected);
+        feed_messages(&rx_to_feed);
+        assert_eq!(inner.node_state.chain_count(), 1);
+
+        // ..until it's been empty for long enough:
+        inner.remove_empty_chains();
+        assert_eq!(inner.node_state.chain_count(), 0);
+        assert_eq!(
+            feed_messages(&rx_to_feed),
+            vec![
+                FeedMessage::RemovedChain { genesis_hash },
+                FeedMessage::NetworkStats {
+                    total_nodes: 0,
+                    total_chains: 0,
+                },
+            ]
+        );
+
+        // There's nothing more to say if there's nothing more to remove:
+        inner.remove_empty_chains();
+        assert!(feed_messages(&rx_to_feed).is_empty());
+    }
+
+    #[test]
+    fn feeds_are_told_about_chain_block_times_when_they_change() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+
+        let shard = ConnId::from(1);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        add_node(&mut inner, shard, 0, node("1", "A"));
+        let import = |height| FromShardWebsocket::Update {
+            local_id: ShardNodeId::from(0),
+            payload: node_message::Payload::BlockImport(common::node_types::Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            }),
+        };
+
+        let block_times = |rx: &flume::Receiver<ToFeedWebsocket>| {
+            feed_messages(rx)
+                .into_iter()
+                .filter(|msg| matches!(msg, FeedMessage::ChainBlockTime { .. }))
+                .collect::<Vec<_>>()
+        };
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(0));
+
+        // With only one best block, there's no block time to speak of yet:
+        inner.handle_from_shard(shard, import(1));
+        inner.broadcast_chain_block_times();
+        assert!(block_times(&rx_to_feed).is_empty());
+
+        inner.handle_from_shard(shard, import(2));
+        inner.broadcast_chain_block_times();
+        let average_block_time = inner
+            .node_state
+            .get_chain_by_genesis_hash(&genesis_hash)
+            .and_then(|chain| chain.average_block_time())
+            .unwrap();
+        assert_eq!(
+            block_times(&rx_to_feed),
+            vec![FeedMessage::ChainBlockTime {
+                genesis_hash,
+                average_block_time
+            }]
+        );
+
+        // Nothing's changed, so there's nothing to say:
+        inner.broadcast_chain_block_times();
+        assert!(block_times(&rx_to_feed).is_empty());
+
+        // Feeds that connect later are told straight away:
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(1));
+        assert_eq!(block_times(&rx_to_feed).len(), 1);
+    }
+
+    #[test]
+    fn feeds_are_told_when_chains_were_first_seen() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+        let shard = ConnId::from(0);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+
+        let first_seen = |rx: &flume::Receiver<ToFeedWebsocket>| {
+            feed_messages(rx)
+                .into_iter()
+                .filter(|msg| matches!(msg, FeedMessage::ChainFirstSeen { .. }))
+                .collect::<Vec<_>>()
+        };
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(1));
+        assert!(first_seen(&rx_to_feed).is_empty());
+
+        // Feeds hear about it when the first node creates the chain, and not after:
+        add_node(&mut inner, shard, 0, node("A", "Chain One"));
+        let expected = vec![FeedMessage::ChainFirstSeen {
+            genesis_hash,
+            first_seen: inner
+                .node_state
+                .get_chain_by_genesis_hash(&genesis_hash)
+                .unwrap()
+                .first_seen(),
+        }];
+        assert_eq!(first_seen(&rx_to_feed), expected);
+        add_node(&mut inner, shard, 1, node("B", "Chain One"));
+        assert!(first_seen(&rx_to_feed).is_empty());
+
+        // Feeds that connect later are told straight away:
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(2));
+        assert_eq!(first_seen(&rx_to_feed), expected);
+    }
+
+    #[test]
+    fn removing_the_last_node_removes_the_chain() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(0));
+
+        let shard = ConnId::from(1);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        add_node(&mut inner, shard, 0, node("1", "A"));
+        feed_messages(&rx_to_feed);
+
+        inner.handle_from_shard(
+            shard,
+            FromShardWebsocket::Remove {
+                local_id: ShardNodeId::from(0),
+            },
+        );
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        assert_eq!(
+            feed_messages(&rx_to_feed),
+            vec![
+                FeedMessage::RemovedChain { genesis_hash },
+                FeedMessage::NetworkStats {
+                    total_nodes: 0,
+                    total_chains: 0,
+                },
+            ]
+        );
+        assert!(inner
+            .node_state
+            .get_chain_by_genesis_hash(&genesis_hash)
+            .is_none());
+    }
+
+    #[test]
+    fn pinned_chains_are_kept_when_their_last_node_is_removed() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let state_opts = StateOpts {
+            pinned_chains: vec![genesis_hash],
+            ..Default::default()
+        };
+        let (mut inner, _rx_to_locator) = test_inner_loop(state_opts);
+
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(0));
+
+        let shard = ConnId::from(1);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        add_node(&mut inner, shard, 0, node("1", "A"));
+        feed_messages(&rx_to_feed);
+
+        // The chain sticks around with no nodes:
+        inner.handle_from_shard(
+            shard,
+            FromShardWebsocket::Remove {
+                local_id: ShardNodeId::from(0),
+            },
+        );
+        assert_eq!(
+            feed_messages(&rx_to_feed),
+            vec![
+                FeedMessage::AddedChain {
+                    name: "A".to_owned(),
+                    genesis_hash,
+                    node_count: 0,
+                },
+                FeedMessage::NetworkStats {
+                    total_nodes: 0,
+                    total_chains: 1,
+                },
+            ]
+        );
+
+        // And counts up as normal when nodes come back:
+        add_node(&mut inner, shard, 1, node("1", "A"));
+        add_node(&mut inner, shard, 2, node("2", "A"));
+        let node_counts: Vec<usize> = feed_messages(&rx_to_feed)
+            .into_iter()
+            .filter_map(|msg| match msg {
+                FeedMessage::AddedChain { node_count, .. } => Some(node_count),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(node_counts, vec![1, 2]);
+    }
+
+    #[test]
+    fn chains_are_only_advertised_once_they_have_enough_nodes() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let state_opts = StateOpts {
+            min_chain_nodes: 2,
+            ..Default::default()
+        };
+        let (mut inner, _rx_to_locator) = test_inner_loop(state_opts);
+
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(0));
+        feed_messages(&rx_to_feed);
+
+        let shard = ConnId::from(1);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        let chain_messages = |rx: &flume::Receiver<ToFeedWebsocket>| -> Vec<FeedMessage> {
+            feed_messages(rx)
+                .into_iter()
+                .filter(|msg| {
+                    matches!(
+                        msg,
+                        FeedMessage::AddedChain { .. }
+                            | FeedMessage::RemovedChain { .. }
+                            | FeedMessage::ChainFirstSeen { .. }
+                    )
+                })
+                .collect()
+        };
+
+        // Feeds don't hear about the chain while it has a single node:
+        add_node(&mut inner, shard, 0, node("1", "A"));
+        assert_eq!(chain_messages(&rx_to_feed), vec![]);
+
+        // They're told about it once it has enough:
+        add_node(&mut inner, shard, 1, node("2", "A"));
+        let messages = chain_messages(&rx_to_feed);
+        assert_eq!(messages.len(), 2);
+        assert_eq!(
+            messages[0],
+            FeedMessage::AddedChain {
+                name: "A".to_owned(),
+                genesis_hash,
+                node_count: 2,
+            }
+        );
+        assert!(matches!(messages[1], FeedMessage::ChainFirstSeen { .. }));
+
+        // New feeds only hear about it while it has enough nodes:
+        let rx_to_feed2 = connect_feed(&mut inner, ConnId::from(2));
+        assert_eq!(chain_messages(&rx_to_feed2).len(), 2);
+
+        // And are told that it's gone once it drops below that again:
+        inner.handle_from_shard(
+            shard,
+            FromShardWebsocket::Remove {
+                local_id: ShardNodeId::from(0),
+            },
+        );
+        assert_eq!(
+            chain_messages(&rx_to_feed),
+            vec![FeedMessage::RemovedChain { genesis_hash }]
+        );
+        let rx_to_feed3 = connect_feed(&mut inner, ConnId::from(3));
+        assert_eq!(chain_messages(&rx_to_feed3), vec![]);
+
+        // Nothing more is said when its last node goes:
+        inner.handle_from_shard(
+            shard,
+            FromShardWebsocket::Remove {
+                local_id: ShardNodeId::from(1),
+            },
+        );
+        assert_eq!(chain_messages(&rx_to_feed), vec![]);
+    }
+
+    #[test]
+    fn pinned_chains_are_advertised_with_any_number_of_nodes() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let state_opts = StateOpts {
+            min_chain_nodes: 2,
+            pinned_chains: vec![genesis_hash],
+            ..Default::default()
+        };
+        let (mut inner, _rx_to_locator) = test_inner_loop(state_opts);
+
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(0));
+        feed_messages(&rx_to_feed);
+
+        let shard = ConnId::from(1);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        add_node(&mut inner, shard, 0, node("1", "A"));
+        assert!(
+            feed_messages(&rx_to_feed).contains(&FeedMessage::AddedChain {
+                name: "A".to_owned(),
+                genesis_hash,
+                node_count: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn relabelled_chain_is_removed_with_its_last_node() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(0));
+
+        let shard1 = ConnId::from(1);
+        let shard2 = ConnId::from(2);
+        for shard in [shard1, shard2] {
+            let _rx_to_shard = connect_shard(&mut inner, shard);
+        }
+        add_node(&mut inner, shard1, 0, node("1", "A"));
+        add_node(&mut inner, shard1, 1, node("2", "A"));
+        add_node(&mut inner, shard2, 0, node("3", "B"));
+        feed_messages(&rx_to_feed);
+
+        // Losing the "A" nodes renames the chain:
+        inner.handle_from_shard(shard1, FromShardWebsocket::Disconnected);
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        assert_eq!(
+            feed_messages(&rx_to_feed),
+            vec![
+                FeedMessage::RemovedChain { genesis_hash },
+                FeedMessage::AddedChain {
+                    name: "B".to_owned(),
+                    genesis_hash,
+                    node_count: 1,
+                },
+                FeedMessage::NetworkStats {
+                    total_nodes: 1,
+                    total_chains: 1,
+                },
+            ]
+        );
+
+        // And losing the last node removes it, without it being added back again:
+        inner.handle_from_shard(shard2, FromShardWebsocket::Disconnected);
+        assert_eq!(
+            feed_messages(&rx_to_feed),
+            vec![
+                FeedMessage::RemovedChain { genesis_hash },
+                FeedMessage::NetworkStats {
+                    total_nodes: 0,
+                    total_chains: 0,
+                },
+            ]
+        );
+        assert!(inner
+            .node_state
+            .get_chain_by_genesis_hash(&genesis_hash)
+            .is_none());
+    }
+
+    #[test]
+    fn each_emptied_chain_is_removed_once_when_a_shard_disconnects() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(0));
+
+        let shard1 = ConnId::from(1);
+        let shard2 = ConnId::from(2);
+        for shard in [shard1, shard2] {
+            let _rx_to_shard = connect_shard(&mut inner, shard);
+        }
+
+        // Chains one and two only have nodes on the first shard, and chain three has
+        // nodes on both:
+        let nodes = [
+            (shard1, 0, 1, "One"),
+            (shard1, 1, 1, "One"),
+            (shard1, 2, 2, "Two"),
+            (shard1, 3, 3, "Three"),
+            (shard2, 0, 3, "Three"),
+        ];
+        for (shard, local_id, genesis_hash, chain) in nodes {
+            inner.handle_from_shard(
+                shard,
+                FromShardWebsocket::Add {
+                    local_id: ShardNodeId::from(local_id),
+                    ip: "127.0.0.1".parse().unwrap(),
+                    node: node(&local_id.to_string(), chain),
+                    genesis_hash: BlockHash::from_low_u64_be(genesis_hash),
+                },
+            );
+        }
+        feed_messages(&rx_to_feed);
+
+        inner.handle_from_shard(shard1, FromShardWebsocket::Disconnected);
+        assert_eq!(
+            feed_messages(&rx_to_feed),
+            vec![
+                FeedMessage::RemovedChain {
+                    genesis_hash: BlockHash::from_low_u64_be(1)
+                },
+                FeedMessage::AddedChain {
+                    name: "Three".to_owned(),
+                    genesis_hash: BlockHash::from_low_u64_be(3),
+                    node_count: 1,
+                },
+                FeedMessage::RemovedChain {
+                    genesis_hash: BlockHash::from_low_u64_be(2)
+                },
+                FeedMessage::NetworkStats {
+                    total_nodes: 1,
+                    total_chains: 1,
+                },
+            ]
+        );
+        assert_eq!(inner.node_state.iter_chains().count(), 1);
+    }
+
+    #[test]
+    fn nodes_are_sent_to_subscribing_feeds_in_chunks() {
+        let state_opts = StateOpts {
+            // This is clamped to 1, rather than leading to empty messages:
+            feed_chunk_size: 0,
+            ..Default::default()
+        };
+        let (mut inner, _rx_to_locator) = test_inner_loop(state_opts);
+
+        let shard = ConnId::from(1);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        for id in 0..3 {
+            add_node(&mut inner, shard, id, node(&id.to_string(), "A"));
+        }
+
+        let feed = ConnId::from(0);
+        let rx_to_feed = connect_feed(&mut inner, feed);
+        rx_to_feed.drain();
+        inner.handle_from_feed(
+            feed,
+            FromFeedWebsocket::Subscribe {
+                chain: BlockHash::from_low_u64_be(1),
+            },
+        );
+
+        // The first batch is about the chain itself, and then there's one per node:
+        let batches: Vec<_> = rx_to_feed
+            .drain()
+            .flat_map(ToFeedWebsocket::into_batches)
+            .collect();
+        assert_eq!(batches.len(), 4);
+        for (id, bytes) in batches.iter().skip(1).enumerate() {
+            let added: Vec<usize> = FeedMessage::from_bytes(bytes)
+                .unwrap()
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    FeedMessage::AddedNode { node_id, .. } => Some(node_id),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(added, vec![id]);
+        }
+    }
+
+    #[test]
+    fn node_locations_are_sent_to_feeds_in_batches() {
+        let state_opts = StateOpts {
+            feed_chunk_size: 2,
+            ..Default::default()
+        };
+        let (mut inner, _rx_to_locator) = test_inner_loop(state_opts);
+
+        let shard = ConnId::from(1);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        let mut node_ids = Vec::new();
+        for id in 0..3 {
+            add_node(&mut inner, shard, id, node(&id.to_string(), "A"));
+            node_ids.push(*inner.node_ids.get_by_right(&(shard, id.into())).unwrap());
+        }
+
+        let feed = ConnId::from(0);
+        let rx_to_feed = connect_feed(&mut inner, feed);
+        inner.handle_from_feed(
+            feed,
+            FromFeedWebsocket::Subscribe {
+                chain: BlockHash::from_low_u64_be(1),
+            },
+        );
+        rx_to_feed.drain();
+
+        let located = |rx: &flume::Receiver<ToFeedWebsocket>| -> Vec<Vec<usize>> {
+            rx.drain()
+                .flat_map(ToFeedWebsocket::into_batches)
+                .map(|bytes| {
+                    FeedMessage::from_bytes(&bytes)
+                        .unwrap()
+                        .into_iter()
+                        .filter_map(|msg| match msg {
+                            FeedMessage::LocatedNode { node_id, .. } => Some(node_id),
+                            _ => None,
+                        })
+                        .collect()
+                })
+                .collect()
+        };
+        for node_id in node_ids {
+            let location = NodeLocation {
+                latitude: 1.0,
+                longitude: 2.0,
+                city: "Somewhere".into(),
+                asn: None,
+                network: None,
+                source: None,
+            };
+            inner.handle_from_find_location(node_id, Some(Arc::new(location)));
+        }
+
+        // A full batch is sent straight away, and the rest once we're told to:
+        assert_eq!(located(&rx_to_feed), vec![vec![0, 1]]);
+        inner.broadcast_pending_locations();
+        assert_eq!(located(&rx_to_feed), vec![vec![2]]);
+        inner.broadcast_pending_locations();
+        assert!(rx_to_feed.is_empty());
+    }
+
+    #[test]
+    fn nodes_located_close_together_are_sent_to_feeds_as_clusters() {
+        let state_opts = StateOpts {
+            node_clusters: Some(state::NodeClusterOpts {
+                radius_km: 1.0,
+                min_nodes: 3,
+            }),
+            ..Default::default()
+        };
+        let (mut inner, _rx_to_locator) = test_inner_loop(state_opts);
+
+        let shard = ConnId::from(1);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        let mut node_ids = Vec::new();
+        for id in 0..5 {
+            add_node(&mut inner, shard, id, node(&id.to_string(), "A"));
+            node_ids.push(*inner.node_ids.get_by_right(&(shard, id.into())).unwrap());
+        }
+
+        let feed = ConnId::from(0);
+        let rx_to_feed = connect_feed(&mut inner, feed);
+        inner.handle_from_feed(
+            feed,
+            FromFeedWebsocket::Subscribe {
+                chain: BlockHash::from_low_u64_be(1),
+            },
+        );
+        rx_to_feed.drain();
+
+        // Three nodes in one place, one nearby and one far away:
+        let coords = [
+            (1.0, 2.0),
+            (1.0, 2.0),
+            (1.001, 2.0),
+            (1.0, 2.002),
+            (50.0, 2.0),
+        ];
+        for (node_id, (latitude, longitude)) in node_ids.into_iter().zip(coords) {
+            let location = NodeLocation {
+                latitude,
+                longitude,
+                city: "Somewhere".into(),
+                asn: None,
+                network: None,
+                source: None,
+            };
+            inner.handle_from_find_location(node_id, Some(Arc::new(location)));
+        }
+        inner.broadcast_pending_locations();
+
+        let messages: Vec<_> = feed_messages(&rx_to_feed)
+            .into_iter()
+            .filter(|msg| {
+                matches!(
+                    msg,
+                    FeedMessage::NodeCluster { .. } | FeedMessage::LocatedNode { .. }
+                )
+            })
+            .collect();
+        assert_eq!(messages.len(), 2);
+        assert!(matches!(
+            &messages[0],
+            FeedMessage::NodeCluster { count: 4, node_ids, city, .. }
+                if node_ids == &[0, 1, 2, 3] && city == "Somewhere"
+        ));
+        assert!(matches!(
+            &messages[1],
+            FeedMessage::LocatedNode { node_id: 4, .. }
+        ));
+    }
+
+    #[test]
+    fn feeds_stop_hearing_about_chains_they_unsubscribe_from() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+
+        let shard = ConnId::from(1);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        add_node(&mut inner, shard, 0, node("1", "A"));
+
+        let feed = ConnId::from(0);
+        let rx_to_feed = connect_feed(&mut inner, feed);
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        inner.handle_from_feed(
+            feed,
+            FromFeedWebsocket::Subscribe {
+                chain: genesis_hash,
+            },
+        );
+        feed_messages(&rx_to_feed);
+
+        // Unsubscribing from some other chain does nothing:
+        inner.handle_from_feed(
+            feed,
+            FromFeedWebsocket::Unsubscribe {
+                chain: BlockHash::from_low_u64_be(2),
+            },
+        );
+        assert!(feed_messages(&rx_to_feed).is_empty());
+
+        inner.handle_from_feed(
+            feed,
+            "unsubscribe:0x0000000000000000000000000000000000000000000000000000000000000001"
+                .parse()
+                .unwrap(),
+        );
+        assert_eq!(
+            feed_messages(&rx_to_feed),
+            vec![FeedMessage::UnsubscribedFrom { genesis_hash }]
+        );
+
+        // We're told about chains coming and going, but nothing more about this one:
+        add_node(&mut inner, shard, 1, node("2", "A"));
+        assert_eq!(
+            feed_messages(&rx_to_feed),
+            vec![
+                FeedMessage::AddedChain {
+                    name: "A".to_owned(),
+                    genesis_hash,
+                    node_count: 2,
+                },
+                FeedMessage::NetworkStats {
+                    total_nodes: 2,
+                    total_chains: 1,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn feeds_that_cannot_keep_up_are_dropped() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+        inner.feeds = FeedBroadcaster::new(Some(3), None);
+
+        // This feed never reads any of the messages sent to it:
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(0));
+
+        let shard = ConnId::from(1);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        for id in 0..5 {
+            add_node(&mut inner, shard, id, node(&id.to_string(), "A"));
+        }
+
+        // Once 3 messages were queued up, we stopped sending any more and let the feed go:
+        let counts = inner.feeds.counts();
+        assert_eq!(counts.connected_feeds, 0);
+        assert_eq!(counts.dropped_feeds, 1);
+        assert_eq!(rx_to_feed.drain().count(), 3);
+        assert!(rx_to_feed.is_disconnected());
+    }
+
+    #[test]
+    fn feeds_are_told_when_the_sync_target_of_a_node_changes() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+
+        let shard = ConnId::from(1);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        add_node(&mut inner, shard, 0, node("A", "Chain One"));
+
+        let subscribe = |inner: &mut InnerLoop, feed: u64| {
+            let rx_to_feed = connect_feed(inner, ConnId::from(feed));
+            inner.handle_from_feed(
+                ConnId::from(feed),
+                FromFeedWebsocket::Subscribe {
+                    chain: BlockHash::from_low_u64_be(1),
+                },
+            );
+            rx_to_feed
+        };
+        let rx_to_feed = subscribe(&mut inner, 0);
+        rx_to_feed.drain();
+
+        let interval = |target_height| FromShardWebsocket::Update {
+            local_id: ShardNodeId::from(0),
+            payload: node_message::Payload::SystemInterval(node_message::SystemInterval {
+                target_height,
+                ..Default::default()
+            }),
+        };
+        let sync_state = FeedMessage::NodeSyncState {
+            node_id: 0,
+            current: 0,
+            target: 100,
+        };
+
+        inner.handle_from_shard(shard, interval(Some(100)));
+        assert!(feed_messages(&rx_to_feed).contains(&sync_state));
+
+        // Nothing new to say:
+        inner.handle_from_shard(shard, interval(Some(100)));
+        inner.handle_from_shard(shard, interval(None));
+        assert!(!feed_messages(&rx_to_feed).contains(&sync_state));
+
+        // Feeds subscribing later are told about it too:
+        let rx_to_feed = subscribe(&mut inner, 2);
+        assert!(feed_messages(&rx_to_feed).contains(&sync_state));
+    }
+
+    #[test]
+    fn nodes_too_far_ahead_of_their_chain_are_flagged() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let state_opts = StateOpts {
+            max_height_deviation: 100,
+            ..Default::default()
+        };
+        let (mut inner, _rx_to_locator) = test_inner_loop(state_opts);
+        let shard = ConnId::from(0);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+
+        let subscribe = |inner: &mut InnerLoop, feed: u64| {
+            let rx_to_feed = connect_feed(inner, ConnId::from(feed));
+            inner.handle_from_feed(
+                ConnId::from(feed),
+                FromFeedWebsocket::Subscribe {
+                    chain: genesis_hash,
+                },
+            );
+            rx_to_feed
+        };
+        let import = |local_id: usize, height| FromShardWebsocket::Update {
+            local_id: ShardNodeId::from(local_id),
+            payload: node_message::Payload::BlockImport(common::node_types::Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            }),
+        };
+
+        for id in 0..3 {
+            add_node(&mut inner, shard, id, node(&id.to_string(), "Chain One"));
+            inner.handle_from_shard(shard, import(id, 10));
+        }
+        let rx_to_feed = subscribe(&mut inner, 0);
+        feed_messages(&rx_to_feed);
+
+        // Way ahead of everybody else, so the chain's best block doesn't move:
+        inner.handle_from_shard(shard, import(2, 1_000_000));
+        let messages = feed_messages(&rx_to_feed);
+        assert!(messages.contains(&FeedMessage::NodeAnomaly {
+            node_id: 2,
+            implausible_height: true
+        }));
+        assert!(!messages
+            .iter()
+            .any(|msg| matches!(msg, FeedMessage::BestBlock { .. })));
+        let chain = inner
+            .node_state
+            .get_chain_by_genesis_hash(&genesis_hash)
+            .unwrap();
+        assert_eq!(chain.best_block().height, 10);
+
+        // A little way ahead is fine:
+        inner.handle_from_shard(shard, import(0, 50));
+        let messages = feed_messages(&rx_to_feed);
+        assert!(messages.iter().any(|msg| matches!(
+            msg,
+            FeedMessage::BestBlock {
+                block_number: 50,
+                ..
+            }
+        )));
+
+        // Feeds subscribing later hear about the flagged node too:
+        let rx_to_feed = subscribe(&mut inner, 1);
+        assert!(
+            feed_messages(&rx_to_feed).contains(&FeedMessage::NodeAnomaly {
+                node_id: 2,
+                implausible_height: true
+            })
+        );
+    }
+
+    #[test]
+    fn only_nodes_reporting_bandwidth_send_it_to_feeds() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+        let shard = ConnId::from(0);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        add_node(&mut inner, shard, 0, node("A", "Chain One"));
+
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(1));
+        inner.handle_from_feed(
+            ConnId::from(1),
+            FromFeedWebsocket::Subscribe {
+                chain: BlockHash::from_low_u64_be(1),
+            },
+        );
+        feed_messages(&rx_to_feed);
+
+        let interval = |bandwidth: Option<f64>| FromShardWebsocket::Update {
+            local_id: ShardNodeId::from(0),
+            payload: node_message::Payload::SystemInterval(node_message::SystemInterval {
+                bandwidth_upload: bandwidth,
+                bandwidth_download: bandwidth,
+                ..Default::default()
+            }),
+        };
+        let hardware = FeedMessage::Hardware { node_id: 0 };
+
+        inner.handle_from_shard(shard, interval(None));
+        assert!(!feed_messages(&rx_to_feed).contains(&hardware));
+
+        inner.handle_from_shard(shard, interval(Some(1024.0)));
+        assert!(feed_messages(&rx_to_feed).contains(&hardware));
+
+        // Each pair of values is stamped with the time that it arrived, and the
+        // intervals without any bandwidth don't get stamps of their own:
+        let chain = inner
+            .node_state
+            .get_chain_by_genesis_hash(&BlockHash::from_low_u64_be(1))
+            .unwrap();
+        let node = chain.nodes_slice()[0].as_ref().unwrap();
+        assert_eq!(node.hardware().upload.slice().len(), 1);
+        assert_eq!(node.hardware().chart_stamps.slice().len(), 1);
+    }
+
+    #[test]
+    fn peer_count_changes_are_sent_to_feeds() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+        let shard = ConnId::from(0);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        add_node(&mut inner, shard, 0, node("A", "Chain One"));
+
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(1));
+        inner.handle_from_feed(
+            ConnId::from(1),
+            FromFeedWebsocket::Subscribe {
+                chain: BlockHash::from_low_u64_be(1),
+            },
+        );
+        feed_messages(&rx_to_feed);
+
+        let interval = |peers: Option<u64>| FromShardWebsocket::Update {
+            local_id: ShardNodeId::from(0),
+            payload: node_message::Payload::SystemInterval(node_message::SystemInterval {
+                peers,
+                ..Default::default()
+            }),
+        };
+        let stats_updates = |rx: &flume::Receiver<ToFeedWebsocket>| -> Vec<u64> {
+            feed_messages(rx)
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    FeedMessage::NodeStatsUpdate { node_id: 0, stats } => Some(stats.peers),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        inner.handle_from_shard(shard, interval(Some(5)));
+        assert_eq!(stats_updates(&rx_to_feed), vec![5]);
+
+        // Nothing is sent if the count hasn't changed, or wasn't reported:
+        inner.handle_from_shard(shard, interval(Some(5)));
+        inner.handle_from_shard(shard, interval(None));
+        assert!(stats_updates(&rx_to_feed).is_empty());
+
+        // Dropping to no peers at all is sent like any other change:
+        inner.handle_from_shard(shard, interval(Some(0)));
+        assert_eq!(stats_updates(&rx_to_feed), vec![0]);
+    }
+
+    #[test]
+    fn operators_can_disconnect_nodes_and_shards() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+        let shard = ConnId::from(0);
+        let rx_to_shard = connect_shard(&mut inner, shard);
+        add_node(&mut inner, shard, 0, node("A", "Chain One"));
+        add_node(&mut inner, shard, 1, node("B", "Chain One"));
+        // Keep the chain around once the shard above has gone:
+        let other_shard = ConnId::from(2);
+        let _rx_to_other_shard = connect_shard(&mut inner, other_shard);
+        add_node(&mut inner, other_shard, 0, node("C", "Chain One"));
+
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(1));
+        inner.handle_from_feed(
+            ConnId::from(1),
+            FromFeedWebsocket::Subscribe {
+                chain: BlockHash::from_low_u64_be(1),
+            },
+        );
+        feed_messages(&rx_to_feed);
+
+        // Nothing happens if the node or shard doesn't exist:
+        assert!(!inner.handle_disconnect_node(BlockHash::from_low_u64_be(2), 0));
+        assert!(!inner.handle_disconnect_node(BlockHash::from_low_u64_be(1), 5));
+        assert!(!inner.handle_disconnect_shard(ConnId::from(5)));
+        assert!(feed_messages(&rx_to_feed).is_empty());
+
+        // Disconnecting a node removes it, and the shard is asked to stop telling us about it:
+        assert!(inner.handle_disconnect_node(BlockHash::from_low_u64_be(1), 0));
+        assert!(feed_messages(&rx_to_feed).contains(&FeedMessage::RemovedNode { node_id: 0 }));
+        assert!(matches!(
+            rx_to_shard.try_recv(),
+            Ok(ToShardWebsocket::Mute {
+                reason: MuteReason::Disconnected,
+                ..
+            })
+        ));
+
+        // Disconnecting a shard removes its nodes and closes our channel to it:
+        assert!(inner.handle_disconnect_shard(shard));
+        assert!(feed_messages(&rx_to_feed).contains(&FeedMessage::RemovedNode { node_id: 1 }));
+        assert!(rx_to_shard.is_disconnected());
+        assert!(!inner.handle_disconnect_shard(shard));
+    }
+
+    #[test]
+    fn idle_nodes_are_removed() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts {
+            node_idle_timeout: Some(std::time::Duration::from_secs(60)),
+            ..StateOpts::default()
+        });
+        let shard = ConnId::from(0);
+        let rx_to_shard = connect_shard(&mut inner, shard);
+        add_node(&mut inner, shard, 0, node("A", "Chain One"));
+        add_node(&mut inner, shard, 1, node("B", "Chain One"));
+
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(1));
+        inner.handle_from_feed(
+            ConnId::from(1),
+            FromFeedWebsocket::Subscribe {
+                chain: BlockHash::from_low_u64_be(1),
+            },
+        );
+        feed_messages(&rx_to_feed);
+
+        // Nothing is removed before the timeout:
+        let last_seen = |inner: &InnerLoop, local_id: usize| {
+            let node_id = inner
+                .node_ids
+                .get_by_right(&(shard, ShardNodeId::from(local_id)));
+            let chain = inner.node_state.get_chain_by_node_id(*node_id.unwrap());
+            chain.unwrap().nodes_slice()[local_id]
+                .as_ref()
+                .unwrap()
+                .last_seen()
+        };
+        let oldest = last_seen(&inner, 0).min(last_seen(&inner, 1));
+        inner.remove_idle_nodes(oldest + 59_999);
+        assert_eq!(inner.node_state.node_count(), 2);
+        assert!(feed_messages(&rx_to_feed).is_empty());
+
+        // Once they've been quiet for long enough, they're removed (taking the chain with
+        // them), and their shard is asked to ignore them from now on:
+        let newest = last_seen(&inner, 0).max(last_seen(&inner, 1));
+        inner.remove_idle_nodes(newest + 60_000);
+        assert_eq!(inner.node_state.node_count(), 0);
+        let messages = feed_messages(&rx_to_feed);
+        assert!(messages.contains(&FeedMessage::RemovedNode { node_id: 0 }));
+        assert!(messages.contains(&FeedMessage::RemovedChain {
+            genesis_hash: BlockHash::from_low_u64_be(1)
+        }));
+        for _ in 0..2 {
+            assert!(matches!(
+                rx_to_shard.try_recv(),
+                Ok(ToShardWebsocket::Mute {
+                    reason: MuteReason::Disconnected,
+                    ..
+                })
+            ));
+        }
+        assert!(inner.node_ids.is_empty());
+    }
+
+    #[test]
+    fn feeds_of_paused_chains_catch_up_when_resumed() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+        let shard = ConnId::from(0);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        add_node(&mut inner, shard, 0, node("A", "Chain One"));
+
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(1));
+        inner.handle_from_feed(
+            ConnId::from(1),
+            FromFeedWebsocket::Subscribe {
+                chain: genesis_hash,
+            },
+        );
+        feed_messages(&rx_to_feed);
+
+        // Only chains that exist can be paused, and only paused chains resumed:
+        assert!(!inner.handle_set_chain_paused(BlockHash::from_low_u64_be(2), true));
+        assert!(!inner.handle_set_chain_paused(genesis_hash, false));
+
+        // Nothing is said about the chain while it's paused, though the state is kept up to date:
+        assert!(inner.handle_set_chain_paused(genesis_hash, true));
+        add_node(&mut inner, shard, 1, node("B", "Chain One"));
+        inner.handle_from_shard(
+            shard,
+            FromShardWebsocket::Update {
+                local_id: ShardNodeId::from(0),
+                payload: node_message::Payload::BlockImport(common::node_types::Block {
+                    hash: BlockHash::from_low_u64_be(10),
+                    height: 10,
+                }),
+            },
+        );
+        assert!(!feed_messages(&rx_to_feed)
+            .iter()
+            .any(|msg| matches!(msg, FeedMessage::AddedNode { .. })));
+
+        // Once resumed, the feed is sent everything afresh:
+        assert!(inner.handle_set_chain_paused(genesis_hash, false));
+        let messages = feed_messages(&rx_to_feed);
+        assert!(messages.contains(&FeedMessage::SubscribedTo { genesis_hash }));
+        let added = messages
+            .iter()
+            .filter(|msg| matches!(msg, FeedMessage::AddedNode { .. }))
+            .count();
+        assert_eq!(added, 2);
+        assert_eq!(
+            inner
+                .node_state
+                .get_chain_by_genesis_hash(&genesis_hash)
+                .unwrap()
+                .best_block()
+                .height,
+            10
+        );
+    }
+
+    #[test]
+    fn feeds_are_told_about_network_wide_totals() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+        let shard = ConnId::from(0);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        add_node(&mut inner, shard, 0, node("A", "Chain One"));
+
+        let network_stats = |rx: &flume::Receiver<ToFeedWebsocket>| -> Vec<(usize, usize)> {
+            feed_messages(rx)
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    FeedMessage::NetworkStats {
+                        total_nodes,
+                        total_chains,
+                    } => Some((total_nodes, total_chains)),
+                    _ => None,
+                })
+                .collect()
+        };
+
+        // New feeds are told the totals straight away:
+        let rx_to_feed = connect_feed(&mut inner, ConnId::from(1));
+        assert_eq!(network_stats(&rx_to_feed), vec![(1, 1)]);
+
+        // ... and again whenever they change:
+        add_node(&mut inner, shard, 1, node("B", "Chain One"));
+        assert_eq!(network_stats(&rx_to_feed), vec![(2, 1)]);
+        inner.handle_from_shard(
+            shard,
+            FromShardWebsocket::Add {
+                local_id: ShardNodeId::from(2),
+                ip: "127.0.0.1".parse().unwrap(),
+                node: node("C", "Chain Two"),
+                genesis_hash: BlockHash::from_low_u64_be(2),
+            },
+        );
+        assert_eq!(network_stats(&rx_to_feed), vec![(3, 2)]);
+        inner.handle_from_shard(
+            shard,
+            FromShardWebsocket::Remove {
+                local_id: ShardNodeId::from(2),
+            },
+        );
+        assert_eq!(network_stats(&rx_to_feed), vec![(2, 1)]);
+    }
+
+    #[test]
+    fn connection_time_only_changes_when_a_node_reconnects() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+        let shard = ConnId::from(0);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        add_node(&mut inner, shard, 0, node("A", "Chain One"));
+
+        let connected_at = |inner: &mut InnerLoop, feed: u64| {
+            let rx_to_feed = connect_feed(inner, ConnId::from(feed));
+            inner.handle_from_feed(
+                ConnId::from(feed),
+                FromFeedWebsocket::Subscribe {
+                    chain: BlockHash::from_low_u64_be(1),
+                },
+            );
+            let times: Vec<u64> = feed_messages(&rx_to_feed)
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    FeedMessage::AddedNode { connected_at, .. } => Some(connected_at),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(times.len(), 1);
+            times[0]
+        };
+        let first_connected_at = connected_at(&mut inner, 0);
+
+        // Updates from the node don't make it look like a new connection:
+        inner.handle_from_shard(
+            shard,
+            FromShardWebsocket::Update {
+                local_id: ShardNodeId::from(0),
+                payload: node_message::Payload::SystemInterval(node_message::SystemInterval {
+                    peers: Some(5),
+                    ..Default::default()
+                }),
+            },
+        );
+        assert_eq!(connected_at(&mut inner, 1), first_connected_at);
+
+        // Reconnecting does:
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        inner.handle_from_shard(
+            shard,
+            FromShardWebsocket::Remove {
+                local_id: ShardNodeId::from(0),
+            },
+        );
+        add_node(&mut inner, shard, 0, node("A", "Chain One"));
+        assert!(connected_at(&mut inner, 2) > first_connected_at);
+    }
+
+    #[test]
+    fn node_updates_are_timed_by_payload_type() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+        let shard = ConnId::from(0);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        add_node(&mut inner, shard, 0, node("A", "Chain One"));
+
+        let import = |height| FromShardWebsocket::Update {
+            local_id: ShardNodeId::from(0),
+            payload: node_message::Payload::BlockImport(common::node_types::Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            }),
+        };
+        inner.handle_from_shard(shard, import(1));
+        inner.handle_from_shard(shard, import(2));
+
+        let (tx_metrics, rx_metrics) = flume::unbounded();
+        inner.handle_gather_metrics(tx_metrics, 0, 0, 0);
+        let metrics = rx_metrics.try_recv().unwrap();
+
+        assert_eq!(metrics.node_message_timings.len(), 1);
+        let timings = &metrics.node_message_timings[0];
+        assert_eq!(timings.payload, "block.import");
+        assert_eq!(timings.state.count(), 2);
+        assert_eq!(timings.feeds.count(), 2);
+        // These didn't go through the aggregator's queue:
+        assert_eq!(timings.queued.count(), 0);
+    }
+
+    fn queued_count(inner: &InnerLoop) -> usize {
+        inner.node_add_queues.values().map(|q| q.len()).sum()
+    }
+
+    #[test]
+    fn nodes_added_too_quickly_are_queued() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+        inner.max_node_adds_per_second = Some(20);
+        let shard = ConnId::from(0);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+
+        // A second's worth of nodes are added right away, and the rest wait:
+        for local_id in 0..22 {
+            add_node(&mut inner, shard, local_id, node("A", "Chain One"));
+        }
+        assert_eq!(inner.node_state.node_count(), 20);
+        assert_eq!(queued_count(&inner), 2);
+
+        // A queued node that goes away is never added:
+        inner.handle_from_shard(
+            shard,
+            FromShardWebsocket::Remove {
+                local_id: ShardNodeId::from(21),
+            },
+        );
+        assert_eq!(queued_count(&inner), 1);
+
+        std::thread::sleep(std::time::Duration::from_millis(100));
+        inner.add_queued_nodes();
+        assert_eq!(inner.node_state.node_count(), 21);
+        assert_eq!(queued_count(&inner), 0);
+        assert!(inner
+            .node_ids
+            .contains_right(&(shard, ShardNodeId::from(20))));
+    }
+
+    #[test]
+    fn disconnected_nodes_can_reconnect_within_the_grace_period() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts {
+            reconnect_grace_period: Some(std::time::Duration::from_secs(30)),
+            ..StateOpts::default()
+        });
+        let feed = ConnId::from(0);
+        let rx_to_feed = connect_feed(&mut inner, feed);
+        let shard = ConnId::from(1);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+
+        let with_network_id = |name: &str, network_id: &str| NodeDetails {
+            network_id: NetworkId::from(network_id).unwrap(),
+            ..node(name, "Chain One")
+        };
+        add_node(&mut inner, shard, 0, with_network_id("A", "peer0"));
+        add_node(&mut inner, shard, 1, with_network_id("B", "peer1"));
+        inner.handle_from_feed(
+            feed,
+            FromFeedWebsocket::Subscribe {
+                chain: BlockHash::from_low_u64_be(1),
+            },
+        );
+        feed_messages(&rx_to_feed);
+
+        // Both nodes go away, but are just shown as stale for now:
+        inner.handle_from_shard(shard, FromShardWebsocket::Disconnected);
+        let mut messages = feed_messages(&rx_to_feed);
+        messages.sort_by_key(|m| format!("{:?}", m));
+        assert_eq!(
+            messages,
+            vec![
+                FeedMessage::StaleNode { node_id: 0 },
+                FeedMessage::StaleNode { node_id: 1 },
+            ]
+        );
+        assert_eq!(inner.node_state.node_count(), 2);
+        assert!(inner.node_ids.is_empty());
+
+        // One comes back, replacing itself:
+        let shard = ConnId::from(2);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        add_node(&mut inner, shard, 0, with_network_id("A", "peer0"));
+        assert_eq!(inner.node_state.node_count(), 2);
+        assert!(feed_messages(&rx_to_feed).contains(&FeedMessage::RemovedNode { node_id: 0 }));
+
+        // Nothing has run out of time yet:
+        let node_ids = inner.node_state.take_expired_disconnected(Instant::now());
+        assert!(node_ids.is_empty());
+
+        // The other doesn't come back in time, so it's removed:
+        let later = Instant::now() + std::time::Duration::from_secs(30);
+        let node_ids = inner.node_state.take_expired_disconnected(later);
+        inner.remove_nodes_and_broadcast_result(node_ids);
+        assert_eq!(inner.node_state.node_count(), 1);
+        assert!(feed_messages(&rx_to_feed).contains(&FeedMessage::RemovedNode { node_id: 1 }));
+        assert!(inner
+            .node_ids
+            .contains_right(&(shard, ShardNodeId::from(0))));
+    }
+
+    #[test]
+    fn reconnecting_nodes_are_located_from_their_new_address() {
+        let (mut inner, rx_to_locator) = test_inner_loop(StateOpts {
+            reconnect_grace_period: Some(std::time::Duration::from_secs(30)),
+            ..StateOpts::default()
+        });
+        let details = NodeDetails {
+            network_id: NetworkId::from("peer0").unwrap(),
+            ..node("A", "Chain One")
+        };
+        let add_from = |inner: &mut InnerLoop, shard: ConnId, ip: IpAddr| {
+            let _rx_to_shard = connect_shard(inner, shard);
+            inner.handle_from_shard(
+                shard,
+                FromShardWebsocket::Add {
+                    local_id: ShardNodeId::from(0),
+                    ip,
+                    node: details.clone(),
+                    genesis_hash: BlockHash::from_low_u64_be(1),
+                },
+            );
+        };
+        let location = |city: &str| {
+            Some(Arc::new(NodeLocation {
+                latitude: 1.0,
+                longitude: 2.0,
+                city: city.into(),
+                asn: None,
+                network: None,
+                source: None,
+            }))
+        };
+        let city = |inner: &InnerLoop, node_id: NodeId| {
+            let chain = inner.node_state.get_chain_by_node_id(node_id).unwrap();
+            let idx: usize = node_id.get_chain_node_id().into();
+            let node = chain.nodes_slice()[idx].as_ref().unwrap();
+            node.location().map(|loc| loc.city.to_string())
+        };
+
+        let old_ip: IpAddr = "1.1.1.1".parse().unwrap();
+        add_from(&mut inner, ConnId::from(1), old_ip);
+        assert_eq!(rx_to_locator.try_recv().unwrap().1, old_ip);
+
+        // The node goes away, and comes back from somewhere else before we've located
+        // it. It replaces itself, and we ask for the location of its new address:
+        let new_ip: IpAddr = "2.2.2.2".parse().unwrap();
+        inner.handle_from_shard(ConnId::from(1), FromShardWebsocket::Disconnected);
+        add_from(&mut inner, ConnId::from(2), new_ip);
+        let (node_id, ip) = rx_to_locator.try_recv().unwrap();
+        assert_eq!(ip, new_ip);
+        assert_eq!(inner.node_state.node_count(), 1);
+
+        // The location of its old address is no use to us now, even if it's the last
+        // one that we hear about:
+        inner.handle_located_ip(node_id, new_ip, location("New"));
+        inner.handle_located_ip(node_id, old_ip, location("Old"));
+        assert_eq!(city(&inner, node_id).as_deref(), Some("New"));
+    }
+
+    #[test]
+    fn snapshots_say_which_shard_nodes_are_connected_through_if_asked() {
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+
+        let shard_addr: SocketAddr = "10.0.0.1:45678".parse().unwrap();
+        for (shard, addr) in [(ConnId::from(1), Some(shard_addr)), (ConnId::from(2), None)] {
+            let (tx_to_shard, _rx_to_shard) = flume::unbounded();
+            inner.handle_from_shard(
+                shard,
+                FromShardWebsocket::Initialize {
+                    channel: tx_to_shard,
+                    addr,
+                },
+            );
+        }
+        add_node(&mut inner, ConnId::from(1), 0, node("A", "Chain One"));
+        add_node(&mut inner, ConnId::from(2), 0, node("B", "Chain One"));
+
+        let shards = |snapshot: StateSnapshot| -> Vec<Option<ShardSnapshot>> {
+            snapshot.chains[0]
+                .nodes
+                .iter()
+                .map(|node| node.shard.clone())
+                .collect()
+        };
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        assert_eq!(
+            shards(inner.gather_snapshot(Some(&genesis_hash), true)),
+            vec![
+                Some(ShardSnapshot {
+                    id: 1,
+                    addr: Some(shard_addr)
+                }),
+                Some(ShardSnapshot { id: 2, addr: None }),
+            ]
+        );
+
+        // Nothing is said about shards unless asked for:
+        assert_eq!(shards(inner.gather_snapshot(None, false)), vec![None, None]);
+
+        // Shards are forgotten about once they've gone:
+        inner.handle_from_shard(ConnId::from(1), FromShardWebsocket::Disconnected);
+        assert!(inner.shard_addrs.is_empty());
+    }
+
+    #[test]
+    fn public_snapshots_only_include_chains_that_feeds_are_told_about() {
+        let state_opts = StateOpts {
+            min_chain_nodes: 2,
+            ..Default::default()
+        };
+        let (mut inner, _rx_to_locator) = test_inner_loop(state_opts);
+
+        let shard = ConnId::from(1);
+        let _rx_to_shard = connect_shard(&mut inner, shard);
+        // Two chains with enough nodes to be advertised, and one without:
+        let nodes = [
+            ("A", "Chain One", 1),
+            ("B", "Chain One", 1),
+            ("C", "Chain Two", 2),
+            ("D", "Chain Three", 3),
+            ("E", "Chain Three", 3),
+        ];
+        for (local_id, (name, chain, genesis_hash)) in nodes.into_iter().enumerate() {
+            inner.handle_from_shard(
+                shard,
+                FromShardWebsocket::Add {
+                    local_id: ShardNodeId::from(local_id),
+                    ip: "127.0.0.1".parse().unwrap(),
+                    node: node(name, chain),
+                    genesis_hash: BlockHash::from_low_u64_be(genesis_hash),
+                },
+            );
+        }
+        assert!(inner.handle_set_chain_paused(BlockHash::from_low_u64_be(3), true));
+
+        let labels = |snapshot: StateSnapshot| -> Vec<Box<str>> {
+            snapshot
+                .chains
+                .into_iter()
+                .map(|chain| chain.label)
+                .collect()
+        };
+        assert_eq!(
+            labels(inner.gather_snapshot(None, false)),
+            vec!["Chain One".into()]
+        );
+        assert!(
+            labels(inner.gather_snapshot(Some(&BlockHash::from_low_u64_be(2)), false)).is_empty()
+        );
+
+        // Operators are told about every chain:
+        assert_eq!(
+            labels(inner.gather_snapshot(None, true)),
+            vec![
+                Box::from("Chain One"),
+                "Chain Three".into(),
+                "Chain Two".into()
+            ]
+        );
+    }
+
+    /// A rough benchmark of sending out the locations pending for lots of chains at once. It's
+    /// ignored by default; run it with:
+    ///
+    /// ```sh
+    /// cargo test --release -p telemetry_core -- pending_locations_benchmark --ignored --nocapture
+    /// ```
+    #[ignore]
+    #[test]
+    fn pending_locations_benchmark() {
+        const NUMBER_OF_CHAINS: u64 = 5_000;
+        const LOCATIONS_PER_CHAIN: usize = 4;
+        const ROUNDS: u32 = 50;
+
+        let (mut inner, _rx_to_locator) = test_inner_loop(StateOpts::default());
+        let location = Arc::new(NodeLocation {
+            latitude: 1.0,
+            longitude: 2.0,
+            city: "Somewhere".into(),
+            asn: None,
+            network: None,
+            source: None,
+        });
+
+        let mut total_time = std::time::Duration::ZERO;
+        for _ in 0..ROUNDS {
+            for n in 0..NUMBER_OF_CHAINS {
+                let locations = (0..LOCATIONS_PER_CHAIN)
+                    .map(|id| (id, Arc::clone(&location)))
+                    .collect();
+                inner
+                    .pending_locations
+                    .insert(BlockHash::from_low_u64_be(n), locations);
+            }
+            let start = Instant::now();
+            inner.broadcast_pending_locations();
+            total_time += start.elapsed();
         }
+        println!(
+            "{} chains with {} pending locations each: {:?} per broadcast",
+            NUMBER_OF_CHAINS,
+            LOCATIONS_PER_CHAIN,
+            total_time / ROUNDS
+        );
     }
 }
@@ -16,7 +16,9 @@
 
 mod aggregator;
 mod aggregator_set;
+mod feed_broadcast;
 mod inner_loop;
+mod node_add_queue;
 
 // Expose the various message types that can be worked with externally:
 pub use aggregator::AggregatorOpts;
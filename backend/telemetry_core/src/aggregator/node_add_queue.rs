@@ -0,0 +1,212 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::internal_messages::ShardNodeId;
+use common::node_types::{BlockHash, NodeDetails};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// A node that a shard has told us about, but which hasn't been added yet.
+#[derive(Debug, Clone)]
+pub struct QueuedNode {
+    pub ip: IpAddr,
+    pub node: NodeDetails,
+    pub genesis_hash: BlockHash,
+}
+
+/// A shard that reconnects will tell us about all of its nodes at once. This smooths
+/// that out, so that nodes from any one shard are added at no more than some rate.
+/// Shards can add up to a second's worth of nodes in one go before any are queued.
+pub struct NodeAddQueue {
+    per_second: f64,
+    /// How many nodes we can add right now. This tops back up over time.
+    tokens: f64,
+    last_refill: Instant,
+    /// The order that queued nodes were added in.
+    order: VecDeque<ShardNodeId>,
+    queued: HashMap<ShardNodeId, QueuedNode>,
+}
+
+impl NodeAddQueue {
+    pub fn new(per_second: u32, now: Instant) -> Self {
+        let per_second = per_second.max(1) as f64;
+        NodeAddQueue {
+            per_second,
+            tokens: per_second,
+            last_refill: now,
+            order: VecDeque::new(),
+            queued: HashMap::new(),
+        }
+    }
+
+    /// Hand the node back if it can be added right away, or otherwise queue it up until
+    /// [`NodeAddQueue::take_ready`] says that it can be.
+    pub fn push(
+        &mut self,
+        local_id: ShardNodeId,
+        node: QueuedNode,
+        now: Instant,
+    ) -> Option<QueuedNode> {
+        // Nodes mustn't jump ahead of any that are already waiting:
+        if self.order.is_empty() && self.take_token(now) {
+            return Some(node);
+        }
+        if self.queued.insert(local_id, node).is_none() {
+            self.order.push_back(local_id);
+        }
+        None
+    }
+
+    /// Forget about a queued node, returning whether it was queued. If it's pushed again,
+    /// it goes to the back of the queue.
+    pub fn remove(&mut self, local_id: ShardNodeId) -> bool {
+        if self.queued.remove(&local_id).is_none() {
+            return false;
+        }
+        self.order.retain(|&id| id != local_id);
+        true
+    }
+
+    /// Is this node waiting to be added?
+    pub fn contains(&self, local_id: ShardNodeId) -> bool {
+        self.queued.contains_key(&local_id)
+    }
+
+    /// How many nodes are waiting to be added.
+    pub fn len(&self) -> usize {
+        self.queued.len()
+    }
+
+    /// Take as many of the queued nodes as can be added now, in the order they arrived.
+    pub fn take_ready(&mut self, now: Instant) -> Vec<(ShardNodeId, QueuedNode)> {
+        let mut ready = Vec::new();
+        while !self.order.is_empty() && self.take_token(now) {
+            if let Some(local_id) = self.order.pop_front() {
+                let node = self
+                    .queued
+                    .remove(&local_id)
+                    .expect("every ID in order is queued");
+                ready.push((local_id, node));
+            }
+        }
+        ready
+    }
+
+    fn take_token(&mut self, now: Instant) -> bool {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.per_second).min(self.per_second);
+        self.last_refill = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::node_details;
+    use std::time::Duration;
+
+    fn queued_node(name: &str) -> QueuedNode {
+        QueuedNode {
+            ip: "127.0.0.1".parse().unwrap(),
+            node: NodeDetails {
+                chain: "Chain".into(),
+                ..node_details(name)
+            },
+            genesis_hash: BlockHash::from_low_u64_be(1),
+        }
+    }
+
+    fn names(ready: Vec<(ShardNodeId, QueuedNode)>) -> Vec<String> {
+        ready
+            .into_iter()
+            .map(|(_, queued)| queued.node.name.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn nodes_beyond_the_rate_are_queued_in_order() {
+        let start = Instant::now();
+        let mut queue = NodeAddQueue::new(2, start);
+
+        assert!(queue.push(0.into(), queued_node("A"), start).is_some());
+        assert!(queue.push(1.into(), queued_node("B"), start).is_some());
+        assert!(queue.push(2.into(), queued_node("C"), start).is_none());
+        assert!(queue.push(3.into(), queued_node("D"), start).is_none());
+        assert!(queue.push(4.into(), queued_node("E"), start).is_none());
+        assert_eq!(queue.len(), 3);
+        assert!(queue.take_ready(start).is_empty());
+
+        // Half a second later, there's room for one more:
+        let later = start + Duration::from_millis(500);
+        assert_eq!(names(queue.take_ready(later)), vec!["C"]);
+        // Even with room, new nodes wait their turn:
+        let later = start + Duration::from_millis(1000);
+        assert!(queue.push(5.into(), queued_node("F"), later).is_none());
+        assert_eq!(names(queue.take_ready(later)), vec!["D"]);
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(names(queue.take_ready(later)), vec!["E", "F"]);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn removed_nodes_are_never_added() {
+        let start = Instant::now();
+        let mut queue = NodeAddQueue::new(1, start);
+
+        assert!(queue.push(0.into(), queued_node("A"), start).is_some());
+        assert!(queue.push(1.into(), queued_node("B"), start).is_none());
+        assert!(queue.push(2.into(), queued_node("C"), start).is_none());
+        assert!(queue.contains(1.into()));
+        assert!(queue.remove(1.into()));
+        assert!(!queue.contains(1.into()));
+        assert!(!queue.remove(0.into()));
+
+        let later = start + Duration::from_secs(1);
+        assert_eq!(names(queue.take_ready(later)), vec!["C"]);
+    }
+
+    #[test]
+    fn removed_nodes_go_to_the_back_if_pushed_again() {
+        let start = Instant::now();
+        let mut queue = NodeAddQueue::new(1, start);
+
+        assert!(queue.push(0.into(), queued_node("A"), start).is_some());
+        assert!(queue.push(1.into(), queued_node("B"), start).is_none());
+        assert!(queue.push(2.into(), queued_node("C"), start).is_none());
+        assert!(queue.remove(1.into()));
+        assert!(queue
+            .push(1.into(), queued_node("B again"), start)
+            .is_none());
+        assert_eq!(queue.order.len(), 2);
+
+        let later = start + Duration::from_secs(10);
+        assert_eq!(names(queue.take_ready(later)), vec!["C"]);
+        let later = start + Duration::from_secs(20);
+        assert_eq!(names(queue.take_ready(later)), vec!["B again"]);
+        assert!(queue.order.is_empty());
+    }
+}
@@ -0,0 +1,103 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! The chains that we deny (or allow) can be given on the command line and in any
+//! number of files, one entry per line. These are merged into a single list here.
+
+use std::collections::HashSet;
+use std::hash::Hash;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// Combine the entries given with those in each of the files, one per line. Blank lines
+/// and lines starting with '#' are ignored, as are duplicates. Lines that can't be parsed
+/// are logged and skipped, so that one bad entry doesn't lose us the rest of the list.
+pub fn read<T>(entries: &[T], paths: &[PathBuf]) -> anyhow::Result<Vec<T>>
+where
+    T: FromStr + Hash + Eq + Clone,
+    T::Err: std::fmt::Display,
+{
+    let mut seen = HashSet::new();
+    let mut list = Vec::new();
+    let mut push = |entry: T| {
+        if seen.insert(entry.clone()) {
+            list.push(entry);
+        }
+    };
+
+    entries.iter().cloned().for_each(&mut push);
+    for path in paths {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Couldn't read {}: {}", path.display(), e))?;
+        let lines = contents
+            .lines()
+            .enumerate()
+            .map(|(idx, line)| (idx + 1, line.trim()))
+            .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'));
+        for (line_number, line) in lines {
+            match line.parse() {
+                Ok(entry) => push(entry),
+                Err(e) => log::warn!(
+                    "Ignoring invalid entry '{}' at {}:{}: {}",
+                    line,
+                    path.display(),
+                    line_number,
+                    e
+                ),
+            }
+        }
+    }
+    Ok(list)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::node_types::BlockHash;
+
+    fn write_file(name: &str, contents: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "telemetry_chain_list_{}_{}",
+            name,
+            std::process::id()
+        ));
+        std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn lists_are_merged_without_duplicates() {
+        let a = write_file("a", "# Team A\nChain One\n\nChain Two\n");
+        let b = write_file("b", "Chain Two\n  Chain Three  \n");
+
+        let list = read(&["Chain One".to_owned()], &[a.clone(), b.clone()]).unwrap();
+        assert_eq!(list, vec!["Chain One", "Chain Two", "Chain Three"]);
+
+        let _ = std::fs::remove_file(a);
+        let _ = std::fs::remove_file(b);
+    }
+
+    #[test]
+    fn invalid_entries_are_skipped() {
+        let hash = BlockHash::from_low_u64_be(1);
+        let path = write_file("c", &format!("not a hash\n{:?}\n", hash));
+
+        let list: Vec<BlockHash> = read(&[], std::slice::from_ref(&path)).unwrap();
+        assert_eq!(list, vec![hash]);
+
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,94 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+
+/// Keeps count of how many connections are open from each IP address, so that
+/// no single address can open too many of them.
+#[derive(Clone)]
+pub struct ConnectionLimiter {
+    max_per_ip: usize,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl ConnectionLimiter {
+    /// Allow up to `max_per_ip` connections from each IP address, or any number
+    /// of them if this is `None`.
+    pub fn new(max_per_ip: Option<usize>) -> Self {
+        ConnectionLimiter {
+            max_per_ip: max_per_ip.unwrap_or(usize::MAX),
+            counts: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Make a note of a new connection from some IP address. This returns `None` if
+    /// there are already too many connections from it. Otherwise, the connection is
+    /// counted until the [`ConnectionGuard`] handed back is dropped.
+    pub fn acquire(&self, ip: IpAddr) -> Option<ConnectionGuard> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(ip).or_default();
+        if *count >= self.max_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionGuard {
+            ip,
+            counts: self.counts.clone(),
+        })
+    }
+}
+
+/// A connection counted by a [`ConnectionLimiter`].
+pub struct ConnectionGuard {
+    ip: IpAddr,
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn connections_are_limited_per_ip() {
+        let limiter = ConnectionLimiter::new(Some(2));
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let a1 = limiter.acquire(a).unwrap();
+        let _a2 = limiter.acquire(a).unwrap();
+        assert!(limiter.acquire(a).is_none());
+        // Other addresses have their own limit:
+        let _b1 = limiter.acquire(b).unwrap();
+
+        // Once a connection closes, there's room for another:
+        drop(a1);
+        assert!(limiter.acquire(a).is_some());
+    }
+}
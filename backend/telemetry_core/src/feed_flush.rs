@@ -0,0 +1,178 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::histogram::Histogram;
+use parking_lot::Mutex;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The upper bounds (in seconds) of the buckets that we use to record the flush intervals chosen.
+const INTERVAL_BUCKETS: &[f64] = &[0.01, 0.025, 0.05, 0.075, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0];
+
+/// If we send a feed at least this many bytes in one go, we're busy, and wait for longer
+/// before sending the next batch so that more messages are sent together.
+const BUSY_BATCH_BYTES: usize = 64 * 1024;
+
+/// If we send a feed no more than this many bytes in one go, we're quiet, and wait for less
+/// time before sending the next batch so that messages reach the feed sooner.
+const QUIET_BATCH_BYTES: usize = 4 * 1024;
+
+/// We never wait for less than this between batches. Waiting for no time at all would also
+/// leave busy feeds stuck without waiting, since doubling nothing leaves nothing.
+const MIN_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Options to configure how long we wait between sending batches of messages to each feed.
+#[derive(Debug, Clone, Copy)]
+pub struct FeedFlushOpts {
+    /// The shortest time to wait between batches. Feeds wait this long unless we're busy.
+    /// Anything shorter than 1ms is treated as 1ms.
+    pub interval: Duration,
+    /// The longest time to wait between batches when we're busy. If this is no longer
+    /// than `interval`, we always wait for `interval`.
+    pub max_interval: Duration,
+    /// Wait for up to this much longer each time, chosen at random, so that the feeds
+    /// of different cores aren't all sent messages at the same moment.
+    pub jitter: Duration,
+}
+
+/// Works out how long a single feed should wait after being sent one batch of messages
+/// before it's sent the next. The wait doubles (up to the maximum) each time a large batch
+/// is sent, and halves (down to the minimum) each time a small one is. Each wait chosen
+/// is recorded in the metrics given.
+#[derive(Debug, Clone)]
+pub struct FlushInterval {
+    opts: FeedFlushOpts,
+    current: Duration,
+    metrics: Arc<FeedFlushMetrics>,
+}
+
+impl FlushInterval {
+    pub fn new(mut opts: FeedFlushOpts, metrics: Arc<FeedFlushMetrics>) -> Self {
+        opts.interval = opts.interval.max(MIN_INTERVAL);
+        FlushInterval {
+            opts,
+            current: opts.interval,
+            metrics,
+        }
+    }
+
+    /// We've just sent a batch of this many bytes; how long should we wait before the next?
+    pub fn next(&mut self, batch_bytes: usize) -> Duration {
+        let max_interval = self.opts.max_interval.max(self.opts.interval);
+        if batch_bytes >= BUSY_BATCH_BYTES {
+            self.current = (self.current * 2).min(max_interval);
+        } else if batch_bytes <= QUIET_BATCH_BYTES {
+            self.current = (self.current / 2).max(self.opts.interval);
+        }
+        let wait = self.current + self.opts.jitter.mul_f64(rand::random::<f64>());
+        self.metrics.record_interval(wait);
+        wait
+    }
+}
+
+/// The flush intervals chosen across every feed. This is shared with whoever wants to
+/// report on them.
+#[derive(Debug)]
+pub struct FeedFlushMetrics {
+    intervals: Mutex<Histogram>,
+}
+
+impl Default for FeedFlushMetrics {
+    fn default() -> Self {
+        FeedFlushMetrics {
+            intervals: Mutex::new(Histogram::new(INTERVAL_BUCKETS)),
+        }
+    }
+}
+
+impl FeedFlushMetrics {
+    fn record_interval(&self, interval: Duration) {
+        self.intervals.lock().observe(interval.as_secs_f64());
+    }
+
+    /// The intervals chosen so far, in seconds.
+    pub fn intervals(&self) -> Histogram {
+        self.intervals.lock().clone()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn interval_adapts_to_batch_size() {
+        let opts = FeedFlushOpts {
+            interval: Duration::from_millis(50),
+            max_interval: Duration::from_millis(300),
+            jitter: Duration::ZERO,
+        };
+        let metrics = Arc::new(FeedFlushMetrics::default());
+        let mut interval = FlushInterval::new(opts, Arc::clone(&metrics));
+
+        // Busy feeds wait for longer, up to the maximum:
+        assert_eq!(interval.next(BUSY_BATCH_BYTES), Duration::from_millis(100));
+        assert_eq!(interval.next(BUSY_BATCH_BYTES), Duration::from_millis(200));
+        assert_eq!(interval.next(BUSY_BATCH_BYTES), Duration::from_millis(300));
+        assert_eq!(interval.next(BUSY_BATCH_BYTES), Duration::from_millis(300));
+
+        // Batches in between leave things as they are:
+        assert_eq!(
+            interval.next(QUIET_BATCH_BYTES + 1),
+            Duration::from_millis(300)
+        );
+
+        // Quiet feeds wait for less time, down to the minimum:
+        assert_eq!(interval.next(0), Duration::from_millis(150));
+        assert_eq!(interval.next(0), Duration::from_millis(75));
+        assert_eq!(interval.next(0), Duration::from_millis(50));
+        assert_eq!(interval.next(0), Duration::from_millis(50));
+
+        // Every wait chosen is recorded:
+        assert_eq!(metrics.intervals().count(), 9);
+    }
+
+    #[test]
+    fn interval_of_zero_still_adapts() {
+        let opts = FeedFlushOpts {
+            interval: Duration::ZERO,
+            max_interval: Duration::from_millis(4),
+            jitter: Duration::ZERO,
+        };
+        let mut interval = FlushInterval::new(opts, Arc::new(FeedFlushMetrics::default()));
+
+        // We wait for at least 1ms, so that busy feeds can wait for longer from there:
+        assert_eq!(interval.next(0), Duration::from_millis(1));
+        assert_eq!(interval.next(BUSY_BATCH_BYTES), Duration::from_millis(2));
+        assert_eq!(interval.next(BUSY_BATCH_BYTES), Duration::from_millis(4));
+    }
+
+    #[test]
+    fn interval_is_fixed_unless_a_longer_maximum_is_given() {
+        let opts = FeedFlushOpts {
+            interval: Duration::from_millis(75),
+            max_interval: Duration::ZERO,
+            jitter: Duration::from_millis(10),
+        };
+        let mut interval = FlushInterval::new(opts, Arc::default());
+
+        for _ in 0..10 {
+            let wait = interval.next(BUSY_BATCH_BYTES);
+            assert!(wait >= Duration::from_millis(75));
+            assert!(wait < Duration::from_millis(85));
+        }
+    }
+}
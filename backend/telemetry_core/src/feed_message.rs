@@ -21,9 +21,11 @@ use serde::Serialize;
 
 use crate::state::Node;
 use common::node_types::{
-    BlockDetails, BlockHash, BlockNumber, NodeHardware, NodeIO, NodeStats, Timestamp,
+    BlockDetails, BlockHash, BlockNumber, NodeHardware, NodeIO, NodeResourceUsage, NodeStats,
+    SyncMethod, Timestamp,
 };
 use serde_json::to_writer;
+use std::io::Write;
 
 type FeedNodeId = usize;
 
@@ -73,6 +75,22 @@ impl FeedMessageSerializer {
         msg.write_to_feed(self);
     }
 
+    /// Push a message whose action and payload we already have to hand.
+    fn push_raw<S>(&mut self, action: u8, payload: &S)
+    where
+        S: Serialize,
+    {
+        let glue = match self.buffer.len() {
+            0 => b'[',
+            _ => b',',
+        };
+
+        self.buffer.push(glue);
+        self.write(&action);
+        self.buffer.push(b',');
+        self.write(payload);
+    }
+
     fn write<S>(&mut self, value: &S)
     where
         S: Serialize,
@@ -92,12 +110,20 @@ impl FeedMessageSerializer {
 }
 
 macro_rules! actions {
-    ($($action:literal: $t:ty,)*) => {
+    ($($action:literal: $name:ident $(<$lt:lifetime>)?,)*) => {
         $(
-            impl FeedMessage for $t {
+            impl FeedMessage for $name $(<$lt>)? {
                 const ACTION: u8 = $action;
             }
         )*
+
+        /// The name of the message with the action given, if there is one.
+        pub fn action_name(action: u8) -> Option<&'static str> {
+            match action {
+                $($action => Some(stringify!($name)),)*
+                _ => None,
+            }
+        }
     }
 }
 
@@ -123,8 +149,31 @@ actions! {
     20: StaleNode,
     21: NodeIOUpdate<'_>,
     22: ChainStatsUpdate<'_>,
+    23: ForkDetected<'_>,
+    24: NodeResourceUsageUpdate<'_>,
+    25: BlockPropagationUpdate,
+    26: NodeAuthorityStatus,
+    27: NodeSyncState,
+    28: NodeAnomaly,
+    29: NetworkStats,
+    30: NodeLastSeen,
+    31: NodeVersionInfo<'_>,
+    32: ChainBlockTime,
+    33: ChainFirstSeen,
+    34: FinalizationStall,
+    35: NodeCluster<'_>,
+    36: NodeBlockImportTime,
+    37: NodeRecovered,
+    38: NodeSyncMethod,
 }
 
+/// The version of the feed format that we send unless asked otherwise. This should be
+/// bumped whenever the format changes in a way that older clients won't understand.
+pub const FEED_VERSION: usize = 33;
+
+/// Older versions of the feed format which we can still send to clients that ask for them.
+pub const LEGACY_FEED_VERSIONS: &[usize] = &[32];
+
 #[derive(Serialize)]
 pub struct Version(pub usize);
 
@@ -139,8 +188,17 @@ pub struct AddedNode<'a>(pub FeedNodeId, pub &'a Node);
 #[derive(Serialize)]
 pub struct RemovedNode(pub FeedNodeId);
 
+/// The node ID, latitude, longitude and city of a node, followed by
+/// the ASN and name of the network that it's on (if known).
 #[derive(Serialize)]
-pub struct LocatedNode<'a>(pub FeedNodeId, pub f32, pub f32, pub &'a str);
+pub struct LocatedNode<'a>(
+    pub FeedNodeId,
+    pub f32,
+    pub f32,
+    pub &'a str,
+    pub Option<u32>,
+    pub Option<&'a str>,
+);
 
 #[derive(Serialize)]
 pub struct ImportedBlock<'a>(pub FeedNodeId, pub &'a BlockDetails);
@@ -178,6 +236,91 @@ pub struct Pong<'a>(pub &'a str);
 #[derive(Serialize)]
 pub struct StaleNode(pub FeedNodeId);
 
+/// A node that feeds were told is stale has imported a new block, and is no longer stale.
+#[derive(Serialize)]
+pub struct NodeRecovered(pub FeedNodeId);
+
+/// How a node is syncing, so that a node which is behind because it's warp syncing can be
+/// told apart from one that's stuck. This is only sent for nodes that have told us.
+#[derive(Serialize)]
+pub struct NodeSyncMethod(pub FeedNodeId, pub SyncMethod);
+
+/// Nodes on the chain have imported differing blocks at this height; the
+/// hashes of those blocks are given.
+#[derive(Serialize)]
+pub struct ForkDetected<'a>(pub BlockNumber, pub &'a [BlockHash]);
+
+/// The CPU, memory and disk usage of a node. This is only sent for nodes that
+/// report any of these.
+#[derive(Serialize)]
+pub struct NodeResourceUsageUpdate<'a>(pub FeedNodeId, pub &'a NodeResourceUsage);
+
+/// How many milliseconds it took the best block at this height to reach half, and
+/// then nine tenths, of the nodes on the chain (if it has yet).
+#[derive(Serialize)]
+pub struct BlockPropagationUpdate(pub BlockNumber, pub Option<u64>, pub Option<u64>);
+
+/// Whether a node is an authority on its chain. Feeds are already told this when the
+/// node is added, so this is only sent when it changes.
+#[derive(Serialize)]
+pub struct NodeAuthorityStatus(pub FeedNodeId, pub bool);
+
+/// The height of a node's best block, and of the block that it's syncing towards.
+/// Nodes that are fully synced will have the same value for both.
+#[derive(Serialize)]
+pub struct NodeSyncState(pub FeedNodeId, pub BlockNumber, pub BlockNumber);
+
+/// Whether a node's best block is so far ahead of the rest of its chain that we don't
+/// believe it. Sent when this changes, and when subscribing for nodes that are flagged.
+#[derive(Serialize)]
+pub struct NodeAnomaly(pub FeedNodeId, pub bool);
+
+/// How many nodes are connected, and how many chains they're on, across every chain.
+#[derive(Serialize)]
+pub struct NetworkStats(pub usize, pub usize);
+
+/// When we last heard from a node (a unix timestamp in ms). Only sent every so often,
+/// rather than for every update from the node.
+#[derive(Serialize)]
+pub struct NodeLastSeen(pub FeedNodeId, pub Timestamp);
+
+/// The version of a node, and the spec version of its runtime (if it's told us). Feeds
+/// are already told this when the node is added, so this is only sent when it changes.
+#[derive(Serialize)]
+pub struct NodeVersionInfo<'a>(pub FeedNodeId, pub &'a str, pub Option<u32>);
+
+/// The average time, in ms, between the last few best blocks of a chain. This is sent to
+/// every feed (not just those subscribed to the chain), every so often if it's changed.
+#[derive(Serialize)]
+pub struct ChainBlockTime(pub BlockHash, pub u64);
+
+/// When the first node for a chain connected, as a unix timestamp in ms. This is sent to every
+/// feed when they connect, and when a chain is added.
+#[derive(Serialize)]
+pub struct ChainFirstSeen(pub BlockHash, pub Timestamp);
+
+/// Finalization has stalled (true) or caught up again (false) on the chain, along with how
+/// many blocks the median finalized block was behind the median best block when we checked.
+#[derive(Serialize)]
+pub struct FinalizationStall(pub bool, pub BlockNumber);
+
+/// Several nodes that have been located close together: the latitude, longitude and city
+/// of the cluster, how many nodes are in it and their IDs. This is sent in place of a
+/// [`LocatedNode`] for each of them.
+#[derive(Serialize)]
+pub struct NodeCluster<'a>(
+    pub f32,
+    pub f32,
+    pub &'a str,
+    pub usize,
+    pub &'a [FeedNodeId],
+);
+
+/// How long, in ms, a node took to import a recent block. Only sent every so often, rather
+/// than for every block that the node imports.
+#[derive(Serialize)]
+pub struct NodeBlockImportTime(pub FeedNodeId, pub u64);
+
 impl FeedMessageWrite for AddedNode<'_> {
     fn write_to_feed(&self, ser: &mut FeedMessageSerializer) {
         let AddedNode(nid, node) = self;
@@ -189,6 +332,10 @@ impl FeedMessageWrite for AddedNode<'_> {
             &details.version,
             &details.validator,
             &details.network_id,
+            details.authority,
+            details.spec_version,
+            &details.operator,
+            node.group(),
         );
 
         ser.write(&(
@@ -200,6 +347,8 @@ impl FeedMessageWrite for AddedNode<'_> {
             node.block_details(),
             &node.location(),
             &node.startup_time(),
+            node.connected_at(),
+            node.last_seen(),
         ));
     }
 }
@@ -230,3 +379,392 @@ pub struct ChainStats {
     pub disk_sequential_write_score: Ranking<(u32, Option<u32>)>,
     pub disk_random_write_score: Ranking<(u32, Option<u32>)>,
 }
+
+/// Rewrite a finalized batch of messages into one of the [`LEGACY_FEED_VERSIONS`], leaving out
+/// anything that clients expecting that version wouldn't understand. `None` is returned if
+/// there's nothing left to send.
+///
+/// Version 32 is the same as version 33, except that:
+///
+/// - [`LocatedNode`] has no ASN or network, and a [`NodeCluster`] is sent as a
+///   [`LocatedNode`] for each of its nodes instead.
+/// - [`AddedNode`] has no ASN or network in its location, and says nothing about when the node
+///   connected or was last seen. Its details don't say whether the node is an authority,
+///   which runtime it's running, who operates it or which group it belongs to.
+/// - There are no [`ForkDetected`], [`NodeResourceUsageUpdate`], [`BlockPropagationUpdate`],
+///   [`NodeAuthorityStatus`], [`NodeSyncState`], [`NodeAnomaly`], [`NetworkStats`],
+///   [`NodeLastSeen`], [`NodeVersionInfo`], [`ChainBlockTime`], [`ChainFirstSeen`],
+///   [`FinalizationStall`], [`NodeBlockImportTime`], [`NodeRecovered`] or [`NodeSyncMethod`]
+///   messages.
+pub fn downgrade(bytes: &[u8], version: usize) -> anyhow::Result<Option<bytes::Bytes>> {
+    if !LEGACY_FEED_VERSIONS.contains(&version) {
+        anyhow::bail!("Feed version {} is not supported", version);
+    }
+
+    let messages: Vec<serde_json::Value> = serde_json::from_slice(bytes)?;
+    let mut serializer = FeedMessageSerializer::new();
+    for message in messages.chunks(2) {
+        let (action, payload) = match message {
+            [action, payload] => (action, payload),
+            _ => anyhow::bail!("Feed message has no payload"),
+        };
+        let action = action
+            .as_u64()
+            .and_then(|action| u8::try_from(action).ok())
+            .ok_or_else(|| anyhow::anyhow!("Invalid feed message action {}", action))?;
+
+        if action == Version::ACTION {
+            serializer.push_raw(action, &version);
+        } else if action == LocatedNode::ACTION {
+            let payload: Vec<&serde_json::Value> = match payload.as_array() {
+                Some(fields) => fields.iter().take(4).collect(),
+                None => anyhow::bail!("LocatedNode payload should be an array"),
+            };
+            serializer.push_raw(action, &payload);
+        } else if action == NodeCluster::ACTION {
+            let (lat, lon, city, _count, node_ids): (f32, f32, String, usize, Vec<FeedNodeId>) =
+                serde_json::from_value(payload.clone())?;
+            for node_id in node_ids {
+                serializer.push_raw(LocatedNode::ACTION, &(node_id, lat, lon, &city));
+            }
+        } else if action == AddedNode::ACTION {
+            let mut payload = payload.clone();
+            let fields = match payload.as_array_mut() {
+                Some(fields) => fields,
+                None => anyhow::bail!("AddedNode payload should be an array"),
+            };
+            fields.truncate(8);
+            match fields.get_mut(1).and_then(|details| details.as_array_mut()) {
+                Some(details) => details.truncate(5),
+                None => anyhow::bail!("AddedNode payload should have an array of details"),
+            }
+            // The location is null if the node hasn't been located yet:
+            if let Some(location) = fields.get_mut(6).and_then(|loc| loc.as_array_mut()) {
+                location.truncate(3);
+            }
+            serializer.push_raw(action, &payload);
+        } else if ![
+            ForkDetected::ACTION,
+            NodeResourceUsageUpdate::ACTION,
+            BlockPropagationUpdate::ACTION,
+            NodeAuthorityStatus::ACTION,
+            NodeSyncState::ACTION,
+            NodeAnomaly::ACTION,
+            NetworkStats::ACTION,
+            NodeLastSeen::ACTION,
+            NodeVersionInfo::ACTION,
+            ChainBlockTime::ACTION,
+            ChainFirstSeen::ACTION,
+            FinalizationStall::ACTION,
+            NodeBlockImportTime::ACTION,
+            NodeRecovered::ACTION,
+            NodeSyncMethod::ACTION,
+        ]
+        .contains(&action)
+        {
+            serializer.push_raw(action, payload);
+        }
+    }
+    Ok(serializer.into_finalized())
+}
+
+/// Rewrite some finalized feed messages in a form that's easier for people to read while
+/// developing or debugging a feed client: a JSON array with an object for each message,
+/// which names it alongside its action and payload, like
+/// `[{"action":1,"name":"BestBlock","payload":[10,1000,null]}]`. Payloads are unchanged.
+pub fn to_readable(bytes: &[u8]) -> anyhow::Result<bytes::Bytes> {
+    #[derive(Serialize)]
+    struct ReadableMessage<'a> {
+        action: u8,
+        name: &'static str,
+        payload: &'a serde_json::value::RawValue,
+    }
+
+    let messages: Vec<&serde_json::value::RawValue> = serde_json::from_slice(bytes)?;
+    let mut readable = Vec::with_capacity(messages.len() / 2);
+    for message in messages.chunks(2) {
+        let (action, payload) = match message {
+            [action, payload] => (action, *payload),
+            _ => anyhow::bail!("Feed message has no payload"),
+        };
+        let action: u8 = serde_json::from_str(action.get())?;
+        let name = action_name(action)
+            .ok_or_else(|| anyhow::anyhow!("Unknown feed message action {}", action))?;
+        readable.push(ReadableMessage {
+            action,
+            name,
+            payload,
+        });
+    }
+    Ok(serde_json::to_vec(&readable)?.into())
+}
+
+/// Join the finalized batches of messages given into a single batch, and gzip it. Feeds that
+/// ask for this get everything about the nodes on a chain in one message when they subscribe
+/// to it, rather than in many. `None` is returned if there's nothing to send.
+pub fn gzip_batches<I>(batches: I) -> anyhow::Result<Option<bytes::Bytes>>
+where
+    I: IntoIterator<Item = bytes::Bytes>,
+{
+    use flate2::{write::GzEncoder, Compression};
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    let mut glue = b'[';
+    for batch in batches {
+        // Each batch is a JSON array of messages; we want what's inside of it:
+        let messages = match &batch[..] {
+            [b'[', messages @ .., b']'] => messages,
+            _ => anyhow::bail!("Feed messages should be a JSON array"),
+        };
+        if messages.is_empty() {
+            continue;
+        }
+        encoder.write_all(&[glue])?;
+        encoder.write_all(messages)?;
+        glue = b',';
+    }
+    if glue == b'[' {
+        return Ok(None);
+    }
+    encoder.write_all(b"]")?;
+    Ok(Some(encoder.finish()?.into()))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::node_details;
+
+    #[test]
+    fn batches_can_be_joined_and_gzipped() {
+        use flate2::read::GzDecoder;
+        use std::io::Read;
+
+        let batches = (1..=3).map(|id| {
+            let mut serializer = FeedMessageSerializer::new();
+            serializer.push(StaleNode(id));
+            serializer.push(RemovedNode(id));
+            serializer.into_finalized().unwrap()
+        });
+        let gzipped = gzip_batches(batches).unwrap().unwrap();
+
+        let mut json = String::new();
+        GzDecoder::new(&gzipped[..])
+            .read_to_string(&mut json)
+            .unwrap();
+        assert_eq!(json, "[20,1,4,1,20,2,4,2,20,3,4,3]");
+
+        assert!(gzip_batches(std::iter::empty()).unwrap().is_none());
+        assert!(gzip_batches([bytes::Bytes::from("{}")]).is_err());
+    }
+
+    #[test]
+    fn messages_can_be_downgraded_to_version_32() {
+        let hash = BlockHash::from_low_u64_be(1);
+        let mut serializer = FeedMessageSerializer::new();
+        serializer.push(Version(FEED_VERSION));
+        serializer.push(LocatedNode(1, 1.5, 2.5, "Berlin", Some(3320), Some("DTAG")));
+        serializer.push(ForkDetected(10, &[hash, hash]));
+        serializer.push(BlockPropagationUpdate(10, Some(250), None));
+        serializer.push(NodeAnomaly(1, true));
+        serializer.push(NetworkStats(10, 2));
+        serializer.push(NodeLastSeen(1, 1000));
+        serializer.push(NodeVersionInfo(1, "0.9.18", Some(9180)));
+        serializer.push(ChainBlockTime(hash, 6000));
+        serializer.push(ChainFirstSeen(hash, 1000));
+        serializer.push(FinalizationStall(true, 100));
+        serializer.push(NodeCluster(3.5, 4.5, "Paris", 2, &[2, 3]));
+        serializer.push(NodeBlockImportTime(1, 250));
+        serializer.push(RemovedNode(1));
+        let bytes = serializer.into_finalized().unwrap();
+
+        let downgraded = downgrade(&bytes, 32).unwrap().unwrap();
+        assert_eq!(
+            std::str::from_utf8(&downgraded).unwrap(),
+            r#"[0,32,5,[1,1.5,2.5,"Berlin"],5,[2,3.5,4.5,"Paris"],5,[3,3.5,4.5,"Paris"],4,1]"#
+        );
+
+        // If there's nothing left to send, we don't send anything at all:
+        let mut serializer = FeedMessageSerializer::new();
+        serializer.push(ForkDetected(10, &[hash, hash]));
+        let bytes = serializer.into_finalized().unwrap();
+        assert!(downgrade(&bytes, 32).unwrap().is_none());
+
+        assert!(downgrade(&bytes, 31).is_err());
+    }
+
+    #[test]
+    fn added_nodes_can_be_downgraded_to_version_32() {
+        let mut node = Node::new(common::node_types::NodeDetails {
+            spec_version: Some(100),
+            authority: true,
+            operator: Some("Acme".into()),
+            ..node_details("Alice")
+        });
+        let location = common::node_types::NodeLocation {
+            latitude: 1.5,
+            longitude: 2.5,
+            city: "Berlin".into(),
+            asn: Some(3320),
+            network: Some("DTAG".into()),
+            source: None,
+        };
+        node.update_location(Some(std::sync::Arc::new(location)));
+
+        let mut serializer = FeedMessageSerializer::new();
+        serializer.push(AddedNode(1, &node));
+        let bytes = serializer.into_finalized().unwrap();
+
+        // The block details (which include when the block arrived) are left as they are:
+        let original: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+        let block = &original[1][5];
+
+        // Just as version 32 clients expect, with no authority, runtime, operator or group in
+        // the details, no ASN or network in the location, and no connected or last seen times:
+        let downgraded = downgrade(&bytes, 32).unwrap().unwrap();
+        let downgraded: serde_json::Value = serde_json::from_slice(&downgraded).unwrap();
+        assert_eq!(
+            downgraded,
+            serde_json::json!([
+                3,
+                [
+                    1,
+                    ["Alice", "Bar", "0.1", null, ""],
+                    [0, 0],
+                    [[]],
+                    [[], [], []],
+                    block,
+                    [1.5, 2.5, "Berlin"],
+                    null
+                ]
+            ])
+        );
+    }
+
+    #[test]
+    fn added_nodes_say_whether_they_are_authorities() {
+        use test_utils::feed_message_de::FeedMessage as DecodedFeedMessage;
+
+        let node = Node::new(common::node_types::NodeDetails {
+            authority: true,
+            operator: Some("Acme".into()),
+            ..node_details("Alice")
+        });
+        let mut serializer = FeedMessageSerializer::new();
+        serializer.push(AddedNode(1, &node));
+        serializer.push(NodeAuthorityStatus(1, false));
+        let bytes = serializer.into_finalized().unwrap();
+
+        let decoded = DecodedFeedMessage::from_bytes(&bytes).unwrap();
+        assert!(
+            matches!(&decoded[0], DecodedFeedMessage::AddedNode { node, .. } if node.authority && node.operator.as_deref() == Some("Acme"))
+        );
+        assert_eq!(
+            decoded[1],
+            DecodedFeedMessage::NodeAuthorityStatus {
+                node_id: 1,
+                authority: false
+            }
+        );
+
+        // Older feeds don't get told about any of this:
+        let downgraded = downgrade(&bytes, 32).unwrap().unwrap();
+        let downgraded: Vec<serde_json::Value> = serde_json::from_slice(&downgraded).unwrap();
+        assert_eq!(downgraded.len(), 2);
+        assert_eq!(downgraded[1].as_array().unwrap().len(), 8);
+        assert_eq!(downgraded[1][1].as_array().unwrap().len(), 5);
+    }
+
+    #[test]
+    fn feeds_are_told_about_node_versions() {
+        use test_utils::feed_message_de::FeedMessage as DecodedFeedMessage;
+
+        let mut node = Node::new(common::node_types::NodeDetails {
+            spec_version: Some(100),
+            ..node_details("Alice")
+        });
+        let mut serializer = FeedMessageSerializer::new();
+        serializer.push(AddedNode(1, &node));
+        assert!(!node.set_version("0.1".into(), Some(100)));
+        assert!(node.set_version("0.2".into(), Some(101)));
+        serializer.push(NodeVersionInfo(
+            1,
+            &node.details().version,
+            node.details().spec_version,
+        ));
+        let bytes = serializer.into_finalized().unwrap();
+
+        let decoded = DecodedFeedMessage::from_bytes(&bytes).unwrap();
+        assert!(
+            matches!(&decoded[0], DecodedFeedMessage::AddedNode { node, .. } if node.spec_version == Some(100))
+        );
+        assert_eq!(
+            decoded[1],
+            DecodedFeedMessage::NodeVersionInfo {
+                node_id: 1,
+                version: "0.2".to_string(),
+                spec_version: Some(101),
+            }
+        );
+    }
+
+    #[test]
+    fn node_resource_usage_round_trips() {
+        use test_utils::feed_message_de::FeedMessage as DecodedFeedMessage;
+
+        let usage = NodeResourceUsage {
+            cpu: Some(12.5),
+            memory: Some(2_000_000_000),
+            disk_usage: None,
+        };
+        let mut serializer = FeedMessageSerializer::new();
+        serializer.push(NodeResourceUsageUpdate(3, &usage));
+        let bytes = serializer.into_finalized().unwrap();
+
+        assert_eq!(
+            DecodedFeedMessage::from_bytes(&bytes).unwrap(),
+            vec![DecodedFeedMessage::NodeResourceUsageUpdate { node_id: 3, usage }]
+        );
+    }
+
+    #[test]
+    fn node_block_import_times_round_trip() {
+        use test_utils::feed_message_de::FeedMessage as DecodedFeedMessage;
+
+        let mut serializer = FeedMessageSerializer::new();
+        serializer.push(NodeBlockImportTime(3, 250));
+        let bytes = serializer.into_finalized().unwrap();
+
+        assert_eq!(&bytes[..], b"[36,[3,250]]");
+        assert_eq!(
+            DecodedFeedMessage::from_bytes(&bytes).unwrap(),
+            vec![DecodedFeedMessage::NodeBlockImportTime {
+                node_id: 3,
+                import_time: 250
+            }]
+        );
+    }
+
+    #[test]
+    fn messages_can_be_made_readable() {
+        let mut serializer = FeedMessageSerializer::new();
+        serializer.push(Version(FEED_VERSION));
+        serializer.push(NodeLastSeen(1, 1000));
+        serializer.push(RemovedNode(1));
+        let bytes = serializer.into_finalized().unwrap();
+
+        let readable = to_readable(&bytes).unwrap();
+        assert_eq!(
+            std::str::from_utf8(&readable).unwrap(),
+            concat!(
+                r#"[{"action":0,"name":"Version","payload":33},"#,
+                r#"{"action":30,"name":"NodeLastSeen","payload":[1,1000]},"#,
+                r#"{"action":4,"name":"RemovedNode","payload":1}]"#
+            )
+        );
+
+        assert_eq!(action_name(AddedNode::ACTION), Some("AddedNode"));
+        assert_eq!(action_name(16), None);
+        assert!(to_readable(b"[16,null]").is_err());
+    }
+}
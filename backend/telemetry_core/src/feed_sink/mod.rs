@@ -0,0 +1,190 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use bytes::Bytes;
+use common::node_types::BlockHash;
+use futures::future::BoxFuture;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+
+mod nats;
+
+pub use nats::NatsSink;
+
+/// Somewhere other than the feed websockets to publish feed messages to, so that
+/// other systems can consume them without speaking the feed protocol.
+pub trait FeedSink: Send {
+    /// A short name for this sink, used in logs.
+    fn name(&self) -> &'static str;
+
+    /// Publish a batch of feed messages (a JSON array, exactly as feeds are sent it)
+    /// under the subject given.
+    fn publish<'a>(
+        &'a mut self,
+        subject: &'a str,
+        messages: Bytes,
+    ) -> BoxFuture<'a, anyhow::Result<()>>;
+}
+
+/// Hands feed messages to a [`FeedSink`], which publishes them from its own task. Handing
+/// messages over never waits; if the sink can't keep up and too many are queued, new ones
+/// are dropped until it catches up.
+#[derive(Clone, Debug)]
+pub struct FeedSinkHandle {
+    tx: flume::Sender<(String, Bytes)>,
+    subject: Arc<str>,
+    dropped: Arc<AtomicU64>,
+    dropping: Arc<AtomicBool>,
+}
+
+impl FeedSinkHandle {
+    /// Spawn a task to publish messages to `sink` (this must be called from within a tokio
+    /// runtime). Messages for every feed are published to "<subject>.all", and those for
+    /// feeds subscribed to a chain to "<subject>.chain.<genesis hash>".
+    pub fn spawn<S: FeedSink + 'static>(
+        mut sink: S,
+        subject: String,
+        max_queue_len: usize,
+    ) -> FeedSinkHandle {
+        let (tx, rx) = flume::bounded::<(String, Bytes)>(max_queue_len.max(1));
+
+        // This ends once every handle is dropped:
+        tokio::spawn(async move {
+            let mut failing = false;
+            while let Ok((subject, messages)) = rx.recv_async().await {
+                match sink.publish(&subject, messages).await {
+                    Ok(()) if failing => {
+                        log::info!("Publishing feed messages to {} again", sink.name());
+                        failing = false;
+                    }
+                    Ok(()) => {}
+                    // Only complain once, rather than for every message while it's down:
+                    Err(e) if !failing => {
+                        log::warn!("Failed to publish feed messages to {}: {}", sink.name(), e);
+                        failing = true;
+                    }
+                    Err(_) => {}
+                }
+            }
+        });
+
+        FeedSinkHandle {
+            tx,
+            subject: subject.into(),
+            dropped: Arc::new(AtomicU64::new(0)),
+            dropping: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Publish messages that are being sent to every feed.
+    pub fn publish_to_all(&self, messages: Bytes) {
+        self.publish(format!("{}.all", self.subject), messages);
+    }
+
+    /// Publish messages that are being sent to feeds subscribed to the given chain.
+    pub fn publish_to_chain(&self, genesis_hash: &BlockHash, messages: Bytes) {
+        self.publish(
+            format!("{}.chain.{:?}", self.subject, genesis_hash),
+            messages,
+        );
+    }
+
+    /// How many messages have been dropped because the sink couldn't keep up.
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn publish(&self, subject: String, messages: Bytes) {
+        match self.tx.try_send((subject, messages)) {
+            Ok(()) => {
+                if self.dropping.swap(false, Ordering::Relaxed) {
+                    log::info!("Feed sink has caught up; no longer dropping messages");
+                }
+            }
+            Err(flume::TrySendError::Full(_)) => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                if !self.dropping.swap(true, Ordering::Relaxed) {
+                    log::warn!("Feed sink can't keep up; dropping messages until it does");
+                }
+            }
+            // The sink task has gone away, so there's nothing to be done:
+            Err(flume::TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use futures::FutureExt;
+
+    /// A sink that hands back what it's asked to publish, or never finishes publishing.
+    struct TestSink(Option<flume::Sender<(String, Bytes)>>);
+
+    impl FeedSink for TestSink {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+        fn publish<'a>(
+            &'a mut self,
+            subject: &'a str,
+            messages: Bytes,
+        ) -> BoxFuture<'a, anyhow::Result<()>> {
+            match &self.0 {
+                Some(tx) => {
+                    let _ = tx.send((subject.to_owned(), messages));
+                    futures::future::ready(Ok(())).boxed()
+                }
+                None => futures::future::pending().boxed(),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn messages_are_published_by_subject() {
+        let (tx, rx) = flume::unbounded();
+        let handle = FeedSinkHandle::spawn(TestSink(Some(tx)), "telemetry".to_owned(), 16);
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+
+        handle.publish_to_all(Bytes::from_static(b"[0,33]"));
+        handle.publish_to_chain(&genesis_hash, Bytes::from_static(b"[1,[]]"));
+
+        let (subject, messages) = rx.recv_async().await.unwrap();
+        assert_eq!(subject, "telemetry.all");
+        assert_eq!(messages, "[0,33]");
+        let (subject, messages) = rx.recv_async().await.unwrap();
+        assert_eq!(subject, format!("telemetry.chain.{:?}", genesis_hash));
+        assert_eq!(messages, "[1,[]]");
+    }
+
+    #[tokio::test]
+    async fn messages_are_dropped_if_the_sink_is_stuck() {
+        let handle = FeedSinkHandle::spawn(TestSink(None), "telemetry".to_owned(), 2);
+
+        // One message is taken by the stuck sink, and two more are queued:
+        for _ in 0..3 {
+            handle.publish_to_all(Bytes::from_static(b"[]"));
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        assert_eq!(handle.dropped(), 0);
+
+        // Anything else is dropped rather than waiting:
+        for _ in 0..10 {
+            handle.publish_to_all(Bytes::from_static(b"[]"));
+        }
+        assert_eq!(handle.dropped(), 10);
+    }
+}
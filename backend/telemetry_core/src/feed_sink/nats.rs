@@ -0,0 +1,200 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use super::FeedSink;
+use bytes::Bytes;
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio::sync::Mutex;
+
+/// How long we give ourselves to connect to the server, or to send it a message.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Publishes feed messages to a NATS server. Only the small part of the NATS protocol
+/// needed to publish messages is spoken: we connect, answer the server's pings, and
+/// reconnect the next time there's something to publish if the connection is lost.
+pub struct NatsSink {
+    addr: String,
+    conn: Option<NatsConnection>,
+}
+
+struct NatsConnection {
+    writer: Arc<Mutex<OwnedWriteHalf>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl NatsSink {
+    /// Publish to the NATS server at this "host:port". Nothing happens until there's
+    /// a message to publish.
+    pub fn new(addr: String) -> Self {
+        NatsSink { addr, conn: None }
+    }
+
+    async fn connect(&self) -> anyhow::Result<NatsConnection> {
+        let stream = tokio::time::timeout(TIMEOUT, TcpStream::connect(&self.addr)).await??;
+        let (reader, writer) = stream.into_split();
+        let writer = Arc::new(Mutex::new(writer));
+        let closed = Arc::new(AtomicBool::new(false));
+
+        writer
+            .lock()
+            .await
+            .write_all(
+                b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"telemetry_core\"}\r\n",
+            )
+            .await?;
+
+        // The server sends us its details, errors and pings; we need to answer the pings
+        // or it'll decide that we've gone away:
+        let pong_writer = Arc::clone(&writer);
+        let reader_closed = Arc::clone(&closed);
+        let addr = self.addr.clone();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line.starts_with("PING") {
+                    if pong_writer
+                        .lock()
+                        .await
+                        .write_all(b"PONG\r\n")
+                        .await
+                        .is_err()
+                    {
+                        break;
+                    }
+                } else if let Some(err) = line.strip_prefix("-ERR") {
+                    log::warn!("NATS server at {} sent an error:{}", addr, err);
+                }
+            }
+            reader_closed.store(true, Ordering::Relaxed);
+        });
+
+        Ok(NatsConnection { writer, closed })
+    }
+
+    async fn publish_inner(&mut self, subject: &str, messages: Bytes) -> anyhow::Result<()> {
+        let connected = matches!(&self.conn, Some(conn) if !conn.closed.load(Ordering::Relaxed));
+        if !connected {
+            self.conn = None;
+            self.conn = Some(self.connect().await?);
+        }
+        let conn = self.conn.as_ref().expect("connected above");
+
+        let mut msg = Vec::with_capacity(messages.len() + subject.len() + 32);
+        msg.extend_from_slice(format!("PUB {} {}\r\n", subject, messages.len()).as_bytes());
+        msg.extend_from_slice(&messages);
+        msg.extend_from_slice(b"\r\n");
+
+        let res = tokio::time::timeout(TIMEOUT, async {
+            conn.writer.lock().await.write_all(&msg).await
+        })
+        .await;
+
+        // Start afresh next time if this didn't work:
+        match res {
+            Ok(Ok(())) => Ok(()),
+            Ok(Err(e)) => {
+                self.conn = None;
+                Err(e.into())
+            }
+            Err(e) => {
+                self.conn = None;
+                Err(e.into())
+            }
+        }
+    }
+}
+
+impl FeedSink for NatsSink {
+    fn name(&self) -> &'static str {
+        "NATS"
+    }
+
+    fn publish<'a>(
+        &'a mut self,
+        subject: &'a str,
+        messages: Bytes,
+    ) -> BoxFuture<'a, anyhow::Result<()>> {
+        self.publish_inner(subject, messages).boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn messages_are_published_and_pings_answered() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let mut sink = NatsSink::new(listener.local_addr().unwrap().to_string());
+
+        let server = tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            socket
+                .write_all(b"INFO {\"max_payload\":1048576}\r\nPING\r\n")
+                .await
+                .unwrap();
+
+            // Read until we've been sent the message and the pong:
+            let mut received = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !(String::from_utf8_lossy(&received).contains("[1,2]\r\n")
+                && String::from_utf8_lossy(&received).contains("PONG\r\n"))
+            {
+                let n = socket.read(&mut buf).await.unwrap();
+                assert_ne!(n, 0, "connection closed early");
+                received.extend_from_slice(&buf[..n]);
+            }
+            String::from_utf8(received).unwrap()
+        });
+
+        sink.publish("telemetry.all", Bytes::from_static(b"[1,2]"))
+            .await
+            .unwrap();
+
+        let received = tokio::time::timeout(Duration::from_secs(5), server)
+            .await
+            .expect("server should receive the message")
+            .unwrap();
+        assert!(received.starts_with("CONNECT {"));
+        assert!(received.contains("PUB telemetry.all 5\r\n[1,2]\r\n"));
+        assert!(received.contains("PONG\r\n"));
+    }
+
+    #[tokio::test]
+    async fn publishing_fails_if_the_server_is_unavailable() {
+        // Grab a free port, and then stop listening on it:
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut sink = NatsSink::new(addr.to_string());
+
+        assert!(sink
+            .publish("telemetry.all", Bytes::from_static(b"[]"))
+            .await
+            .is_err());
+    }
+}
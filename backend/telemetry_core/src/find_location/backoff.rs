@@ -0,0 +1,187 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::time::{Duration, Instant};
+
+use parking_lot::Mutex;
+
+/// How long do we leave a provider alone the first time that it rate limits us?
+const INITIAL_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Each time a provider rate limits us again, we leave it alone for twice as
+/// long as before, up to this limit.
+const MAX_BACKOFF: Duration = Duration::from_secs(15 * 60);
+
+/// Providers that we have an API token for have a far bigger quota, so being rate
+/// limited by one is more likely to be a brief burst than the quota running out.
+/// We don't leave these alone for as long.
+const AUTHENTICATED_INITIAL_BACKOFF: Duration = Duration::from_secs(5);
+const AUTHENTICATED_MAX_BACKOFF: Duration = Duration::from_secs(2 * 60);
+
+/// Keeps track of whether a provider has been rate limiting us, so that we can
+/// leave it alone for a while rather than using up our quota any further.
+#[derive(Debug)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    state: Mutex<BackoffState>,
+}
+
+#[derive(Debug, Default)]
+struct BackoffState {
+    /// How long we backed off for last time. Zero if we aren't being rate limited.
+    delay: Duration,
+    /// Don't use the provider again until this time.
+    until: Option<Instant>,
+    /// Has a single request been let through to see whether we're still being rate limited?
+    probing: bool,
+}
+
+/// What are we allowed to ask a provider for at the moment?
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Permit {
+    /// Ask it for as much as we like.
+    Any,
+    /// We're recovering from being rate limited; just ask it for one thing to see how it goes,
+    /// and then report back via [`Backoff::rate_limited`] or [`Backoff::not_rate_limited`].
+    Probe,
+    /// Leave it alone.
+    None,
+}
+
+impl Backoff {
+    pub fn new() -> Self {
+        Backoff::with_limits(INITIAL_BACKOFF, MAX_BACKOFF)
+    }
+
+    /// Back off less for a provider that we're using an API token with.
+    pub fn authenticated() -> Self {
+        Backoff::with_limits(AUTHENTICATED_INITIAL_BACKOFF, AUTHENTICATED_MAX_BACKOFF)
+    }
+
+    fn with_limits(initial: Duration, max: Duration) -> Self {
+        Backoff {
+            initial,
+            max,
+            state: Mutex::new(BackoffState::default()),
+        }
+    }
+
+    /// Can we use the provider right now?
+    pub fn permit(&self, now: Instant) -> Permit {
+        let mut state = self.state.lock();
+        if state.delay.is_zero() {
+            return Permit::Any;
+        }
+        if state.probing || state.until.map(|until| now < until).unwrap_or(false) {
+            return Permit::None;
+        }
+        state.probing = true;
+        Permit::Probe
+    }
+
+    /// The provider has rate limited us; leave it alone for a while. Returns how long
+    /// we'll wait, or `None` if we were already backing off.
+    pub fn rate_limited(&self, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock();
+
+        // Several requests may have been rate limited at once; only back off once for them.
+        if !state.probing && state.until.map(|until| now < until).unwrap_or(false) {
+            return None;
+        }
+
+        state.delay = if state.delay.is_zero() {
+            self.initial
+        } else {
+            std::cmp::min(state.delay * 2, self.max)
+        };
+        state.until = Some(now + state.delay);
+        state.probing = false;
+        Some(state.delay)
+    }
+
+    /// The provider responded without rate limiting us, so we can go back to using it normally.
+    pub fn not_rate_limited(&self) {
+        let mut state = self.state.lock();
+        if !state.delay.is_zero() && !state.probing {
+            // Requests from before we started backing off may still be finishing; they
+            // don't tell us anything about whether we're still being rate limited.
+            return;
+        }
+        *state = BackoffState::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backs_off_exponentially_and_probes() {
+        let backoff = Backoff::new();
+        let now = Instant::now();
+        assert_eq!(backoff.permit(now), Permit::Any);
+
+        assert_eq!(backoff.rate_limited(now), Some(INITIAL_BACKOFF));
+        // Other requests rate limited at the same time don't back off further:
+        assert_eq!(backoff.rate_limited(now), None);
+        assert_eq!(backoff.permit(now), Permit::None);
+
+        // Once the cooldown has elapsed, only one request is let through:
+        let now = now + INITIAL_BACKOFF;
+        assert_eq!(backoff.permit(now), Permit::Probe);
+        assert_eq!(backoff.permit(now), Permit::None);
+
+        // If that's rate limited too, we wait twice as long:
+        assert_eq!(backoff.rate_limited(now), Some(INITIAL_BACKOFF * 2));
+        let now = now + INITIAL_BACKOFF * 2;
+        assert_eq!(backoff.permit(now), Permit::Probe);
+
+        // And if not, we go back to normal:
+        backoff.not_rate_limited();
+        assert_eq!(backoff.permit(now), Permit::Any);
+    }
+
+    #[test]
+    fn backoff_is_capped() {
+        let backoff = Backoff::new();
+        let mut now = Instant::now();
+        let mut delay = Duration::ZERO;
+        for _ in 0..20 {
+            delay = backoff.rate_limited(now).unwrap();
+            now += delay;
+            assert_eq!(backoff.permit(now), Permit::Probe);
+        }
+        assert_eq!(delay, MAX_BACKOFF);
+    }
+
+    #[test]
+    fn authenticated_providers_back_off_less() {
+        let backoff = Backoff::authenticated();
+        let mut now = Instant::now();
+        assert_eq!(
+            backoff.rate_limited(now),
+            Some(AUTHENTICATED_INITIAL_BACKOFF)
+        );
+        let mut delay = Duration::ZERO;
+        for _ in 0..20 {
+            now += AUTHENTICATED_MAX_BACKOFF;
+            assert_eq!(backoff.permit(now), Permit::Probe);
+            delay = backoff.rate_limited(now).unwrap();
+        }
+        assert_eq!(delay, AUTHENTICATED_MAX_BACKOFF);
+    }
+}
@@ -0,0 +1,520 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::ffi::OsString;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use common::node_types::NodeLocation;
+use parking_lot::{Mutex, RwLock};
+use rustc_hash::FxHashMap;
+use serde::{Deserialize, Serialize};
+
+use super::lru::LruMap;
+use super::Location;
+
+/// A location that we've previously looked up. If the location is `None`, we
+/// were told that the IP address couldn't be located.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    location: Option<Arc<NodeLocation>>,
+    /// When we obtained this location, in seconds since the unix epoch. Entries
+    /// without this never expire, and aren't written to disk.
+    #[serde(default)]
+    fetched_at: Option<u64>,
+    /// Where the location came from. Locations are serialized as they are for feeds,
+    /// which leaves this out, so we keep hold of it separately.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source: Option<Box<str>>,
+}
+
+/// A cache of the locations we've found so far, optionally persisted to a JSON
+/// file on disk so that we don't need to look everything up again on restart.
+/// The file is gzipped if its name ends in ".gz", or compressed with zstd if it
+/// ends in ".zst". If the cache is given a capacity,
+/// the least recently used locations are forgotten (in memory and on disk) to make
+/// room for new ones.
+pub struct LocationCache {
+    entries: Mutex<LruMap<IpAddr, CacheEntry>>,
+    /// Locations that never expire or get evicted, and aren't written to disk.
+    permanent: RwLock<FxHashMap<IpAddr, Arc<NodeLocation>>>,
+    /// Entries older than this are ignored, so that they'll be looked up again.
+    ttl: Option<Duration>,
+    /// Like `ttl`, but for IP addresses that we couldn't find a location for. If
+    /// this is zero, we don't remember those at all.
+    not_found_ttl: Duration,
+    /// Where to persist the cache, if anywhere.
+    file: Option<PathBuf>,
+    /// Has the cache changed since we last wrote it to disk?
+    dirty: AtomicBool,
+}
+
+impl LocationCache {
+    /// Create a new cache holding at most `capacity` locations (or any number of them if
+    /// this is `None`), loading any entries that have been persisted to the file given.
+    pub fn new(
+        file: Option<PathBuf>,
+        ttl: Option<Duration>,
+        not_found_ttl: Duration,
+        capacity: Option<usize>,
+    ) -> Self {
+        let mut entries = LruMap::new(capacity);
+
+        if let Some(path) = &file {
+            match load_cache_file(path) {
+                Ok(Some(loaded)) => {
+                    let now = now_secs();
+                    let mut loaded: Vec<_> = loaded
+                        .into_iter()
+                        .filter(|(_, entry)| !is_expired(entry, ttl, not_found_ttl, now))
+                        .collect();
+                    // If there are too many to hold on to, keep the most recently fetched:
+                    loaded.sort_by_key(|(_, entry)| entry.fetched_at);
+                    let skip = loaded.len().saturating_sub(capacity.unwrap_or(usize::MAX));
+                    for (ip, mut entry) in loaded.into_iter().skip(skip) {
+                        if let (Some(location), Some(source)) = (&mut entry.location, &entry.source)
+                        {
+                            Arc::make_mut(location).source = Some(source.clone());
+                        }
+                        entries.insert(ip, entry);
+                    }
+                    log::info!(
+                        "Loaded {} cached locations from {}",
+                        entries.len(),
+                        path.display()
+                    );
+                }
+                Ok(None) => {}
+                Err(e) => {
+                    log::warn!(
+                        "Couldn't load cached locations from {} (starting afresh): {}",
+                        path.display(),
+                        e
+                    );
+                }
+            }
+        }
+
+        LocationCache {
+            entries: Mutex::new(entries),
+            permanent: RwLock::new(FxHashMap::default()),
+            ttl,
+            not_found_ttl,
+            file,
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Return the location for this IP address if we have an entry for it that hasn't
+    /// expired. `Some(None)` means that we know the address couldn't be located.
+    pub fn get(&self, ip: &IpAddr) -> Option<Location> {
+        if let Some(location) = self.permanent.read().get(ip) {
+            return Some(Some(location.clone()));
+        }
+        let mut entries = self.entries.lock();
+        let entry = entries.get(ip)?;
+        if is_expired(entry, self.ttl, self.not_found_ttl, now_secs()) {
+            return None;
+        }
+        Some(entry.location.clone())
+    }
+
+    /// Cache a newly obtained location.
+    pub fn insert(&self, ip: IpAddr, location: Arc<NodeLocation>) {
+        self.insert_entry(ip, Some(location));
+    }
+
+    /// Remember that this IP address couldn't be located, so that we don't keep asking.
+    pub fn insert_not_found(&self, ip: IpAddr) {
+        if !self.not_found_ttl.is_zero() {
+            self.insert_entry(ip, None);
+        }
+    }
+
+    fn insert_entry(&self, ip: IpAddr, location: Location) {
+        let source = location.as_ref().and_then(|loc| loc.source.clone());
+        let entry = CacheEntry {
+            location,
+            fetched_at: Some(now_secs()),
+            source,
+        };
+        self.entries.lock().insert(ip, entry);
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Cache a location that will never expire or be evicted (and won't be persisted).
+    pub fn insert_permanent(&self, ip: IpAddr, location: Arc<NodeLocation>) {
+        self.permanent.write().insert(ip, location);
+    }
+
+    /// How many locations are cached (not counting permanent ones), and how many have
+    /// been evicted to make room for others so far.
+    pub fn usage(&self) -> (usize, u64) {
+        let entries = self.entries.lock();
+        (entries.len(), entries.evictions())
+    }
+
+    /// Periodically write the cache to disk if it has changed. This stops once the
+    /// cache itself has been dropped (at which point it's flushed one last time).
+    pub fn spawn_flush_loop(self: &Arc<Self>, interval: Duration) {
+        if self.file.is_none() {
+            return;
+        }
+
+        let cache = Arc::downgrade(self);
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            interval.tick().await;
+            loop {
+                interval.tick().await;
+                match Weak::upgrade(&cache) {
+                    Some(cache) => cache.flush(),
+                    None => break,
+                }
+            }
+        });
+    }
+
+    /// Write the cache to disk if anything has changed since we last did so.
+    pub fn flush(&self) {
+        let path = match &self.file {
+            Some(path) => path,
+            None => return,
+        };
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return;
+        }
+
+        let entries: FxHashMap<_, _> = self
+            .entries
+            .lock()
+            .iter()
+            .filter(|(_, entry)| entry.fetched_at.is_some())
+            .map(|(ip, entry)| (*ip, entry.clone()))
+            .collect();
+
+        if let Err(e) = save_cache_file(path, &entries) {
+            log::warn!(
+                "Couldn't write cached locations to {}: {}",
+                path.display(),
+                e
+            );
+            // Try again next time:
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+impl Drop for LocationCache {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+fn is_expired(
+    entry: &CacheEntry,
+    ttl: Option<Duration>,
+    not_found_ttl: Duration,
+    now: u64,
+) -> bool {
+    let ttl = match entry.location {
+        Some(_) => ttl,
+        None => Some(not_found_ttl),
+    };
+    match (entry.fetched_at, ttl) {
+        (Some(fetched_at), Some(ttl)) => now.saturating_sub(fetched_at) > ttl.as_secs(),
+        _ => false,
+    }
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+/// The first bytes of any gzipped file.
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// The first bytes of any file compressed with zstd.
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+
+/// Load the cache file, returning `None` if it doesn't exist yet. Compressed files are
+/// decompressed whatever they're called, so that compression can be turned on, off or
+/// changed by renaming the file.
+fn load_cache_file(path: &Path) -> anyhow::Result<Option<FxHashMap<IpAddr, CacheEntry>>> {
+    let bytes = match std::fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if bytes.starts_with(&GZIP_MAGIC) {
+        let decoder = flate2::read::GzDecoder::new(&bytes[..]);
+        return Ok(Some(serde_json::from_reader(std::io::BufReader::new(
+            decoder,
+        ))?));
+    }
+    if bytes.starts_with(&ZSTD_MAGIC) {
+        let decoder = zstd::stream::read::Decoder::new(&bytes[..])?;
+        return Ok(Some(serde_json::from_reader(std::io::BufReader::new(
+            decoder,
+        ))?));
+    }
+    Ok(Some(serde_json::from_slice(&bytes)?))
+}
+
+/// Should the cache be gzipped when it's written to this file?
+fn is_gzip_path(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+/// Should the cache be compressed with zstd when it's written to this file?
+fn is_zstd_path(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "zst")
+}
+
+/// Write the cache to a temporary file first and then move it into place, so that
+/// we never leave a half written cache behind. Each aggregator has its own locator,
+/// so the temporary file name is unique to every write in case they overlap.
+fn save_cache_file(path: &Path, entries: &FxHashMap<IpAddr, CacheEntry>) -> anyhow::Result<()> {
+    static WRITE_ID: AtomicUsize = AtomicUsize::new(0);
+    let write_id = WRITE_ID.fetch_add(1, Ordering::Relaxed);
+
+    let mut tmp_path = OsString::from(path.as_os_str());
+    tmp_path.push(format!(".{}.{}.tmp", std::process::id(), write_id));
+    let tmp_path = PathBuf::from(tmp_path);
+
+    let mut bytes = serde_json::to_vec(entries)?;
+    if is_gzip_path(path) {
+        use flate2::{write::GzEncoder, Compression};
+        use std::io::Write;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&bytes)?;
+        bytes = encoder.finish()?;
+    } else if is_zstd_path(path) {
+        bytes = zstd::encode_all(&bytes[..], zstd::DEFAULT_COMPRESSION_LEVEL)?;
+    }
+
+    std::fs::write(&tmp_path, bytes)?;
+    std::fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn location(city: &str) -> Arc<NodeLocation> {
+        Arc::new(NodeLocation {
+            latitude: 1.0,
+            longitude: 2.0,
+            city: city.into(),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn expired_entries_are_ignored() {
+        let cache = LocationCache::new(None, Some(Duration::from_secs(60)), Duration::ZERO, None);
+        let ip = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+
+        cache.entries.lock().insert(
+            ip,
+            CacheEntry {
+                location: Some(location("Old")),
+                fetched_at: Some(now_secs() - 120),
+                source: None,
+            },
+        );
+        assert!(cache.get(&ip).is_none());
+
+        cache.insert(ip, location("New"));
+        assert_eq!(&*cache.get(&ip).flatten().unwrap().city, "New");
+    }
+
+    #[test]
+    fn not_found_entries_have_their_own_ttl() {
+        let cache = LocationCache::new(None, None, Duration::from_secs(60), None);
+        let ip = IpAddr::V4(Ipv4Addr::new(1, 2, 3, 4));
+
+        cache.insert_not_found(ip);
+        assert!(matches!(cache.get(&ip), Some(None)));
+
+        cache.entries.lock().get_mut(&ip).unwrap().fetched_at = Some(now_secs() - 120);
+        assert!(cache.get(&ip).is_none());
+
+        // We don't remember them at all if the TTL is zero:
+        let cache = LocationCache::new(None, None, Duration::ZERO, None);
+        cache.insert_not_found(ip);
+        assert!(cache.get(&ip).is_none());
+    }
+
+    #[test]
+    fn least_recently_used_locations_are_evicted() {
+        let path = std::env::temp_dir().join(format!(
+            "telemetry_location_cache_lru_{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let ip = |n| IpAddr::V4(Ipv4Addr::new(1, 2, 3, n));
+
+        let cache = LocationCache::new(Some(path.clone()), None, Duration::from_secs(60), Some(2));
+        cache.insert_permanent(Ipv4Addr::LOCALHOST.into(), location("Local"));
+        cache.insert(ip(1), location("One"));
+        cache.insert(ip(2), location("Two"));
+        assert!(cache.get(&ip(1)).is_some());
+        cache.insert(ip(3), location("Three"));
+
+        // "Two" was used least recently; permanent locations don't count towards the capacity:
+        assert!(cache.get(&ip(2)).is_none());
+        assert!(cache.get(&Ipv4Addr::LOCALHOST.into()).is_some());
+        assert_eq!(cache.usage(), (2, 1));
+        drop(cache);
+
+        // If there are more locations on disk than we have room for, the newest are kept:
+        cache_with_fetched_at(&path, &[(ip(1), 300), (ip(2), 100), (ip(3), 200)]);
+        let cache = LocationCache::new(Some(path.clone()), None, Duration::from_secs(60), Some(2));
+        assert!(cache.get(&ip(1)).is_some());
+        assert!(cache.get(&ip(2)).is_none());
+        assert!(cache.get(&ip(3)).is_some());
+        assert_eq!(cache.usage(), (2, 0));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    /// Write a cache file of locations fetched at the times given.
+    fn cache_with_fetched_at(path: &Path, entries: &[(IpAddr, u64)]) {
+        let entries: FxHashMap<_, _> = entries
+            .iter()
+            .map(|&(ip, fetched_at)| {
+                let entry = CacheEntry {
+                    location: Some(location("Somewhere")),
+                    fetched_at: Some(fetched_at),
+                    source: None,
+                };
+                (ip, entry)
+            })
+            .collect();
+        save_cache_file(path, &entries).unwrap();
+    }
+
+    #[test]
+    fn cache_is_persisted_and_reloaded() {
+        let path = std::env::temp_dir().join(format!(
+            "telemetry_location_cache_{}.json",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let cache = LocationCache::new(Some(path.clone()), None, Duration::from_secs(60), None);
+        let foo = NodeLocation {
+            source: Some("ipinfo.io".into()),
+            ..(*location("Foo")).clone()
+        };
+        cache.insert(Ipv4Addr::new(1, 2, 3, 4).into(), Arc::new(foo.clone()));
+        cache.insert_permanent(Ipv4Addr::new(127, 0, 0, 1).into(), location("Local"));
+        cache.insert_not_found(Ipv4Addr::new(5, 6, 7, 8).into());
+        drop(cache);
+
+        let cache = LocationCache::new(Some(path.clone()), None, Duration::from_secs(60), None);
+        assert_eq!(
+            cache.get(&Ipv4Addr::new(1, 2, 3, 4).into()).flatten(),
+            Some(Arc::new(foo))
+        );
+        assert!(cache.get(&Ipv4Addr::new(127, 0, 0, 1).into()).is_none());
+        assert!(matches!(
+            cache.get(&Ipv4Addr::new(5, 6, 7, 8).into()),
+            Some(None)
+        ));
+
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn cache_can_be_gzipped() {
+        let path = std::env::temp_dir().join(format!(
+            "telemetry_location_cache_{}.json.gz",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let cache = LocationCache::new(Some(path.clone()), None, Duration::from_secs(60), None);
+        cache.insert(Ipv4Addr::new(1, 2, 3, 4).into(), location("Foo"));
+        drop(cache);
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(bytes.starts_with(&GZIP_MAGIC));
+
+        let cache = LocationCache::new(Some(path.clone()), None, Duration::from_secs(60), None);
+        assert_eq!(
+            &*cache
+                .get(&Ipv4Addr::new(1, 2, 3, 4).into())
+                .flatten()
+                .unwrap()
+                .city,
+            "Foo"
+        );
+
+        // A gzipped file is still loaded if it's renamed to something else:
+        let renamed = path.with_extension("");
+        std::fs::rename(&path, &renamed).unwrap();
+        let cache = LocationCache::new(Some(renamed.clone()), None, Duration::from_secs(60), None);
+        assert!(cache.get(&Ipv4Addr::new(1, 2, 3, 4).into()).is_some());
+
+        let _ = std::fs::remove_file(&renamed);
+    }
+
+    #[test]
+    fn cache_can_be_compressed_with_zstd() {
+        let path = std::env::temp_dir().join(format!(
+            "telemetry_location_cache_zstd_{}.json.zst",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+
+        let cache = LocationCache::new(Some(path.clone()), None, Duration::from_secs(60), None);
+        cache.insert(Ipv4Addr::new(1, 2, 3, 4).into(), location("Foo"));
+        drop(cache);
+
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(bytes.starts_with(&ZSTD_MAGIC));
+
+        // Renaming the file to end in ".gz" loads it as it is, and gzips it from then on:
+        let renamed = path.with_extension("gz");
+        std::fs::rename(&path, &renamed).unwrap();
+        let cache = LocationCache::new(Some(renamed.clone()), None, Duration::from_secs(60), None);
+        assert_eq!(
+            &*cache
+                .get(&Ipv4Addr::new(1, 2, 3, 4).into())
+                .flatten()
+                .unwrap()
+                .city,
+            "Foo"
+        );
+        cache.insert(Ipv4Addr::new(5, 6, 7, 8).into(), location("Bar"));
+        drop(cache);
+
+        let bytes = std::fs::read(&renamed).unwrap();
+        assert!(bytes.starts_with(&GZIP_MAGIC));
+
+        let _ = std::fs::remove_file(&renamed);
+    }
+}
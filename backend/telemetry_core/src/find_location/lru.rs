@@ -0,0 +1,134 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::BTreeMap;
+use std::hash::Hash;
+
+use rustc_hash::FxHashMap;
+
+/// A map which holds on to at most so many entries. Once it's full, the entry that was
+/// least recently inserted or looked up is evicted to make room for each new one.
+#[derive(Debug, Clone)]
+pub struct LruMap<K, V> {
+    /// Each value, and when it was last used.
+    entries: FxHashMap<K, (V, u64)>,
+    /// The key of each entry, ordered by when it was last used (oldest first).
+    recency: BTreeMap<u64, K>,
+    /// Incremented each time an entry is used, so that we know which was used last.
+    tick: u64,
+    /// The most entries to hold on to. If `None`, there's no limit.
+    capacity: Option<usize>,
+    /// How many entries have been evicted to make room for others.
+    evictions: u64,
+}
+
+impl<K: Hash + Eq + Clone, V> LruMap<K, V> {
+    pub fn new(capacity: Option<usize>) -> Self {
+        LruMap {
+            entries: FxHashMap::default(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            capacity,
+            evictions: 0,
+        }
+    }
+
+    /// Look up a value, marking it as the most recently used.
+    pub fn get(&mut self, key: &K) -> Option<&V> {
+        self.get_mut(key).map(|value| &*value)
+    }
+
+    /// Look up a value to modify, marking it as the most recently used.
+    pub fn get_mut(&mut self, key: &K) -> Option<&mut V> {
+        let (value, last_used) = self.entries.get_mut(key)?;
+        self.tick += 1;
+        let key = self
+            .recency
+            .remove(last_used)
+            .expect("every entry has a tick");
+        self.recency.insert(self.tick, key);
+        *last_used = self.tick;
+        Some(value)
+    }
+
+    /// Insert a value, evicting the least recently used entry if there's no room for it.
+    /// Hands back the entry evicted, if any.
+    pub fn insert(&mut self, key: K, value: V) -> Option<(K, V)> {
+        self.tick += 1;
+        if let Some((_, last_used)) = self.entries.insert(key.clone(), (value, self.tick)) {
+            self.recency.remove(&last_used);
+        }
+        self.recency.insert(self.tick, key);
+
+        if self.entries.len() <= self.capacity.unwrap_or(usize::MAX) {
+            return None;
+        }
+        let (_, oldest) = self.recency.pop_first()?;
+        let (value, _) = self.entries.remove(&oldest)?;
+        self.evictions += 1;
+        Some((oldest, value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// How many entries have been evicted to make room for others so far.
+    pub fn evictions(&self) -> u64 {
+        self.evictions
+    }
+
+    /// Iterate over every entry, without changing when any of them were last used.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(key, (value, _))| (key, value))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let mut map = LruMap::new(Some(2));
+        assert!(map.insert("a", 1).is_none());
+        assert!(map.insert("b", 2).is_none());
+
+        // Looking "a" up means that "b" is now the least recently used:
+        assert_eq!(map.get(&"a"), Some(&1));
+        assert_eq!(map.insert("c", 3), Some(("b", 2)));
+        assert_eq!(map.get(&"b"), None);
+
+        // Replacing an entry doesn't evict anything, but does count as using it:
+        assert!(map.insert("a", 10).is_none());
+        assert_eq!(map.insert("d", 4), Some(("c", 3)));
+
+        assert_eq!(map.len(), 2);
+        assert_eq!(map.evictions(), 2);
+        assert_eq!(map.get(&"a"), Some(&10));
+        assert_eq!(map.get(&"d"), Some(&4));
+    }
+
+    #[test]
+    fn maps_without_a_capacity_are_unbounded() {
+        let mut map = LruMap::new(None);
+        for n in 0..1000 {
+            assert!(map.insert(n, n).is_none());
+        }
+        assert_eq!(map.len(), 1000);
+        assert_eq!(map.evictions(), 0);
+    }
+}
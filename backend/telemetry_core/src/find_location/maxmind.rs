@@ -0,0 +1,174 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use common::node_types::NodeLocation;
+use futures::future::BoxFuture;
+use parking_lot::RwLock;
+
+use super::providers::{GeoProvider, LookupError};
+
+/// How often do we check whether the MaxMind database file has changed on disk?
+const MAXMIND_RELOAD_INTERVAL: Duration = Duration::from_secs(60);
+
+type MaxMindReader = maxminddb::Reader<Vec<u8>>;
+
+/// An offline MaxMind GeoLite2-City database. If the file can't be loaded, lookups
+/// return `None` and we fall back to the online providers.
+#[derive(Clone)]
+pub struct MaxMindDb {
+    path: Arc<PathBuf>,
+    reader: Arc<RwLock<Option<Arc<MaxMindReader>>>>,
+    modified: Arc<RwLock<Option<SystemTime>>>,
+}
+
+impl MaxMindDb {
+    /// Load the database at the path given, logging a warning if this fails.
+    pub fn open(path: PathBuf) -> Self {
+        let db = MaxMindDb {
+            path: Arc::new(path),
+            reader: Arc::new(RwLock::new(None)),
+            modified: Arc::new(RwLock::new(None)),
+        };
+        db.reload();
+        db
+    }
+
+    /// Periodically check whether the database file has been modified, reloading it if so.
+    pub fn spawn_reload_loop(&self) {
+        let db = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(MAXMIND_RELOAD_INTERVAL).await;
+                db.reload_if_changed();
+            }
+        });
+    }
+
+    /// Reload the database if the modification time of the file has changed since we last
+    /// looked at it.
+    fn reload_if_changed(&self) {
+        if file_modified(&self.path) != *self.modified.read() {
+            self.reload();
+        }
+    }
+
+    /// (Re)load the database. If it fails to load, we keep using whatever we had loaded before.
+    fn reload(&self) {
+        *self.modified.write() = file_modified(&self.path);
+
+        match load_maxmind_db(&self.path) {
+            Ok(reader) => {
+                log::info!("Loaded MaxMind database from {}", self.path.display());
+                *self.reader.write() = Some(Arc::new(reader));
+            }
+            Err(e) => {
+                log::warn!(
+                    "Couldn't load MaxMind database from {} (falling back to online providers): {}",
+                    self.path.display(),
+                    e
+                );
+            }
+        }
+    }
+
+    /// Attempt to locate an IP address using the database.
+    pub fn locate(&self, ip: IpAddr) -> Option<NodeLocation> {
+        let reader = self.reader.read().clone()?;
+        let city: maxminddb::geoip2::City = match reader.lookup(ip) {
+            Ok(city) => city,
+            Err(e) => {
+                log::debug!("Couldn't find {} in MaxMind database: {}", ip, e);
+                return None;
+            }
+        };
+
+        let location = city.location?;
+        let name = city
+            .city
+            .and_then(|c| c.names)
+            .or_else(|| city.country.and_then(|c| c.names))
+            .and_then(|names| names.get("en").map(|&name| name.into()))
+            .unwrap_or_default();
+
+        Some(NodeLocation {
+            latitude: location.latitude? as f32,
+            longitude: location.longitude? as f32,
+            city: name,
+            asn: None,
+            network: None,
+            source: None,
+        })
+    }
+}
+
+fn file_modified(path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+fn load_maxmind_db(path: &Path) -> Result<MaxMindReader, anyhow::Error> {
+    let reader = maxminddb::Reader::open_readfile(path)?;
+    if !reader.metadata.database_type.contains("City") {
+        anyhow::bail!(
+            "expected a City database but got '{}'",
+            reader.metadata.database_type
+        );
+    }
+    Ok(reader)
+}
+
+impl GeoProvider for MaxMindDb {
+    fn name(&self) -> &'static str {
+        "maxmind"
+    }
+
+    // Lookups are cheap, so we don't cache the result; that way a
+    // reloaded database is reflected immediately.
+    fn cacheable(&self) -> bool {
+        false
+    }
+
+    // GeoLite2 databases can be built without any IPv6 data.
+    fn supports_ipv6(&self) -> bool {
+        match &*self.reader.read() {
+            Some(reader) => reader.metadata.ip_version == 6,
+            None => false,
+        }
+    }
+
+    fn lookup(&self, ip: IpAddr) -> BoxFuture<'_, Result<NodeLocation, LookupError>> {
+        let location = self.locate(ip).ok_or(LookupError::NotFound);
+        Box::pin(async move { location })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn missing_maxmind_db_falls_back_to_none() {
+        let db = MaxMindDb::open("/this/path/does/not/exist.mmdb".into());
+
+        assert!(db.reader.read().is_none());
+        assert!(db.locate(Ipv4Addr::new(1, 2, 3, 4).into()).is_none());
+    }
+}
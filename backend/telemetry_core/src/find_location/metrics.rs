@@ -0,0 +1,154 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use common::histogram::Histogram;
+use parking_lot::Mutex;
+
+/// The upper bounds (in seconds) of the buckets that we use to record lookup latency.
+const LATENCY_BUCKETS: &[f64] = &[0.001, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Counters describing how well we're doing at locating nodes. These are
+/// shared with whoever wants to report on them.
+#[derive(Debug, Default)]
+pub struct LocatorMetrics {
+    cache_hits: AtomicU64,
+    cache_misses: AtomicU64,
+    cache_size: AtomicU64,
+    cache_evictions: AtomicU64,
+    providers: Vec<(&'static str, ProviderMetrics)>,
+}
+
+/// Counters for a single geolocation provider.
+#[derive(Debug)]
+pub struct ProviderMetrics {
+    successes: AtomicU64,
+    failures: AtomicU64,
+    rate_limited: AtomicU64,
+    latency: Mutex<Histogram>,
+}
+
+impl Default for ProviderMetrics {
+    fn default() -> Self {
+        ProviderMetrics {
+            successes: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            rate_limited: AtomicU64::new(0),
+            latency: Mutex::new(Histogram::new(LATENCY_BUCKETS)),
+        }
+    }
+}
+
+/// A point in time copy of [`LocatorMetrics`].
+#[derive(Debug, Clone, Default)]
+pub struct LocatorMetricsSnapshot {
+    pub cache_hits: u64,
+    pub cache_misses: u64,
+    /// How many locations are cached right now.
+    pub cache_size: u64,
+    /// How many cached locations have been evicted to make room for others.
+    pub cache_evictions: u64,
+    pub providers: Vec<ProviderMetricsSnapshot>,
+}
+
+/// A point in time copy of [`ProviderMetrics`].
+#[derive(Debug, Clone, Default)]
+pub struct ProviderMetricsSnapshot {
+    pub name: &'static str,
+    /// How many addresses has this provider located.
+    pub successes: u64,
+    /// How many addresses has this provider failed to locate.
+    pub failures: u64,
+    /// How many times has this provider told us that we're being rate limited.
+    pub rate_limited: u64,
+    /// How long each request to the provider took, in seconds.
+    pub latency: Histogram,
+}
+
+impl LocatorMetrics {
+    /// Create metrics for a locator which uses the providers named.
+    pub fn new(provider_names: impl IntoIterator<Item = &'static str>) -> Self {
+        LocatorMetrics {
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
+            cache_size: AtomicU64::new(0),
+            cache_evictions: AtomicU64::new(0),
+            providers: provider_names
+                .into_iter()
+                .map(|name| (name, ProviderMetrics::default()))
+                .collect(),
+        }
+    }
+
+    pub fn record_cache_hit(&self) {
+        self.cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_cache_miss(&self) {
+        self.cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Record how many locations are cached, and how many have been evicted so far.
+    pub fn record_cache_usage(&self, size: usize, evictions: u64) {
+        self.cache_size.store(size as u64, Ordering::Relaxed);
+        self.cache_evictions.store(evictions, Ordering::Relaxed);
+    }
+
+    /// Metrics for the provider at the index given (in the order they were named).
+    pub fn provider(&self, idx: usize) -> &ProviderMetrics {
+        &self.providers[idx].1
+    }
+
+    pub fn snapshot(&self) -> LocatorMetricsSnapshot {
+        LocatorMetricsSnapshot {
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+            cache_misses: self.cache_misses.load(Ordering::Relaxed),
+            cache_size: self.cache_size.load(Ordering::Relaxed),
+            cache_evictions: self.cache_evictions.load(Ordering::Relaxed),
+            providers: self
+                .providers
+                .iter()
+                .map(|(name, m)| ProviderMetricsSnapshot {
+                    name,
+                    successes: m.successes.load(Ordering::Relaxed),
+                    failures: m.failures.load(Ordering::Relaxed),
+                    rate_limited: m.rate_limited.load(Ordering::Relaxed),
+                    latency: m.latency.lock().clone(),
+                })
+                .collect(),
+        }
+    }
+}
+
+impl ProviderMetrics {
+    pub fn record_success(&self) {
+        self.successes.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_failures(&self, n: usize) {
+        self.failures.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    pub fn record_rate_limited(&self) {
+        self.rate_limited.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_latency(&self, duration: Duration) {
+        self.latency.lock().observe(duration.as_secs_f64());
+    }
+}
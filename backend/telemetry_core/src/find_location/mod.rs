@@ -0,0 +1,682 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::{Sink, SinkExt};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use common::node_types::NodeLocation;
+use tokio::sync::Semaphore;
+
+mod backoff;
+mod cache;
+mod lru;
+mod maxmind;
+mod metrics;
+mod overrides;
+mod providers;
+
+use backoff::{Backoff, Permit};
+use cache::LocationCache;
+use maxmind::MaxMindDb;
+pub use metrics::{LocatorMetrics, LocatorMetricsSnapshot};
+use overrides::LocationOverrides;
+use providers::{GeoClient, GeoProvider, IpApiCo, IpInfoIo, LookupError};
+pub use providers::{GeoProviderConfig, GeoTimeouts, ProviderName};
+
+/// The returned location is optional; it may be None if not found.
+pub type Location = Option<Arc<NodeLocation>>;
+
+/// Send an ID and IP address here to ask for it to be located.
+pub type LocationRequests<Id> = flume::Sender<(Id, IpAddr)>;
+
+/// How long do we wait for more location requests to arrive before
+/// locating the ones we have as a batch?
+const BATCH_WINDOW: Duration = Duration::from_millis(50);
+
+/// The most location requests that we'll bundle up into a single batch.
+const MAX_BATCH_SIZE: usize = 100;
+
+/// Options to configure how we locate nodes.
+#[derive(Debug, Clone)]
+pub struct LocatorOpts {
+    /// The order in which to try the geolocation providers. If this is empty,
+    /// we use MaxMind (if a database is given) followed by ipapi.co and then ipinfo.io.
+    pub providers: Vec<ProviderName>,
+    /// API tokens and the like for the online providers.
+    pub provider_config: GeoProviderConfig,
+    /// An optional MaxMind GeoLite2-City database to locate nodes with.
+    pub geoip_database: Option<PathBuf>,
+    /// If given, the locations we find are persisted to this file and reloaded on startup.
+    pub cache_file: Option<PathBuf>,
+    /// Cached locations older than this are looked up again. If `None`, they never expire.
+    pub cache_ttl: Option<Duration>,
+    /// How long to remember that an IP address couldn't be located. If zero, we don't.
+    pub not_found_ttl: Duration,
+    /// How often to write the location cache to disk if it's changed.
+    pub cache_flush_interval: Duration,
+    /// The most locations to cache at once; the least recently used are forgotten to make
+    /// room for new ones. If `None`, there's no limit.
+    pub cache_capacity: Option<usize>,
+    /// A JSON file of CIDR ranges and the locations to use for them, which take
+    /// precedence over the cache and every provider.
+    pub overrides_file: Option<PathBuf>,
+    /// How many requests can be made to the online providers at once, between all of them,
+    /// and how many batches of lookups are worked on at once. Any more lookups than this
+    /// wait until there's room for them.
+    pub max_concurrent_lookups: usize,
+}
+
+impl Default for LocatorOpts {
+    fn default() -> Self {
+        LocatorOpts {
+            providers: Vec::new(),
+            provider_config: GeoProviderConfig::default(),
+            geoip_database: None,
+            cache_file: None,
+            cache_ttl: None,
+            not_found_ttl: Duration::from_secs(60 * 60),
+            cache_flush_interval: Duration::from_secs(60),
+            cache_capacity: Some(100_000),
+            overrides_file: None,
+            max_concurrent_lookups: 8,
+        }
+    }
+}
+
+/// This is responsible for taking an IP address and attempting
+/// to find a geographical location from this. Each of the configured
+/// providers is tried in turn until one of them hands back a location.
+///
+/// Along with a channel to send location requests to, this hands back
+/// metrics describing how the lookups are going. Locations are sent to
+/// `response_chan` along with the ID and IP address that they were asked for.
+pub fn find_location<Id, R>(
+    response_chan: R,
+    opts: LocatorOpts,
+) -> anyhow::Result<(LocationRequests<Id>, Arc<LocatorMetrics>)>
+where
+    R: Sink<(Id, IpAddr, Option<Arc<NodeLocation>>)> + Unpin + Send + Clone + 'static,
+    Id: Clone + Send + 'static,
+{
+    let (tx, rx) = flume::unbounded();
+
+    // Locations that we've been told to use for certain IP ranges, no matter what.
+    let overrides = match &opts.overrides_file {
+        Some(path) => LocationOverrides::load(path)?,
+        None => LocationOverrides::default(),
+    };
+
+    // cache entries, loaded from disk if we've been asked to persist them
+    let cache = Arc::new(LocationCache::new(
+        opts.cache_file.clone(),
+        opts.cache_ttl,
+        opts.not_found_ttl,
+        opts.cache_capacity,
+    ));
+    cache.spawn_flush_loop(opts.cache_flush_interval);
+
+    // Default entries for localhost
+    let localhost = Arc::new(NodeLocation {
+        latitude: 52.516_6667,
+        longitude: 13.4,
+        city: "Berlin".into(),
+        asn: None,
+        network: None,
+        source: Some("localhost".into()),
+    });
+    cache.insert_permanent(Ipv4Addr::LOCALHOST.into(), localhost.clone());
+    cache.insert_permanent(Ipv6Addr::LOCALHOST.into(), localhost);
+
+    // Create a locator with our cache. This is used to obtain locations.
+    let locator = Locator::new(cache, build_providers(&opts)?).with_overrides(overrides);
+    let metrics = locator.metrics.clone();
+    locator.record_cache_usage();
+
+    // Work on no more batches at once than we can make requests at once. Acquiring a
+    // permit blocks until a batch finishes, so that meanwhile new requests pile up here
+    // and can be batched together, rather than waiting on requests to the providers.
+    let max_concurrent_batches = opts.max_concurrent_lookups.max(1);
+
+    // Spawn a loop to handle location requests
+    tokio::spawn(async move {
+        let semaphore = Arc::new(Semaphore::new(max_concurrent_batches));
+
+        // Once every sender has gone away, we stop; the cache is flushed to
+        // disk when the last locator holding it is dropped.
+        while let Ok(request) = rx.recv_async().await {
+            // Gather up any other requests that arrive shortly after this one, so that
+            // providers which support it can locate them all in a single request.
+            let mut batch = vec![request];
+            let window = tokio::time::sleep(BATCH_WINDOW);
+            tokio::pin!(window);
+            while batch.len() < MAX_BATCH_SIZE {
+                tokio::select! {
+                    _ = &mut window => break,
+                    request = rx.recv_async() => match request {
+                        Ok(request) => batch.push(request),
+                        Err(_) => break,
+                    },
+                }
+            }
+
+            let permit = semaphore.clone().acquire_owned().await.unwrap();
+            let mut response_chan = response_chan.clone();
+            let locator = locator.clone();
+
+            // Once we have acquired our permit, spawn a task to avoid
+            // blocking this loop so that we can handle concurrent requests.
+            tokio::spawn(async move {
+                let ips: Vec<IpAddr> = batch.iter().map(|(_, ip)| *ip).collect();
+                let locations = locator.locate_batch(ips).await;
+                for (id, ip) in batch {
+                    let location = locations.get(&normalize_ip(ip)).cloned();
+                    let _ = response_chan.send((id, ip, location)).await;
+                }
+
+                // ensure permit is moved into task by dropping it explicitly:
+                drop(permit);
+            });
+        }
+    });
+
+    Ok((tx, metrics))
+}
+
+/// Work out which providers to use, and in which order, based on the options given.
+fn build_providers(opts: &LocatorOpts) -> anyhow::Result<Vec<Box<dyn GeoProvider>>> {
+    let names = if opts.providers.is_empty() {
+        let mut names = Vec::new();
+        if opts.geoip_database.is_some() {
+            names.push(ProviderName::MaxMind);
+        }
+        names.push(ProviderName::IpApi);
+        names.push(ProviderName::IpInfo);
+        names
+    } else {
+        opts.providers.clone()
+    };
+
+    if opts.geoip_database.is_some() && !names.contains(&ProviderName::MaxMind) {
+        log::warn!("A MaxMind database was given but 'maxmind' is not in the list of geolocation providers; it will not be used");
+    }
+
+    let client = GeoClient::new(opts.max_concurrent_lookups);
+    let mut geoip_database = opts.geoip_database.clone();
+    let mut providers: Vec<Box<dyn GeoProvider>> = Vec::new();
+    for name in names {
+        match name {
+            ProviderName::IpApi => providers.push(Box::new(IpApiCo::new(
+                client.with_timeouts(opts.provider_config.ipapi_timeouts),
+                opts.provider_config.ipapi_key.clone(),
+            ))),
+            ProviderName::IpInfo => providers.push(Box::new(IpInfoIo::new(
+                client.with_timeouts(opts.provider_config.ipinfo_timeouts),
+                opts.provider_config.ipinfo_token.clone(),
+            ))),
+            ProviderName::MaxMind => {
+                // Load the offline database, and keep an eye on it so that
+                // updates to the file are picked up without a restart.
+                let path = geoip_database.take().ok_or_else(|| {
+                    anyhow::anyhow!(
+                        "The 'maxmind' geolocation provider requires --geoip-database to be given (and can only be listed once)"
+                    )
+                })?;
+                let maxmind = MaxMindDb::open(path);
+                maxmind.spawn_reload_loop();
+                providers.push(Box::new(maxmind));
+            }
+        }
+    }
+
+    Ok(providers)
+}
+
+/// This struct can be used to make location requests, given
+/// some IP addresses.
+#[derive(Clone)]
+struct Locator {
+    overrides: Arc<LocationOverrides>,
+    cache: Arc<LocationCache>,
+    providers: Arc<Vec<(Box<dyn GeoProvider>, Backoff)>>,
+    metrics: Arc<LocatorMetrics>,
+}
+
+impl Locator {
+    pub fn new(cache: Arc<LocationCache>, providers: Vec<Box<dyn GeoProvider>>) -> Self {
+        let metrics = LocatorMetrics::new(providers.iter().map(|p| p.name()));
+        Locator {
+            overrides: Arc::new(LocationOverrides::default()),
+            cache,
+            providers: Arc::new(
+                providers
+                    .into_iter()
+                    .map(|p| {
+                        let backoff = if p.authenticated() {
+                            Backoff::authenticated()
+                        } else {
+                            Backoff::new()
+                        };
+                        (p, backoff)
+                    })
+                    .collect(),
+            ),
+            metrics: Arc::new(metrics),
+        }
+    }
+
+    /// Use the given locations for certain IP ranges instead of looking them up.
+    pub fn with_overrides(mut self, overrides: LocationOverrides) -> Self {
+        self.overrides = Arc::new(overrides);
+        self
+    }
+
+    /// Locate a batch of IP addresses, handing back whichever locations we manage to find.
+    pub async fn locate_batch(
+        &self,
+        ips: impl IntoIterator<Item = IpAddr>,
+    ) -> FxHashMap<IpAddr, Arc<NodeLocation>> {
+        let mut found = FxHashMap::default();
+        let mut remaining = Vec::new();
+        // Addresses that we failed to locate for reasons that might not
+        // apply next time, so we shouldn't remember that they weren't found.
+        let mut retry_later = FxHashSet::default();
+
+        for ip in ips {
+            let ip = normalize_ip(ip);
+            if found.contains_key(&ip) || remaining.contains(&ip) {
+                continue;
+            }
+
+            // Locations that we've been told to use always win:
+            if let Some(location) = self.overrides.get(ip) {
+                found.insert(ip, location);
+                continue;
+            }
+
+            // Return location quickly if it's cached (or if we know it can't be found):
+            if let Some(location) = self.cache.get(&ip) {
+                self.metrics.record_cache_hit();
+                if let Some(location) = location {
+                    found.insert(ip, location);
+                }
+                continue;
+            }
+
+            // No external provider will know where a private address is, so don't ask.
+            if !is_public(ip) {
+                log::debug!("Not locating non-public IP address {}", ip);
+                continue;
+            }
+
+            self.metrics.record_cache_miss();
+            remaining.push(ip);
+        }
+
+        // Try each provider in turn with whatever is left to locate, logging
+        // any failures along the way.
+        for (idx, (provider, backoff)) in self.providers.iter().enumerate() {
+            if remaining.is_empty() {
+                break;
+            }
+
+            let (mut ips, skipped): (Vec<IpAddr>, Vec<IpAddr>) = remaining
+                .iter()
+                .partition(|ip| !ip.is_ipv6() || provider.supports_ipv6());
+            remaining = skipped;
+            if ips.is_empty() {
+                continue;
+            }
+
+            // Leave providers alone while they're rate limiting us, and only send a
+            // single address to them once the cooldown has elapsed.
+            let held_back = match backoff.permit(Instant::now()) {
+                Permit::Any => Vec::new(),
+                Permit::Probe => ips.split_off(1),
+                Permit::None => std::mem::take(&mut ips),
+            };
+            retry_later.extend(held_back.iter().copied());
+            remaining.extend(held_back);
+            if ips.is_empty() {
+                continue;
+            }
+
+            let provider_metrics = self.metrics.provider(idx);
+            let started = Instant::now();
+            let results = provider.lookup_batch(&ips).await;
+            provider_metrics.record_latency(started.elapsed());
+
+            let results = match results {
+                Ok(results) => results,
+                Err(LookupError::RateLimited) => {
+                    provider_metrics.record_rate_limited();
+                    provider_metrics.record_failures(ips.len());
+                    self.rate_limited(provider.as_ref(), backoff);
+                    retry_later.extend(ips.iter().copied());
+                    remaining.extend(ips);
+                    continue;
+                }
+                Err(e) => {
+                    provider_metrics.record_failures(ips.len());
+                    backoff.not_rate_limited();
+                    if e.is_temporary() {
+                        retry_later.extend(ips.iter().copied());
+                    }
+                    log::warn!(
+                        "Couldn't obtain location information for {} addresses from {}: {}",
+                        ips.len(),
+                        provider.name(),
+                        e
+                    );
+                    remaining.extend(ips);
+                    continue;
+                }
+            };
+
+            let mut was_rate_limited = false;
+            for (ip, location) in results {
+                match location {
+                    Err(LookupError::RateLimited) => {
+                        provider_metrics.record_failures(1);
+                        was_rate_limited = true;
+                        retry_later.insert(ip);
+                        remaining.push(ip);
+                    }
+                    Ok(location) => {
+                        provider_metrics.record_success();
+                        let location = Arc::new(NodeLocation {
+                            source: Some(provider.name().into()),
+                            ..location
+                        });
+                        if provider.cacheable() {
+                            self.cache.insert(ip, location.clone());
+                        }
+                        found.insert(ip, location);
+                    }
+                    Err(e) => {
+                        provider_metrics.record_failures(1);
+                        if e.is_temporary() {
+                            retry_later.insert(ip);
+                        }
+                        log::warn!(
+                            "Couldn't obtain location information for {} from {}: {}",
+                            ip,
+                            provider.name(),
+                            e
+                        );
+                        remaining.push(ip);
+                    }
+                }
+            }
+
+            if was_rate_limited {
+                provider_metrics.record_rate_limited();
+                self.rate_limited(provider.as_ref(), backoff);
+            } else {
+                backoff.not_rate_limited();
+            }
+        }
+
+        // Anything still remaining has failed; we've logged the errors already. If every
+        // provider told us outright that it doesn't know about an address, remember that.
+        for ip in remaining {
+            if !retry_later.contains(&ip) {
+                self.cache.insert_not_found(ip);
+            }
+        }
+
+        self.record_cache_usage();
+        found
+    }
+
+    fn record_cache_usage(&self) {
+        let (size, evictions) = self.cache.usage();
+        self.metrics.record_cache_usage(size, evictions);
+    }
+
+    fn rate_limited(&self, provider: &dyn GeoProvider, backoff: &Backoff) {
+        if let Some(delay) = backoff.rate_limited(Instant::now()) {
+            log::warn!(
+                "Rate limited by {}; not using it again for {}s",
+                provider.name(),
+                delay.as_secs()
+            );
+        }
+    }
+}
+
+/// IPv4 addresses can arrive wrapped up as IPv6 ones; unwrap them so that
+/// they're treated (and cached) the same way regardless.
+fn normalize_ip(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ip_v6) => ip_v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip,
+    }
+}
+
+/// Is this an IP address that could plausibly be located? Private, shared (CGNAT),
+/// link-local and unique local addresses (amongst others) can't be.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [a, b, ..] = ip.octets();
+            let is_shared = a == 100 && b & 0xc0 == 64;
+            !(ip.is_private()
+                || is_shared
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation())
+        }
+        IpAddr::V6(ip) => {
+            let segment = ip.segments()[0];
+            let is_link_local = segment & 0xffc0 == 0xfe80;
+            let is_unique_local = segment & 0xfe00 == 0xfc00;
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                || is_link_local
+                || is_unique_local)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn non_public_addresses_are_detected() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        assert!(is_public(ip("1.2.3.4")));
+        assert!(is_public(ip("2a01:4f8::1")));
+
+        assert!(!is_public(ip("192.168.0.1")));
+        assert!(!is_public(ip("169.254.1.1")));
+        assert!(!is_public(ip("100.64.0.1")));
+        assert!(is_public(ip("100.128.0.1")));
+        assert!(!is_public(ip("::1")));
+        assert!(!is_public(ip("fe80::1")));
+        assert!(!is_public(ip("fd12:3456::1")));
+    }
+
+    #[test]
+    fn default_provider_order() {
+        let names = |opts| {
+            build_providers(&opts)
+                .unwrap()
+                .iter()
+                .map(|p| p.name())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(names(LocatorOpts::default()), vec!["ipapi.co", "ipinfo.io"]);
+        assert_eq!(
+            names(LocatorOpts {
+                providers: vec![ProviderName::IpInfo, ProviderName::IpApi],
+                ..Default::default()
+            }),
+            vec!["ipinfo.io", "ipapi.co"]
+        );
+    }
+
+    /// Locates only the addresses it's given, counting how many batches it's asked about.
+    struct FakeProvider {
+        known: Vec<IpAddr>,
+        batches: Arc<AtomicUsize>,
+    }
+
+    impl GeoProvider for FakeProvider {
+        fn name(&self) -> &'static str {
+            "fake"
+        }
+
+        fn lookup(
+            &self,
+            ip: IpAddr,
+        ) -> futures::future::BoxFuture<'_, Result<NodeLocation, providers::LookupError>> {
+            let location = if self.known.contains(&ip) {
+                Ok(NodeLocation {
+                    latitude: 1.0,
+                    longitude: 2.0,
+                    city: ip.to_string().into(),
+                    ..Default::default()
+                })
+            } else {
+                Err(providers::LookupError::NotFound)
+            };
+            Box::pin(async move { location })
+        }
+
+        fn lookup_batch<'a>(
+            &'a self,
+            ips: &'a [IpAddr],
+        ) -> futures::future::BoxFuture<'a, providers::BatchLookupResult> {
+            self.batches.fetch_add(1, Ordering::Relaxed);
+            Box::pin(async move {
+                let mut results = Vec::new();
+                for &ip in ips {
+                    results.push((ip, self.lookup(ip).await));
+                }
+                Ok(results)
+            })
+        }
+    }
+
+    #[tokio::test]
+    async fn batches_fall_through_providers() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let batches = Arc::new(AtomicUsize::new(0));
+        let fake = |known: &str| FakeProvider {
+            known: vec![ip(known)],
+            batches: batches.clone(),
+        };
+
+        let locator = Locator::new(
+            Arc::new(LocationCache::new(
+                None,
+                None,
+                Duration::from_secs(60),
+                None,
+            )),
+            vec![Box::new(fake("1.1.1.1")), Box::new(fake("2.2.2.2"))],
+        );
+
+        let found = locator
+            .locate_batch(vec![
+                ip("1.1.1.1"),
+                ip("2.2.2.2"),
+                ip("3.3.3.3"),
+                ip("10.0.0.1"),
+            ])
+            .await;
+
+        assert_eq!(found.len(), 2);
+        assert_eq!(&*found[&ip("1.1.1.1")].city, "1.1.1.1");
+        assert_eq!(&*found[&ip("2.2.2.2")].city, "2.2.2.2");
+        assert_eq!(found[&ip("1.1.1.1")].source.as_deref(), Some("fake"));
+
+        // Each provider was asked once; the second was handed whatever
+        // the first couldn't locate, and the private address was never sent.
+        assert_eq!(batches.load(Ordering::Relaxed), 2);
+
+        // Found locations are cached, so we don't need to ask again:
+        let found = locator.locate_batch(vec![ip("2.2.2.2")]).await;
+        assert_eq!(&*found[&ip("2.2.2.2")].city, "2.2.2.2");
+        assert_eq!(batches.load(Ordering::Relaxed), 2);
+
+        let metrics = locator.metrics.snapshot();
+        assert_eq!(metrics.cache_hits, 1);
+        assert_eq!(metrics.cache_misses, 3);
+        assert_eq!(metrics.cache_size, 3);
+        assert_eq!(metrics.providers[0].successes, 1);
+        assert_eq!(metrics.providers[0].failures, 2);
+        assert_eq!(metrics.providers[1].successes, 1);
+        assert_eq!(metrics.providers[1].failures, 1);
+        assert_eq!(metrics.providers[1].latency.count(), 1);
+
+        // Neither provider knew about this, so we don't ask about it again:
+        let found = locator.locate_batch(vec![ip("3.3.3.3")]).await;
+        assert!(found.is_empty());
+        assert_eq!(batches.load(Ordering::Relaxed), 2);
+    }
+
+    #[tokio::test]
+    async fn overrides_take_precedence() {
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let batches = Arc::new(AtomicUsize::new(0));
+        let overrides = LocationOverrides::from_json(
+            br#"[{ "cidr": "1.1.0.0/16", "latitude": 3.0, "longitude": 4.0, "city": "Override" }]"#,
+        )
+        .unwrap();
+
+        let locator = Locator::new(
+            Arc::new(LocationCache::new(
+                None,
+                None,
+                Duration::from_secs(60),
+                None,
+            )),
+            vec![Box::new(FakeProvider {
+                known: vec![ip("1.1.1.1")],
+                batches: batches.clone(),
+            })],
+        )
+        .with_overrides(overrides);
+
+        let found = locator.locate_batch(vec![ip("1.1.1.1")]).await;
+        assert_eq!(&*found[&ip("1.1.1.1")].city, "Override");
+        assert_eq!(found[&ip("1.1.1.1")].source.as_deref(), Some("override"));
+        assert_eq!(batches.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn maxmind_provider_requires_database() {
+        let opts = LocatorOpts {
+            providers: vec![ProviderName::MaxMind],
+            ..Default::default()
+        };
+
+        assert!(build_providers(&opts).is_err());
+    }
+}
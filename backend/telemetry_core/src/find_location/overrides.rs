@@ -0,0 +1,119 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Arc;
+
+use anyhow::Context;
+use common::ip_range::IpRange;
+use common::node_types::NodeLocation;
+use serde::Deserialize;
+
+/// A single entry in the overrides file.
+#[derive(Deserialize)]
+struct OverrideEntry {
+    /// The range of addresses this applies to, eg "203.0.113.0/24".
+    cidr: String,
+    latitude: f32,
+    longitude: f32,
+    city: Box<str>,
+}
+
+/// A fixed set of locations for ranges of IP addresses, which take precedence
+/// over anything that the cache or geolocation providers would tell us.
+#[derive(Default)]
+pub struct LocationOverrides {
+    /// Sorted so that the most specific ranges come first.
+    ranges: Vec<(IpRange, Arc<NodeLocation>)>,
+}
+
+impl LocationOverrides {
+    /// Load overrides from a JSON file containing a list of entries like
+    /// `{ "cidr": "203.0.113.0/24", "latitude": 51.5, "longitude": -0.12, "city": "London" }`.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let bytes = std::fs::read(path)
+            .with_context(|| format!("Couldn't read location overrides from {}", path.display()))?;
+        let overrides = Self::from_json(&bytes)
+            .with_context(|| format!("Couldn't parse location overrides in {}", path.display()))?;
+        log::info!(
+            "Loaded {} location overrides from {}",
+            overrides.ranges.len(),
+            path.display()
+        );
+        Ok(overrides)
+    }
+
+    /// Parse overrides from the same JSON that [`LocationOverrides::load`] expects.
+    pub fn from_json(bytes: &[u8]) -> anyhow::Result<Self> {
+        let entries: Vec<OverrideEntry> = serde_json::from_slice(bytes)?;
+        let mut ranges = entries
+            .into_iter()
+            .map(|entry| {
+                let range = entry
+                    .cidr
+                    .parse()
+                    .with_context(|| format!("Invalid CIDR range '{}'", entry.cidr))?;
+                let location = NodeLocation {
+                    latitude: entry.latitude,
+                    longitude: entry.longitude,
+                    city: entry.city,
+                    source: Some("override".into()),
+                    ..Default::default()
+                };
+                Ok((range, Arc::new(location)))
+            })
+            .collect::<anyhow::Result<Vec<(IpRange, _)>>>()?;
+
+        // A stable sort, so that if the same range is given twice, the first one wins.
+        ranges.sort_by_key(|(range, _)| std::cmp::Reverse(range.prefix_len()));
+        Ok(LocationOverrides { ranges })
+    }
+
+    /// Find the location given for the most specific range containing this IP address.
+    pub fn get(&self, ip: IpAddr) -> Option<Arc<NodeLocation>> {
+        self.ranges
+            .iter()
+            .find(|(range, _)| range.contains(ip))
+            .map(|(_, location)| location.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn longest_prefix_wins() {
+        let overrides = LocationOverrides::from_json(
+            br#"[
+                { "cidr": "10.0.0.0/8", "latitude": 1.0, "longitude": 1.0, "city": "Wide" },
+                { "cidr": "10.1.2.0/24", "latitude": 2.0, "longitude": 2.0, "city": "Narrow" },
+                { "cidr": "10.1.2.3", "latitude": 3.0, "longitude": 3.0, "city": "Exact" },
+                { "cidr": "2001:db8::/32", "latitude": 4.0, "longitude": 4.0, "city": "V6" }
+            ]"#,
+        )
+        .unwrap();
+
+        let city = |ip: &str| overrides.get(ip.parse().unwrap()).map(|l| l.city.clone());
+        assert_eq!(city("10.9.9.9").as_deref(), Some("Wide"));
+        assert_eq!(city("10.1.2.200").as_deref(), Some("Narrow"));
+        assert_eq!(city("10.1.2.3").as_deref(), Some("Exact"));
+        assert_eq!(city("2001:db8::1").as_deref(), Some("V6"));
+        assert_eq!(city("11.0.0.1"), None);
+        assert_eq!(city("2001:db9::1"), None);
+    }
+}
@@ -0,0 +1,732 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use common::node_types::NodeLocation;
+use futures::future::BoxFuture;
+use futures::{FutureExt, StreamExt};
+use rustc_hash::FxHashMap;
+use serde::Deserialize;
+use tokio::sync::Semaphore;
+
+/// When looking up a batch of addresses one at a time, how many lookups
+/// can be in flight at once?
+const INDIVIDUAL_LOOKUP_CONCURRENCY: usize = 4;
+
+/// The result of looking up a batch of addresses. The outer error is for when the
+/// whole batch failed, and the inner ones for any individual addresses that did.
+pub type BatchLookupResult = Result<Vec<(IpAddr, Result<NodeLocation, LookupError>)>, LookupError>;
+
+/// Something went wrong trying to look up a location.
+#[derive(thiserror::Error, Debug)]
+pub enum LookupError {
+    #[error("No location found")]
+    NotFound,
+    #[error("Request failed: {0}")]
+    Request(#[from] reqwest::Error),
+    #[error("Failed to decode '{0}'")]
+    Decode(String),
+    #[error("Rate limited")]
+    RateLimited,
+    #[error("Timed out")]
+    Timeout,
+    #[error("Provider returned an error: {0}")]
+    Provider(String),
+}
+
+impl LookupError {
+    /// Might we have more luck if we try again later? If not, the provider has
+    /// told us outright that it can't locate the address.
+    pub fn is_temporary(&self) -> bool {
+        match self {
+            LookupError::NotFound | LookupError::Provider(_) => false,
+            LookupError::Request(_)
+            | LookupError::Decode(_)
+            | LookupError::RateLimited
+            | LookupError::Timeout => true,
+        }
+    }
+}
+
+/// A source of geographical locations for IP addresses.
+pub trait GeoProvider: Send + Sync {
+    /// A short name for this provider, used in logs.
+    fn name(&self) -> &'static str;
+
+    /// Should the locations handed back from this provider be cached? Providers which
+    /// are cheap to query may prefer not to be.
+    fn cacheable(&self) -> bool {
+        true
+    }
+
+    /// Can this provider locate IPv6 addresses? If not, we won't ask it to.
+    fn supports_ipv6(&self) -> bool {
+        true
+    }
+
+    /// Are we using an API token with this provider? If so, we have a bigger quota
+    /// with it, and don't back off for as long when it rate limits us.
+    fn authenticated(&self) -> bool {
+        false
+    }
+
+    /// Attempt to find the location of the IP address given.
+    fn lookup(&self, ip: IpAddr) -> BoxFuture<'_, Result<NodeLocation, LookupError>>;
+
+    /// Attempt to find the locations of several IP addresses at once. Providers with a
+    /// batch API should override this; by default each address is looked up on its own.
+    fn lookup_batch<'a>(&'a self, ips: &'a [IpAddr]) -> BoxFuture<'a, BatchLookupResult> {
+        lookup_individually(self, ips)
+    }
+}
+
+/// Look up a batch of IP addresses one at a time (a few at once), for providers
+/// without a batch API, or which can't use it right now.
+fn lookup_individually<'a, P: GeoProvider + ?Sized>(
+    provider: &'a P,
+    ips: &'a [IpAddr],
+) -> BoxFuture<'a, BatchLookupResult> {
+    futures::stream::iter(ips)
+        .map(move |&ip| provider.lookup(ip).map(move |res| (ip, res)))
+        .buffer_unordered(INDIVIDUAL_LOOKUP_CONCURRENCY)
+        .collect()
+        .map(Ok)
+        .boxed()
+}
+
+/// The geolocation providers that we know about. These can be given in
+/// order on the command line to say which providers to try first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProviderName {
+    IpApi,
+    IpInfo,
+    MaxMind,
+}
+
+impl FromStr for ProviderName {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ipapi" => Ok(ProviderName::IpApi),
+            "ipinfo" => Ok(ProviderName::IpInfo),
+            "maxmind" => Ok(ProviderName::MaxMind),
+            _ => Err(anyhow::anyhow!(
+                "Geolocation provider '{}' not recognised; expected one of 'ipapi', 'ipinfo' or 'maxmind'",
+                s
+            )),
+        }
+    }
+}
+
+/// How long we give an online provider to answer before giving up on it, so that a provider
+/// which has hung doesn't hold up locating nodes. Lookups that time out are tried with the
+/// next provider instead.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GeoTimeouts {
+    /// How long to wait to connect to the provider.
+    pub connect: Duration,
+    /// How long to wait for the provider to send back a response once we've connected.
+    pub read: Duration,
+}
+
+impl Default for GeoTimeouts {
+    fn default() -> Self {
+        GeoTimeouts {
+            connect: Duration::from_secs(3),
+            read: Duration::from_secs(5),
+        }
+    }
+}
+
+/// Credentials and timeouts for the online geolocation providers. Without credentials, we're
+/// limited to whatever the providers allow anonymous users.
+#[derive(Clone, Default)]
+pub struct GeoProviderConfig {
+    /// An API token for ipinfo.io, which is sent as a bearer token.
+    pub ipinfo_token: Option<String>,
+    /// An API key for ipapi.co, which is sent in the `key` query parameter.
+    pub ipapi_key: Option<String>,
+    /// Timeouts for requests to ipinfo.io.
+    pub ipinfo_timeouts: GeoTimeouts,
+    /// Timeouts for requests to ipapi.co.
+    pub ipapi_timeouts: GeoTimeouts,
+}
+
+// Don't print the tokens themselves anywhere by accident:
+impl std::fmt::Debug for GeoProviderConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let redact = |token: &Option<String>| token.as_ref().map(|_| "<redacted>");
+        f.debug_struct("GeoProviderConfig")
+            .field("ipinfo_token", &redact(&self.ipinfo_token))
+            .field("ipapi_key", &redact(&self.ipapi_key))
+            .field("ipinfo_timeouts", &self.ipinfo_timeouts)
+            .field("ipapi_timeouts", &self.ipapi_timeouts)
+            .finish()
+    }
+}
+
+/// The HTTP client that the online providers share. This limits how many requests can be
+/// in flight at once across all of them; any more than this wait for their turn.
+#[derive(Clone)]
+pub struct GeoClient {
+    client: reqwest::Client,
+    timeouts: GeoTimeouts,
+    permits: Arc<Semaphore>,
+}
+
+impl GeoClient {
+    pub fn new(max_concurrent_requests: usize) -> Self {
+        let timeouts = GeoTimeouts::default();
+        GeoClient {
+            client: build_client(timeouts),
+            timeouts,
+            permits: Arc::new(Semaphore::new(max_concurrent_requests.max(1))),
+        }
+    }
+
+    /// A client which uses the timeouts given, but still shares its limit on how many
+    /// requests can be in flight at once with this one.
+    pub fn with_timeouts(&self, timeouts: GeoTimeouts) -> Self {
+        GeoClient {
+            client: build_client(timeouts),
+            timeouts,
+            permits: Arc::clone(&self.permits),
+        }
+    }
+
+    fn get(&self, url: impl reqwest::IntoUrl) -> reqwest::RequestBuilder {
+        self.client.get(url)
+    }
+
+    fn post(&self, url: impl reqwest::IntoUrl) -> reqwest::RequestBuilder {
+        self.client.post(url)
+    }
+
+    /// Send a request and read the response, once there's room for another request.
+    async fn fetch(&self, req: reqwest::RequestBuilder) -> Result<bytes::Bytes, LookupError> {
+        let _permit = self
+            .permits
+            .acquire()
+            .await
+            .expect("the semaphore is never closed");
+        // Connecting is limited by the client, so this leaves the rest of the time for
+        // the response to arrive:
+        let res = req
+            .timeout(self.timeouts.connect + self.timeouts.read)
+            .send()
+            .await
+            .map_err(timeout_error)?;
+        check_rate_limit(res)?.bytes().await.map_err(timeout_error)
+    }
+}
+
+fn build_client(timeouts: GeoTimeouts) -> reqwest::Client {
+    reqwest::Client::builder()
+        .connect_timeout(timeouts.connect)
+        .build()
+        .expect("the HTTP client should build")
+}
+
+fn timeout_error(e: reqwest::Error) -> LookupError {
+    if e.is_timeout() {
+        LookupError::Timeout
+    } else {
+        LookupError::Request(e)
+    }
+}
+
+/// Locate IP addresses using <https://ipapi.co>.
+pub struct IpApiCo {
+    client: GeoClient,
+    key: Option<String>,
+}
+
+impl IpApiCo {
+    pub fn new(client: GeoClient, key: Option<String>) -> Self {
+        IpApiCo { client, key }
+    }
+
+    fn authorize(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.key {
+            Some(key) => req.query(&[("key", key)]),
+            None => req,
+        }
+    }
+}
+
+impl GeoProvider for IpApiCo {
+    fn name(&self) -> &'static str {
+        "ipapi.co"
+    }
+
+    fn authenticated(&self) -> bool {
+        self.key.is_some()
+    }
+
+    fn lookup(&self, ip: IpAddr) -> BoxFuture<'_, Result<NodeLocation, LookupError>> {
+        Box::pin(async move {
+            let req = self.client.get(format!("https://ipapi.co/{}/json", ip));
+            let res: IpApiCoResponse = query(&self.client, self.authorize(req)).await?;
+            match res {
+                IpApiCoResponse::Located(location) => Ok(location.into_node_location()),
+                IpApiCoResponse::Error { reason } if reason == "RateLimited" => {
+                    Err(LookupError::RateLimited)
+                }
+                IpApiCoResponse::Error { reason } => Err(LookupError::Provider(reason)),
+            }
+        })
+    }
+}
+
+/// Locate IP addresses using <https://ipinfo.io>. Its batch API needs a token, so
+/// without one, addresses are looked up one at a time.
+pub struct IpInfoIo {
+    client: GeoClient,
+    token: Option<String>,
+    base_url: String,
+}
+
+impl IpInfoIo {
+    pub fn new(client: GeoClient, token: Option<String>) -> Self {
+        IpInfoIo {
+            client,
+            token,
+            base_url: "https://ipinfo.io".to_owned(),
+        }
+    }
+
+    /// Send requests somewhere other than ipinfo.io, so that we can see what's sent.
+    #[cfg(test)]
+    fn with_base_url(mut self, base_url: String) -> Self {
+        self.base_url = base_url;
+        self
+    }
+
+    fn authorize(&self, req: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+        match &self.token {
+            Some(token) => req.bearer_auth(token),
+            None => req,
+        }
+    }
+}
+
+impl GeoProvider for IpInfoIo {
+    fn name(&self) -> &'static str {
+        "ipinfo.io"
+    }
+
+    fn authenticated(&self) -> bool {
+        self.token.is_some()
+    }
+
+    fn lookup(&self, ip: IpAddr) -> BoxFuture<'_, Result<NodeLocation, LookupError>> {
+        Box::pin(async move {
+            let req = self.client.get(format!("{}/{}/json", self.base_url, ip));
+            query::<IPApiLocate>(&self.client, self.authorize(req))
+                .await?
+                .into_node_location()
+                .ok_or(LookupError::NotFound)
+        })
+    }
+
+    fn lookup_batch<'a>(&'a self, ips: &'a [IpAddr]) -> BoxFuture<'a, BatchLookupResult> {
+        if self.token.is_none() {
+            return lookup_individually(self, ips);
+        }
+        Box::pin(async move {
+            let req = self
+                .client
+                .post(format!("{}/batch", self.base_url))
+                .json(ips);
+            let res = self.client.fetch(self.authorize(req)).await?;
+
+            // Addresses that ipinfo can't locate come back without a "loc", so we decode
+            // each entry separately rather than failing the whole batch because of them.
+            let mut entries: FxHashMap<IpAddr, serde_json::Value> =
+                serde_json::from_slice(&res).map_err(|_| decode_error(&res))?;
+
+            Ok(ips
+                .iter()
+                .map(|&ip| {
+                    let location = entries
+                        .remove(&ip)
+                        .and_then(|entry| serde_json::from_value::<IPApiLocate>(entry).ok())
+                        .and_then(IPApiLocate::into_node_location)
+                        .ok_or(LookupError::NotFound);
+                    (ip, location)
+                })
+                .collect())
+        })
+    }
+}
+
+async fn query<T>(client: &GeoClient, req: reqwest::RequestBuilder) -> Result<T, LookupError>
+where
+    for<'de> T: Deserialize<'de>,
+{
+    let res = client.fetch(req).await?;
+
+    serde_json::from_slice(&res).map_err(|_| decode_error(&res))
+}
+
+fn check_rate_limit(res: reqwest::Response) -> Result<reqwest::Response, LookupError> {
+    if res.status() == reqwest::StatusCode::TOO_MANY_REQUESTS {
+        Err(LookupError::RateLimited)
+    } else {
+        Ok(res)
+    }
+}
+
+fn decode_error(res: &[u8]) -> LookupError {
+    LookupError::Decode(
+        std::str::from_utf8(res)
+            .unwrap_or("INVALID_UTF8")
+            .to_owned(),
+    )
+}
+
+/// ipapi.co hands back either a location or an error, like
+/// `{"error": true, "reason": "RateLimited"}`.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(untagged)]
+enum IpApiCoResponse {
+    Located(IpApiCoLocate),
+    Error { reason: String },
+}
+
+/// This is the format returned from ipapi.co.
+#[derive(Deserialize, Debug, Clone)]
+struct IpApiCoLocate {
+    latitude: f32,
+    longitude: f32,
+    city: Box<str>,
+    /// Like "AS15169".
+    asn: Option<Box<str>>,
+    /// Like "GOOGLE".
+    org: Option<Box<str>>,
+}
+
+impl IpApiCoLocate {
+    fn into_node_location(self) -> NodeLocation {
+        NodeLocation {
+            latitude: self.latitude,
+            longitude: self.longitude,
+            city: self.city,
+            asn: self.asn.as_deref().and_then(parse_asn),
+            network: self.org,
+            source: None,
+        }
+    }
+}
+
+/// This is the format returned from ipinfo.co, so we do
+/// a little conversion to get it into the shape we want.
+#[derive(Deserialize, Debug, Clone)]
+struct IPApiLocate {
+    city: Box<str>,
+    loc: Box<str>,
+    /// The ASN followed by the network name, like "AS15169 Google LLC".
+    #[serde(default)]
+    org: Option<Box<str>>,
+}
+
+impl IPApiLocate {
+    fn into_node_location(self) -> Option<NodeLocation> {
+        let IPApiLocate { city, loc, org } = self;
+
+        let mut loc = loc.split(',').map(|n| n.parse());
+
+        let latitude = loc.next()?.ok()?;
+        let longitude = loc.next()?.ok()?;
+
+        // Guarantee that the iterator has been exhausted
+        if loc.next().is_some() {
+            return None;
+        }
+
+        let (asn, network) = match org.as_deref().and_then(|org| org.split_once(' ')) {
+            Some((asn, network)) => (parse_asn(asn), Some(network.into())),
+            None => (org.as_deref().and_then(parse_asn), None),
+        };
+
+        Some(NodeLocation {
+            latitude,
+            longitude,
+            city,
+            asn,
+            network,
+            source: None,
+        })
+    }
+}
+
+/// Parse an autonomous system number like "AS15169".
+fn parse_asn(asn: &str) -> Option<u32> {
+    asn.strip_prefix("AS")?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ipapi_locate_to_node_location() {
+        let ipapi = IPApiLocate {
+            loc: "12.5,56.25".into(),
+            city: "Foobar".into(),
+            org: None,
+        };
+
+        let location = ipapi.into_node_location().unwrap();
+
+        assert_eq!(location.latitude, 12.5);
+        assert_eq!(location.longitude, 56.25);
+        assert_eq!(&*location.city, "Foobar");
+        assert_eq!(location.asn, None);
+    }
+
+    #[test]
+    fn ipapi_locate_org_to_network_details() {
+        let ipapi = IPApiLocate {
+            loc: "12.5,56.25".into(),
+            city: "Foobar".into(),
+            org: Some("AS24940 Hetzner Online GmbH".into()),
+        };
+
+        let location = ipapi.into_node_location().unwrap();
+
+        assert_eq!(location.asn, Some(24940));
+        assert_eq!(location.network.as_deref(), Some("Hetzner Online GmbH"));
+    }
+
+    #[test]
+    fn ipapi_locate_to_node_location_too_many() {
+        let ipapi = IPApiLocate {
+            loc: "12.5,56.25,1.0".into(),
+            city: "Foobar".into(),
+            org: None,
+        };
+
+        let location = ipapi.into_node_location();
+
+        assert!(location.is_none());
+    }
+
+    #[test]
+    fn ipapi_co_response_decodes() {
+        let json = r#"{"ip":"1.2.3.4","city":"Berlin","latitude":52.5,"longitude":13.4,"asn":"AS3320","org":"Foo"}"#;
+
+        let location = serde_json::from_str::<IpApiCoLocate>(json)
+            .unwrap()
+            .into_node_location();
+
+        assert_eq!(location.latitude, 52.5);
+        assert_eq!(location.longitude, 13.4);
+        assert_eq!(&*location.city, "Berlin");
+        assert_eq!(location.asn, Some(3320));
+        assert_eq!(location.network.as_deref(), Some("Foo"));
+    }
+
+    #[test]
+    fn ipapi_co_rate_limit_decodes() {
+        let json = r#"{"error":true,"reason":"RateLimited","message":"Visit https://ipapi.co/ratelimited/ for details"}"#;
+
+        let res = serde_json::from_str::<IpApiCoResponse>(json).unwrap();
+
+        assert!(matches!(res, IpApiCoResponse::Error { reason } if reason == "RateLimited"));
+    }
+
+    #[test]
+    fn api_tokens_are_sent_to_providers() {
+        let client = GeoClient::new(1);
+        let url = "https://ipapi.co/1.2.3.4/json";
+
+        let ipapi = IpApiCo::new(client.clone(), Some("secret".to_owned()));
+        let req = ipapi.authorize(client.get(url)).build().unwrap();
+        assert_eq!(
+            req.url().as_str(),
+            "https://ipapi.co/1.2.3.4/json?key=secret"
+        );
+        assert!(ipapi.authenticated());
+
+        let ipinfo = IpInfoIo::new(client.clone(), Some("secret".to_owned()));
+        let req = ipinfo
+            .authorize(client.get("https://ipinfo.io"))
+            .build()
+            .unwrap();
+        assert_eq!(req.headers()["authorization"], "Bearer secret");
+        assert!(ipinfo.authenticated());
+
+        // Nothing is sent without a token:
+        let ipapi = IpApiCo::new(client.clone(), None);
+        let req = ipapi.authorize(client.get(url)).build().unwrap();
+        assert_eq!(req.url().as_str(), url);
+        let ipinfo = IpInfoIo::new(client.clone(), None);
+        let req = ipinfo
+            .authorize(client.get("https://ipinfo.io"))
+            .build()
+            .unwrap();
+        assert!(req.headers().get("authorization").is_none());
+        assert!(!ipinfo.authenticated());
+    }
+
+    /// A server which answers every request with the body given, and hands back
+    /// the request line (like "GET /1.2.3.4/json HTTP/1.1") of each request.
+    async fn recording_server(body: &'static str) -> (String, flume::Receiver<String>) {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (tx, rx) = flume::unbounded();
+        tokio::spawn(async move {
+            loop {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let tx = tx.clone();
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    let n = socket.read(&mut buf).await.unwrap();
+                    let req = String::from_utf8_lossy(&buf[..n]);
+                    let _ = tx.send(req.lines().next().unwrap_or_default().to_owned());
+                    let res = format!(
+                        "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    );
+                    let _ = socket.write_all(res.as_bytes()).await;
+                });
+            }
+        });
+        (url, rx)
+    }
+
+    #[tokio::test]
+    async fn ipinfo_batches_are_only_used_with_a_token() {
+        let ips: Vec<IpAddr> = vec!["1.2.3.4".parse().unwrap(), "5.6.7.8".parse().unwrap()];
+
+        // Without a token, each address is looked up on its own:
+        let (url, requests) = recording_server(r#"{"city":"Berlin","loc":"52.5,13.4"}"#).await;
+        let ipinfo = IpInfoIo::new(GeoClient::new(4), None).with_base_url(url);
+        let results = ipinfo.lookup_batch(&ips).await.unwrap();
+        assert!(results.iter().all(|(_, res)| res.is_ok()));
+        let mut sent: Vec<String> = requests.drain().collect();
+        sent.sort();
+        assert_eq!(
+            sent,
+            vec!["GET /1.2.3.4/json HTTP/1.1", "GET /5.6.7.8/json HTTP/1.1"]
+        );
+
+        // With one, they're all sent in a single batch:
+        let (url, requests) = recording_server(
+            r#"{"1.2.3.4":{"city":"Berlin","loc":"52.5,13.4"},"5.6.7.8":{"bogon":true}}"#,
+        )
+        .await;
+        let ipinfo = IpInfoIo::new(GeoClient::new(4), Some("secret".to_owned())).with_base_url(url);
+        let results = ipinfo.lookup_batch(&ips).await.unwrap();
+        assert!(results[0].1.is_ok());
+        assert!(matches!(results[1].1, Err(LookupError::NotFound)));
+        let sent: Vec<String> = requests.drain().collect();
+        assert_eq!(sent, vec!["POST /batch HTTP/1.1"]);
+    }
+
+    #[test]
+    fn provider_names_parse() {
+        assert_eq!(
+            "ipapi".parse::<ProviderName>().unwrap(),
+            ProviderName::IpApi
+        );
+        assert_eq!(
+            "ipinfo".parse::<ProviderName>().unwrap(),
+            ProviderName::IpInfo
+        );
+        assert_eq!(
+            "maxmind".parse::<ProviderName>().unwrap(),
+            ProviderName::MaxMind
+        );
+        assert!("geoip".parse::<ProviderName>().is_err());
+    }
+
+    #[tokio::test]
+    async fn requests_beyond_the_limit_wait_their_turn() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        // A server which takes a little while to answer each request, and keeps
+        // track of the most requests that it's been handling at once:
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most_in_flight = Arc::new(AtomicUsize::new(0));
+        {
+            let in_flight = Arc::clone(&in_flight);
+            let most_in_flight = Arc::clone(&most_in_flight);
+            tokio::spawn(async move {
+                loop {
+                    let (mut socket, _) = listener.accept().await.unwrap();
+                    let in_flight = Arc::clone(&in_flight);
+                    let most_in_flight = Arc::clone(&most_in_flight);
+                    tokio::spawn(async move {
+                        let mut buf = [0; 1024];
+                        let _ = socket.read(&mut buf).await;
+                        let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                        most_in_flight.fetch_max(now, Ordering::SeqCst);
+                        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+                        in_flight.fetch_sub(1, Ordering::SeqCst);
+                        let _ = socket
+                            .write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\nConnection: close\r\n\r\n{}")
+                            .await;
+                    });
+                }
+            });
+        }
+
+        let client = GeoClient::new(2);
+        let results =
+            futures::future::join_all((0..8).map(|_| client.fetch(client.get(&url)))).await;
+
+        // Every request was made in the end, but never more than 2 at once:
+        assert!(results
+            .iter()
+            .all(|res| matches!(res, Ok(body) if &body[..] == b"{}")));
+        assert_eq!(most_in_flight.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn requests_to_unresponsive_providers_time_out() {
+        // A server which accepts connections but never answers:
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let client = GeoClient::new(1).with_timeouts(GeoTimeouts {
+            connect: Duration::from_millis(100),
+            read: Duration::from_millis(100),
+        });
+        let res = tokio::time::timeout(Duration::from_secs(5), client.fetch(client.get(&url)))
+            .await
+            .expect("the request should time out by itself");
+        assert!(matches!(res, Err(LookupError::Timeout)));
+        assert!(res.unwrap_err().is_temporary());
+    }
+}
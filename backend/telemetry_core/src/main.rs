@@ -14,24 +14,52 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+mod accept_rate_limiter;
 mod aggregator;
+mod chain_lists;
+mod connection_limiter;
+mod feed_flush;
 mod feed_message;
+mod feed_sink;
 mod find_location;
+#[cfg(any(test, feature = "recording"))]
+mod recording;
+mod replica;
+mod shard_message;
 mod state;
+#[cfg(test)]
+mod test_helpers;
 use std::str::FromStr;
+use std::sync::Arc;
 use tokio::time::{Duration, Instant};
 
+use accept_rate_limiter::AcceptRateLimiter;
 use aggregator::{
     AggregatorOpts, AggregatorSet, FromFeedWebsocket, FromShardWebsocket, ToFeedWebsocket,
     ToShardWebsocket,
 };
 use bincode::Options;
-use common::http_utils;
+use common::byte_size::ByteSize;
+use common::http_utils::{self, BasePath};
 use common::internal_messages;
+use common::logging::{self, JsonLogger, LogFormat};
+use common::node_types::BlockHash;
 use common::ready_chunks_all::ReadyChunksAll;
+use common::real_ip;
+use common::secret_token::SecretToken;
+use common::tls;
+use connection_limiter::ConnectionLimiter;
+use feed_flush::{FeedFlushMetrics, FeedFlushOpts, FlushInterval};
+use feed_sink::{FeedSinkHandle, NatsSink};
+use find_location::{GeoProviderConfig, GeoTimeouts, LocatorOpts, ProviderName};
 use futures::{SinkExt, StreamExt};
 use hyper::{Method, Response};
+use shard_message::{ShardMessageError, ShardMessageParser};
 use simple_logger::SimpleLogger;
+use state::{
+    FinalizationStallOpts, ForkDetectionOpts, NetworkIdMapping, NetworkIdPrefix, NodeClusterOpts,
+    NodeDedupKey, NodeGrouper, PersistedState, StateOpts,
+};
 use structopt::StructOpt;
 
 #[cfg(not(target_env = "msvc"))]
@@ -47,6 +75,10 @@ const NAME: &str = "Substrate Telemetry Backend Core";
 const ABOUT: &str = "This is the Telemetry Backend Core that receives telemetry messages \
                      from Substrate/Polkadot nodes and provides the data to a subsribed feed";
 
+/// Feeds give us their token by offering to speak a websocket subprotocol
+/// made up of this followed by the token.
+const FEED_TOKEN_PROTOCOL: &str = "feed-token.";
+
 #[derive(StructOpt, Debug)]
 #[structopt(name = NAME, version = VERSION, author = AUTHORS, about = ABOUT)]
 struct Opts {
@@ -55,18 +87,130 @@ struct Opts {
     /// you are using Telemetry in a container, you likely want to set this to '0.0.0.0:8000'
     #[structopt(short = "l", long = "listen", default_value = "127.0.0.1:8000")]
     socket: std::net::SocketAddr,
+    /// A path to serve every endpoint under (eg "/telemetry"), including the feed and shard websockets, metrics and admin endpoints. This
+    /// is for running behind a reverse proxy which doesn't strip the path from requests.
+    /// Health checks are answered at the root as well.
+    #[structopt(long, default_value = "/")]
+    base_path: BasePath,
+    /// A PEM encoded certificate chain to serve over TLS with. If this and '--tls-key' are
+    /// given, feeds and shards must connect to us using TLS ('wss://'), which saves putting
+    /// a proxy in front of us just to add it. Otherwise, we accept plain connections.
+    #[structopt(long, requires = "tls-key")]
+    tls_cert: Option<std::path::PathBuf>,
+    /// The PEM encoded private key for the certificate given in '--tls-cert'.
+    #[structopt(long, requires = "tls-cert")]
+    tls_key: Option<std::path::PathBuf>,
     /// The desired log level; one of 'error', 'warn', 'info', 'debug' or 'trace', where
     /// 'error' only logs errors and 'trace' logs everything.
     #[structopt(long = "log", default_value = "info")]
     log_level: log::LevelFilter,
+    /// How to write out logs; either 'text' for human readable lines, or 'json' to write
+    /// one JSON object per line, including extra fields like the IP address of the
+    /// connection being logged about where we know them.
+    #[structopt(long, default_value = "text")]
+    log_format: LogFormat,
     /// Space delimited list of the names of chains that are not allowed to connect to
     /// telemetry. Case sensitive.
     #[structopt(long, required = false)]
     denylist: Vec<String>,
+    /// Files listing more chains that are not allowed to connect, one per line (lines
+    /// starting with '#' are ignored). Any number of files can be given, and their entries
+    /// are merged. On unix, these are read again when we receive a SIGHUP, and nodes
+    /// connected to any newly denied chains are removed.
+    #[structopt(long, required = false)]
+    denylist_file: Vec<std::path::PathBuf>,
+    /// Space delimited list of the genesis hashes of the only chains that are allowed to
+    /// connect to telemetry. This can't be used alongside --denylist or --denylist-file.
+    #[structopt(long, required = false)]
+    allowlist: Vec<BlockHash>,
+    /// Files listing more genesis hashes of chains that are allowed to connect, in the same
+    /// format as --denylist-file. These are merged with any given in --allowlist.
+    #[structopt(long, required = false)]
+    allowlist_file: Vec<std::path::PathBuf>,
     /// If it takes longer than this number of seconds to send the current batch of messages
     /// to a feed, the feed connection will be closed.
     #[structopt(long, default_value = "10")]
     feed_timeout: u64,
+    /// How often, in seconds, to ping feeds to check that they're still there. If "0" is
+    /// given, feeds aren't pinged.
+    #[structopt(long, default_value = "30")]
+    feed_ping_interval: u64,
+    /// If a feed doesn't respond to a ping within this number of seconds, the feed connection
+    /// will be closed.
+    #[structopt(long, default_value = "30")]
+    feed_ping_timeout: u64,
+    /// How long, in milliseconds, to wait between sending batches of messages to each feed.
+    /// This is at least 1ms; 0 is treated as 1.
+    #[structopt(long, default_value = "75")]
+    feed_flush_interval_ms: u64,
+    /// When lots of messages are being sent to a feed, wait for longer between batches (up
+    /// to this many milliseconds) so that more are sent together. If this is no longer than
+    /// --feed-flush-interval-ms, we always wait for that long.
+    #[structopt(long, default_value = "0")]
+    feed_flush_max_interval_ms: u64,
+    /// Wait for up to this many milliseconds longer between batches, chosen at random, so
+    /// that feeds connected to different cores aren't all sent messages at the same moment.
+    #[structopt(long, default_value = "0")]
+    feed_flush_jitter_ms: u64,
+    /// The most feed connections that can be open from a single IP address at once. By
+    /// default, there is no limit.
+    #[structopt(long)]
+    max_feeds_per_ip: Option<usize>,
+    /// Work out the IP address of feed connections from the "Forwarded", "X-Forwarded-For"
+    /// or "X-Real-IP" headers. Only use this if the core is behind a proxy which sets them;
+    /// otherwise, clients can pretend to be whoever they like.
+    #[structopt(long)]
+    trust_proxy_headers: bool,
+    /// A secret that shards must provide (using their '--core-token' option) in order to
+    /// connect to the /shard_submit endpoint. By default, any shard can connect.
+    #[structopt(long)]
+    shard_token: Option<SecretToken>,
+    /// Also accept shards on another address, which only serves /shard_submit (and health
+    /// checks). This can be given more than once. Each is given as "<address>", followed by
+    /// any of ",token=<token>" (the shard token for this listener, rather than '--shard-token')
+    /// and ",tls-cert=<path>,tls-key=<path>" (to serve it over TLS, rather than using
+    /// '--tls-cert' and '--tls-key'). For example, shards on a private network could connect
+    /// to "10.0.0.1:8001" without TLS, while others connect to
+    /// "0.0.0.0:8443,token=secret,tls-cert=cert.pem,tls-key=key.pem".
+    #[structopt(long)]
+    shard_listener: Vec<ShardListener>,
+    /// Record every message that shards send us to this file, one JSON object per line, so
+    /// that what happened can be replayed against a fresh state in tests later. Anything
+    /// already in the file is replaced.
+    #[cfg(feature = "recording")]
+    #[structopt(long)]
+    record_shard_messages: Option<std::path::PathBuf>,
+    /// A secret that feeds must provide in order to connect to the /feed endpoint. Browsers
+    /// can't set headers on websocket connections, so it's given by offering to speak the
+    /// websocket subprotocol "feed-token.<token>". By default, any feed can connect.
+    #[structopt(long, env = "TELEMETRY_FEED_TOKEN", hide_env_values = true)]
+    feed_token: Option<SecretToken>,
+    /// A secret that must be given (as an "Authorization: Bearer <token>" header) to use the
+    /// /admin endpoints, which let operators see the chain lists and quotas in use and which
+    /// shard each node is connected through, export the state for offline analysis, disconnect
+    /// nodes and shards, and pause updates to the feeds of a chain. The endpoints are only
+    /// available if this is set.
+    #[structopt(long, env = "TELEMETRY_ADMIN_TOKEN", hide_env_values = true)]
+    admin_token: Option<SecretToken>,
+    /// The largest message that a shard can send us. Shards sending anything bigger are
+    /// disconnected rather than having it buffered. Messages from shards each carry a single
+    /// node message, which is rarely more than a few KB even for the details a node sends
+    /// when it first connects.
+    #[structopt(long, default_value = "1MiB")]
+    max_shard_message_size: ByteSize,
+    /// The most nodes that any one shard can add per second. A shard that reconnects tells us
+    /// about all of its nodes at once; with this set, any beyond the limit are queued up and
+    /// added gradually, rather than all at once. By default, there's no limit.
+    #[structopt(long)]
+    max_shard_node_adds_per_second: Option<u32>,
+    /// The most shard connections to accept per second. When the core restarts, every shard
+    /// reconnects at once; with this set, any beyond the limit are turned away with a
+    /// "503 Service Unavailable" response whose "Retry-After" header gives each its own time
+    /// to come back, spread out at this rate and jittered so that they don't all return
+    /// together. Shards back off (with jitter of their own) whenever connecting fails, and
+    /// so will try again after a similar time. By default, there's no limit.
+    #[structopt(long)]
+    max_shard_connections_per_second: Option<u32>,
     /// Number of worker threads to spawn. If "0" is given, use the number of CPUs available
     /// on the machine. If no value is given, use an internal default that we have deemed sane.
     #[structopt(long)]
@@ -75,22 +219,232 @@ struct Opts {
     /// aggregators.
     #[structopt(long)]
     num_aggregators: Option<usize>,
+    /// If non-zero, each aggregator splits its feeds between this many tasks, which send
+    /// updates out to them in parallel. Node state is still only updated by the aggregator
+    /// itself. By default, each aggregator sends updates to all of its feeds itself.
+    #[structopt(long, default_value = "0")]
+    feed_workers: usize,
     /// How big can the message queue for each aggregator grow before we start dropping non-essential
-    /// messages in an attempt to let it reduce?
+    /// messages in an attempt to let it reduce? Defaults to 10000. This isn't a hard limit:
+    /// shards and feeds never wait for room in the queue, and messages that can't be dropped,
+    /// like nodes being added or removed, are still queued past it.
     #[structopt(long)]
     aggregator_queue_len: Option<usize>,
+    /// If more than this many messages are waiting to be sent to a feed, it's disconnected
+    /// rather than letting the queue keep growing. By default, there is no limit. A feed can
+    /// fall behind by this many batches of updates, and the batch of nodes sent when it
+    /// subscribes to a chain counts as one however big the chain is.
+    #[structopt(long)]
+    max_feed_queue_len: Option<usize>,
+    /// The "host:port" of a NATS server to publish feed messages to, so that other systems
+    /// can consume them without connecting as a feed. Messages for every feed are published
+    /// (as the same JSON that feeds are sent) to "<subject>.all", and those for feeds of a
+    /// chain to "<subject>.chain.<genesis hash>", where the subject is --feed-sink-subject.
+    #[structopt(long)]
+    feed_sink_nats: Option<String>,
+    /// The subject that feed messages are published under, if --feed-sink-nats is given.
+    #[structopt(long, default_value = "telemetry.feed")]
+    feed_sink_subject: String,
+    /// How many feed messages can be waiting to be published before any more are dropped.
+    /// Publishing never holds up sending messages to feeds.
+    #[structopt(long, default_value = "10000")]
+    feed_sink_queue_len: usize,
     /// How many nodes from third party chains are allowed to connect before we prevent connections from them.
     #[structopt(long, default_value = "1000")]
     max_third_party_nodes: usize,
+    /// Node names and operator labels are truncated to this many characters. Control and zero
+    /// width characters are always removed from them.
+    #[structopt(long, default_value = "64")]
+    max_node_name_length: usize,
+    /// Space delimited list of "<genesis hash>=<max nodes>" pairs, setting how many nodes can
+    /// connect to specific chains. These override --max-third-party-nodes (and the lack of any
+    /// limit on first party chains).
+    #[structopt(long, required = false)]
+    chain_quota: Vec<ChainQuota>,
+    /// If non-zero, nodes are turned away once they'd need a new chain to be added and
+    /// this many chains already exist. First party chains and those given a --chain-quota
+    /// can always be added. This bounds how much memory unique genesis hashes can take up.
+    #[structopt(long, default_value = "0")]
+    max_chains: usize,
+    /// Space delimited list of the genesis hashes of chains that should stay in the list of
+    /// chains (with a node count of 0) when all of their nodes disconnect, rather than being
+    /// removed. A pinned chain appears once its first node connects.
+    #[structopt(long, required = false)]
+    pinned_chains: Vec<BlockHash>,
+    /// Chains with fewer nodes than this aren't shown to feeds, which keeps one-off and test
+    /// nodes from cluttering the list of chains. Feeds are told about a chain as soon as it
+    /// has this many nodes, and that it's gone if it drops below this again. Pinned chains are
+    /// always shown.
+    #[structopt(long, default_value = "0")]
+    min_chain_nodes: usize,
+    /// If non-zero, chains that have had no nodes for this many seconds are removed, even if
+    /// they're pinned. This also cleans up chains left empty because the nodes they were created
+    /// for were over quota, which could otherwise build up over a long uptime.
+    #[structopt(long, default_value = "0")]
+    empty_chain_ttl: u64,
+    /// Any number of "<genesis hash>=<label>" pairs, giving the label that specific chains are
+    /// shown with. Otherwise, chains are shown with the label that most of their nodes report.
+    #[structopt(long, required = false)]
+    chain_label: Vec<ChainLabel>,
+    /// How to tell that a newly connected node is one that we already know about (because it's
+    /// reconnected before we noticed its old connection drop), so that it replaces the old node
+    /// rather than being shown twice. One of "none", "network-id" or "name".
+    #[structopt(long, default_value = "none")]
+    dedup_nodes_by: NodeDedupKey,
+    /// How many blocks behind the best block of a chain we look out for forks at, reporting
+    /// them to feeds when nodes import different blocks at the same height. "0" turns fork
+    /// detection off.
+    #[structopt(long, default_value = "16")]
+    fork_detection_window: u64,
+    /// How many nodes need to have imported each of the competing blocks at some height
+    /// before we report it as a fork.
+    #[structopt(long, default_value = "2")]
+    fork_detection_min_nodes: usize,
+    /// If the median finalized block of the nodes on a chain falls more than this many blocks
+    /// behind their median best block for --finalization-stall-secs, feeds are told that
+    /// finalization has stalled on it. "0" turns this check off.
+    #[structopt(long, default_value = "0")]
+    finalization_stall_gap: u64,
+    /// How many seconds finalization needs to be too far behind before we say it's stalled.
+    #[structopt(long, default_value = "60")]
+    finalization_stall_secs: u64,
+    /// If a node's best block is more than this many blocks ahead of the median of the other
+    /// nodes on its chain, it's flagged as anomalous and isn't allowed to become the best
+    /// block of the chain. "0" turns this check off.
+    #[structopt(long, default_value = "0")]
+    max_block_height_deviation: u64,
+    /// If non-zero, the best block shown for each chain is the highest block that at least this
+    /// fraction of its nodes have finalized (eg "0.67" for two thirds), rather than the highest
+    /// block that any node has imported. This jumps around less on chains that fork a lot.
+    #[structopt(long, default_value = "0")]
+    best_block_finalized_quorum: f64,
+    /// How many nodes are described in each message sent to a feed when it subscribes to a
+    /// chain. Smaller messages let the UI show nodes sooner; larger ones mean fewer messages.
+    #[structopt(long, default_value = "64")]
+    feed_chunk_size: usize,
+    /// If non-zero, nodes located within this many km of each other at around the same time
+    /// are described to feeds as a single cluster (once there are --node-cluster-min-nodes of
+    /// them), rather than one at a time. This keeps map updates small when lots of nodes in
+    /// the same datacenter connect at once.
+    #[structopt(long, default_value = "0")]
+    node_cluster_radius: f64,
+    /// How many nodes need to be close together before they're described as a cluster.
+    #[structopt(long, default_value = "5")]
+    node_cluster_min_nodes: usize,
+    /// Any number of "<network id prefix>=<group>" pairs. Nodes whose network ID starts with
+    /// one of the prefixes are placed in its group (the longest matching prefix wins), which
+    /// feeds are told about so that fleets of nodes can be shown together. A whole network
+    /// ID can be given to place a single node in a group.
+    #[structopt(long, required = false)]
+    node_group: Vec<NodeGroup>,
+    /// If non-zero, nodes that aren't placed in a group by --node-group are grouped by this
+    /// many characters from the start of their network ID. By default, they aren't grouped.
+    #[structopt(long, default_value = "0")]
+    node_group_prefix_len: usize,
+    /// Path to a MaxMind GeoLite2-City database (.mmdb) used to locate nodes offline. If
+    /// given, it's consulted before any online geolocation provider (unless --geo-providers
+    /// says otherwise), and reloaded if the file changes on disk.
+    #[structopt(long)]
+    geoip_database: Option<std::path::PathBuf>,
+    /// The geolocation providers to try, in order, when locating a node. Valid providers are
+    /// "maxmind", "ipapi" and "ipinfo". Defaults to "maxmind" (if --geoip-database is given),
+    /// then "ipapi", then "ipinfo".
+    #[structopt(long, required = false)]
+    geo_providers: Vec<ProviderName>,
+    /// An API token to use with ipinfo.io, which gives us a much bigger quota than
+    /// going without, and lets us look addresses up in batches rather than one at a
+    /// time. Can also be given in the IPINFO_TOKEN environment variable.
+    #[structopt(long, env = "IPINFO_TOKEN", hide_env_values = true)]
+    ipinfo_token: Option<String>,
+    /// An API key to use with ipapi.co. Can also be given in the IPAPI_KEY environment
+    /// variable.
+    #[structopt(long, env = "IPAPI_KEY", hide_env_values = true)]
+    ipapi_key: Option<String>,
+    /// How many seconds to wait to connect to ipinfo.io before giving up and trying the
+    /// next geolocation provider.
+    #[structopt(long, default_value = "3")]
+    ipinfo_connect_timeout: u64,
+    /// How many seconds to wait for ipinfo.io to respond, once connected, before giving up
+    /// and trying the next geolocation provider.
+    #[structopt(long, default_value = "5")]
+    ipinfo_read_timeout: u64,
+    /// How many seconds to wait to connect to ipapi.co before giving up and trying the
+    /// next geolocation provider.
+    #[structopt(long, default_value = "3")]
+    ipapi_connect_timeout: u64,
+    /// How many seconds to wait for ipapi.co to respond, once connected, before giving up
+    /// and trying the next geolocation provider.
+    #[structopt(long, default_value = "5")]
+    ipapi_read_timeout: u64,
+    /// If given, locations that we look up are persisted to this JSON file and reloaded on
+    /// startup, so that we don't need to query the geolocation providers again after a restart.
+    /// The file is gzipped if its name ends in ".gz", or compressed with zstd if it ends in
+    /// ".zst". Compressed files are loaded whatever they're called.
+    #[structopt(long)]
+    location_cache_file: Option<std::path::PathBuf>,
+    /// Cached locations older than this number of seconds are looked up again. "0" means that
+    /// cached locations never expire.
+    #[structopt(long, default_value = "2592000")]
+    location_cache_ttl: u64,
+    /// How many seconds to remember that an IP address couldn't be located for, so that we
+    /// don't keep asking about it. "0" means that we'll ask every time.
+    #[structopt(long, default_value = "3600")]
+    location_not_found_ttl: u64,
+    /// How often, in seconds, to write any new locations to the --location-cache-file.
+    #[structopt(long, default_value = "60")]
+    location_cache_flush_interval: u64,
+    /// The most locations to keep cached at once. Once there are this many, the least recently
+    /// used are forgotten (and looked up again if they're needed). "0" means that there's no limit.
+    #[structopt(long, default_value = "100000")]
+    location_cache_capacity: usize,
+    /// A JSON file of IP ranges and the locations to use for nodes in them, overriding
+    /// whatever the geolocation providers say. Each entry looks like `{ "cidr": "203.0.113.0/24",
+    /// "latitude": 51.5, "longitude": -0.12, "city": "London" }`; the most specific range wins.
+    #[structopt(long)]
+    location_overrides: Option<std::path::PathBuf>,
+    /// The most requests to make to the online geolocation providers at once. Any more
+    /// lookups than this are queued until there's room for them.
+    #[structopt(long, default_value = "8")]
+    max_concurrent_location_lookups: usize,
+    /// If given, the chains and nodes that we know about are written to this file when we shut
+    /// down, and restored from it when we start up, so that feeds don't start off empty.
+    #[structopt(long)]
+    state_file: Option<std::path::PathBuf>,
+    /// How many seconds nodes restored from the --state-file have to reconnect before we remove
+    /// them. Until then, they are shown as stale.
+    #[structopt(long, default_value = "300")]
+    restored_node_timeout: u64,
+    /// If non-zero, nodes whose connection goes away are shown as stale for this many seconds
+    /// rather than being removed straight away, so that a node which is quick to reconnect (with
+    /// the same network ID) replaces itself rather than briefly dropping out of feeds.
+    #[structopt(long, default_value = "0")]
+    node_reconnect_grace_period: u64,
+    /// Nodes that haven't sent us anything for this many seconds are removed, even if their
+    /// connection is still open, and their shard is asked to ignore anything more that they
+    /// send. This is separate from nodes being shown as stale, which happens much sooner and
+    /// doesn't remove them. "0" means that nodes are never removed for being idle.
+    #[structopt(long, default_value = "3600")]
+    node_idle_timeout: u64,
+    /// If non-zero, we process at most one update of each kind from a node every this many
+    /// milliseconds. Nodes sending updates more often than this have all but the latest held
+    /// back, which caps how much work (and how many feed messages) a chatty node can cause.
+    #[structopt(long, default_value = "0")]
+    min_node_update_interval_ms: u64,
+    /// The feed URL of another telemetry core (eg "wss://telemetry.example.com/feed") whose
+    /// chains and nodes we should mirror, connecting to it like any other feed would. This lets
+    /// feeds be served from more places without shards having to connect to each of them.
+    #[structopt(long)]
+    upstream_feed: Option<http::Uri>,
 }
 
 fn main() {
     let opts = Opts::from_args();
 
-    SimpleLogger::new()
-        .with_level(opts.log_level)
-        .init()
-        .expect("Must be able to start a logger");
+    match opts.log_format {
+        LogFormat::Text => SimpleLogger::new().with_level(opts.log_level).init(),
+        LogFormat::Json => JsonLogger::new(opts.log_level).init(),
+    }
+    .expect("Must be able to start a logger");
 
     log::info!("Starting Telemetry Core version: {}", VERSION);
 
@@ -123,75 +477,567 @@ fn main() {
         });
 }
 
+/// The maximum number of nodes allowed to connect to a chain, given as "<genesis hash>=<max nodes>".
+#[derive(Debug, Clone, Copy)]
+struct ChainQuota {
+    genesis_hash: BlockHash,
+    max_nodes: usize,
+}
+
+impl FromStr for ChainQuota {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (genesis_hash, max_nodes) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected '<genesis hash>=<max nodes>'"))?;
+        Ok(ChainQuota {
+            genesis_hash: genesis_hash
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid genesis hash: {:?}", e))?,
+            max_nodes: max_nodes.parse()?,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct NodeGroup {
+    prefix: Box<str>,
+    group: Box<str>,
+}
+
+impl FromStr for NodeGroup {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, group) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected '<network id prefix>=<group>'"))?;
+        if prefix.trim().is_empty() {
+            return Err(anyhow::anyhow!("the network ID prefix cannot be empty"));
+        }
+        if group.trim().is_empty() {
+            return Err(anyhow::anyhow!("the group cannot be empty"));
+        }
+        Ok(NodeGroup {
+            prefix: prefix.trim().into(),
+            group: group.trim().into(),
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+struct ChainLabel {
+    genesis_hash: BlockHash,
+    label: Box<str>,
+}
+
+impl FromStr for ChainLabel {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (genesis_hash, label) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("expected '<genesis hash>=<label>'"))?;
+        if label.trim().is_empty() {
+            return Err(anyhow::anyhow!("the label cannot be empty"));
+        }
+        Ok(ChainLabel {
+            genesis_hash: genesis_hash
+                .parse()
+                .map_err(|e| anyhow::anyhow!("invalid genesis hash: {:?}", e))?,
+            label: label.trim().into(),
+        })
+    }
+}
+
+/// Another address to accept shards on, with its own TLS config and shard token.
+#[derive(Debug, Clone)]
+struct ShardListener {
+    addr: std::net::SocketAddr,
+    token: Option<SecretToken>,
+    tls: Option<(std::path::PathBuf, std::path::PathBuf)>,
+}
+
+impl FromStr for ShardListener {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(',');
+        let addr = parts.next().unwrap_or("").trim().parse()?;
+        let mut token = None;
+        let mut tls_cert = None;
+        let mut tls_key = None;
+        for part in parts {
+            let (key, value) = part
+                .split_once('=')
+                .ok_or_else(|| anyhow::anyhow!("expected '<option>=<value>', got '{}'", part))?;
+            match key.trim() {
+                "token" => token = Some(value.trim().parse()?),
+                "tls-cert" => tls_cert = Some(value.trim().into()),
+                "tls-key" => tls_key = Some(value.trim().into()),
+                key => {
+                    return Err(anyhow::anyhow!(
+                        "'{}' is not one of 'token', 'tls-cert' or 'tls-key'",
+                        key
+                    ))
+                }
+            }
+        }
+        let tls = match (tls_cert, tls_key) {
+            (Some(cert), Some(key)) => Some((cert, key)),
+            (None, None) => None,
+            _ => {
+                return Err(anyhow::anyhow!(
+                    "tls-cert and tls-key must be given together"
+                ))
+            }
+        };
+        Ok(ShardListener { addr, token, tls })
+    }
+}
+
 /// Declare our routes and start the server.
 async fn start_server(num_aggregators: usize, opts: Opts) -> anyhow::Result<()> {
     let aggregator_queue_len = opts.aggregator_queue_len.unwrap_or(10_000);
+    let node_groupers = node_groupers(&opts);
     let aggregator = AggregatorSet::spawn(
         num_aggregators,
         AggregatorOpts {
             max_queue_len: aggregator_queue_len,
-            denylist: opts.denylist,
-            max_third_party_nodes: opts.max_third_party_nodes,
+            max_feed_queue_len: opts.max_feed_queue_len,
+            max_node_adds_per_second: opts.max_shard_node_adds_per_second,
+            feed_workers: match opts.feed_workers {
+                0 => None,
+                n => Some(n),
+            },
+            feed_sink: opts.feed_sink_nats.clone().map(|addr| {
+                FeedSinkHandle::spawn(
+                    NatsSink::new(addr),
+                    opts.feed_sink_subject.clone(),
+                    opts.feed_sink_queue_len,
+                )
+            }),
+            #[cfg(feature = "recording")]
+            shard_recorder: match &opts.record_shard_messages {
+                Some(path) => {
+                    log::info!("Recording shard messages to {}", path.display());
+                    Some(recording::ShardRecorder::create(path)?)
+                }
+                None => None,
+            },
+            state: StateOpts {
+                denylist: denylist(&opts)?,
+                allowlist: allowlist(&opts)?,
+                max_third_party_nodes: opts.max_third_party_nodes,
+                chain_quotas: opts
+                    .chain_quota
+                    .iter()
+                    .map(|q| (q.genesis_hash, q.max_nodes))
+                    .collect(),
+                max_chains: match opts.max_chains {
+                    0 => None,
+                    n => Some(n),
+                },
+                pinned_chains: opts.pinned_chains,
+                min_chain_nodes: opts.min_chain_nodes,
+                empty_chain_ttl: match opts.empty_chain_ttl {
+                    0 => None,
+                    n => Some(Duration::from_secs(n)),
+                },
+                chain_labels: opts
+                    .chain_label
+                    .into_iter()
+                    .map(|l| (l.genesis_hash, l.label))
+                    .collect(),
+                dedup_key: opts.dedup_nodes_by,
+                fork_detection: ForkDetectionOpts {
+                    window: opts.fork_detection_window,
+                    min_nodes: opts.fork_detection_min_nodes.max(1),
+                },
+                finalization_stall: FinalizationStallOpts {
+                    max_gap: opts.finalization_stall_gap,
+                    duration: Duration::from_secs(opts.finalization_stall_secs),
+                },
+                max_height_deviation: opts.max_block_height_deviation,
+                finalized_quorum: match opts.best_block_finalized_quorum {
+                    q if q > 0.0 => Some(q.min(1.0)),
+                    _ => None,
+                },
+                max_node_name_length: opts.max_node_name_length,
+                feed_chunk_size: opts.feed_chunk_size,
+                node_clusters: match opts.node_cluster_radius {
+                    r if r > 0.0 => Some(NodeClusterOpts {
+                        radius_km: r,
+                        min_nodes: opts.node_cluster_min_nodes,
+                    }),
+                    _ => None,
+                },
+                node_groupers,
+                reconnect_grace_period: match opts.node_reconnect_grace_period {
+                    0 => None,
+                    n => Some(Duration::from_secs(n)),
+                },
+                node_idle_timeout: match opts.node_idle_timeout {
+                    0 => None,
+                    n => Some(Duration::from_secs(n)),
+                },
+                min_update_interval: match opts.min_node_update_interval_ms {
+                    0 => None,
+                    n => Some(Duration::from_millis(n)),
+                },
+            },
+            locator: LocatorOpts {
+                providers: opts.geo_providers,
+                provider_config: GeoProviderConfig {
+                    ipinfo_token: opts.ipinfo_token,
+                    ipapi_key: opts.ipapi_key,
+                    ipinfo_timeouts: GeoTimeouts {
+                        connect: Duration::from_secs(opts.ipinfo_connect_timeout.max(1)),
+                        read: Duration::from_secs(opts.ipinfo_read_timeout.max(1)),
+                    },
+                    ipapi_timeouts: GeoTimeouts {
+                        connect: Duration::from_secs(opts.ipapi_connect_timeout.max(1)),
+                        read: Duration::from_secs(opts.ipapi_read_timeout.max(1)),
+                    },
+                },
+                geoip_database: opts.geoip_database,
+                cache_file: opts.location_cache_file,
+                cache_ttl: match opts.location_cache_ttl {
+                    0 => None,
+                    n => Some(Duration::from_secs(n)),
+                },
+                not_found_ttl: Duration::from_secs(opts.location_not_found_ttl),
+                cache_flush_interval: Duration::from_secs(
+                    opts.location_cache_flush_interval.max(1),
+                ),
+                cache_capacity: match opts.location_cache_capacity {
+                    0 => None,
+                    n => Some(n),
+                },
+                overrides_file: opts.location_overrides,
+                max_concurrent_lookups: opts.max_concurrent_location_lookups,
+            },
         },
     )
     .await?;
+    if let Some(state_file) = &opts.state_file {
+        restore_state(&aggregator, state_file, opts.restored_node_timeout)?;
+    }
+    if let Some(upstream) = opts.upstream_feed.clone() {
+        tokio::spawn(replica::mirror(upstream, aggregator.clone()));
+    }
+    #[cfg(unix)]
+    if !opts.denylist_file.is_empty() {
+        spawn_denylist_reload_loop(
+            aggregator.clone(),
+            opts.denylist.clone(),
+            opts.denylist_file.clone(),
+        );
+    }
     let socket_addr = opts.socket;
-    let feed_timeout = opts.feed_timeout;
+    let base_path = opts.base_path;
+    let tls_config = match (&opts.tls_cert, &opts.tls_key) {
+        (Some(cert), Some(key)) => Some(tls::server_config(cert, key)?),
+        _ => None,
+    };
+    let feed_timeouts = FeedTimeouts {
+        send: Duration::from_secs(opts.feed_timeout),
+        ping_interval: match opts.feed_ping_interval {
+            0 => None,
+            n => Some(Duration::from_secs(n)),
+        },
+        pong: Duration::from_secs(opts.feed_ping_timeout),
+    };
+    let feed_flush_opts = FeedFlushOpts {
+        interval: Duration::from_millis(opts.feed_flush_interval_ms),
+        max_interval: Duration::from_millis(opts.feed_flush_max_interval_ms),
+        jitter: Duration::from_millis(opts.feed_flush_jitter_ms),
+    };
+    let feed_flush_metrics = Arc::new(FeedFlushMetrics::default());
+    let feed_limiter = ConnectionLimiter::new(opts.max_feeds_per_ip);
+    let trust_proxy_headers = opts.trust_proxy_headers;
+    let shard_limiter = opts
+        .max_shard_connections_per_second
+        .map(|n| AcceptRateLimiter::new(n, std::time::Instant::now()));
+    let shard_token = opts.shard_token;
+    let shard_listeners = opts.shard_listener;
+    let feed_token = opts.feed_token;
+    let admin_token = opts.admin_token;
+    let shard_ws_opts = http_utils::WsOpts {
+        max_message_size: opts.max_shard_message_size.num_bytes(),
+    };
+    let state_file = opts.state_file;
+    let aggregator_on_shutdown = aggregator.clone();
+    // We're only ready once the main server and every shard listener are listening:
+    let ready = http_utils::Readiness::for_listeners(shard_listeners.len() + 1);
+    let ready_on_shutdown = ready.clone();
+    let ready_check = ready.clone();
+
+    // Any other addresses that shards can connect to only serve what shards need. These are
+    // all started alongside the main server, and feed nodes into the same aggregators:
+    let mut servers = Vec::with_capacity(shard_listeners.len() + 1);
+    for listener in shard_listeners {
+        let tls_config = match &listener.tls {
+            Some((cert, key)) => Some(tls::server_config(cert, key)?),
+            None => None,
+        };
+        let aggregator = aggregator.clone();
+        let ready_check = ready.clone();
+        let shard_limiter = shard_limiter.clone();
+        let shard_token = listener.token;
+        let base_path = base_path.clone();
+        servers.push(futures::future::Either::Left(http_utils::start_server(
+            listener.addr,
+            tls_config,
+            ready.clone(),
+            move |addr, req| {
+                let aggregator = aggregator.clone();
+                let ready_check = ready_check.clone();
+                let shard_limiter = shard_limiter.clone();
+                let shard_token = shard_token.clone();
+                let base_path = base_path.clone();
+                async move {
+                    match (req.method(), base_path.route(req.uri().path())) {
+                        (&Method::GET, "/health" | "/healthz") => Ok(Response::new("OK".into())),
+                        (&Method::GET, "/readyz") => Ok(ready_check.response()),
+                        (&Method::GET, "/shard_submit") => Ok(shard_submit(
+                            req,
+                            addr,
+                            aggregator,
+                            shard_token.as_ref(),
+                            shard_limiter.as_ref(),
+                            shard_ws_opts,
+                        )),
+                        _ => Ok(Response::builder()
+                            .status(404)
+                            .body("Not found".into())
+                            .unwrap()),
+                    }
+                }
+            },
+        )));
+    }
 
-    let server = http_utils::start_server(socket_addr, move |addr, req| {
+    let server = http_utils::start_server(socket_addr, tls_config, ready, move |addr, req| {
         let aggregator = aggregator.clone();
+        let ready_check = ready_check.clone();
+        let feed_limiter = feed_limiter.clone();
+        let feed_flush_metrics = Arc::clone(&feed_flush_metrics);
+        let shard_limiter = shard_limiter.clone();
+        let shard_token = shard_token.clone();
+        let feed_token = feed_token.clone();
+        let admin_token = admin_token.clone();
+        let base_path = base_path.clone();
         async move {
-            match (req.method(), req.uri().path().trim_end_matches('/')) {
+            match (req.method(), base_path.route(req.uri().path())) {
                 // Check that the server is up and running:
-                (&Method::GET, "/health") => Ok(Response::new("OK".into())),
+                (&Method::GET, "/health" | "/healthz") => Ok(Response::new("OK".into())),
+                // Check that we're accepting shards and feeds; not while starting up or
+                // shutting down:
+                (&Method::GET, "/readyz") => Ok(ready_check.response()),
                 // Subscribe to feed messages:
                 (&Method::GET, "/feed") => {
-                    log::info!("Opening /feed connection from {:?}", addr);
-                    Ok(http_utils::upgrade_to_websocket(
-                        req,
-                        move |ws_send, ws_recv| async move {
-                            let (feed_id, tx_to_aggregator) = aggregator.subscribe_feed();
-                            let (mut tx_to_aggregator, mut ws_send) =
-                                handle_feed_websocket_connection(
-                                    ws_send,
-                                    ws_recv,
-                                    tx_to_aggregator,
-                                    feed_timeout,
-                                    feed_id,
-                                )
-                                .await;
-                            log::info!("Closing /feed connection from {:?}", addr);
-                            // Tell the aggregator that this connection has closed, so it can tidy up.
-                            let _ = tx_to_aggregator.send(FromFeedWebsocket::Disconnected).await;
-                            let _ = ws_send.close().await;
+                    // Clients can ask for an older version of the feed format if they
+                    // don't understand the current one yet:
+                    let feed_version = match query_param(req.uri().query(), "version") {
+                        None => feed_message::FEED_VERSION,
+                        Some(version) => match version.parse() {
+                            Ok(version)
+                                if version == feed_message::FEED_VERSION
+                                    || feed_message::LEGACY_FEED_VERSIONS.contains(&version) =>
+                            {
+                                version
+                            }
+                            _ => {
+                                return Ok(Response::builder()
+                                    .status(400)
+                                    .body("Unsupported feed version".into())
+                                    .unwrap())
+                            }
                         },
-                    ))
-                }
-                // Subscribe to shard messages:
-                (&Method::GET, "/shard_submit") => {
-                    Ok(http_utils::upgrade_to_websocket(
+                    };
+
+                    // Clients can ask for the nodes on each chain that they subscribe to to be
+                    // sent as one gzipped message, rather than as many uncompressed ones:
+                    let gzip_snapshots = match query_param(req.uri().query(), "snapshot") {
+                        None => false,
+                        Some("gzip") => true,
+                        Some(_) => {
+                            return Ok(Response::builder()
+                                .status(400)
+                                .body("Unsupported snapshot encoding".into())
+                                .unwrap())
+                        }
+                    };
+
+                    // Clients can ask for messages in a more readable form while they're being
+                    // developed. These are sent as text, so they can't be gzipped:
+                    let readable = match query_param(req.uri().query(), "format") {
+                        None | Some("compact") => false,
+                        Some("json") if !gzip_snapshots => true,
+                        Some("json") => {
+                            return Ok(Response::builder()
+                                .status(400)
+                                .body("Gzipped snapshots can't be sent as JSON".into())
+                                .unwrap())
+                        }
+                        Some(_) => {
+                            return Ok(Response::builder()
+                                .status(400)
+                                .body("Unsupported feed format".into())
+                                .unwrap())
+                        }
+                    };
+
+                    // Feeds hand us their token as a subprotocol, which we accept by naming it
+                    // again in our response. Feeds can offer one even if we don't need it:
+                    let token_protocol = http_utils::offered_protocols(req.headers())
+                        .find(|protocol| protocol.starts_with(FEED_TOKEN_PROTOCOL))
+                        .map(|protocol| protocol.to_owned());
+                    if let Some(feed_token) = &feed_token {
+                        let given = token_protocol
+                            .as_deref()
+                            .and_then(|protocol| protocol.strip_prefix(FEED_TOKEN_PROTOCOL))
+                            .unwrap_or("");
+                        if !feed_token.matches(given) {
+                            log::warn!(
+                                "Rejecting /feed connection from {:?}: invalid feed token",
+                                addr
+                            );
+                            return Ok(Response::builder()
+                                .status(401)
+                                .body("Invalid feed token".into())
+                                .unwrap());
+                        }
+                    }
+
+                    let feed_ip = if trust_proxy_headers {
+                        real_ip::real_ip(addr, req.headers()).0
+                    } else {
+                        addr.ip()
+                    };
+                    let connection_guard = feed_limiter.acquire(feed_ip);
+
+                    log::info!(
+                        "Opening /feed connection from {:?} (feed version {}, gzipped snapshots: {}, readable: {})",
+                        addr,
+                        feed_version,
+                        gzip_snapshots,
+                        readable
+                    );
+                    let mut response = http_utils::upgrade_to_compressed_websocket(
                         req,
-                        move |ws_send, ws_recv| async move {
-                            log::info!("Opening /shard_submit connection from {:?}", addr);
-                            let tx_to_aggregator = aggregator.subscribe_shard();
-                            let (mut tx_to_aggregator, mut ws_send) =
-                                handle_shard_websocket_connection(
-                                    ws_send,
-                                    ws_recv,
-                                    tx_to_aggregator,
-                                )
-                                .await;
-                            log::info!("Closing /shard_submit connection from {:?}", addr);
-                            // Tell the aggregator that this connection has closed, so it can tidy up.
-                            let _ = tx_to_aggregator
-                                .send(FromShardWebsocket::Disconnected)
-                                .await;
-                            let _ = ws_send.close().await;
+                        http_utils::WsOpts::default(),
+                        move |mut ws_send, ws_recv, compression| {
+                            logging::scope([("ip", feed_ip.to_string())], async move {
+                                // Keep hold of this until the connection is closed:
+                                let _connection_guard = match connection_guard {
+                                    Some(guard) => guard,
+                                    None => {
+                                        log::warn!(
+                                        "Closing /feed connection from {:?}; too many feeds are connected from {}",
+                                        addr,
+                                        feed_ip
+                                    );
+                                        let _ = ws_send.close().await;
+                                        return;
+                                    }
+                                };
+
+                                let (_feed_id, tx_to_aggregator) = aggregator.subscribe_feed();
+                                let (mut tx_to_aggregator, mut ws_send) =
+                                    handle_feed_websocket_connection(
+                                        ws_send,
+                                        ws_recv,
+                                        compression,
+                                        tx_to_aggregator,
+                                        FeedFormat {
+                                            version: feed_version,
+                                            gzip_snapshots,
+                                            readable,
+                                        },
+                                        feed_timeouts,
+                                        FlushInterval::new(feed_flush_opts, feed_flush_metrics),
+                                    )
+                                    .await;
+                                log::info!("Closing /feed connection from {:?}", addr);
+                                // Tell the aggregator that this connection has closed, so it can tidy up.
+                                let _ =
+                                    tx_to_aggregator.send(FromFeedWebsocket::Disconnected).await;
+                                let _ = ws_send.close().await;
+                            })
                         },
-                    ))
+                    );
+                    let token_protocol = token_protocol.and_then(|p| p.parse().ok());
+                    if let (101, Some(protocol)) = (response.status().as_u16(), token_protocol) {
+                        response
+                            .headers_mut()
+                            .insert("Sec-WebSocket-Protocol", protocol);
+                    }
+                    Ok(response)
                 }
+                // Subscribe to shard messages:
+                (&Method::GET, "/shard_submit") => Ok(shard_submit(
+                    req,
+                    addr,
+                    aggregator,
+                    shard_token.as_ref(),
+                    shard_limiter.as_ref(),
+                    shard_ws_opts,
+                )),
                 // Return metrics in a prometheus-friendly text based format:
-                (&Method::GET, "/metrics") => Ok(return_prometheus_metrics(aggregator).await),
+                (&Method::GET, "/metrics") => {
+                    Ok(return_prometheus_metrics(aggregator, &feed_flush_metrics).await)
+                }
+                // Return the current chains and nodes as JSON:
+                (&Method::GET, "/state") => {
+                    Ok(return_state_snapshot(aggregator, req.uri().query(), false).await)
+                }
+                // Let operators see what's going on, and get rid of misbehaving nodes and shards:
+                (method, path) if path.starts_with("/admin/") && admin_token.is_some() => {
+                    let given = req
+                        .headers()
+                        .get(http::header::AUTHORIZATION)
+                        .and_then(|value| value.to_str().ok())
+                        .and_then(|value| value.strip_prefix("Bearer "))
+                        .unwrap_or("");
+                    if !admin_token.as_ref().unwrap().matches(given) {
+                        log::warn!(
+                            "Rejecting {} request from {:?}: invalid admin token",
+                            path,
+                            addr
+                        );
+                        return Ok(Response::builder()
+                            .status(401)
+                            .body("Invalid admin token".into())
+                            .unwrap());
+                    }
+                    match (method, path) {
+                        (&Method::POST, "/admin/disconnect_node") => {
+                            Ok(disconnect_node(aggregator, req.uri().query()).await)
+                        }
+                        (&Method::POST, "/admin/disconnect_shard") => {
+                            Ok(disconnect_shard(aggregator, req.uri().query()).await)
+                        }
+                        (&Method::POST, "/admin/pause_chain") => {
+                            Ok(set_chain_paused(aggregator, req.uri().query(), true).await)
+                        }
+                        (&Method::POST, "/admin/resume_chain") => {
+                            Ok(set_chain_paused(aggregator, req.uri().query(), false).await)
+                        }
+                        (&Method::GET, "/admin/state") => {
+                            Ok(return_state_snapshot(aggregator, req.uri().query(), true).await)
+                        }
+                        (&Method::GET, "/admin/chain_lists") => {
+                            Ok(return_chain_lists(aggregator).await)
+                        }
+                        (&Method::GET, "/admin/quotas") => Ok(return_quotas(aggregator).await),
+                        (&Method::GET, "/admin/export") => {
+                            Ok(return_state_export(aggregator).await)
+                        }
+                        _ => Ok(Response::builder()
+                            .status(404)
+                            .body("Not found".into())
+                            .unwrap()),
+                    }
+                }
                 // 404 for anything else:
                 _ => Ok(Response::builder()
                     .status(404)
@@ -200,15 +1046,271 @@ async fn start_server(num_aggregators: usize, opts: Opts) -> anyhow::Result<()>
             }
         }
     });
+    // The main server comes first, so that it's the first to say where it's listening:
+    servers.insert(0, futures::future::Either::Right(server));
+    let server = futures::future::try_join_all(servers);
 
-    server.await?;
+    // Stop gracefully if asked to, so that anything which needs to be written to disk
+    // (like the location cache) is flushed as everything is torn down. We keep serving
+    // requests while that happens, but say that we're no longer ready for them.
+    tokio::pin!(server);
+    tokio::select! {
+        res = &mut server => { res?; }
+        _ = shutdown_signal() => {
+            log::info!("Shutting down");
+            ready_on_shutdown.set_ready(false);
+            let save = async {
+                if let Some(state_file) = &state_file {
+                    save_state(&aggregator_on_shutdown, state_file).await;
+                }
+            };
+            tokio::select! {
+                res = &mut server => { res?; }
+                _ = save => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Accept a shard connecting to /shard_submit, so long as it has the token we need (if any)
+/// and we're not accepting shards too quickly.
+fn shard_submit(
+    req: hyper::Request<hyper::Body>,
+    addr: std::net::SocketAddr,
+    aggregator: AggregatorSet,
+    shard_token: Option<&SecretToken>,
+    shard_limiter: Option<&AcceptRateLimiter>,
+    shard_ws_opts: http_utils::WsOpts,
+) -> Response<hyper::Body> {
+    // Shards that don't know the token never get as far as telling us about nodes:
+    if let Some(shard_token) = shard_token {
+        let given = query_param(req.uri().query(), "token").unwrap_or("");
+        if !shard_token.matches(given) {
+            log::warn!(
+                "Rejecting /shard_submit connection from {:?}: invalid shard token",
+                addr
+            );
+            return Response::builder()
+                .status(401)
+                .body("Invalid shard token".into())
+                .unwrap();
+        }
+    }
+    // Spread out shards that all try to connect at once:
+    if let Some(shard_limiter) = shard_limiter {
+        if let Err(wait) = shard_limiter.try_accept(std::time::Instant::now()) {
+            log::debug!(
+                "Deferring /shard_submit connection from {:?} for {:?}",
+                addr,
+                wait
+            );
+            return Response::builder()
+                .status(503)
+                .header(hyper::header::RETRY_AFTER, retry_after_secs(wait))
+                .body("Too many shards connecting; try again later".into())
+                .unwrap();
+        }
+    }
+    // Shards can ask for node messages to be compressed on the way to us:
+    http_utils::upgrade_to_compressed_websocket(
+        req,
+        shard_ws_opts,
+        move |ws_send, ws_recv, compression| async move {
+            let (shard_conn_id, tx_to_aggregator) = aggregator.subscribe_shard();
+            log::info!(
+                "Opening /shard_submit connection {} from {:?} (compressed: {})",
+                u64::from(shard_conn_id),
+                addr,
+                compression.is_some()
+            );
+            let (mut tx_to_aggregator, mut ws_send) = handle_shard_websocket_connection(
+                addr,
+                ws_send,
+                ws_recv,
+                compression,
+                shard_ws_opts.max_message_size,
+                tx_to_aggregator,
+            )
+            .await;
+            log::info!("Closing /shard_submit connection from {:?}", addr);
+            // Tell the aggregator that this connection has closed, so it can tidy up.
+            let _ = tx_to_aggregator
+                .send(FromShardWebsocket::Disconnected)
+                .await;
+            let _ = ws_send.close().await;
+        },
+    )
+}
+
+/// Restore any state that we saved to disk last time we shut down. Restored nodes that
+/// haven't reconnected after `timeout_secs` are removed.
+fn restore_state(
+    aggregator: &AggregatorSet,
+    state_file: &std::path::Path,
+    timeout_secs: u64,
+) -> anyhow::Result<()> {
+    let persisted = match PersistedState::load(state_file)? {
+        Some(persisted) => persisted,
+        None => return Ok(()),
+    };
+    log::info!(
+        "Restoring {} nodes from {}",
+        persisted.node_count(),
+        state_file.display()
+    );
+    aggregator.restore_state(persisted)?;
+
+    let aggregator = aggregator.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(Duration::from_secs(timeout_secs)).await;
+        if let Err(e) = aggregator.expire_restored_nodes() {
+            log::error!("Couldn't remove restored nodes: {}", e);
+        }
+    });
     Ok(())
 }
 
+/// Write the current state to disk, so that it can be restored when we start up again.
+async fn save_state(aggregator: &AggregatorSet, state_file: &std::path::Path) {
+    let persisted = match aggregator.gather_persisted_state().await {
+        Ok(persisted) => persisted,
+        Err(e) => {
+            log::error!("Couldn't obtain the state to save: {}", e);
+            return;
+        }
+    };
+    match persisted.save(state_file) {
+        Ok(()) => log::info!(
+            "Saved {} nodes to {}",
+            persisted.node_count(),
+            state_file.display()
+        ),
+        Err(e) => log::error!("Couldn't save state to {}: {}", state_file.display(), e),
+    }
+}
+
+/// The genesis hashes of the only chains we'll allow, if any were given.
+fn allowlist(opts: &Opts) -> anyhow::Result<Option<Vec<BlockHash>>> {
+    if opts.allowlist.is_empty() && opts.allowlist_file.is_empty() {
+        return Ok(None);
+    }
+    if !opts.denylist.is_empty() || !opts.denylist_file.is_empty() {
+        anyhow::bail!(
+            "--allowlist and --allowlist-file can't be used alongside --denylist or --denylist-file"
+        );
+    }
+    let allowlist = chain_lists::read(&opts.allowlist, &opts.allowlist_file)?;
+    log::info!(
+        "Allowing {} chains (from --allowlist and {} files)",
+        allowlist.len(),
+        opts.allowlist_file.len()
+    );
+    Ok(Some(allowlist))
+}
+
+/// How to work out which group each node belongs to: first by any prefixes given in
+/// --node-group, and then by the start of the network ID if --node-group-prefix-len is given.
+fn node_groupers(opts: &Opts) -> Vec<Arc<dyn NodeGrouper>> {
+    let mut groupers: Vec<Arc<dyn NodeGrouper>> = Vec::new();
+    if !opts.node_group.is_empty() {
+        groupers.push(Arc::new(NetworkIdMapping::new(
+            opts.node_group
+                .iter()
+                .map(|g| (g.prefix.clone(), g.group.clone())),
+        )));
+    }
+    if opts.node_group_prefix_len > 0 {
+        groupers.push(Arc::new(NetworkIdPrefix(opts.node_group_prefix_len)));
+    }
+    groupers
+}
+
+/// The chains denied on the command line, combined with any listed in the denylist files.
+fn denylist(opts: &Opts) -> anyhow::Result<Vec<String>> {
+    let denylist = chain_lists::read(&opts.denylist, &opts.denylist_file)?;
+    if !denylist.is_empty() {
+        log::info!(
+            "Denying {} chains (from --denylist and {} files)",
+            denylist.len(),
+            opts.denylist_file.len()
+        );
+    }
+    Ok(denylist)
+}
+
+/// Read the denylist files again whenever we receive a SIGHUP, and hand the
+/// result to the aggregators.
+#[cfg(unix)]
+fn spawn_denylist_reload_loop(
+    aggregator: AggregatorSet,
+    denylist: Vec<String>,
+    denylist_files: Vec<std::path::PathBuf>,
+) {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sighup = match signal(SignalKind::hangup()) {
+        Ok(sighup) => sighup,
+        Err(e) => {
+            log::warn!(
+                "Can't listen for SIGHUP; the denylist won't be reloaded: {}",
+                e
+            );
+            return;
+        }
+    };
+
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            let new_denylist = match chain_lists::read(&denylist, &denylist_files) {
+                Ok(new_denylist) => new_denylist,
+                Err(e) => {
+                    log::warn!("Not updating the denylist: {}", e);
+                    continue;
+                }
+            };
+            log::info!(
+                "Reloaded denylist from {} files ({} chains denied)",
+                denylist_files.len(),
+                new_denylist.len()
+            );
+            if let Err(e) = aggregator.set_denylist(new_denylist) {
+                log::error!("Couldn't update the denylist: {}", e);
+            }
+        }
+    });
+}
+
+/// Resolves when we receive a SIGINT or (on unix) SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = sigterm.recv() => {},
+                }
+            }
+            Err(e) => {
+                log::warn!("Can't listen for SIGTERM: {}", e);
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = tokio::signal::ctrl_c().await;
+    }
+}
+
 /// This handles messages coming to/from a shard connection
 async fn handle_shard_websocket_connection<S>(
+    addr: std::net::SocketAddr,
     mut ws_send: http_utils::WsSender,
     mut ws_recv: http_utils::WsReceiver,
+    compression: Option<http_utils::WsCompression>,
+    max_message_size: usize,
     mut tx_to_aggregator: S,
 ) -> (S, http_utils::WsSender)
 where
@@ -219,6 +1321,7 @@ where
     // Tell the aggregator about this new connection, and give it a way to send messages to us:
     let init_msg = FromShardWebsocket::Initialize {
         channel: tx_to_shard_conn,
+        addr: Some(addr),
     };
     if let Err(e) = tx_to_aggregator.send(init_msg).await {
         log::error!("Error sending message to aggregator: {}", e);
@@ -231,6 +1334,10 @@ where
 
     // Receive messages from a shard:
     let recv_handle = tokio::spawn(async move {
+        // How many bytes we've received, after any decompression:
+        let mut bytes_received: u64 = 0;
+        let mut parser = ShardMessageParser::new(max_message_size);
+
         loop {
             let mut bytes = Vec::new();
 
@@ -246,6 +1353,15 @@ where
             if let Err(soketto::connection::Error::Closed) = msg_info {
                 break;
             }
+            if let Err(soketto::connection::Error::MessageTooLarge { current, maximum }) = msg_info
+            {
+                let e = ShardMessageError::TooLarge {
+                    size: current,
+                    max: maximum,
+                };
+                log::error!("Booting shard {:?}: {}", addr, e);
+                break;
+            }
             if let Err(e) = msg_info {
                 log::error!(
                     "Shutting down websocket connection: Failed to receive data: {}",
@@ -253,18 +1369,24 @@ where
                 );
                 break;
             }
+            bytes_received += bytes.len() as u64;
 
-            let msg: internal_messages::FromShardAggregator =
-                match bincode::options().deserialize(&bytes) {
-                    Ok(msg) => msg,
-                    Err(e) => {
-                        log::error!(
-                            "Failed to deserialize message from shard; booting it: {}",
-                            e
-                        );
-                        break;
-                    }
-                };
+            let msg = match parser.parse(&bytes) {
+                Ok(msg) => msg,
+                Err(e) if e.is_fatal() => {
+                    log::error!(
+                        "Booting shard {:?} after a bad message ({} bytes): {}",
+                        addr,
+                        bytes.len(),
+                        e
+                    );
+                    break;
+                }
+                Err(e) => {
+                    log::warn!("Ignoring message from shard {:?}: {}", addr, e);
+                    continue;
+                }
+            };
 
             // Convert and send to the aggregator:
             let aggregator_msg = match msg {
@@ -293,6 +1415,18 @@ where
             }
         }
 
+        // Let operators see how much bandwidth compression is saving, so that they
+        // can weigh it up against the CPU time it costs on either end:
+        if let Some(compression) = compression {
+            let bytes_read = compression.bytes_read();
+            log::info!(
+                "Received {} bytes of messages from shard as {} compressed bytes ({:.1}% of the original size)",
+                bytes_received,
+                bytes_read,
+                100.0 * bytes_read as f64 / bytes_received.max(1) as f64
+            );
+        }
+
         drop(send_closer_tx); // Kill the send task if this recv task ends
         tx_to_aggregator
     });
@@ -344,18 +1478,45 @@ where
     (tx_to_aggregator, ws_send)
 }
 
+/// How long feeds have to do things before we close their connection.
+#[derive(Debug, Clone, Copy)]
+struct FeedTimeouts {
+    /// How long a feed has to receive each batch of messages that we send it.
+    send: Duration,
+    /// How often to ping the feed, if at all.
+    ping_interval: Option<Duration>,
+    /// How long a feed has to respond to a ping.
+    pong: Duration,
+}
+
+/// How the messages that we send to a feed should be encoded.
+#[derive(Debug, Clone, Copy)]
+struct FeedFormat {
+    /// The version of the feed format that the feed wants.
+    version: usize,
+    /// Should the nodes on a chain be sent as a single gzipped message when the
+    /// feed subscribes to it?
+    gzip_snapshots: bool,
+    /// Should messages be sent as text, in the more readable form described by
+    /// [`feed_message::to_readable`]?
+    readable: bool,
+}
+
 /// This handles messages coming from a feed connection
 async fn handle_feed_websocket_connection<S>(
     mut ws_send: http_utils::WsSender,
     mut ws_recv: http_utils::WsReceiver,
+    compression: Option<http_utils::WsCompression>,
     mut tx_to_aggregator: S,
-    feed_timeout: u64,
-    _feed_id: u64, // <- can be useful for debugging purposes.
+    format: FeedFormat,
+    timeouts: FeedTimeouts,
+    mut flush_interval: FlushInterval,
 ) -> (S, http_utils::WsSender)
 where
     S: futures::Sink<FromFeedWebsocket, Error = anyhow::Error> + Unpin + Send + 'static,
 {
-    // unbounded channel so that slow feeds don't block aggregator progress:
+    // unbounded channel so that slow feeds don't block aggregator progress. How far behind
+    // a feed can fall is limited by --max-feed-queue-len instead:
     let (tx_to_feed_conn, rx_from_aggregator) = flume::unbounded();
 
     // `Receiver::into_stream()` is currently problematic at the time of writing
@@ -377,6 +1538,8 @@ where
     // Channels to notify each loop if the other closes:
     let (recv_closer_tx, mut recv_closer_rx) = tokio::sync::oneshot::channel::<()>();
     let (send_closer_tx, mut send_closer_rx) = tokio::sync::oneshot::channel::<()>();
+    // The recv task lets the send task know when the feed has responded to a ping:
+    let (pong_tx, mut pong_rx) = tokio::sync::mpsc::unbounded_channel::<()>();
 
     // Receive messages from the feed:
     let recv_handle = tokio::spawn(async move {
@@ -386,20 +1549,27 @@ where
             // if we're halfway through receiving a message, no biggie since we're closing the
             // connection anyway.
             let msg_info = tokio::select! {
-                msg_info = ws_recv.receive_data(&mut bytes) => msg_info,
+                msg_info = ws_recv.receive(&mut bytes) => msg_info,
                 _ = &mut recv_closer_rx => { break }
             };
 
             // Handle the socket closing, or errors receiving the message.
-            if let Err(soketto::connection::Error::Closed) = msg_info {
-                break;
-            }
-            if let Err(e) = msg_info {
-                log::error!(
-                    "Shutting down websocket connection: Failed to receive data: {}",
-                    e
-                );
-                break;
+            match msg_info {
+                Ok(soketto::Incoming::Data(_)) => {}
+                Ok(soketto::Incoming::Pong(_)) => {
+                    let _ = pong_tx.send(());
+                    continue;
+                }
+                Ok(soketto::Incoming::Closed(_)) | Err(soketto::connection::Error::Closed) => {
+                    break;
+                }
+                Err(e) => {
+                    log::error!(
+                        "Shutting down websocket connection: Failed to receive data: {}",
+                        e
+                    );
+                    break;
+                }
             }
 
             // We ignore all but valid UTF8 text messages from the frontend:
@@ -432,11 +1602,49 @@ where
 
     // Send messages to the feed:
     let send_handle = tokio::spawn(async move {
+        // How many bytes we've sent, before any compression:
+        let mut bytes_sent = 0;
+        // Ping the feed every so often, and expect a pong back before this deadline:
+        let mut ping_interval = timeouts
+            .ping_interval
+            .map(|every| tokio::time::interval_at(Instant::now() + every, every));
+        let mut pong_deadline: Option<Instant> = None;
         'outer: loop {
-            let debounce = tokio::time::sleep_until(Instant::now() + Duration::from_millis(75));
+            let batch_started = Instant::now();
 
             let msgs = tokio::select! {
                 msgs = rx_from_aggregator_chunks.next() => msgs,
+                _ = async { ping_interval.as_mut().unwrap().tick().await }, if ping_interval.is_some() => {
+                    // Only one ping needs to be waiting for a response at a time:
+                    if pong_deadline.is_some() {
+                        continue;
+                    }
+                    let ping = async {
+                        ws_send.send_ping((&[][..]).try_into().unwrap()).await?;
+                        ws_send.flush().await
+                    };
+                    match tokio::time::timeout(timeouts.send, ping).await {
+                        Err(_) => {
+                            log::warn!("Closing feed websocket that was too slow to keep up (too slow to send ping)");
+                            break;
+                        }
+                        Ok(Err(e)) => {
+                            log::warn!("Closing feed websocket due to error sending ping: {}", e);
+                            break;
+                        }
+                        Ok(_) => {}
+                    }
+                    pong_deadline = Some(Instant::now() + timeouts.pong);
+                    continue;
+                },
+                _ = tokio::time::sleep_until(pong_deadline.unwrap_or_else(Instant::now)), if pong_deadline.is_some() => {
+                    log::warn!("Closing feed websocket that didn't respond to a ping");
+                    break;
+                },
+                Some(()) = pong_rx.recv() => {
+                    pong_deadline = None;
+                    continue;
+                },
                 _ = &mut send_closer_rx => { break }
             };
 
@@ -446,19 +1654,67 @@ where
                 None => break,
             };
 
-            // There is only one message type at the mo; bytes to send
-            // to the websocket. collect them all up to dispatch in one shot.
-            let all_msg_bytes = msgs.into_iter().map(|msg| match msg {
-                ToFeedWebsocket::Bytes(bytes) => bytes,
-            });
+            // Rewrite the messages if the feed wants an older version of the format, or
+            // wants them to be readable:
+            let to_feed_version = |bytes: bytes::Bytes| {
+                let bytes = if format.version == feed_message::FEED_VERSION {
+                    bytes
+                } else {
+                    match feed_message::downgrade(&bytes, format.version) {
+                        Ok(bytes) => bytes?,
+                        Err(e) => {
+                            log::error!(
+                                "Couldn't convert feed message to version {}: {}",
+                                format.version,
+                                e
+                            );
+                            return None;
+                        }
+                    }
+                };
+                if !format.readable {
+                    return Some(bytes);
+                }
+                match feed_message::to_readable(&bytes) {
+                    Ok(bytes) => Some(bytes),
+                    Err(e) => {
+                        log::error!("Couldn't make feed message readable: {}", e);
+                        None
+                    }
+                }
+            };
+
+            // Collect up all of the bytes to send to the websocket, to dispatch in one shot.
+            let mut all_msg_bytes = Vec::new();
+            for msg in msgs {
+                match msg {
+                    ToFeedWebsocket::Bytes(bytes) => all_msg_bytes.extend(to_feed_version(bytes)),
+                    ToFeedWebsocket::Snapshot(batches) if format.gzip_snapshots => {
+                        let batches = batches.into_iter().filter_map(to_feed_version);
+                        match feed_message::gzip_batches(batches) {
+                            Ok(bytes) => all_msg_bytes.extend(bytes),
+                            Err(e) => log::error!("Couldn't gzip feed messages: {}", e),
+                        }
+                    }
+                    ToFeedWebsocket::Snapshot(batches) => {
+                        all_msg_bytes.extend(batches.into_iter().filter_map(to_feed_version))
+                    }
+                }
+            }
+
+            let batch_bytes = all_msg_bytes.iter().map(|bytes| bytes.len()).sum();
 
             // If the feed is too slow to receive the current batch of messages, we'll drop it.
-            let message_send_deadline = Instant::now() + Duration::from_secs(feed_timeout);
+            let message_send_deadline = Instant::now() + timeouts.send;
 
             for bytes in all_msg_bytes {
-                match tokio::time::timeout_at(message_send_deadline, ws_send.send_binary(&bytes))
-                    .await
-                {
+                let send = async {
+                    match std::str::from_utf8(&bytes) {
+                        Ok(text) if format.readable => ws_send.send_text(text).await,
+                        _ => ws_send.send_binary(&bytes).await,
+                    }
+                };
+                match tokio::time::timeout_at(message_send_deadline, send).await {
                     Err(_) => {
                         log::warn!("Closing feed websocket that was too slow to keep up (too slow to send messages)");
                         break 'outer;
@@ -467,7 +1723,7 @@ where
                         log::warn!("Closing feed websocket due to error sending data: {}", e);
                         break 'outer;
                     }
-                    Ok(_) => {}
+                    Ok(_) => bytes_sent += bytes.len() as u64,
                 }
             }
             match tokio::time::timeout_at(message_send_deadline, ws_send.flush()).await {
@@ -482,7 +1738,18 @@ where
                 Ok(_) => {}
             }
 
-            debounce.await;
+            // Wait a little before sending the next batch, so that messages are sent together:
+            tokio::time::sleep_until(batch_started + flush_interval.next(batch_bytes)).await;
+        }
+
+        if let Some(compression) = compression {
+            let bytes_written = compression.bytes_written();
+            log::info!(
+                "Sent {} bytes of messages to feed as {} compressed bytes ({:.1}% of the original size)",
+                bytes_sent,
+                bytes_written,
+                100.0 * bytes_written as f64 / bytes_sent.max(1) as f64
+            );
         }
 
         drop(recv_closer_tx); // Kill the recv task if this send task ends
@@ -498,7 +1765,243 @@ where
     (tx_to_aggregator, ws_send)
 }
 
-async fn return_prometheus_metrics(aggregator: AggregatorSet) -> Response<hyper::Body> {
+/// Find the value of some parameter in the query string of a request.
+fn query_param<'a>(query: Option<&'a str>, name: &str) -> Option<&'a str> {
+    query
+        .unwrap_or("")
+        .split('&')
+        .filter_map(|param| param.split_once('='))
+        .find(|(key, _)| *key == name)
+        .map(|(_, value)| value)
+}
+
+/// "Retry-After" headers are given in whole seconds; round up so that nobody comes back early.
+fn retry_after_secs(wait: Duration) -> u64 {
+    (wait.as_millis() as u64).div_ceil(1000)
+}
+
+/// Hand back a JSON snapshot of the chains and nodes we know about. A `chain=<genesis hash>`
+/// query parameter can be given to only return details for that chain. Operators are told
+/// about every chain, and which shard each node is connected through. Everybody else is only
+/// told about the chains that feeds are, and may be handed a snapshot that's a second old.
+async fn return_state_snapshot(
+    aggregator: AggregatorSet,
+    query: Option<&str>,
+    for_operators: bool,
+) -> Response<hyper::Body> {
+    let genesis_hash = query_param(query, "chain").map(BlockHash::from_str);
+
+    let genesis_hash = match genesis_hash.transpose() {
+        Ok(genesis_hash) => genesis_hash,
+        Err(_) => {
+            return Response::builder()
+                .status(400)
+                .body("Invalid genesis hash given for 'chain'".into())
+                .unwrap()
+        }
+    };
+
+    let snapshot = if for_operators {
+        aggregator
+            .gather_snapshot(genesis_hash, true)
+            .await
+            .map(Arc::new)
+    } else {
+        aggregator.public_snapshot(genesis_hash).await
+    };
+    let snapshot = match snapshot {
+        Ok(snapshot) => snapshot,
+        Err(e) => {
+            log::error!("Couldn't obtain a snapshot of the current state: {}", e);
+            return Response::builder()
+                .status(500)
+                .body("Couldn't obtain a snapshot of the current state".into())
+                .unwrap();
+        }
+    };
+
+    Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&snapshot).unwrap().into())
+        .unwrap()
+}
+
+/// Hand back the denylist and allowlist that are currently in use, as JSON.
+async fn return_chain_lists(aggregator: AggregatorSet) -> Response<hyper::Body> {
+    let chain_lists = match aggregator.gather_chain_lists().await {
+        Ok(chain_lists) => chain_lists,
+        Err(e) => {
+            log::error!("Couldn't obtain the current chain lists: {}", e);
+            return Response::builder()
+                .status(500)
+                .body("Couldn't obtain the current chain lists".into())
+                .unwrap();
+        }
+    };
+
+    Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&chain_lists).unwrap().into())
+        .unwrap()
+}
+
+/// Hand back the quotas that are currently in use, and how many nodes each chain has
+/// against its own, as JSON.
+async fn return_quotas(aggregator: AggregatorSet) -> Response<hyper::Body> {
+    let quotas = match aggregator.gather_quotas().await {
+        Ok(quotas) => quotas,
+        Err(e) => {
+            log::error!("Couldn't obtain the current quotas: {}", e);
+            return Response::builder()
+                .status(500)
+                .body("Couldn't obtain the current quotas".into())
+                .unwrap();
+        }
+    };
+
+    Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(serde_json::to_vec(&quotas).unwrap().into())
+        .unwrap()
+}
+
+/// Hand back everything we know about every chain and node, as a versioned bincode blob
+/// (see [`state::StateExport`]), for offline analysis.
+async fn return_state_export(aggregator: AggregatorSet) -> Response<hyper::Body> {
+    let bytes = match aggregator
+        .gather_export()
+        .await
+        .and_then(|export| export.to_bytes())
+    {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            log::error!("Couldn't export the current state: {}", e);
+            return Response::builder()
+                .status(500)
+                .body("Couldn't export the current state".into())
+                .unwrap();
+        }
+    };
+
+    Response::builder()
+        .header(http::header::CONTENT_TYPE, "application/octet-stream")
+        .header(
+            http::header::CONTENT_DISPOSITION,
+            "attachment; filename=\"telemetry-state.bin\"",
+        )
+        .body(bytes.into())
+        .unwrap()
+}
+
+/// Disconnect the node given by the `chain=<genesis hash>` and `node=<id>` query parameters.
+/// The ID is the one that feeds (and the /state endpoint) know the node by.
+async fn disconnect_node(aggregator: AggregatorSet, query: Option<&str>) -> Response<hyper::Body> {
+    let genesis_hash = match query_param(query, "chain").map(BlockHash::from_str) {
+        Some(Ok(genesis_hash)) => genesis_hash,
+        _ => {
+            return Response::builder()
+                .status(400)
+                .body("Invalid or missing genesis hash given for 'chain'".into())
+                .unwrap()
+        }
+    };
+    let node_id = match query_param(query, "node").map(usize::from_str) {
+        Some(Ok(node_id)) => node_id,
+        _ => {
+            return Response::builder()
+                .status(400)
+                .body("Invalid or missing node ID given for 'node'".into())
+                .unwrap()
+        }
+    };
+
+    match aggregator.disconnect_node(genesis_hash, node_id).await {
+        Ok(true) => Response::new("OK".into()),
+        Ok(false) => Response::builder()
+            .status(404)
+            .body("No such node".into())
+            .unwrap(),
+        Err(e) => {
+            log::error!("Couldn't disconnect node: {}", e);
+            Response::builder()
+                .status(500)
+                .body("Couldn't disconnect node".into())
+                .unwrap()
+        }
+    }
+}
+
+/// Pause or resume updates to feeds subscribed to the chain given by the `chain=<genesis hash>`
+/// query parameter. While a chain is paused, its nodes are still kept up to date, and feeds
+/// are sent everything about the chain afresh once it's resumed.
+async fn set_chain_paused(
+    aggregator: AggregatorSet,
+    query: Option<&str>,
+    paused: bool,
+) -> Response<hyper::Body> {
+    let genesis_hash = match query_param(query, "chain").map(BlockHash::from_str) {
+        Some(Ok(genesis_hash)) => genesis_hash,
+        _ => {
+            return Response::builder()
+                .status(400)
+                .body("Invalid or missing genesis hash given for 'chain'".into())
+                .unwrap()
+        }
+    };
+
+    match aggregator.set_chain_paused(genesis_hash, paused).await {
+        Ok(true) => Response::new("OK".into()),
+        Ok(false) if paused => Response::builder()
+            .status(404)
+            .body("No such chain".into())
+            .unwrap(),
+        Ok(false) => Response::builder()
+            .status(404)
+            .body("Chain isn't paused".into())
+            .unwrap(),
+        Err(e) => {
+            log::error!("Couldn't pause or resume chain: {}", e);
+            Response::builder()
+                .status(500)
+                .body("Couldn't pause or resume chain".into())
+                .unwrap()
+        }
+    }
+}
+
+/// Disconnect the shard given by the `shard=<id>` query parameter. Shard connection IDs
+/// are logged when shards connect.
+async fn disconnect_shard(aggregator: AggregatorSet, query: Option<&str>) -> Response<hyper::Body> {
+    let shard_conn_id = match query_param(query, "shard").map(u64::from_str) {
+        Some(Ok(shard_conn_id)) => shard_conn_id,
+        _ => {
+            return Response::builder()
+                .status(400)
+                .body("Invalid or missing shard ID given for 'shard'".into())
+                .unwrap()
+        }
+    };
+
+    match aggregator.disconnect_shard(shard_conn_id.into()).await {
+        Ok(true) => Response::new("OK".into()),
+        Ok(false) => Response::builder()
+            .status(404)
+            .body("No such shard".into())
+            .unwrap(),
+        Err(e) => {
+            log::error!("Couldn't disconnect shard: {}", e);
+            Response::builder()
+                .status(500)
+                .body("Couldn't disconnect shard".into())
+                .unwrap()
+        }
+    }
+}
+
+async fn return_prometheus_metrics(
+    aggregator: AggregatorSet,
+    feed_flush_metrics: &FeedFlushMetrics,
+) -> Response<hyper::Body> {
     let metrics = aggregator.latest_metrics();
 
     // Instead of using the rust prometheus library (which is optimised around global variables updated across a codebase),
@@ -529,6 +2032,26 @@ async fn return_prometheus_metrics(aggregator: AggregatorSet) -> Response<hyper:
             "telemetry_core_connected_shards{{aggregator=\"{}\"}} {} {}\n",
             idx, m.connected_shards, m.timestamp_unix_ms
         );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_connected_chains{{aggregator=\"{}\"}} {} {}",
+            idx, m.connected_chains, m.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_estimated_state_bytes{{aggregator=\"{}\"}} {} {}",
+            idx, m.estimated_state_bytes, m.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_nodes_over_chain_limit{{aggregator=\"{}\"}} {} {}",
+            idx, m.nodes_over_chain_limit, m.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_queued_node_additions{{aggregator=\"{}\"}} {} {}",
+            idx, m.queued_node_additions, m.timestamp_unix_ms
+        );
         let _ = write!(
             &mut s,
             "telemetry_core_chains_subscribed_to{{aggregator=\"{}\"}} {} {}\n",
@@ -559,11 +2082,179 @@ async fn return_prometheus_metrics(aggregator: AggregatorSet) -> Response<hyper:
             "telemetry_core_dropped_messages_to_aggregator{{aggregator=\"{}\"}} {} {}\n\n",
             idx, m.dropped_messages_to_aggregator, m.timestamp_unix_ms
         );
+        let _ = write!(
+            &mut s,
+            "telemetry_core_dropped_feeds{{aggregator=\"{}\"}} {} {}\n\n",
+            idx, m.dropped_feeds, m.timestamp_unix_ms
+        );
+        let _ = write!(
+            &mut s,
+            "telemetry_core_dropped_feed_sink_messages{{aggregator=\"{}\"}} {} {}\n\n",
+            idx, m.dropped_feed_sink_messages, m.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_location_cache_hits{{aggregator=\"{}\"}} {} {}",
+            idx, m.locator.cache_hits, m.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_location_cache_misses{{aggregator=\"{}\"}} {} {}",
+            idx, m.locator.cache_misses, m.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_location_cache_size{{aggregator=\"{}\"}} {} {}",
+            idx, m.locator.cache_size, m.timestamp_unix_ms
+        );
+        let _ = writeln!(
+            &mut s,
+            "telemetry_core_location_cache_evictions{{aggregator=\"{}\"}} {} {}",
+            idx, m.locator.cache_evictions, m.timestamp_unix_ms
+        );
+        for p in &m.locator.providers {
+            let labels = format!("aggregator=\"{}\",provider=\"{}\"", idx, p.name);
+            let _ = writeln!(
+                &mut s,
+                "telemetry_core_location_lookups_succeeded{{{}}} {} {}",
+                labels, p.successes, m.timestamp_unix_ms
+            );
+            let _ = writeln!(
+                &mut s,
+                "telemetry_core_location_lookups_failed{{{}}} {} {}",
+                labels, p.failures, m.timestamp_unix_ms
+            );
+            let _ = writeln!(
+                &mut s,
+                "telemetry_core_location_rate_limited{{{}}} {} {}",
+                labels, p.rate_limited, m.timestamp_unix_ms
+            );
+            write_histogram(
+                &mut s,
+                "telemetry_core_location_lookup_seconds",
+                &labels,
+                &p.latency,
+                m.timestamp_unix_ms,
+            );
+        }
+        for t in &m.node_message_timings {
+            let stages = [
+                ("queued", &t.queued),
+                ("state", &t.state),
+                ("feeds", &t.feeds),
+            ];
+            for (stage, histogram) in stages {
+                let labels = format!(
+                    "aggregator=\"{}\",payload=\"{}\",stage=\"{}\"",
+                    idx, t.payload, stage
+                );
+                write_histogram(
+                    &mut s,
+                    "telemetry_core_node_message_seconds",
+                    &labels,
+                    histogram,
+                    m.timestamp_unix_ms,
+                );
+            }
+        }
+        s.push('\n');
+    }
+
+    // Every aggregator knows about every node, so the churn in each chain is the same
+    // whichever aggregator we ask; just report it from the first one.
+    if let Some(m) = metrics.first() {
+        for churn in &m.chain_churn {
+            let labels = format!(
+                "genesis_hash=\"{:?}\",chain=\"{}\"",
+                churn.genesis_hash,
+                escape_label_value(&churn.label)
+            );
+            let _ = writeln!(
+                &mut s,
+                "telemetry_core_chain_nodes_added{{{}}} {} {}",
+                labels, churn.nodes_added, m.timestamp_unix_ms
+            );
+            let _ = writeln!(
+                &mut s,
+                "telemetry_core_chain_nodes_removed{{{}}} {} {}",
+                labels, churn.nodes_removed, m.timestamp_unix_ms
+            );
+            let _ = writeln!(
+                &mut s,
+                "telemetry_core_chain_nodes_over_quota{{{}}} {} {}",
+                labels, churn.nodes_over_quota, m.timestamp_unix_ms
+            );
+        }
     }
 
+    // How long feeds have been waiting between batches of messages. This isn't specific to
+    // any one aggregator:
+    write_histogram(
+        &mut s,
+        "telemetry_core_feed_flush_interval_seconds",
+        "",
+        &feed_flush_metrics.intervals(),
+        common::time::now(),
+    );
+
     Response::builder()
         // The version number here tells prometheus which version of the text format we're using:
         .header(http::header::CONTENT_TYPE, "text/plain; version=0.0.4")
         .body(s.into())
         .unwrap()
 }
+
+/// Chain labels come from nodes, so they need escaping before we can use them as label
+/// values in the prometheus text format.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Write out a histogram in the prometheus text format; each bucket is a separate
+/// sample with an "le" label, followed by the sum and count of observed values. The
+/// labels given can be empty.
+fn write_histogram(
+    s: &mut String,
+    name: &str,
+    labels: &str,
+    histogram: &common::histogram::Histogram,
+    timestamp_unix_ms: u64,
+) {
+    use std::fmt::Write;
+    let sep = if labels.is_empty() { "" } else { "," };
+    for (bound, count) in histogram.cumulative_buckets() {
+        let _ = writeln!(
+            s,
+            "{}_bucket{{{}{}le=\"{}\"}} {} {}",
+            name, labels, sep, bound, count, timestamp_unix_ms
+        );
+    }
+    let _ = writeln!(
+        s,
+        "{}_bucket{{{}{}le=\"+Inf\"}} {} {}",
+        name,
+        labels,
+        sep,
+        histogram.count(),
+        timestamp_unix_ms
+    );
+    let _ = writeln!(
+        s,
+        "{}_sum{{{}}} {} {}",
+        name,
+        labels,
+        histogram.sum(),
+        timestamp_unix_ms
+    );
+    let _ = writeln!(
+        s,
+        "{}_count{{{}}} {} {}",
+        name,
+        labels,
+        histogram.count(),
+        timestamp_unix_ms
+    );
+}
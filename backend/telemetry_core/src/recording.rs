@@ -0,0 +1,330 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Record the messages that shards send us to a file, so that they can be replayed against
+//! a fresh [`State`](crate::state::State) in tests to reproduce what happened. Recordings
+//! are only made when built with the "recording" feature, and replayed only in tests.
+
+use crate::aggregator::FromShardWebsocket;
+use common::internal_messages::FromShardAggregator;
+use serde::{Deserialize, Serialize};
+use std::io::Write;
+use std::path::Path;
+use std::time::Instant;
+
+/// Something that happened on a shard connection, as written to a recording. Each is
+/// written as a line of JSON.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RecordedMessage {
+    /// The ID of the shard connection that this happened on.
+    pub shard: u64,
+    /// How long after the recording started this happened, in ms.
+    pub at: u64,
+    /// What happened.
+    pub event: ShardEvent,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[allow(clippy::large_enum_variant)]
+pub enum ShardEvent {
+    /// The shard sent us a message.
+    Message(FromShardAggregator),
+    /// The shard disconnected, taking its nodes with it.
+    Disconnected,
+}
+
+/// Writes the messages that shards send us to a file. This can be cloned and handed to
+/// each shard connection; the file is written to from a thread of its own.
+#[derive(Clone, Debug)]
+pub struct ShardRecorder {
+    tx: flume::Sender<RecordedMessage>,
+    started: Instant,
+}
+
+impl ShardRecorder {
+    /// Start recording to the file given, replacing anything already in it.
+    pub fn create(path: &Path) -> anyhow::Result<ShardRecorder> {
+        let mut file = std::io::BufWriter::new(std::fs::File::create(path)?);
+        let (tx, rx) = flume::unbounded::<RecordedMessage>();
+        let path = path.to_owned();
+
+        // This ends once every recorder is dropped:
+        std::thread::spawn(move || {
+            while let Ok(msg) = rx.recv() {
+                let res = serde_json::to_writer(&mut file, &msg)
+                    .map_err(anyhow::Error::from)
+                    .and_then(|()| Ok(file.write_all(b"\n")?))
+                    // Write everything we have so far before waiting for more:
+                    .and_then(|()| {
+                        if rx.is_empty() {
+                            Ok(file.flush()?)
+                        } else {
+                            Ok(())
+                        }
+                    });
+                if let Err(e) = res {
+                    log::error!(
+                        "Stopped recording shard messages to {}: {}",
+                        path.display(),
+                        e
+                    );
+                    return;
+                }
+            }
+        });
+
+        Ok(ShardRecorder {
+            tx,
+            started: Instant::now(),
+        })
+    }
+
+    /// Record a message that's been sent on the shard connection given. Only messages
+    /// which come from shards themselves are recorded.
+    pub fn record(&self, shard: u64, msg: &FromShardWebsocket) {
+        let event = match msg.clone() {
+            FromShardWebsocket::Add {
+                local_id,
+                ip,
+                node,
+                genesis_hash,
+            } => ShardEvent::Message(FromShardAggregator::AddNode {
+                ip,
+                node,
+                local_id,
+                genesis_hash,
+            }),
+            FromShardWebsocket::Update { local_id, payload } => {
+                ShardEvent::Message(FromShardAggregator::UpdateNode { local_id, payload })
+            }
+            FromShardWebsocket::Remove { local_id } => {
+                ShardEvent::Message(FromShardAggregator::RemoveNode { local_id })
+            }
+            FromShardWebsocket::Disconnected => ShardEvent::Disconnected,
+            FromShardWebsocket::Initialize { .. } | FromShardWebsocket::Located { .. } => return,
+        };
+        let _ = self.tx.send(RecordedMessage {
+            shard,
+            at: self.started.elapsed().as_millis() as u64,
+            event,
+        });
+    }
+}
+
+#[cfg(test)]
+pub use replay::{read, Replay};
+
+#[cfg(test)]
+mod replay {
+    use super::{RecordedMessage, ShardEvent};
+    use crate::feed_message::FeedMessageSerializer;
+    use crate::state::{AddNodeResult, NodeId, State, StateOpts};
+    use bimap::BiMap;
+    use common::internal_messages::{FromShardAggregator, ShardNodeId};
+    use std::io::BufRead;
+    use std::path::Path;
+    use std::time::{Duration, Instant};
+
+    /// Read back a recording made by a [`ShardRecorder`](super::ShardRecorder).
+    pub fn read(path: &Path) -> anyhow::Result<Vec<RecordedMessage>> {
+        let file = std::io::BufReader::new(std::fs::File::open(path)?);
+        let mut messages = Vec::new();
+        for (idx, line) in file.lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let msg = serde_json::from_str(&line)
+                .map_err(|e| anyhow::anyhow!("line {}: {}", idx + 1, e))?;
+            messages.push(msg);
+        }
+        Ok(messages)
+    }
+
+    /// Replays recorded shard messages against a fresh [`State`], in the same way that the
+    /// aggregator would have handled them (though without locating nodes, or anything to do
+    /// with feeds). This lets tests reproduce what happened from a recording, or from
+    /// messages written by hand.
+    pub struct Replay {
+        state: State,
+        node_ids: BiMap<NodeId, (u64, ShardNodeId)>,
+        started: Instant,
+    }
+
+    impl Replay {
+        pub fn new(opts: StateOpts) -> Self {
+            Replay {
+                state: State::new(opts),
+                node_ids: BiMap::new(),
+                started: Instant::now(),
+            }
+        }
+
+        /// The state, as it is after the messages replayed so far.
+        pub fn state(&self) -> &State {
+            &self.state
+        }
+
+        /// The ID that a node was given when it was added by a shard, if it's still around.
+        pub fn node_id(&self, shard: u64, local_id: ShardNodeId) -> Option<NodeId> {
+            self.node_ids.get_by_right(&(shard, local_id)).copied()
+        }
+
+        /// Replay each of the messages given, in order.
+        pub fn replay<I: IntoIterator<Item = RecordedMessage>>(&mut self, messages: I) {
+            for msg in messages {
+                self.apply(msg);
+            }
+        }
+
+        /// Replay a single message, handing back any feed messages that it led to.
+        pub fn apply(&mut self, msg: RecordedMessage) -> FeedMessageSerializer {
+            let mut feed = FeedMessageSerializer::new();
+            let now = self.started + Duration::from_millis(msg.at);
+            match msg.event {
+                ShardEvent::Message(FromShardAggregator::AddNode {
+                    node,
+                    local_id,
+                    genesis_hash,
+                    ..
+                }) => {
+                    if let AddNodeResult::NodeAddedToChain(details) =
+                        self.state.add_node(genesis_hash, node)
+                    {
+                        let node_id = details.id;
+                        if let Some(replaced_node_id) = details.replaced_node_id {
+                            self.node_ids.remove_by_left(&replaced_node_id);
+                        }
+                        self.node_ids.insert(node_id, (msg.shard, local_id));
+                    }
+                }
+                ShardEvent::Message(FromShardAggregator::UpdateNode { local_id, payload }) => {
+                    if let Some(node_id) = self.node_id(msg.shard, local_id) {
+                        self.state.update_node(node_id, payload, &mut feed, now);
+                    }
+                }
+                ShardEvent::Message(FromShardAggregator::RemoveNode { local_id }) => {
+                    if let Some((node_id, _)) =
+                        self.node_ids.remove_by_right(&(msg.shard, local_id))
+                    {
+                        self.state.remove_node(node_id);
+                    }
+                }
+                ShardEvent::Disconnected => {
+                    let node_ids: Vec<NodeId> = self
+                        .node_ids
+                        .iter()
+                        .filter(|(_, &(shard, _))| shard == msg.shard)
+                        .map(|(&node_id, _)| node_id)
+                        .collect();
+                    for node_id in node_ids {
+                        self.node_ids.remove_by_left(&node_id);
+                        self.state.remove_node(node_id);
+                    }
+                }
+            }
+            feed
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::StateOpts;
+    use crate::test_helpers::node_details;
+    use common::internal_messages::ShardNodeId;
+    use common::node_message::Payload;
+    use common::node_types::{Block, BlockHash, NodeDetails};
+
+    fn node(name: &str) -> NodeDetails {
+        NodeDetails {
+            implementation: "Substrate".into(),
+            version: "1.0.0".into(),
+            ..node_details(name)
+        }
+    }
+
+    fn add(local_id: usize, name: &str) -> FromShardWebsocket {
+        FromShardWebsocket::Add {
+            local_id: ShardNodeId::from(local_id),
+            ip: "127.0.0.1".parse().unwrap(),
+            node: node(name),
+            genesis_hash: BlockHash::from_low_u64_be(1),
+        }
+    }
+
+    fn import(local_id: usize, height: u64) -> FromShardWebsocket {
+        FromShardWebsocket::Update {
+            local_id: ShardNodeId::from(local_id),
+            payload: Payload::BlockImport(Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            }),
+        }
+    }
+
+    #[test]
+    fn recorded_messages_can_be_replayed() {
+        let path = std::env::temp_dir().join(format!(
+            "telemetry_shard_recording_{}.jsonl",
+            std::process::id()
+        ));
+
+        // Two shards each tell us about a node, and then one of them goes away:
+        let recorder = ShardRecorder::create(&path).unwrap();
+        recorder.record(1, &add(0, "Alice"));
+        recorder.record(2, &add(0, "Bob"));
+        recorder.record(1, &import(0, 10));
+        recorder.record(2, &import(0, 12));
+        recorder.record(
+            2,
+            &FromShardWebsocket::Located {
+                local_id: ShardNodeId::from(0),
+                location: None,
+            },
+        );
+        recorder.record(2, &FromShardWebsocket::Disconnected);
+        drop(recorder);
+
+        // Wait for everything to be written:
+        let mut messages = Vec::new();
+        for _ in 0..100 {
+            messages = read(&path).unwrap();
+            if messages.len() == 5 {
+                break;
+            }
+            std::thread::sleep(std::time::Duration::from_millis(10));
+        }
+        assert_eq!(messages.len(), 5, "locations aren't recorded");
+
+        let mut replay = Replay::new(StateOpts::default());
+        replay.replay(messages);
+
+        // Only the node on the shard that's still connected is left:
+        assert_eq!(replay.state().node_count(), 1);
+        assert!(replay.node_id(2, ShardNodeId::from(0)).is_none());
+        let node_id = replay.node_id(1, ShardNodeId::from(0)).unwrap();
+        let chain = replay.state().get_chain_by_node_id(node_id).unwrap();
+        let node = chain.nodes_slice()[usize::from(node_id.get_chain_node_id())]
+            .as_ref()
+            .unwrap();
+        assert_eq!(node.details().name.as_ref(), "Alice");
+        assert_eq!(node.best().height, 10);
+
+        let _ = std::fs::remove_file(&path);
+    }
+}
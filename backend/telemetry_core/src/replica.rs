@@ -0,0 +1,624 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! A core can mirror the chains and nodes of another ("upstream") core by connecting to it
+//! like any other feed would, rather than having shards connect to it. This lets us serve
+//! feeds from more places without every node having to send telemetry to each of them.
+//!
+//! We follow the list of chains over one connection, and the nodes on each chain over a
+//! connection of their own, since feeds can only subscribe to one chain at a time. What we
+//! hear about the nodes on a chain is turned into the messages that a shard would send us
+//! about them, so the aggregators don't need to treat mirrored nodes any differently.
+
+use crate::aggregator::{AggregatorSet, FromShardWebsocket};
+use common::internal_messages::ShardNodeId;
+use common::node_message::{Finalized, Payload, SystemInterval, SystemVersion};
+use common::node_types::{
+    Block, BlockDetails, BlockHash, BlockNumber, NetworkId, NodeDetails, NodeLocation,
+    NodeResourceUsage, NodeStats, SyncMethod, Timestamp,
+};
+use common::ws_client;
+use futures::{SinkExt, StreamExt};
+use serde_json::value::RawValue;
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long to wait before connecting to the upstream core again after losing it.
+const RECONNECT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Mirror the chains and nodes of the upstream core whose feed is at the URI given into
+/// our own aggregators, reconnecting whenever we lose the connection to it.
+pub async fn mirror(upstream: http::Uri, aggregator: AggregatorSet) {
+    loop {
+        if let Err(e) = mirror_chains(&upstream, &aggregator).await {
+            log::warn!(
+                "Lost connection to upstream core at {} (will reconnect): {:#}",
+                upstream,
+                e
+            );
+        }
+        tokio::time::sleep(RECONNECT_INTERVAL).await;
+    }
+}
+
+/// Follow the chains that the upstream core knows about, mirroring the nodes on each of them
+/// until it tells us that the chain has gone, or we lose our connection to it.
+async fn mirror_chains(upstream: &http::Uri, aggregator: &AggregatorSet) -> anyhow::Result<()> {
+    let connection = ws_client::connect(upstream).await?;
+    // The connection is closed when the sender is dropped, so hold on to it:
+    let (_tx_to_upstream, mut rx_from_upstream) = connection.into_channels();
+    log::info!("Mirroring chains from upstream core at {}", upstream);
+
+    // Each chain is mirrored until we drop the sender that we hold for it:
+    let mut chains: HashMap<BlockHash, flume::Sender<()>> = HashMap::new();
+    while let Some(msg) = rx_from_upstream.next().await {
+        // A chain that's relabelled is removed and then added again in the same batch of
+        // messages, and we don't want to stop mirroring it when that happens:
+        let mut removed = Vec::new();
+        for msg in UpstreamMessage::from_bytes(&message_bytes(msg?))? {
+            match msg {
+                UpstreamMessage::AddedChain {
+                    label,
+                    genesis_hash,
+                } => {
+                    removed.retain(|hash| *hash != genesis_hash);
+                    chains.entry(genesis_hash).or_insert_with(|| {
+                        let (stop_tx, stop_rx) = flume::bounded(1);
+                        let mirror = ChainMirror {
+                            genesis_hash,
+                            label,
+                        };
+                        tokio::spawn(mirror_chain(
+                            upstream.clone(),
+                            aggregator.clone(),
+                            mirror,
+                            stop_rx,
+                        ));
+                        stop_tx
+                    });
+                }
+                UpstreamMessage::RemovedChain { genesis_hash } => removed.push(genesis_hash),
+                _ => {}
+            }
+        }
+        for genesis_hash in removed {
+            chains.remove(&genesis_hash);
+        }
+    }
+
+    Err(anyhow::anyhow!("the upstream core closed the connection"))
+}
+
+/// Mirror the nodes on one chain until we're told to stop (by the sender for `stop` being
+/// dropped). The nodes are removed from our aggregators whenever we lose the connection
+/// to the upstream core, and added again once we've reconnected.
+async fn mirror_chain(
+    upstream: http::Uri,
+    aggregator: AggregatorSet,
+    mirror: ChainMirror,
+    stop: flume::Receiver<()>,
+) {
+    loop {
+        // Each connection looks like a new shard to the aggregators, so that they forget
+        // about all of the nodes we'd mirrored so far when it goes away:
+        let (_, mut tx_to_aggregator) = aggregator.subscribe_shard();
+        let result = tokio::select! {
+            result = mirror_chain_once(&upstream, &mirror, &mut tx_to_aggregator) => result,
+            _ = stop.recv_async() => Ok(()),
+        };
+        let _ = tx_to_aggregator
+            .send(FromShardWebsocket::Disconnected)
+            .await;
+
+        if stop.is_disconnected() {
+            return;
+        }
+        if let Err(e) = result {
+            log::warn!(
+                "Lost upstream connection for chain {:?} (will reconnect): {:#}",
+                mirror.genesis_hash,
+                e
+            );
+        }
+        tokio::time::sleep(RECONNECT_INTERVAL).await;
+    }
+}
+
+async fn mirror_chain_once(
+    upstream: &http::Uri,
+    mirror: &ChainMirror,
+    tx_to_aggregator: &mut (impl futures::Sink<FromShardWebsocket, Error = anyhow::Error> + Unpin),
+) -> anyhow::Result<()> {
+    let connection = ws_client::connect(upstream).await?;
+    let (tx_to_upstream, mut rx_from_upstream) = connection.into_channels();
+    tx_to_upstream.unbounded_send(ws_client::SentMessage::Text(format!(
+        "subscribe:{:?}",
+        mirror.genesis_hash
+    )))?;
+
+    // We never have anything to say back to the upstream core, so nothing that the
+    // aggregators send back about these nodes (like asking us to mute one) is needed:
+    let (channel, _) = flume::unbounded();
+    tx_to_aggregator
+        .send(FromShardWebsocket::Initialize {
+            channel,
+            addr: None,
+        })
+        .await?;
+
+    while let Some(msg) = rx_from_upstream.next().await {
+        for msg in UpstreamMessage::from_bytes(&message_bytes(msg?))? {
+            for msg in mirror.to_shard_messages(msg) {
+                tx_to_aggregator.send(msg).await?;
+            }
+        }
+    }
+
+    Err(anyhow::anyhow!("the upstream core closed the connection"))
+}
+
+fn message_bytes(msg: ws_client::RecvMessage) -> Vec<u8> {
+    match msg {
+        ws_client::RecvMessage::Binary(bytes) => bytes,
+        ws_client::RecvMessage::Text(s) => s.into_bytes(),
+    }
+}
+
+/// Turns what an upstream feed tells us about the nodes on a chain into the messages that a
+/// shard would send about them. Nodes are known to the shard by the IDs that the upstream
+/// core gave them, which are unique to the chain.
+struct ChainMirror {
+    genesis_hash: BlockHash,
+    label: Box<str>,
+}
+
+impl ChainMirror {
+    fn to_shard_messages(&self, msg: UpstreamMessage) -> Vec<FromShardWebsocket> {
+        let update = |node_id: usize, payload| FromShardWebsocket::Update {
+            local_id: ShardNodeId::from(node_id),
+            payload,
+        };
+        let interval =
+            |node_id: usize, interval| update(node_id, Payload::SystemInterval(interval));
+
+        match msg {
+            UpstreamMessage::AddedNode {
+                node_id,
+                details,
+                stats,
+                block_details,
+                location,
+            } => {
+                let mut msgs = vec![FromShardWebsocket::Add {
+                    local_id: ShardNodeId::from(node_id),
+                    // There's no address for us to look up; the upstream core locates the node:
+                    ip: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+                    node: NodeDetails {
+                        chain: self.label.clone(),
+                        ..*details
+                    },
+                    genesis_hash: self.genesis_hash,
+                }];
+                if block_details.block.height > 0 {
+                    msgs.push(update(node_id, Payload::BlockImport(block_details.block)));
+                }
+                msgs.push(interval(node_id, stats_interval(stats)));
+                if let Some(location) = location {
+                    msgs.push(FromShardWebsocket::Located {
+                        local_id: ShardNodeId::from(node_id),
+                        location: Some(Arc::new(location)),
+                    });
+                }
+                msgs
+            }
+            UpstreamMessage::RemovedNode { node_id } => vec![FromShardWebsocket::Remove {
+                local_id: ShardNodeId::from(node_id),
+            }],
+            UpstreamMessage::LocatedNode { node_id, location } => {
+                vec![FromShardWebsocket::Located {
+                    local_id: ShardNodeId::from(node_id),
+                    location: Some(Arc::new(location)),
+                }]
+            }
+            UpstreamMessage::ImportedBlock { node_id, block } => {
+                vec![update(node_id, Payload::BlockImport(block))]
+            }
+            UpstreamMessage::FinalizedBlock { node_id, block } => vec![update(
+                node_id,
+                Payload::NotifyFinalized(Finalized {
+                    hash: block.hash,
+                    height: block.height.to_string().into(),
+                }),
+            )],
+            UpstreamMessage::NodeStatsUpdate { node_id, stats } => {
+                vec![interval(node_id, stats_interval(stats))]
+            }
+            UpstreamMessage::NodeResourceUsageUpdate { node_id, usage } => vec![interval(
+                node_id,
+                SystemInterval {
+                    cpu: usage.cpu,
+                    memory: usage.memory,
+                    disk_usage: usage.disk_usage,
+                    ..Default::default()
+                },
+            )],
+            UpstreamMessage::NodeSyncState { node_id, target } => vec![interval(
+                node_id,
+                SystemInterval {
+                    target_height: Some(target),
+                    ..Default::default()
+                },
+            )],
+            UpstreamMessage::NodeVersionInfo {
+                node_id,
+                version,
+                spec_version,
+            } => vec![update(
+                node_id,
+                Payload::SystemVersion(SystemVersion {
+                    version,
+                    spec_version,
+                }),
+            )],
+            UpstreamMessage::NodeSyncMethod {
+                node_id,
+                sync_method,
+            } => vec![interval(
+                node_id,
+                SystemInterval {
+                    sync_method: Some(sync_method),
+                    ..Default::default()
+                },
+            )],
+            UpstreamMessage::AddedChain { .. } | UpstreamMessage::RemovedChain { .. } => Vec::new(),
+        }
+    }
+}
+
+fn stats_interval(stats: NodeStats) -> SystemInterval {
+    SystemInterval {
+        peers: Some(stats.peers),
+        txcount: Some(stats.txcount),
+        ..Default::default()
+    }
+}
+
+/// The messages from an upstream feed that we need in order to mirror its chains and nodes.
+/// Anything else (like the bandwidth charts that we can't decode losslessly, or the stats
+/// that our aggregators work out for themselves) is ignored.
+#[derive(Debug)]
+enum UpstreamMessage {
+    AddedChain {
+        label: Box<str>,
+        genesis_hash: BlockHash,
+    },
+    RemovedChain {
+        genesis_hash: BlockHash,
+    },
+    AddedNode {
+        node_id: usize,
+        details: Box<NodeDetails>,
+        stats: NodeStats,
+        block_details: BlockDetails,
+        location: Option<NodeLocation>,
+    },
+    RemovedNode {
+        node_id: usize,
+    },
+    LocatedNode {
+        node_id: usize,
+        location: NodeLocation,
+    },
+    ImportedBlock {
+        node_id: usize,
+        block: Block,
+    },
+    FinalizedBlock {
+        node_id: usize,
+        block: Block,
+    },
+    NodeStatsUpdate {
+        node_id: usize,
+        stats: NodeStats,
+    },
+    NodeResourceUsageUpdate {
+        node_id: usize,
+        usage: NodeResourceUsage,
+    },
+    NodeSyncState {
+        node_id: usize,
+        target: BlockNumber,
+    },
+    NodeVersionInfo {
+        node_id: usize,
+        version: Box<str>,
+        spec_version: Option<u32>,
+    },
+    NodeSyncMethod {
+        node_id: usize,
+        sync_method: SyncMethod,
+    },
+}
+
+/// How the upstream core describes a node that's been added, leaving undecoded anything that
+/// we don't need (or can't decode losslessly). See [`crate::feed_message::AddedNode`].
+type AddedNodeValue<'a> = (
+    usize,
+    (
+        Box<str>,
+        Box<str>,
+        Box<str>,
+        Option<Box<str>>,
+        NetworkId,
+        bool,
+        Option<u32>,
+        Option<Box<str>>,
+        Option<Box<str>>,
+    ),
+    NodeStats,
+    &'a RawValue,
+    &'a RawValue,
+    BlockDetails,
+    Option<NodeLocation>,
+    Option<Timestamp>,
+    &'a RawValue,
+    &'a RawValue,
+);
+
+impl UpstreamMessage {
+    /// Decode a batch of feed messages, which alternate between action and value.
+    fn from_bytes(bytes: &[u8]) -> anyhow::Result<Vec<UpstreamMessage>> {
+        let values: Vec<&RawValue> = serde_json::from_slice(bytes)?;
+        let mut msgs = Vec::new();
+        for pair in values.chunks(2) {
+            let (action, value) = match pair {
+                [action, value] => (action, value),
+                _ => return Err(anyhow::anyhow!("feed message has an action but no value")),
+            };
+            let action: u8 = serde_json::from_str(action.get())?;
+            let msg = UpstreamMessage::decode(action, value.get()).map_err(|e| {
+                anyhow::anyhow!(
+                    "failed to decode feed message with action {}: {}",
+                    action,
+                    e
+                )
+            })?;
+            msgs.extend(msg);
+        }
+        Ok(msgs)
+    }
+
+    fn decode(action: u8, value: &str) -> Result<Option<UpstreamMessage>, serde_json::Error> {
+        let msg = match action {
+            // AddedNode
+            3 => {
+                let (
+                    node_id,
+                    (
+                        name,
+                        implementation,
+                        version,
+                        validator,
+                        network_id,
+                        authority,
+                        spec_version,
+                        operator,
+                        // We work out which group each node is in ourselves:
+                        _group,
+                    ),
+                    stats,
+                    _io,
+                    _hardware,
+                    block_details,
+                    location,
+                    startup_time,
+                    _connected_at,
+                    _last_seen,
+                ): AddedNodeValue = serde_json::from_str(value)?;
+                UpstreamMessage::AddedNode {
+                    node_id,
+                    details: Box::new(NodeDetails {
+                        // Every node on the chain is given the chain's label when it's added:
+                        chain: "".into(),
+                        name,
+                        implementation,
+                        version,
+                        spec_version,
+                        validator,
+                        authority,
+                        operator,
+                        network_id,
+                        startup_time: startup_time.map(|t| t.to_string().into()),
+                        target_os: None,
+                        target_arch: None,
+                        target_env: None,
+                        sysinfo: None,
+                        sync_method: None,
+                    }),
+                    stats,
+                    block_details,
+                    location,
+                }
+            }
+            // RemovedNode
+            4 => UpstreamMessage::RemovedNode {
+                node_id: serde_json::from_str(value)?,
+            },
+            // LocatedNode
+            5 => {
+                let (node_id, latitude, longitude, city, asn, network) =
+                    serde_json::from_str(value)?;
+                UpstreamMessage::LocatedNode {
+                    node_id,
+                    location: NodeLocation {
+                        latitude,
+                        longitude,
+                        city,
+                        asn,
+                        network,
+                        source: None,
+                    },
+                }
+            }
+            // ImportedBlock
+            6 => {
+                let (node_id, block_details): (_, BlockDetails) = serde_json::from_str(value)?;
+                UpstreamMessage::ImportedBlock {
+                    node_id,
+                    block: block_details.block,
+                }
+            }
+            // FinalizedBlock
+            7 => {
+                let (node_id, height, hash) = serde_json::from_str(value)?;
+                UpstreamMessage::FinalizedBlock {
+                    node_id,
+                    block: Block { hash, height },
+                }
+            }
+            // NodeStatsUpdate
+            8 => {
+                let (node_id, stats) = serde_json::from_str(value)?;
+                UpstreamMessage::NodeStatsUpdate { node_id, stats }
+            }
+            // AddedChain
+            11 => {
+                let (label, genesis_hash, _node_count): (_, _, usize) =
+                    serde_json::from_str(value)?;
+                UpstreamMessage::AddedChain {
+                    label,
+                    genesis_hash,
+                }
+            }
+            // RemovedChain
+            12 => UpstreamMessage::RemovedChain {
+                genesis_hash: serde_json::from_str(value)?,
+            },
+            // NodeResourceUsageUpdate
+            24 => {
+                let (node_id, usage) = serde_json::from_str(value)?;
+                UpstreamMessage::NodeResourceUsageUpdate { node_id, usage }
+            }
+            // NodeSyncState
+            27 => {
+                let (node_id, _current, target): (_, BlockNumber, _) = serde_json::from_str(value)?;
+                UpstreamMessage::NodeSyncState { node_id, target }
+            }
+            // NodeVersionInfo
+            31 => {
+                let (node_id, version, spec_version) = serde_json::from_str(value)?;
+                UpstreamMessage::NodeVersionInfo {
+                    node_id,
+                    version,
+                    spec_version,
+                }
+            }
+            // NodeSyncMethod
+            38 => {
+                let (node_id, sync_method) = serde_json::from_str(value)?;
+                UpstreamMessage::NodeSyncMethod {
+                    node_id,
+                    sync_method,
+                }
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(msg))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::feed_message::{self, FeedMessageSerializer};
+    use crate::state::Node;
+    use crate::test_helpers::node_details;
+
+    #[test]
+    fn upstream_feed_messages_become_shard_messages() {
+        let mut node = Node::new(NodeDetails {
+            chain: "Upstream Chain Name".into(),
+            implementation: "Substrate Node".into(),
+            spec_version: Some(9),
+            authority: true,
+            ..node_details("Alice")
+        });
+        node.update_block(Block {
+            hash: BlockHash::from_low_u64_be(10),
+            height: 10,
+        });
+
+        let mut feed = FeedMessageSerializer::new();
+        feed.push(feed_message::AddedChain(
+            "Local Testnet",
+            BlockHash::from_low_u64_be(1),
+            1,
+        ));
+        feed.push(feed_message::AddedNode(3, &node));
+        feed.push(feed_message::FinalizedBlock(
+            3,
+            8,
+            BlockHash::from_low_u64_be(8),
+        ));
+        feed.push(feed_message::RemovedNode(3));
+        let bytes = feed.into_finalized().unwrap();
+
+        let mirror = ChainMirror {
+            genesis_hash: BlockHash::from_low_u64_be(1),
+            label: "Local Testnet".into(),
+        };
+        let msgs: Vec<_> = UpstreamMessage::from_bytes(&bytes)
+            .unwrap()
+            .into_iter()
+            .flat_map(|msg| mirror.to_shard_messages(msg))
+            .collect();
+
+        let local_id = ShardNodeId::from(3);
+        match &msgs[..] {
+            [FromShardWebsocket::Add {
+                local_id: added_id,
+                ip,
+                node,
+                genesis_hash,
+            }, FromShardWebsocket::Update {
+                payload: Payload::BlockImport(block),
+                ..
+            }, FromShardWebsocket::Update {
+                payload: Payload::SystemInterval(_),
+                ..
+            }, FromShardWebsocket::Update {
+                payload: Payload::NotifyFinalized(finalized),
+                ..
+            }, FromShardWebsocket::Remove {
+                local_id: removed_id,
+            }] => {
+                assert_eq!(*added_id, local_id);
+                assert!(ip.is_unspecified());
+                assert_eq!(&*node.chain, "Local Testnet");
+                assert_eq!(&*node.name, "Alice");
+                assert_eq!(node.spec_version, Some(9));
+                assert!(node.authority);
+                assert_eq!(*genesis_hash, BlockHash::from_low_u64_be(1));
+                assert_eq!(block.height, 10);
+                assert_eq!(&*finalized.height, "8");
+                assert_eq!(*removed_id, local_id);
+            }
+            msgs => panic!("unexpected shard messages: {:?}", msgs),
+        }
+    }
+}
@@ -0,0 +1,213 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use bincode::Options;
+use common::internal_messages::{FromShardAggregator, ShardNodeId};
+use std::collections::HashSet;
+
+/// How many variants [`FromShardAggregator`] has. Shards encode the variant as
+/// its index, so anything at or above this is a message type that we don't know.
+const MESSAGE_TYPES: u32 = 3;
+
+/// Why a message from a shard was rejected.
+#[derive(thiserror::Error, Debug)]
+pub enum ShardMessageError {
+    #[error("Message of {size} bytes is larger than the {max} byte limit")]
+    TooLarge { size: usize, max: usize },
+    #[error("Unknown message type {0}")]
+    UnknownMessageType(u32),
+    #[error("Failed to decode message: {0}")]
+    Decode(#[from] bincode::Error),
+    #[error("Message refers to unknown node local ID {}", usize::from(*.0))]
+    UnknownLocalId(ShardNodeId),
+}
+
+impl ShardMessageError {
+    /// Can we carry on receiving messages from the shard after this? If a message
+    /// can't be decoded then we can't trust anything else that it sends us, but a
+    /// message about a node we don't know is harmless to skip.
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, ShardMessageError::UnknownLocalId(..))
+    }
+}
+
+/// Decodes the messages arriving on a single shard connection, keeping track of
+/// which nodes the shard has told us about so far.
+pub struct ShardMessageParser {
+    max_message_size: usize,
+    local_ids: HashSet<ShardNodeId>,
+}
+
+impl ShardMessageParser {
+    /// Reject any messages larger than `max_message_size` bytes once decompressed.
+    pub fn new(max_message_size: usize) -> Self {
+        ShardMessageParser {
+            max_message_size,
+            local_ids: HashSet::new(),
+        }
+    }
+
+    /// Decode the next message that the shard has sent us.
+    pub fn parse(&mut self, bytes: &[u8]) -> Result<FromShardAggregator, ShardMessageError> {
+        if bytes.len() > self.max_message_size {
+            return Err(ShardMessageError::TooLarge {
+                size: bytes.len(),
+                max: self.max_message_size,
+            });
+        }
+
+        let msg: FromShardAggregator = match bincode::options().deserialize(bytes) {
+            Ok(msg) => msg,
+            Err(e) => {
+                // Tell an unknown message type apart from a message that's just broken:
+                let message_type = bincode::options()
+                    .allow_trailing_bytes()
+                    .deserialize::<u32>(bytes);
+                return Err(match message_type {
+                    Ok(n) if n >= MESSAGE_TYPES => ShardMessageError::UnknownMessageType(n),
+                    _ => ShardMessageError::Decode(e),
+                });
+            }
+        };
+
+        match &msg {
+            FromShardAggregator::AddNode { local_id, .. } => {
+                self.local_ids.insert(*local_id);
+            }
+            FromShardAggregator::UpdateNode { local_id, .. } => {
+                if !self.local_ids.contains(local_id) {
+                    return Err(ShardMessageError::UnknownLocalId(*local_id));
+                }
+            }
+            FromShardAggregator::RemoveNode { local_id } => {
+                if !self.local_ids.remove(local_id) {
+                    return Err(ShardMessageError::UnknownLocalId(*local_id));
+                }
+            }
+        }
+
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::node_details;
+    use common::node_message::{Payload, SystemVersion};
+    use common::node_types::{BlockHash, NodeDetails};
+
+    fn encode(msg: &FromShardAggregator) -> Vec<u8> {
+        bincode::options().serialize(msg).unwrap()
+    }
+
+    fn add_node(local_id: usize) -> FromShardAggregator {
+        FromShardAggregator::AddNode {
+            ip: "127.0.0.1".parse().unwrap(),
+            node: NodeDetails {
+                chain: "Polkadot".into(),
+                implementation: "Substrate".into(),
+                version: "1.0.0".into(),
+                ..node_details("Node")
+            },
+            local_id: ShardNodeId::new(local_id),
+            genesis_hash: BlockHash::zero(),
+        }
+    }
+
+    #[test]
+    fn message_types_are_all_known() {
+        let last = FromShardAggregator::RemoveNode {
+            local_id: ShardNodeId::new(0),
+        };
+        let message_type: u32 = bincode::options()
+            .allow_trailing_bytes()
+            .deserialize(&encode(&last))
+            .unwrap();
+        assert_eq!(message_type, MESSAGE_TYPES - 1);
+    }
+
+    #[test]
+    fn known_nodes_can_be_updated_and_removed() {
+        let mut parser = ShardMessageParser::new(1024);
+        let update = FromShardAggregator::UpdateNode {
+            local_id: ShardNodeId::new(1),
+            payload: Payload::SystemVersion(SystemVersion {
+                version: "1.0.1".into(),
+                spec_version: None,
+            }),
+        };
+        let remove = FromShardAggregator::RemoveNode {
+            local_id: ShardNodeId::new(1),
+        };
+
+        assert!(parser.parse(&encode(&add_node(1))).is_ok());
+        assert!(parser.parse(&encode(&update)).is_ok());
+        assert!(parser.parse(&encode(&remove)).is_ok());
+
+        // Once removed, the node is unknown again:
+        let err = parser.parse(&encode(&update)).unwrap_err();
+        assert!(matches!(err, ShardMessageError::UnknownLocalId(id) if id == ShardNodeId::new(1)));
+        assert!(!err.is_fatal());
+    }
+
+    #[test]
+    fn unknown_local_ids_are_rejected() {
+        let mut parser = ShardMessageParser::new(1024);
+        parser.parse(&encode(&add_node(1))).unwrap();
+
+        let remove = FromShardAggregator::RemoveNode {
+            local_id: ShardNodeId::new(2),
+        };
+        let err = parser.parse(&encode(&remove)).unwrap_err();
+        assert!(matches!(err, ShardMessageError::UnknownLocalId(id) if id == ShardNodeId::new(2)));
+        assert!(!err.is_fatal());
+    }
+
+    #[test]
+    fn large_messages_are_rejected() {
+        let bytes = encode(&add_node(1));
+        let mut parser = ShardMessageParser::new(bytes.len() - 1);
+
+        let err = parser.parse(&bytes).unwrap_err();
+        assert!(matches!(
+            err,
+            ShardMessageError::TooLarge { size, max } if size == bytes.len() && max == bytes.len() - 1
+        ));
+        assert!(err.is_fatal());
+    }
+
+    #[test]
+    fn unknown_message_types_are_rejected() {
+        let mut parser = ShardMessageParser::new(1024);
+        let bytes = bincode::options().serialize(&(7u32, 1usize)).unwrap();
+
+        let err = parser.parse(&bytes).unwrap_err();
+        assert!(matches!(err, ShardMessageError::UnknownMessageType(7)));
+        assert!(err.is_fatal());
+    }
+
+    #[test]
+    fn broken_messages_are_rejected() {
+        let mut parser = ShardMessageParser::new(1024);
+        let mut bytes = encode(&add_node(1));
+        bytes.truncate(bytes.len() / 2);
+
+        let err = parser.parse(&bytes).unwrap_err();
+        assert!(matches!(err, ShardMessageError::Decode(..)));
+        assert!(err.is_fatal());
+    }
+}
@@ -0,0 +1,122 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::node_types::BlockNumber;
+
+/// Keeps track of how long the latest best block on a chain took to reach
+/// half, and then nine tenths, of the nodes on it.
+#[derive(Debug, Default)]
+pub struct BlockPropagation {
+    /// The height of the block that we're timing.
+    height: BlockNumber,
+    /// How many nodes we expect to import the block.
+    expected: usize,
+    /// How many nodes have imported it so far.
+    seen: usize,
+    /// Milliseconds until half of the expected nodes had imported the block.
+    p50: Option<u64>,
+    /// Milliseconds until nine tenths of the expected nodes had imported the block.
+    p90: Option<u64>,
+}
+
+impl BlockPropagation {
+    /// Start timing a new best block, which the first of `expected` nodes has just
+    /// imported. Returns true if this alone is enough to reach one of the percentiles.
+    pub fn start(&mut self, height: BlockNumber, expected: usize) -> bool {
+        *self = BlockPropagation {
+            height,
+            expected,
+            seen: 0,
+            p50: None,
+            p90: None,
+        };
+        self.record(height, 0)
+    }
+
+    /// Note that another node has imported a block at `height`, `elapsed` milliseconds
+    /// after the first one did. Returns true if we've reached a percentile that we
+    /// hadn't reached before.
+    pub fn record(&mut self, height: BlockNumber, elapsed: u64) -> bool {
+        if height != self.height || self.p90.is_some() {
+            return false;
+        }
+        self.seen += 1;
+
+        let mut changed = false;
+        if self.p50.is_none() && self.seen * 2 >= self.expected {
+            self.p50 = Some(elapsed);
+            changed = true;
+        }
+        if self.seen * 10 >= self.expected * 9 {
+            self.p90 = Some(elapsed);
+            changed = true;
+        }
+        changed
+    }
+
+    pub fn height(&self) -> BlockNumber {
+        self.height
+    }
+    pub fn p50(&self) -> Option<u64> {
+        self.p50
+    }
+    pub fn p90(&self) -> Option<u64> {
+        self.p90
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percentiles_are_recorded_as_nodes_see_the_block() {
+        let mut propagation = BlockPropagation::default();
+        assert!(!propagation.start(10, 10));
+        for n in 1..4 {
+            assert!(!propagation.record(10, n * 100));
+        }
+        // The fifth node to see the block:
+        assert!(propagation.record(10, 400));
+        assert_eq!((propagation.p50(), propagation.p90()), (Some(400), None));
+        for n in 5..8 {
+            assert!(!propagation.record(10, n * 100));
+        }
+        // The ninth one:
+        assert!(propagation.record(10, 800));
+        assert_eq!(
+            (propagation.p50(), propagation.p90()),
+            (Some(400), Some(800))
+        );
+        // Nothing more to report after that:
+        assert!(!propagation.record(10, 900));
+        assert_eq!(propagation.p90(), Some(800));
+    }
+
+    #[test]
+    fn blocks_at_other_heights_are_ignored() {
+        let mut propagation = BlockPropagation::default();
+        assert!(!propagation.start(10, 4));
+        assert!(!propagation.record(9, 100));
+        assert!(!propagation.record(11, 100));
+        assert!(propagation.record(10, 200));
+        assert_eq!((propagation.p50(), propagation.p90()), (Some(200), None));
+
+        // A lone node sees its own blocks straight away:
+        assert!(propagation.start(11, 1));
+        assert_eq!((propagation.p50(), propagation.p90()), (Some(0), Some(0)));
+    }
+}
@@ -16,7 +16,7 @@
 
 use common::node_message::Payload;
 use common::node_types::BlockHash;
-use common::node_types::{Block, Timestamp};
+use common::node_types::{Block, BlockNumber, Timestamp};
 use common::{id_type, time, DenseMap, MostSeen, NumStats};
 use once_cell::sync::Lazy;
 use std::collections::HashSet;
@@ -26,8 +26,11 @@ use std::time::{Duration, Instant};
 use crate::feed_message::{self, ChainStats, FeedMessageSerializer};
 use crate::find_location;
 
+use super::block_propagation::BlockPropagation;
 use super::chain_stats::ChainStatsCollator;
 use super::counter::CounterValue;
+use super::finalization_stall::{FinalizationStallDetector, FinalizationStallOpts};
+use super::fork_detector::{ForkDetectionOpts, ForkDetector};
 use super::node::Node;
 
 id_type! {
@@ -40,10 +43,16 @@ pub type Label = Box<str>;
 const STALE_TIMEOUT: u64 = 2 * 60 * 1000; // 2 minutes
 const STATS_UPDATE_INTERVAL: Duration = Duration::from_secs(5);
 
+/// How many other nodes need to be on a chain before we'll judge a node's best
+/// block against theirs.
+const MIN_NODES_TO_COMPARE_HEIGHT: usize = 2;
+
 pub struct Chain {
     /// Labels that nodes use for this chain. We keep track of
     /// the most commonly used label as nodes are added/removed.
     labels: MostSeen<Label>,
+    /// If set, the chain is always shown with this label, whatever its nodes call it.
+    label_override: Option<Label>,
     /// Set of nodes that are in this chain
     nodes: DenseMap<ChainNodeId, Node>,
     /// Best block
@@ -66,8 +75,51 @@ pub struct Chain {
     stats: ChainStats,
     /// Timestamp of when the stats were last regenerated.
     stats_last_regenerated: Instant,
+    /// Looks out for nodes disagreeing about which block is at some height.
+    forks: ForkDetector,
+    /// How quickly the current best block is reaching nodes.
+    propagation: BlockPropagation,
+    /// Looks out for finalization falling too far behind the best block.
+    finalization_stall: FinalizationStallDetector,
+    /// How far ahead of the median best block of the other nodes a node can be before
+    /// we consider its best block implausible. If 0, we don't check.
+    max_height_deviation: BlockNumber,
+    /// How many nodes have been added to this chain since it was created.
+    nodes_added: u64,
+    /// How many nodes have been removed from this chain since it was created.
+    nodes_removed: u64,
+    /// How many nodes have been turned away because the chain was at its quota.
+    nodes_over_quota: u64,
+    /// When we last warned about nodes being turned away, and how many have been
+    /// turned away since then.
+    over_quota_warned_at: Option<Instant>,
+    over_quota_since_warning: u64,
+    /// If set, the best block that we show for the chain is the highest block that at
+    /// least this fraction of its nodes have finalized, rather than its best block.
+    finalized_quorum: Option<f64>,
+    /// The highest block finalized by a quorum of nodes, and when we first saw it.
+    quorum_finalized: Block,
+    quorum_timestamp: Option<Timestamp>,
+    /// When the chain last became empty, if it has no nodes right now.
+    empty_since: Option<Instant>,
+    /// When the chain was created, as a unix timestamp in ms. This sticks around for as
+    /// long as we keep the chain, whether or not it has any nodes.
+    first_seen: Timestamp,
 }
 
+/// The middle of the heights given, or `None` if there aren't any.
+fn median(heights: &mut [BlockNumber]) -> Option<BlockNumber> {
+    if heights.is_empty() {
+        return None;
+    }
+    let mid = heights.len() / 2;
+    let (_, &mut median, _) = heights.select_nth_unstable(mid);
+    Some(median)
+}
+
+/// We'll warn about nodes being turned away from any one chain at most this often.
+const OVER_QUOTA_WARNING_INTERVAL: Duration = Duration::from_secs(60);
+
 pub enum AddNodeResult {
     Overquota,
     Added {
@@ -104,9 +156,18 @@ pub fn is_first_party_network(genesis_hash: &BlockHash) -> bool {
 
 impl Chain {
     /// Create a new chain with an initial label.
-    pub fn new(genesis_hash: BlockHash, max_nodes: usize) -> Self {
+    pub fn new(
+        genesis_hash: BlockHash,
+        max_nodes: usize,
+        fork_detection: ForkDetectionOpts,
+        max_height_deviation: BlockNumber,
+        label_override: Option<Label>,
+        finalized_quorum: Option<f64>,
+        finalization_stall: FinalizationStallOpts,
+    ) -> Self {
         Chain {
             labels: MostSeen::default(),
+            label_override,
             nodes: DenseMap::new(),
             best: Block::zero(),
             finalized: Block::zero(),
@@ -118,6 +179,20 @@ impl Chain {
             stats_collator: Default::default(),
             stats: Default::default(),
             stats_last_regenerated: Instant::now(),
+            forks: ForkDetector::new(fork_detection),
+            propagation: BlockPropagation::default(),
+            finalization_stall: FinalizationStallDetector::new(finalization_stall),
+            max_height_deviation,
+            nodes_added: 0,
+            nodes_removed: 0,
+            nodes_over_quota: 0,
+            over_quota_warned_at: None,
+            over_quota_since_warning: 0,
+            finalized_quorum,
+            quorum_finalized: Block::zero(),
+            quorum_timestamp: None,
+            empty_since: Some(Instant::now()),
+            first_seen: time::now(),
         }
     }
 
@@ -129,6 +204,16 @@ impl Chain {
     /// Assign a node to this chain.
     pub fn add_node(&mut self, node: Node) -> AddNodeResult {
         if self.is_overquota() {
+            self.nodes_over_quota += 1;
+            if let Some(rejected) = self.over_quota_warning(Instant::now()) {
+                log::warn!(
+                    "Chain {:?} is at its quota of {} nodes ({} connected); turned away {} node(s) since the last warning",
+                    self.genesis_hash,
+                    self.max_nodes,
+                    self.nodes.len(),
+                    rejected
+                );
+            }
             return AddNodeResult::Overquota;
         }
 
@@ -139,10 +224,12 @@ impl Chain {
         let node_chain_label = &details.chain;
         let label_result = self.labels.insert(node_chain_label);
         let node_id = self.nodes.add(node);
+        self.nodes_added += 1;
+        self.empty_since = None;
 
         AddNodeResult::Added {
             id: node_id,
-            chain_renamed: label_result.has_changed(),
+            chain_renamed: label_result.has_changed() && self.label_override.is_none(),
         }
     }
 
@@ -163,9 +250,13 @@ impl Chain {
 
         let node_chain_label = &node.details().chain;
         let label_result = self.labels.remove(node_chain_label);
+        self.nodes_removed += 1;
+        if self.nodes.is_empty() {
+            self.empty_since = Some(Instant::now());
+        }
 
         RemoveNodeResult {
-            chain_renamed: label_result.has_changed(),
+            chain_renamed: label_result.has_changed() && self.label_override.is_none(),
         }
     }
 
@@ -176,10 +267,18 @@ impl Chain {
         payload: Payload,
         feed: &mut FeedMessageSerializer,
     ) {
+        if let Some(node) = self.nodes.get_mut(nid) {
+            if node.update_last_seen(time::now()) {
+                feed.push(feed_message::NodeLastSeen(nid.into(), node.last_seen()));
+            }
+        }
+
         if let Some(block) = payload.best_block() {
             self.handle_block(block, nid, feed);
         }
 
+        let mut newly_finalized = None;
+
         if let Some(node) = self.nodes.get_mut(nid) {
             match payload {
                 Payload::SystemInterval(ref interval) => {
@@ -193,15 +292,73 @@ impl Chain {
                     if let Some(io) = node.update_io(interval) {
                         feed.push(feed_message::NodeIOUpdate(nid.into(), io));
                     }
+                    if let Some(usage) = node.update_resource_usage(interval) {
+                        feed.push(feed_message::NodeResourceUsageUpdate(nid.into(), usage));
+                    }
+                    if let Some(target) = node.update_sync_target(interval) {
+                        feed.push(feed_message::NodeSyncState(
+                            nid.into(),
+                            node.best().height,
+                            target,
+                        ));
+                    }
+                    if node.set_sync_method(interval.sync_method) {
+                        if let Some(method) = node.details().sync_method {
+                            feed.push(feed_message::NodeSyncMethod(nid.into(), method));
+                        }
+                    }
                 }
                 Payload::AfgAuthoritySet(authority) => {
+                    // Nodes only have an authority ID to tell us about if they are one:
+                    let authority_changed = node.set_authority(!authority.authority_id.is_empty());
                     // If our node validator address (and thus details) change, send an
-                    // updated "add node" feed message:
+                    // updated "add node" feed message. Otherwise, tell feeds if the node
+                    // has started or stopped being an authority:
                     if node.set_validator_address(authority.authority_id.clone()) {
                         feed.push(feed_message::AddedNode(nid.into(), &node));
+                        // Feeds start afresh with a node when it's added again:
+                        if let Some(method) = node.details().sync_method {
+                            feed.push(feed_message::NodeSyncMethod(nid.into(), method));
+                        }
+                    } else if authority_changed {
+                        feed.push(feed_message::NodeAuthorityStatus(
+                            nid.into(),
+                            node.details().authority,
+                        ));
+                    }
+                    return;
+                }
+                Payload::SystemVersion(version) => {
+                    // The version is one of the stats we collate, so take the node out of
+                    // them and put it back in again with whatever its version now is:
+                    self.stats_collator.add_or_remove_node(
+                        node.details(),
+                        None,
+                        CounterValue::Decrement,
+                    );
+                    let version_changed = node.set_version(version.version, version.spec_version);
+                    self.stats_collator.add_or_remove_node(
+                        node.details(),
+                        None,
+                        CounterValue::Increment,
+                    );
+                    if version_changed {
+                        feed.push(feed_message::NodeVersionInfo(
+                            nid.into(),
+                            &node.details().version,
+                            node.details().spec_version,
+                        ));
                     }
                     return;
                 }
+                Payload::TimedBlockImport(ref import)
+                    if node.update_block_import_time(import.import_time, time::now()) =>
+                {
+                    feed.push(feed_message::NodeBlockImportTime(
+                        nid.into(),
+                        import.import_time,
+                    ));
+                }
                 Payload::HwBench(ref hwbench) => {
                     let new_hwbench = common::node_types::NodeHwBench {
                         cpu_hashrate_score: hwbench.cpu_hashrate_score,
@@ -233,26 +390,90 @@ impl Chain {
                             finalized.hash,
                         ));
                     }
+                    newly_finalized = Some(finalized.height);
                 }
             }
         }
+
+        if let Some(height) = newly_finalized {
+            self.update_quorum_finalized(height, feed);
+        }
+
+        self.check_finalization_stall(feed);
+    }
+
+    /// Tell feeds if finalization has stalled on this chain, or has started keeping up again.
+    fn check_finalization_stall(&mut self, feed: &mut FeedMessageSerializer) {
+        let nodes = &self.nodes;
+        let stalled = self.finalization_stall.check(Instant::now(), || {
+            let (mut best, mut finalized): (Vec<BlockNumber>, Vec<BlockNumber>) = nodes
+                .iter()
+                .filter(|(_, node)| !node.stale())
+                .map(|(_, node)| (node.best().height, node.finalized().height))
+                .unzip();
+            let best = median(&mut best)?;
+            let finalized = median(&mut finalized)?;
+            Some(best.saturating_sub(finalized))
+        });
+        let stalled = match stalled {
+            Some(stalled) => stalled,
+            None => return,
+        };
+
+        let gap = self.finalization_stall.gap();
+        if stalled {
+            log::warn!(
+                "Finalization has stalled on chain {:?} ({:?}); the median finalized block is {} blocks behind the median best block",
+                self.label(),
+                self.genesis_hash,
+                gap
+            );
+        } else {
+            log::info!(
+                "Finalization has caught up again on chain {:?} ({:?})",
+                self.label(),
+                self.genesis_hash
+            );
+        }
+        feed.push(feed_message::FinalizationStall(stalled, gap));
     }
 
     fn handle_block(&mut self, block: &Block, nid: ChainNodeId, feed: &mut FeedMessageSerializer) {
         let mut propagation_time = None;
+        let mut is_new_best = false;
         let now = time::now();
         let nodes_len = self.nodes.len();
 
         self.update_stale_nodes(now, feed);
         self.regenerate_stats_if_necessary(feed);
 
+        // A node that's too far ahead of the others is probably misconfigured; we keep
+        // track of its best block, but don't let it become the best block of the chain.
+        let implausible_height = match self.nodes.get(nid) {
+            Some(node) if node.implausible_height() || block.height > self.best.height => {
+                let height = std::cmp::max(node.best().height, block.height);
+                self.is_implausible_height(nid, height)
+            }
+            Some(_) => false,
+            None => return,
+        };
+
         let node = match self.nodes.get_mut(nid) {
             Some(node) => node,
             None => return,
         };
 
+        if node.set_implausible_height(implausible_height) {
+            feed.push(feed_message::NodeAnomaly(nid.into(), implausible_height));
+        }
+
+        let was_stale = node.stale();
         if node.update_block(*block) {
-            if block.height > self.best.height {
+            // Importing a new block is what brings a stale node back:
+            if was_stale {
+                feed.push(feed_message::NodeRecovered(nid.into()));
+            }
+            if block.height > self.best.height && !implausible_height {
                 self.best = *block;
                 log::debug!(
                     "[{}] [nodes={}] new best block={}/{:?}",
@@ -266,12 +487,17 @@ impl Chain {
                     self.average_block_time = Some(self.block_times.average());
                 }
                 self.timestamp = Some(now);
-                feed.push(feed_message::BestBlock(
-                    self.best.height,
-                    now,
-                    self.average_block_time,
-                ));
+                // Feeds are told about the block that a quorum of nodes have finalized
+                // instead, if that's what we're showing them:
+                if self.finalized_quorum.is_none() {
+                    feed.push(feed_message::BestBlock(
+                        self.best.height,
+                        now,
+                        self.average_block_time,
+                    ));
+                }
                 propagation_time = Some(0);
+                is_new_best = true;
             } else if block.height == self.best.height {
                 if let Some(timestamp) = self.timestamp {
                     propagation_time = Some(now.saturating_sub(timestamp));
@@ -281,7 +507,100 @@ impl Chain {
             if let Some(details) = node.update_details(now, propagation_time) {
                 feed.push(feed_message::ImportedBlock(nid.into(), details));
             }
+
+            if let Some(hashes) = self
+                .forks
+                .record(nid, block.height, block.hash, self.best.height)
+            {
+                feed.push(feed_message::ForkDetected(block.height, &hashes));
+            }
+        }
+
+        let reached_percentile = if is_new_best {
+            // Nodes that have gone stale aren't going to tell us about this block:
+            let expected = self.nodes.iter().filter(|(_, n)| !n.stale()).count();
+            self.propagation.start(block.height, expected)
+        } else if let Some(elapsed) = propagation_time {
+            self.propagation.record(block.height, elapsed)
+        } else {
+            false
+        };
+        if reached_percentile {
+            feed.push(self.propagation_update());
+        }
+    }
+
+    /// Is the height given too far ahead of the best blocks of the other nodes on the chain
+    /// for us to believe it?
+    fn is_implausible_height(&self, nid: ChainNodeId, height: BlockNumber) -> bool {
+        if self.max_height_deviation == 0 {
+            return false;
         }
+
+        let mut heights: Vec<BlockNumber> = self
+            .nodes
+            .iter()
+            .filter(|&(id, node)| id != nid && !node.stale())
+            .map(|(_, node)| node.best().height)
+            .collect();
+        if heights.len() < MIN_NODES_TO_COMPARE_HEIGHT {
+            return false;
+        }
+
+        let mid = heights.len() / 2;
+        let (_, &mut median, _) = heights.select_nth_unstable(mid);
+        height > median.saturating_add(self.max_height_deviation)
+    }
+
+    /// If we show the block that a quorum of nodes have finalized as the chain's best block,
+    /// see whether a node finalizing a block at the height given has moved it on.
+    fn update_quorum_finalized(&mut self, height: BlockNumber, feed: &mut FeedMessageSerializer) {
+        // A node can only move the quorum on by finalizing a block beyond it:
+        let quorum = match self.finalized_quorum {
+            Some(quorum) if height > self.quorum_finalized.height => quorum,
+            _ => return,
+        };
+        let block = match self.quorum_finalized_block(quorum) {
+            Some(block) if block.height > self.quorum_finalized.height => block,
+            _ => return,
+        };
+
+        let now = time::now();
+        self.quorum_finalized = block;
+        self.quorum_timestamp = Some(now);
+        feed.push(feed_message::BestBlock(
+            block.height,
+            now,
+            self.average_block_time,
+        ));
+    }
+
+    /// The highest block that at least the fraction of nodes given have finalized. Stale
+    /// nodes aren't counted, unless every node is stale.
+    fn quorum_finalized_block(&self, quorum: f64) -> Option<Block> {
+        let mut finalized: Vec<Block> = self
+            .nodes
+            .iter()
+            .filter(|(_, node)| !node.stale())
+            .map(|(_, node)| *node.finalized())
+            .collect();
+        if finalized.is_empty() {
+            finalized = self
+                .nodes
+                .iter()
+                .map(|(_, node)| *node.finalized())
+                .collect();
+        }
+        if finalized.is_empty() {
+            return None;
+        }
+
+        // The nth highest finalized block has been finalized by at least n nodes:
+        let needed = (finalized.len() as f64 * quorum).ceil() as usize;
+        let nth = needed.clamp(1, finalized.len()) - 1;
+        let (_, &mut block, _) =
+            finalized.select_nth_unstable_by(nth, |a, b| b.height.cmp(&a.height));
+        Some(block)
     }
 
     /// Check if the chain is stale (has not received a new best block in a while).
@@ -303,8 +622,10 @@ impl Chain {
         let mut timestamp = None;
 
         for (nid, node) in self.nodes.iter_mut() {
+            // Feeds already know about nodes that were stale before now:
+            let was_stale = node.stale();
             if !node.update_stale(threshold) {
-                if node.best().height > best.height {
+                if node.best().height > best.height && !node.implausible_height() {
                     best = *node.best();
                     timestamp = Some(node.best_timestamp());
                 }
@@ -312,7 +633,7 @@ impl Chain {
                 if node.finalized().height > finalized.height {
                     finalized = *node.finalized();
                 }
-            } else {
+            } else if !was_stale {
                 feed.push(feed_message::StaleNode(nid.into()));
             }
         }
@@ -322,10 +643,15 @@ impl Chain {
             self.finalized = finalized;
             self.block_times.reset();
             self.timestamp = timestamp;
+            if let Some(quorum) = self.finalized_quorum {
+                self.quorum_finalized = self
+                    .quorum_finalized_block(quorum)
+                    .unwrap_or_else(Block::zero);
+            }
 
             feed.push(feed_message::BestBlock(
-                self.best.height,
-                timestamp.unwrap_or(now),
+                self.best_block().height,
+                self.timestamp().unwrap_or(now),
                 None,
             ));
             feed.push(feed_message::BestFinalized(
@@ -363,6 +689,91 @@ impl Chain {
         }
     }
 
+    /// Hand back the update if it should be processed now, or hold on to it if we've
+    /// processed one of the same kind from this node within the last `interval`.
+    pub fn sample_update(
+        &mut self,
+        node_id: ChainNodeId,
+        payload: Payload,
+        interval: Duration,
+        now: Instant,
+    ) -> Option<Payload> {
+        match self.nodes.get_mut(node_id) {
+            Some(node) => node.update_sampler().sample(payload, interval, now),
+            None => Some(payload),
+        }
+    }
+
+    /// Hand back any updates that we've held on to for at least `interval`, along with
+    /// the nodes that they're from.
+    pub fn take_sampled_updates(
+        &mut self,
+        interval: Duration,
+        now: Instant,
+    ) -> Vec<(ChainNodeId, Payload)> {
+        self.nodes
+            .iter_mut()
+            .flat_map(|(node_id, node)| {
+                node.update_sampler()
+                    .take_due(interval, now)
+                    .into_iter()
+                    .map(move |payload| (node_id, payload))
+            })
+            .collect()
+    }
+
+    /// Mark a node as stale until we hear from it again, returning false if there's no
+    /// such node.
+    pub fn mark_node_stale(&mut self, node_id: ChainNodeId) -> bool {
+        match self.nodes.get_mut(node_id) {
+            Some(node) => {
+                node.mark_stale();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Set when the chain was first seen before we restarted, so that it isn't reset.
+    pub fn restore_first_seen(&mut self, first_seen: Timestamp) {
+        self.first_seen = self.first_seen.min(first_seen);
+    }
+
+    /// Set the blocks and location that a node had before we restarted, marking
+    /// it as stale until we hear from it again.
+    pub fn restore_node(
+        &mut self,
+        node_id: ChainNodeId,
+        best: Block,
+        finalized: Block,
+        location: find_location::Location,
+        last_seen: Option<Timestamp>,
+    ) {
+        let node = match self.nodes.get_mut(node_id) {
+            Some(node) => node,
+            None => return,
+        };
+        node.update_block(best);
+        node.update_finalized(finalized);
+        node.update_location(location);
+        node.mark_stale();
+        if let Some(last_seen) = last_seen {
+            node.restore_last_seen(last_seen);
+        }
+
+        if best.height > self.best.height {
+            self.best = best;
+        }
+        if finalized.height > self.finalized.height {
+            self.finalized = finalized;
+        }
+        if let Some(quorum) = self.finalized_quorum {
+            if let Some(block) = self.quorum_finalized_block(quorum) {
+                self.quorum_finalized = block;
+            }
+        }
+    }
+
     pub fn get_node(&self, id: ChainNodeId) -> Option<&Node> {
         self.nodes.get(id)
     }
@@ -370,16 +781,36 @@ impl Chain {
         self.nodes.as_slice()
     }
     pub fn label(&self) -> &str {
-        &self.labels.best()
+        match &self.label_override {
+            Some(label) => label,
+            None => self.labels.best(),
+        }
     }
     pub fn node_count(&self) -> usize {
         self.nodes.len()
     }
+    /// When the chain last became empty, if it has no nodes right now.
+    pub fn empty_since(&self) -> Option<Instant> {
+        self.empty_since
+    }
+    /// When the first node for this chain connected, as a unix timestamp in ms.
+    pub fn first_seen(&self) -> Timestamp {
+        self.first_seen
+    }
+    /// The best block that we show for the chain. Unless we've been asked to show the block
+    /// finalized by a quorum of nodes instead, this is the highest block any node has imported.
     pub fn best_block(&self) -> &Block {
-        &self.best
+        match self.finalized_quorum {
+            Some(_) => &self.quorum_finalized,
+            None => &self.best,
+        }
     }
+    /// When the chain's [`Chain::best_block`] first arrived.
     pub fn timestamp(&self) -> Option<Timestamp> {
-        self.timestamp
+        match self.finalized_quorum {
+            Some(_) => self.quorum_timestamp,
+            None => self.timestamp,
+        }
     }
     pub fn average_block_time(&self) -> Option<u64> {
         self.average_block_time
@@ -393,4 +824,265 @@ impl Chain {
     pub fn stats(&self) -> &ChainStats {
         &self.stats
     }
+    /// If finalization has stalled on the chain, how many blocks it's behind by.
+    pub fn finalization_stall(&self) -> Option<BlockNumber> {
+        if self.finalization_stall.stalled() {
+            Some(self.finalization_stall.gap())
+        } else {
+            None
+        }
+    }
+    /// How long the current best block took to reach half and nine tenths of the nodes.
+    pub fn propagation_update(&self) -> feed_message::BlockPropagationUpdate {
+        feed_message::BlockPropagationUpdate(
+            self.propagation.height(),
+            self.propagation.p50(),
+            self.propagation.p90(),
+        )
+    }
+    pub fn nodes_added(&self) -> u64 {
+        self.nodes_added
+    }
+    pub fn nodes_removed(&self) -> u64 {
+        self.nodes_removed
+    }
+    pub fn nodes_over_quota(&self) -> u64 {
+        self.nodes_over_quota
+    }
+    pub fn max_nodes(&self) -> usize {
+        self.max_nodes
+    }
+
+    /// Note that a node was turned away for being over quota. If it's time to warn
+    /// about this again, this hands back how many have been turned away since we last did.
+    fn over_quota_warning(&mut self, now: Instant) -> Option<u64> {
+        self.over_quota_since_warning += 1;
+        let warned_recently = self
+            .over_quota_warned_at
+            .is_some_and(|at| now.saturating_duration_since(at) < OVER_QUOTA_WARNING_INTERVAL);
+        if warned_recently {
+            return None;
+        }
+        self.over_quota_warned_at = Some(now);
+        Some(std::mem::take(&mut self.over_quota_since_warning))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::{
+        add_node, chain_with_nodes, decode_feed, node_details, TestChainOpts,
+    };
+
+    #[test]
+    fn over_quota_warnings_are_throttled() {
+        let (mut chain, _) = chain_with_nodes(
+            0,
+            TestChainOpts {
+                max_nodes: 1,
+                ..Default::default()
+            },
+        );
+        let start = Instant::now();
+
+        assert_eq!(chain.over_quota_warning(start), Some(1));
+        assert_eq!(chain.over_quota_warning(start), None);
+        let later = start + Duration::from_secs(30);
+        assert_eq!(chain.over_quota_warning(later), None);
+
+        // Once enough time has passed, we hear about everything turned away since:
+        let later = start + OVER_QUOTA_WARNING_INTERVAL;
+        assert_eq!(chain.over_quota_warning(later), Some(3));
+        assert_eq!(chain.over_quota_warning(later), None);
+    }
+
+    #[test]
+    fn best_block_can_follow_a_quorum_of_finalized_blocks() {
+        use common::node_message::Finalized;
+
+        let (mut chain, ids) = chain_with_nodes(
+            4,
+            TestChainOpts {
+                finalized_quorum: Some(0.5),
+                ..Default::default()
+            },
+        );
+        let finalize = |chain: &mut Chain, nid: ChainNodeId, height: BlockNumber| {
+            let payload = Payload::NotifyFinalized(Finalized {
+                hash: BlockHash::from_low_u64_be(height),
+                height: height.to_string().into(),
+            });
+            let mut feed = FeedMessageSerializer::new();
+            chain.update_node(nid, payload, &mut feed);
+        };
+
+        // One node finalizing a block isn't enough to move the best block on:
+        finalize(&mut chain, ids[0], 30);
+        assert_eq!(chain.best_block().height, 0);
+        assert_eq!(chain.finalized_block().height, 30);
+
+        // Once half of the nodes have finalized a block, it's the best block:
+        finalize(&mut chain, ids[1], 20);
+        assert_eq!(chain.best_block().height, 20);
+        assert_eq!(chain.best_block().hash, BlockHash::from_low_u64_be(20));
+        finalize(&mut chain, ids[2], 40);
+        assert_eq!(chain.best_block().height, 30);
+        assert!(chain.timestamp().is_some());
+    }
+
+    #[test]
+    fn feeds_are_only_told_once_that_a_node_is_stale() {
+        use test_utils::feed_message_de::FeedMessage;
+
+        let (mut chain, ids) = chain_with_nodes(2, TestChainOpts::default());
+        let stale_nodes = |feed: FeedMessageSerializer| -> Vec<usize> {
+            decode_feed(feed)
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    FeedMessage::StaleNode { node_id } => Some(node_id),
+                    _ => None,
+                })
+                .collect()
+        };
+        let import = |chain: &mut Chain, nid: ChainNodeId, height: BlockNumber| {
+            let block = Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            };
+            chain.handle_block(&block, nid, &mut FeedMessageSerializer::new());
+        };
+
+        import(&mut chain, ids[0], 1);
+        import(&mut chain, ids[1], 1);
+
+        // Both nodes stop importing blocks for long enough to go stale:
+        let later = time::now() + STALE_TIMEOUT + 1;
+        let mut feed = FeedMessageSerializer::new();
+        chain.update_stale_nodes(later, &mut feed);
+        assert_eq!(stale_nodes(feed), vec![0, 1]);
+
+        // One of them comes back, and then goes stale again. Feeds are told about
+        // that, but not again about the node that they already know is stale:
+        import(&mut chain, ids[1], 2);
+        let mut feed = FeedMessageSerializer::new();
+        chain.update_stale_nodes(later + STALE_TIMEOUT + 1, &mut feed);
+        assert_eq!(stale_nodes(feed), vec![1]);
+    }
+
+    #[test]
+    fn feeds_are_told_when_a_stale_node_recovers() {
+        use test_utils::feed_message_de::FeedMessage;
+
+        let (mut chain, ids) = chain_with_nodes(1, TestChainOpts::default());
+        let nid = ids[0];
+        let staleness = |feed: FeedMessageSerializer| -> Vec<FeedMessage> {
+            decode_feed(feed)
+                .into_iter()
+                .filter(|msg| {
+                    matches!(
+                        msg,
+                        FeedMessage::StaleNode { .. } | FeedMessage::NodeRecovered { .. }
+                    )
+                })
+                .collect()
+        };
+        let import = |chain: &mut Chain, height: BlockNumber| {
+            let block = Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            };
+            let mut feed = FeedMessageSerializer::new();
+            chain.handle_block(&block, nid, &mut feed);
+            staleness(feed)
+        };
+
+        // A node that isn't stale doesn't recover:
+        assert!(import(&mut chain, 1).is_empty());
+        assert!(import(&mut chain, 2).is_empty());
+
+        // Feeds are told as soon as it goes stale:
+        let mut feed = FeedMessageSerializer::new();
+        chain.update_stale_nodes(time::now() + STALE_TIMEOUT + 1, &mut feed);
+        assert_eq!(staleness(feed), vec![FeedMessage::StaleNode { node_id: 0 }]);
+
+        // And as soon as it imports a new block again:
+        assert_eq!(
+            import(&mut chain, 3),
+            vec![FeedMessage::NodeRecovered { node_id: 0 }]
+        );
+        assert!(import(&mut chain, 4).is_empty());
+    }
+
+    #[test]
+    fn feeds_are_told_when_a_node_changes_how_it_syncs() {
+        use common::node_message::SystemInterval;
+        use common::node_types::{NodeDetails, SyncMethod};
+        use test_utils::feed_message_de::FeedMessage;
+
+        let (mut chain, _) = chain_with_nodes(0, TestChainOpts::default());
+        let nid = add_node(
+            &mut chain,
+            NodeDetails {
+                sync_method: Some(SyncMethod::Warp),
+                ..node_details("Node")
+            },
+        );
+        let interval = |chain: &mut Chain, sync_method: Option<SyncMethod>| {
+            let interval = SystemInterval {
+                sync_method,
+                ..Default::default()
+            };
+            let mut feed = FeedMessageSerializer::new();
+            chain.update_node(nid, Payload::SystemInterval(interval), &mut feed);
+            decode_feed(feed)
+                .into_iter()
+                .filter(|msg| matches!(msg, FeedMessage::NodeSyncMethod { .. }))
+                .collect::<Vec<_>>()
+        };
+
+        // Nothing is sent unless the node changes how it's syncing:
+        assert!(interval(&mut chain, None).is_empty());
+        assert!(interval(&mut chain, Some(SyncMethod::Warp)).is_empty());
+        assert_eq!(
+            interval(&mut chain, Some(SyncMethod::Full)),
+            vec![FeedMessage::NodeSyncMethod {
+                node_id: 0,
+                sync_method: SyncMethod::Full
+            }]
+        );
+        assert!(interval(&mut chain, None).is_empty());
+    }
+
+    #[test]
+    fn feeds_are_told_when_finalization_stalls() {
+        use test_utils::feed_message_de::FeedMessage;
+
+        let (mut chain, ids) = chain_with_nodes(
+            3,
+            TestChainOpts {
+                finalization_stall: FinalizationStallOpts {
+                    max_gap: 10,
+                    duration: Duration::ZERO,
+                },
+                ..Default::default()
+            },
+        );
+
+        let block = |height: BlockNumber| Block {
+            hash: BlockHash::from_low_u64_be(height),
+            height,
+        };
+        chain.handle_block(&block(50), ids[0], &mut FeedMessageSerializer::new());
+        assert_eq!(chain.finalization_stall(), None);
+
+        // Once most nodes are far enough ahead of the finalized block, it's stalled:
+        let mut feed = FeedMessageSerializer::new();
+        chain.update_node(ids[1], Payload::BlockImport(block(50)), &mut feed);
+        assert!(decode_feed(feed).contains(&FeedMessage::FinalizationStall {
+            stalled: true,
+            gap: 50
+        }));
+        assert_eq!(chain.finalization_stall(), Some(50));
+    }
 }
@@ -0,0 +1,206 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::node_types::{Block, BlockHash, BlockNumber, NodeDetails, Timestamp};
+use serde::{Deserialize, Serialize};
+
+use super::{State, StateChain};
+
+/// The first byte of every export, which says how the rest of it is laid out. This
+/// must be bumped whenever any of the types below change.
+pub const STATE_EXPORT_VERSION: u8 = 4;
+
+/// Everything that we know about every chain and node, for offline analysis. This is
+/// handed out as bincode (see [`StateExport::to_bytes`]), so unlike [`super::StateSnapshot`],
+/// it's laid out for machines rather than people, and includes as much as we can.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct StateExport {
+    /// When the export was taken, as a unix timestamp in ms.
+    pub exported_at: Timestamp,
+    pub chains: Vec<ChainExport>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ChainExport {
+    pub genesis_hash: BlockHash,
+    pub label: Box<str>,
+    pub best_block: Block,
+    pub finalized_block: Block,
+    pub average_block_time: Option<u64>,
+    pub nodes: Vec<NodeExport>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct NodeExport {
+    /// The ID of the node, as given to feeds subscribed to its chain.
+    pub id: usize,
+    pub details: NodeDetails,
+    pub peers: u64,
+    pub txcount: u64,
+    pub best_block: Block,
+    pub best_block_timestamp: u64,
+    pub finalized_block: Block,
+    pub sync_target: Option<BlockNumber>,
+    pub location: Option<LocationExport>,
+    pub startup_time: Option<Timestamp>,
+    pub connected_at: Timestamp,
+    pub last_seen: Timestamp,
+    pub stale: bool,
+}
+
+/// [`common::node_types::NodeLocation`] is serialized compactly for feeds, in a way that
+/// bincode can't read back in, so locations are exported as this instead.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LocationExport {
+    pub latitude: f32,
+    pub longitude: f32,
+    pub city: Box<str>,
+    pub asn: Option<u32>,
+    pub network: Option<Box<str>>,
+}
+
+impl StateExport {
+    pub fn new(state: &State) -> Self {
+        StateExport {
+            exported_at: common::time::now(),
+            chains: state.iter_chains().map(ChainExport::new).collect(),
+        }
+    }
+
+    /// The version byte, followed by the export encoded as bincode.
+    pub fn to_bytes(&self) -> anyhow::Result<Vec<u8>> {
+        let mut bytes = vec![STATE_EXPORT_VERSION];
+        bincode::serialize_into(&mut bytes, self)?;
+        Ok(bytes)
+    }
+
+    #[cfg(test)]
+    pub fn from_bytes(bytes: &[u8]) -> anyhow::Result<Self> {
+        match bytes.split_first() {
+            Some((&STATE_EXPORT_VERSION, rest)) => Ok(bincode::deserialize(rest)?),
+            Some((version, _)) => anyhow::bail!("Unsupported export version {}", version),
+            None => anyhow::bail!("Export is empty"),
+        }
+    }
+}
+
+impl ChainExport {
+    fn new(chain: StateChain<'_>) -> Self {
+        let nodes = chain
+            .nodes_slice()
+            .iter()
+            .enumerate()
+            .filter_map(|(id, node)| {
+                let node = node.as_ref()?;
+                Some(NodeExport {
+                    id,
+                    details: node.details().clone(),
+                    peers: node.stats().peers,
+                    txcount: node.stats().txcount,
+                    best_block: *node.best(),
+                    best_block_timestamp: node.best_timestamp(),
+                    finalized_block: *node.finalized(),
+                    sync_target: node.sync_target(),
+                    location: node.location().map(|loc| LocationExport {
+                        latitude: loc.latitude,
+                        longitude: loc.longitude,
+                        city: loc.city.clone(),
+                        asn: loc.asn,
+                        network: loc.network.clone(),
+                    }),
+                    startup_time: node.startup_time(),
+                    connected_at: node.connected_at(),
+                    last_seen: node.last_seen(),
+                    stale: node.stale(),
+                })
+            })
+            .collect();
+
+        ChainExport {
+            genesis_hash: chain.genesis_hash(),
+            label: chain.label().into(),
+            best_block: *chain.best_block(),
+            finalized_block: *chain.finalized_block(),
+            average_block_time: chain.average_block_time(),
+            nodes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::StateOpts;
+    use crate::test_helpers::node_details;
+    use common::node_types::NodeLocation;
+    use std::sync::Arc;
+
+    fn node(name: &str) -> NodeDetails {
+        NodeDetails {
+            startup_time: Some("1000".into()),
+            ..node_details(name)
+        }
+    }
+
+    #[test]
+    fn exports_can_be_read_back_in() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let mut state = State::new(StateOpts::default());
+        let node_id = state.add_node(genesis_hash, node("A")).unwrap_id();
+        state.add_node(genesis_hash, node("B"));
+        let location = NodeLocation {
+            latitude: 1.5,
+            longitude: 2.5,
+            city: "Berlin".into(),
+            asn: Some(3320),
+            network: None,
+            source: None,
+        };
+        state.update_node_location(node_id, Some(Arc::new(location)));
+
+        let bytes = StateExport::new(&state).to_bytes().unwrap();
+        assert_eq!(bytes[0], STATE_EXPORT_VERSION);
+
+        let export = StateExport::from_bytes(&bytes).unwrap();
+        assert_eq!(export.chains.len(), 1);
+        let chain = &export.chains[0];
+        assert_eq!(chain.genesis_hash, genesis_hash);
+        assert_eq!(&*chain.label, "Chain One");
+        let names: Vec<&str> = chain.nodes.iter().map(|n| &*n.details.name).collect();
+        assert_eq!(names, vec!["A", "B"]);
+        assert_eq!(chain.nodes[0].startup_time, Some(1000));
+        assert_eq!(
+            chain.nodes[0].location,
+            Some(LocationExport {
+                latitude: 1.5,
+                longitude: 2.5,
+                city: "Berlin".into(),
+                asn: Some(3320),
+                network: None,
+            })
+        );
+        assert_eq!(chain.nodes[1].location, None);
+    }
+
+    #[test]
+    fn exports_with_other_versions_are_rejected() {
+        let mut bytes = StateExport::default().to_bytes().unwrap();
+        assert!(StateExport::from_bytes(&bytes).is_ok());
+        bytes[0] = STATE_EXPORT_VERSION + 1;
+        assert!(StateExport::from_bytes(&bytes).is_err());
+        assert!(StateExport::from_bytes(&[]).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::node_types::BlockNumber;
+use std::time::{Duration, Instant};
+
+/// We work out how far finalization is behind at most this often, since it means
+/// looking at every node on the chain.
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Options to configure how we spot finalization stalling on a chain.
+#[derive(Debug, Clone, Copy)]
+pub struct FinalizationStallOpts {
+    /// How many blocks the median best block of a chain's nodes can be ahead of their
+    /// median finalized block. If this is 0, we don't look for stalls at all.
+    pub max_gap: BlockNumber,
+    /// How long the gap needs to stay bigger than `max_gap` before we call it a stall.
+    pub duration: Duration,
+}
+
+impl Default for FinalizationStallOpts {
+    fn default() -> Self {
+        FinalizationStallOpts {
+            max_gap: 0,
+            duration: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Keeps an eye on how far finalization is lagging behind the best block of a chain,
+/// so that we can tell when it's stopped keeping up.
+pub struct FinalizationStallDetector {
+    opts: FinalizationStallOpts,
+    /// When we last worked out the gap.
+    last_checked: Option<Instant>,
+    /// The gap between the median best and finalized blocks when we last checked.
+    gap: BlockNumber,
+    /// When the gap first became too big, if it's too big right now.
+    too_big_since: Option<Instant>,
+    /// Have we decided that finalization has stalled?
+    stalled: bool,
+}
+
+impl FinalizationStallDetector {
+    pub fn new(opts: FinalizationStallOpts) -> Self {
+        FinalizationStallDetector {
+            opts,
+            last_checked: None,
+            gap: 0,
+            too_big_since: None,
+            stalled: false,
+        }
+    }
+
+    /// Has finalization stalled?
+    pub fn stalled(&self) -> bool {
+        self.stalled
+    }
+
+    /// The gap between the median best and finalized blocks when we last checked.
+    pub fn gap(&self) -> BlockNumber {
+        self.gap
+    }
+
+    /// Check how far finalization is behind, if we haven't done so recently. `gap` hands back
+    /// the gap between the median best and finalized blocks, or `None` if there are no nodes
+    /// to work it out from. If finalization has just stalled or recovered, we return whether
+    /// or not it's stalled now.
+    pub fn check(
+        &mut self,
+        now: Instant,
+        gap: impl FnOnce() -> Option<BlockNumber>,
+    ) -> Option<bool> {
+        if self.opts.max_gap == 0 {
+            return None;
+        }
+        if let Some(last_checked) = self.last_checked {
+            if now.saturating_duration_since(last_checked) < CHECK_INTERVAL {
+                return None;
+            }
+        }
+        self.last_checked = Some(now);
+
+        let gap = gap();
+        self.gap = gap.unwrap_or(0);
+        let stalled = match gap {
+            Some(gap) if gap > self.opts.max_gap => {
+                let since = *self.too_big_since.get_or_insert(now);
+                now.saturating_duration_since(since) >= self.opts.duration
+            }
+            _ => {
+                self.too_big_since = None;
+                false
+            }
+        };
+
+        if stalled == self.stalled {
+            return None;
+        }
+        self.stalled = stalled;
+        Some(stalled)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn detector(max_gap: BlockNumber, secs: u64) -> FinalizationStallDetector {
+        FinalizationStallDetector::new(FinalizationStallOpts {
+            max_gap,
+            duration: Duration::from_secs(secs),
+        })
+    }
+
+    #[test]
+    fn stalls_need_to_last_a_while() {
+        let mut detector = detector(10, 60);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        assert_eq!(detector.check(at(0), || Some(11)), None);
+        assert_eq!(detector.check(at(30), || Some(50)), None);
+        assert!(!detector.stalled());
+
+        // Finalization catching up for a moment starts the clock again:
+        assert_eq!(detector.check(at(40), || Some(10)), None);
+        assert_eq!(detector.check(at(50), || Some(20)), None);
+        assert_eq!(detector.check(at(100), || Some(30)), None);
+        assert_eq!(detector.check(at(110), || Some(40)), Some(true));
+        assert!(detector.stalled());
+        assert_eq!(detector.gap(), 40);
+
+        // We only say so once, and then again when it recovers:
+        assert_eq!(detector.check(at(120), || Some(50)), None);
+        assert_eq!(detector.check(at(130), || Some(2)), Some(false));
+        assert!(!detector.stalled());
+    }
+
+    #[test]
+    fn chains_without_nodes_have_not_stalled() {
+        let mut detector = detector(10, 0);
+        let start = Instant::now();
+
+        assert_eq!(detector.check(start, || Some(11)), Some(true));
+        let later = start + CHECK_INTERVAL;
+        assert_eq!(detector.check(later, || None), Some(false));
+        assert_eq!(detector.gap(), 0);
+    }
+
+    #[test]
+    fn checks_are_throttled() {
+        let mut detector = detector(10, 0);
+        let start = Instant::now();
+
+        assert_eq!(detector.check(start, || Some(11)), Some(true));
+        let soon = start + CHECK_INTERVAL / 2;
+        assert_eq!(
+            detector.check(soon, || panic!("shouldn't check again yet")),
+            None
+        );
+    }
+
+    #[test]
+    fn stalls_are_not_looked_for_if_turned_off() {
+        let mut detector = detector(0, 0);
+        assert_eq!(
+            detector.check(Instant::now(), || panic!("shouldn't check at all")),
+            None
+        );
+    }
+}
@@ -0,0 +1,140 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::node_types::{BlockHash, BlockNumber};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use super::chain::ChainNodeId;
+
+/// Options to configure how we spot forks in a chain.
+#[derive(Debug, Clone, Copy)]
+pub struct ForkDetectionOpts {
+    /// How many blocks behind the best block we keep track of. If this is 0,
+    /// we don't look for forks at all.
+    pub window: u64,
+    /// How many nodes need to have reported each of the competing blocks at
+    /// some height before we consider it to be a fork.
+    pub min_nodes: usize,
+}
+
+impl Default for ForkDetectionOpts {
+    fn default() -> Self {
+        ForkDetectionOpts {
+            window: 16,
+            min_nodes: 2,
+        }
+    }
+}
+
+/// Keeps track of the blocks that nodes on a chain have imported recently, so
+/// that we can tell when they disagree about the block at some height.
+pub struct ForkDetector {
+    opts: ForkDetectionOpts,
+    /// Which nodes have reported importing which blocks, for each recent height.
+    seen: BTreeMap<BlockNumber, HashMap<BlockHash, HashSet<ChainNodeId>>>,
+    /// Heights that we've already reported a fork at.
+    reported: BTreeSet<BlockNumber>,
+}
+
+impl ForkDetector {
+    pub fn new(opts: ForkDetectionOpts) -> Self {
+        ForkDetector {
+            opts,
+            seen: BTreeMap::new(),
+            reported: BTreeSet::new(),
+        }
+    }
+
+    /// Note that a node has imported some block. If this means that there are now
+    /// competing blocks at that height, the hashes of them are returned. We only
+    /// report a fork at any given height once.
+    pub fn record(
+        &mut self,
+        nid: ChainNodeId,
+        height: BlockNumber,
+        hash: BlockHash,
+        best_height: BlockNumber,
+    ) -> Option<Vec<BlockHash>> {
+        if self.opts.window == 0 {
+            return None;
+        }
+
+        // Forget about anything that's fallen too far behind the best block:
+        let oldest = best_height.saturating_sub(self.opts.window);
+        self.seen = self.seen.split_off(&oldest);
+        self.reported = self.reported.split_off(&oldest);
+        if height < oldest {
+            return None;
+        }
+
+        let blocks = self.seen.entry(height).or_default();
+        blocks.entry(hash).or_default().insert(nid);
+        if self.reported.contains(&height) {
+            return None;
+        }
+
+        let mut competing: Vec<BlockHash> = blocks
+            .iter()
+            .filter(|(_, nodes)| nodes.len() >= self.opts.min_nodes)
+            .map(|(&hash, _)| hash)
+            .collect();
+        if competing.len() < 2 {
+            return None;
+        }
+
+        competing.sort();
+        self.reported.insert(height);
+        Some(competing)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fork_is_reported_once_enough_nodes_disagree() {
+        let mut forks = ForkDetector::new(ForkDetectionOpts {
+            window: 10,
+            min_nodes: 2,
+        });
+        let a = BlockHash::from_low_u64_be(1);
+        let b = BlockHash::from_low_u64_be(2);
+
+        assert_eq!(forks.record(0.into(), 5, a, 5), None);
+        assert_eq!(forks.record(1.into(), 5, a, 5), None);
+        // Only one node on the competing block so far:
+        assert_eq!(forks.record(2.into(), 5, b, 5), None);
+        assert_eq!(forks.record(3.into(), 5, b, 5), Some(vec![a, b]));
+        // Already reported this one:
+        assert_eq!(forks.record(4.into(), 5, b, 5), None);
+    }
+
+    #[test]
+    fn old_blocks_are_forgotten() {
+        let mut forks = ForkDetector::new(ForkDetectionOpts {
+            window: 10,
+            min_nodes: 1,
+        });
+        let a = BlockHash::from_low_u64_be(1);
+        let b = BlockHash::from_low_u64_be(2);
+
+        assert_eq!(forks.record(0.into(), 5, a, 5), None);
+        // By now, block 5 is too old for us to care about:
+        assert_eq!(forks.record(1.into(), 5, b, 20), None);
+        assert!(forks.seen.is_empty());
+    }
+}
@@ -14,12 +14,32 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+mod block_propagation;
 mod chain;
 mod chain_stats;
 mod counter;
+mod export;
+mod finalization_stall;
+mod fork_detector;
 mod node;
+mod node_clusters;
+mod node_groups;
+mod persist;
+mod snapshot;
+mod update_sampler;
 
 mod state;
 
+pub use export::StateExport;
+pub use finalization_stall::FinalizationStallOpts;
+pub use fork_detector::ForkDetectionOpts;
 pub use node::Node;
+pub use node_clusters::{group_nearby, NodeClusterOpts};
+pub use node_groups::{NetworkIdMapping, NetworkIdPrefix, NodeGrouper};
+pub use persist::PersistedState;
+pub use snapshot::{ChainListsSnapshot, QuotasSnapshot, ShardSnapshot, StateSnapshot};
 pub use state::*;
+
+// Chains are otherwise only handled through `State`, but tests build them directly:
+#[cfg(test)]
+pub use chain::{AddNodeResult as ChainAddNodeResult, Chain, ChainNodeId};
@@ -14,11 +14,12 @@
 // You should have received a copy of the GNU General Public License
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
+use super::update_sampler::UpdateSampler;
 use crate::find_location;
 use common::node_message::SystemInterval;
 use common::node_types::{
-    Block, BlockDetails, NodeDetails, NodeHardware, NodeHwBench, NodeIO, NodeLocation, NodeStats,
-    Timestamp,
+    Block, BlockDetails, BlockNumber, NodeDetails, NodeHardware, NodeHwBench, NodeIO, NodeLocation,
+    NodeResourceUsage, NodeStats, SyncMethod, Timestamp,
 };
 use common::time;
 
@@ -26,6 +27,10 @@ use common::time;
 const THROTTLE_THRESHOLD: u64 = 100;
 /// Minimum time of intervals for block updates sent to the browser when throttled, in ms.
 const THROTTLE_INTERVAL: u64 = 1000;
+/// Minimum time between telling the browser that we've heard from a node again, in ms.
+const LAST_SEEN_INTERVAL: u64 = 10_000;
+/// Minimum time between telling the browser how long a node took to import a block, in ms.
+const BLOCK_IMPORT_TIME_INTERVAL: u64 = 10_000;
 
 pub struct Node {
     /// Static details
@@ -34,6 +39,10 @@ pub struct Node {
     stats: NodeStats,
     /// Node IO stats
     io: NodeIO,
+    /// CPU, memory and disk usage
+    resource_usage: NodeResourceUsage,
+    /// The height that the node is syncing towards, if it's told us
+    sync_target: Option<BlockNumber>,
     /// Best block
     best: BlockDetails,
     /// Finalized block
@@ -46,10 +55,27 @@ pub struct Node {
     location: find_location::Location,
     /// Flag marking if the node is stale (not syncing or producing blocks)
     stale: bool,
+    /// Flag marking if the node's best block is implausibly far ahead of the rest of its chain
+    implausible_height: bool,
     /// Unix timestamp for when node started up (falls back to connection time)
     startup_time: Option<Timestamp>,
+    /// Unix timestamp (in ms) for when the node connected to us. This only changes
+    /// when the node connects again, at which point it's a new `Node`.
+    connected_at: Timestamp,
+    /// Unix timestamp (in ms) for when we last heard anything from the node.
+    last_seen: Timestamp,
+    /// The last seen time that feeds were last told about.
+    last_seen_sent: Timestamp,
+    /// How long (in ms) the node took to import its latest block, if it's told us
+    block_import_time: Option<u64>,
+    /// When feeds were last told how long the node took to import a block.
+    block_import_time_sent: Timestamp,
     /// Hardware benchmark results for the node
     hwbench: Option<NodeHwBench>,
+    /// The group (eg a fleet run by one operator) that the node belongs to, if any
+    group: Option<Box<str>>,
+    /// Updates from the node that are held back because it's sending them too often
+    sampler: UpdateSampler,
 }
 
 impl Node {
@@ -58,19 +84,30 @@ impl Node {
             .startup_time
             .take()
             .and_then(|time| time.parse().ok());
+        let now = time::now();
 
         Node {
             details,
             stats: NodeStats::default(),
             io: NodeIO::default(),
+            resource_usage: NodeResourceUsage::default(),
+            sync_target: None,
             best: BlockDetails::default(),
             finalized: Block::zero(),
             throttle: 0,
             hardware: NodeHardware::default(),
             location: None,
             stale: false,
+            implausible_height: false,
             startup_time,
+            connected_at: now,
+            last_seen: now,
+            last_seen_sent: now,
+            block_import_time: None,
+            block_import_time_sent: 0,
             hwbench: None,
+            group: None,
+            sampler: UpdateSampler::default(),
         }
     }
 
@@ -78,6 +115,14 @@ impl Node {
         &self.details
     }
 
+    pub fn group(&self) -> Option<&str> {
+        self.group.as_deref()
+    }
+
+    pub fn set_group(&mut self, group: Option<Box<str>>) {
+        self.group = group;
+    }
+
     pub fn stats(&self) -> &NodeStats {
         &self.stats
     }
@@ -86,10 +131,20 @@ impl Node {
         &self.io
     }
 
+    /// The resources that the node is using, if it has told us about any.
+    pub fn resource_usage(&self) -> Option<&NodeResourceUsage> {
+        Some(&self.resource_usage).filter(|usage| !usage.is_empty())
+    }
+
     pub fn best(&self) -> &Block {
         &self.best.block
     }
 
+    /// The height of the block that the node is syncing towards, if it has told us.
+    pub fn sync_target(&self) -> Option<BlockNumber> {
+        self.sync_target
+    }
+
     pub fn best_timestamp(&self) -> u64 {
         self.best.block_timestamp
     }
@@ -162,7 +217,11 @@ impl Node {
         if let Some(download) = interval.bandwidth_download {
             changed |= self.hardware.download.push(download);
         }
-        self.hardware.chart_stamps.push(time::now() as f64);
+        // Only note the time for intervals which told us about bandwidth, so that the
+        // stamps line up with the upload and download values:
+        if interval.bandwidth_upload.is_some() || interval.bandwidth_download.is_some() {
+            self.hardware.chart_stamps.push(time::now() as f64);
+        }
 
         changed
     }
@@ -204,6 +263,35 @@ impl Node {
         }
     }
 
+    pub fn update_resource_usage(
+        &mut self,
+        interval: &SystemInterval,
+    ) -> Option<&NodeResourceUsage> {
+        let usage = NodeResourceUsage {
+            cpu: interval.cpu.or(self.resource_usage.cpu),
+            memory: interval.memory.or(self.resource_usage.memory),
+            disk_usage: interval.disk_usage.or(self.resource_usage.disk_usage),
+        };
+
+        if usage != self.resource_usage {
+            self.resource_usage = usage;
+            Some(&self.resource_usage)
+        } else {
+            None
+        }
+    }
+
+    /// Returns the new sync target if it's changed.
+    pub fn update_sync_target(&mut self, interval: &SystemInterval) -> Option<BlockNumber> {
+        match interval.target_height {
+            Some(target) if Some(target) != self.sync_target => {
+                self.sync_target = Some(target);
+                Some(target)
+            }
+            _ => None,
+        }
+    }
+
     pub fn update_finalized(&mut self, block: Block) -> Option<&Block> {
         if block.height > self.finalized.height {
             self.finalized = block;
@@ -221,10 +309,29 @@ impl Node {
         self.stale
     }
 
+    pub fn mark_stale(&mut self) {
+        self.stale = true;
+    }
+
     pub fn stale(&self) -> bool {
         self.stale
     }
 
+    /// Is the node's best block too far ahead of the rest of its chain to be believed?
+    pub fn implausible_height(&self) -> bool {
+        self.implausible_height
+    }
+
+    /// Returns `true` if this changes whether the node's best block is implausible.
+    pub fn set_implausible_height(&mut self, implausible: bool) -> bool {
+        if self.implausible_height == implausible {
+            false
+        } else {
+            self.implausible_height = implausible;
+            true
+        }
+    }
+
     pub fn set_validator_address(&mut self, addr: Box<str>) -> bool {
         if self.details.validator.as_ref() == Some(&addr) {
             false
@@ -234,7 +341,121 @@ impl Node {
         }
     }
 
+    /// Returns true if the node's authority status has changed.
+    pub fn set_authority(&mut self, authority: bool) -> bool {
+        if self.details.authority == authority {
+            false
+        } else {
+            self.details.authority = authority;
+            true
+        }
+    }
+
+    /// Returns true if the node's version or runtime spec version has changed.
+    pub fn set_version(&mut self, version: Box<str>, spec_version: Option<u32>) -> bool {
+        if self.details.version == version && self.details.spec_version == spec_version {
+            false
+        } else {
+            self.details.version = version;
+            self.details.spec_version = spec_version;
+            true
+        }
+    }
+
+    /// Returns true if the node's sync method has changed. Nodes that stop telling us how
+    /// they're syncing are assumed to still be syncing the same way.
+    pub fn set_sync_method(&mut self, sync_method: Option<SyncMethod>) -> bool {
+        match sync_method {
+            Some(method) if self.details.sync_method != Some(method) => {
+                self.details.sync_method = Some(method);
+                true
+            }
+            _ => false,
+        }
+    }
+
     pub fn startup_time(&self) -> Option<Timestamp> {
         self.startup_time
     }
+
+    pub fn connected_at(&self) -> Timestamp {
+        self.connected_at
+    }
+
+    pub fn last_seen(&self) -> Timestamp {
+        self.last_seen
+    }
+
+    /// Note that we've heard from the node. Returns `true` if feeds should be told,
+    /// which is at most every [`LAST_SEEN_INTERVAL`] for any one node.
+    pub fn update_last_seen(&mut self, now: Timestamp) -> bool {
+        self.last_seen = now;
+        if now.saturating_sub(self.last_seen_sent) < LAST_SEEN_INTERVAL {
+            return false;
+        }
+        self.last_seen_sent = now;
+        true
+    }
+
+    /// Updates from the node that we're holding back, if we're limiting how often
+    /// they're processed.
+    pub fn update_sampler(&mut self) -> &mut UpdateSampler {
+        &mut self.sampler
+    }
+
+    pub fn block_import_time(&self) -> Option<u64> {
+        self.block_import_time
+    }
+
+    /// Note how long the node took to import its latest block. Returns `true` if feeds
+    /// should be told, which is at most every [`BLOCK_IMPORT_TIME_INTERVAL`] for any one
+    /// node, rather than for every block.
+    pub fn update_block_import_time(&mut self, import_time: u64, now: Timestamp) -> bool {
+        self.block_import_time = Some(import_time);
+        if now.saturating_sub(self.block_import_time_sent) < BLOCK_IMPORT_TIME_INTERVAL {
+            return false;
+        }
+        self.block_import_time_sent = now;
+        true
+    }
+
+    /// Set when we last heard from the node, without telling feeds about it. This
+    /// is used when restoring nodes that we knew about before restarting.
+    pub fn restore_last_seen(&mut self, last_seen: Timestamp) {
+        self.last_seen = last_seen;
+        self.last_seen_sent = last_seen;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::node_details;
+
+    #[test]
+    fn feeds_are_only_told_about_last_seen_times_every_so_often() {
+        let mut node = Node::new(node_details("A"));
+        let connected_at = node.connected_at();
+        assert_eq!(node.last_seen(), connected_at);
+
+        assert!(!node.update_last_seen(connected_at + 1000));
+        assert_eq!(node.last_seen(), connected_at + 1000);
+        assert!(node.update_last_seen(connected_at + LAST_SEEN_INTERVAL));
+        assert!(!node.update_last_seen(connected_at + LAST_SEEN_INTERVAL + 1000));
+        assert_eq!(node.last_seen(), connected_at + LAST_SEEN_INTERVAL + 1000);
+    }
+
+    #[test]
+    fn feeds_are_only_told_about_block_import_times_every_so_often() {
+        let mut node = Node::new(node_details("A"));
+        let now = node.connected_at();
+        assert_eq!(node.block_import_time(), None);
+
+        // Feeds hear about the first one straight away, but not every one after it:
+        assert!(node.update_block_import_time(200, now));
+        assert!(!node.update_block_import_time(300, now + 1000));
+        assert_eq!(node.block_import_time(), Some(300));
+        assert!(node.update_block_import_time(400, now + BLOCK_IMPORT_TIME_INTERVAL));
+        assert_eq!(node.block_import_time(), Some(400));
+    }
 }
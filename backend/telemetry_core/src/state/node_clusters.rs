@@ -0,0 +1,77 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+/// The (mean) radius of the earth in km.
+const EARTH_RADIUS_KM: f64 = 6371.0;
+
+/// Options to configure how we group nodes that are located close together, so that
+/// feeds are told about them all at once rather than one at a time.
+#[derive(Debug, Clone, Copy)]
+pub struct NodeClusterOpts {
+    /// Nodes within this many km of the first node in a cluster belong to it.
+    pub radius_km: f64,
+    /// Clusters of fewer nodes than this aren't worth it, and their nodes are sent on
+    /// their own as usual.
+    pub min_nodes: usize,
+}
+
+/// Split some coordinates (latitude and longitude, in degrees) into groups of those that are
+/// close together, handing back the indexes of the coordinates in each. Each coordinate joins
+/// the first group whose first member is within `radius_km` of it, or else starts a new group.
+/// Groups are in the order that their first members appear.
+pub fn group_nearby(coords: &[(f32, f32)], radius_km: f64) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    for (idx, &coord) in coords.iter().enumerate() {
+        let group = groups
+            .iter_mut()
+            .find(|group| distance_km(coords[group[0]], coord) <= radius_km);
+        match group {
+            Some(group) => group.push(idx),
+            None => groups.push(vec![idx]),
+        }
+    }
+    groups
+}
+
+/// The approximate distance in km between two coordinates. This treats the earth as flat
+/// between them, which is plenty accurate enough over the distances that we cluster nodes.
+fn distance_km((lat1, lon1): (f32, f32), (lat2, lon2): (f32, f32)) -> f64 {
+    let (lat1, lon1) = ((lat1 as f64).to_radians(), (lon1 as f64).to_radians());
+    let (lat2, lon2) = ((lat2 as f64).to_radians(), (lon2 as f64).to_radians());
+    let x = (lon2 - lon1) * ((lat1 + lat2) / 2.0).cos();
+    let y = lat2 - lat1;
+    (x * x + y * y).sqrt() * EARTH_RADIUS_KM
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn identical_coordinates_are_grouped() {
+        let coords = [(52.5, 13.4), (48.8, 2.3), (52.5, 13.4), (52.5, 13.4)];
+        assert_eq!(group_nearby(&coords, 0.0), vec![vec![0, 2, 3], vec![1]]);
+    }
+
+    #[test]
+    fn nearby_coordinates_are_grouped() {
+        // About 1km apart, and then Berlin and Paris (about 880km apart):
+        let coords = [(52.50, 13.40), (52.509, 13.40), (48.85, 2.35)];
+        assert_eq!(group_nearby(&coords, 0.5), vec![vec![0], vec![1], vec![2]]);
+        assert_eq!(group_nearby(&coords, 2.0), vec![vec![0, 1], vec![2]]);
+        assert_eq!(group_nearby(&coords, 1000.0), vec![vec![0, 1, 2]]);
+    }
+}
@@ -0,0 +1,117 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::node_types::NodeDetails;
+use std::fmt::Debug;
+
+/// Works out which group a node belongs to, if any. Feeds are told the group of each node,
+/// so that nodes run by the same operator (a fleet) can be shown together.
+pub trait NodeGrouper: Debug + Send + Sync {
+    fn group(&self, details: &NodeDetails) -> Option<Box<str>>;
+}
+
+/// Groups nodes by the first so many characters of their network ID.
+#[derive(Debug, Clone, Copy)]
+pub struct NetworkIdPrefix(pub usize);
+
+impl NodeGrouper for NetworkIdPrefix {
+    fn group(&self, details: &NodeDetails) -> Option<Box<str>> {
+        // Network IDs shorter than the prefix don't belong to any group:
+        if self.0 == 0 || details.network_id.chars().count() < self.0 {
+            return None;
+        }
+        let prefix: String = details.network_id.chars().take(self.0).collect();
+        Some(prefix.into())
+    }
+}
+
+/// Groups nodes using a list of network ID prefixes and the group that nodes whose network
+/// ID starts with each belong to. A full network ID can be given to place a single node in
+/// a group. If several prefixes match, the longest one wins.
+#[derive(Debug, Clone, Default)]
+pub struct NetworkIdMapping(Vec<(Box<str>, Box<str>)>);
+
+impl NetworkIdMapping {
+    pub fn new<I, P, G>(prefixes: I) -> Self
+    where
+        I: IntoIterator<Item = (P, G)>,
+        P: Into<Box<str>>,
+        G: Into<Box<str>>,
+    {
+        let mut prefixes: Vec<(Box<str>, Box<str>)> = prefixes
+            .into_iter()
+            .map(|(prefix, group)| (prefix.into(), group.into()))
+            .collect();
+        // Longest first, so that the first match is the one we want:
+        prefixes.sort_by_key(|(prefix, _)| std::cmp::Reverse(prefix.len()));
+        NetworkIdMapping(prefixes)
+    }
+}
+
+impl NodeGrouper for NetworkIdMapping {
+    fn group(&self, details: &NodeDetails) -> Option<Box<str>> {
+        self.0
+            .iter()
+            .find(|(prefix, _)| details.network_id.starts_with(&**prefix))
+            .map(|(_, group)| group.clone())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_helpers::node_details;
+    use common::node_types::NetworkId;
+
+    fn details(network_id: &str) -> NodeDetails {
+        NodeDetails {
+            chain: "Polkadot".into(),
+            implementation: "Substrate".into(),
+            version: "1.0.0".into(),
+            network_id: NetworkId::from(network_id).unwrap(),
+            ..node_details("Node")
+        }
+    }
+
+    #[test]
+    fn nodes_are_grouped_by_network_id_prefix() {
+        let grouper = NetworkIdPrefix(10);
+        assert_eq!(
+            grouper.group(&details("12D3KooWAbcdef")).as_deref(),
+            Some("12D3KooWAb")
+        );
+        assert_eq!(grouper.group(&details("12D3KooWA")), None);
+        assert_eq!(NetworkIdPrefix(0).group(&details("12D3KooWAbcdef")), None);
+    }
+
+    #[test]
+    fn nodes_are_grouped_by_the_longest_matching_prefix() {
+        let grouper = NetworkIdMapping::new([
+            ("12D3KooWA", "Fleet A"),
+            ("12D3KooWAbc", "Fleet B"),
+            ("12D3KooWC", "Fleet C"),
+        ]);
+        assert_eq!(
+            grouper.group(&details("12D3KooWAbcdef")).as_deref(),
+            Some("Fleet B")
+        );
+        assert_eq!(
+            grouper.group(&details("12D3KooWAxyz")).as_deref(),
+            Some("Fleet A")
+        );
+        assert_eq!(grouper.group(&details("12D3KooWDxyz")), None);
+    }
+}
@@ -0,0 +1,182 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use std::path::Path;
+
+use anyhow::Context;
+use common::node_types::{Block, BlockHash, NodeDetails, NodeLocation, Timestamp};
+use serde::{Deserialize, Serialize};
+
+use super::State;
+
+/// The parts of the [`State`] that we write to disk on shutdown, so that feeds
+/// see roughly what they saw before once we've started up again.
+///
+/// We don't keep track of which shard connection nodes belonged to; that's gone
+/// once we restart. Nodes are restored as stale, and replaced when they reconnect.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PersistedState {
+    pub chains: Vec<PersistedChain>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PersistedChain {
+    pub genesis_hash: BlockHash,
+    pub nodes: Vec<PersistedNode>,
+    /// When the chain was first seen. Files saved before we kept track of this don't have it.
+    #[serde(default)]
+    pub first_seen: Option<Timestamp>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PersistedNode {
+    pub details: NodeDetails,
+    pub best: Block,
+    pub finalized: Block,
+    pub location: Option<NodeLocation>,
+    /// When we last heard from the node. Files saved before we kept track of this
+    /// don't have it.
+    #[serde(default)]
+    pub last_seen: Option<Timestamp>,
+}
+
+impl PersistedState {
+    /// Gather up everything that we want to persist from the state.
+    pub fn new(state: &State) -> Self {
+        let chains = state
+            .iter_chains()
+            .map(|chain| PersistedChain {
+                genesis_hash: chain.genesis_hash(),
+                first_seen: Some(chain.first_seen()),
+                nodes: chain
+                    .nodes_slice()
+                    .iter()
+                    .flatten()
+                    .map(|node| {
+                        // The startup time is taken out of the details when a node is
+                        // created, so put it back for when we create it again.
+                        let mut details = node.details().clone();
+                        details.startup_time = node.startup_time().map(|t| t.to_string().into());
+                        PersistedNode {
+                            details,
+                            best: *node.best(),
+                            finalized: *node.finalized(),
+                            location: node.location().cloned(),
+                            last_seen: Some(node.last_seen()),
+                        }
+                    })
+                    .collect(),
+            })
+            .collect();
+        PersistedState { chains }
+    }
+
+    /// Load state that was written to disk previously, if there is any.
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let state = serde_json::from_slice(&bytes)
+            .with_context(|| format!("Couldn't parse saved state in {}", path.display()))?;
+        Ok(Some(state))
+    }
+
+    /// Write the state to disk. We write to a temporary file first, so that
+    /// we never leave a half written file behind.
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        let mut tmp_path = path.as_os_str().to_owned();
+        tmp_path.push(".tmp");
+        let bytes = serde_json::to_vec(self)?;
+        std::fs::write(&tmp_path, bytes)?;
+        std::fs::rename(&tmp_path, path)?;
+        Ok(())
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.chains.iter().map(|c| c.nodes.len()).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::{AddNodeResult, NodeDedupKey, StateOpts};
+    use crate::test_helpers::node_details;
+    use common::node_types::NetworkId;
+
+    fn node(name: &str, network_id: &str) -> NodeDetails {
+        NodeDetails {
+            network_id: NetworkId::from(network_id).unwrap(),
+            startup_time: Some("1000".into()),
+            ..node_details(name)
+        }
+    }
+
+    #[test]
+    fn restored_nodes_are_stale_until_they_reconnect() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let mut state = State::new(StateOpts::default());
+        state.add_node(genesis_hash, node("A", "peer0"));
+        state.add_node(genesis_hash, node("B", "peer1"));
+
+        // Write the state out and read it back in via JSON:
+        let persisted = PersistedState::new(&state);
+        let json = serde_json::to_vec(&persisted).unwrap();
+        let mut persisted: PersistedState = serde_json::from_slice(&json).unwrap();
+        assert_eq!(persisted.node_count(), 2);
+        assert!(persisted.chains[0].first_seen.is_some());
+        persisted.chains[0].first_seen = Some(1000);
+
+        let mut state = State::new(StateOpts {
+            dedup_key: NodeDedupKey::None,
+            ..Default::default()
+        });
+        let persisted_nodes: Vec<_> = persisted.chains.iter().map(|c| c.nodes.clone()).collect();
+        let restored = state.restore(persisted);
+        assert_eq!(restored.len(), 2);
+
+        let chain = state.get_chain_by_genesis_hash(&genesis_hash).unwrap();
+        assert_eq!(chain.node_count(), 2);
+        // The chain was first seen before the restart, not when it was restored:
+        assert_eq!(chain.first_seen(), 1000);
+        for (node, persisted) in chain
+            .nodes_slice()
+            .iter()
+            .flatten()
+            .zip(persisted_nodes.iter().flatten())
+        {
+            assert!(node.stale());
+            assert_eq!(node.startup_time(), Some(1000));
+            // We still know when they were last heard from before the restart:
+            assert_eq!(Some(node.last_seen()), persisted.last_seen);
+        }
+
+        // A node reconnecting replaces its restored self, even though we aren't
+        // otherwise deduplicating nodes:
+        match state.add_node(genesis_hash, node("A", "peer0")) {
+            AddNodeResult::NodeAddedToChain(details) => {
+                assert_eq!(details.replaced_node_id, Some(restored[0]));
+                assert_eq!(details.chain_node_count, 2);
+            }
+            _ => panic!("Node should have been added"),
+        }
+
+        // Only the node that hasn't reconnected is left to expire:
+        assert_eq!(state.take_restored(), vec![restored[1]]);
+    }
+}
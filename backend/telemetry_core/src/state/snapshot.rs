@@ -0,0 +1,339 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::node_types::{Block, BlockHash, SyncMethod, Timestamp};
+use serde::Serialize;
+use std::net::SocketAddr;
+
+use super::{State, StateChain};
+
+/// A point in time view of the chains and nodes that we know about, which is
+/// handed out as JSON to anybody who'd rather not speak the feed protocol.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct StateSnapshot {
+    pub chains: Vec<ChainSnapshot>,
+}
+
+/// The chains that nodes are and aren't allowed to connect to, as things stand.
+#[derive(Serialize, Debug, Clone, Default, PartialEq)]
+pub struct ChainListsSnapshot {
+    /// The labels of chains that nodes can't connect to, in order.
+    pub denylist: Vec<String>,
+    /// If given, nodes can only connect to chains with these genesis hashes (in order).
+    pub allowlist: Option<Vec<BlockHash>>,
+}
+
+/// The limits on how many nodes and chains we'll accept, as things stand, and how close
+/// each chain is to its own limit.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct QuotasSnapshot {
+    /// How many nodes each third party chain can have, unless it's been given a quota.
+    pub max_third_party_nodes: usize,
+    /// The chains that have been given a quota of their own, in order of genesis hash.
+    pub chain_quotas: Vec<ChainQuotaSnapshot>,
+    /// The most chains we'll keep track of at once, if there's a limit.
+    pub max_chains: Option<usize>,
+    /// How many chains we're keeping track of.
+    pub chain_count: usize,
+    /// How many nodes have been turned away because they'd have needed a new chain.
+    pub nodes_over_chain_limit: u64,
+    /// How many nodes each chain has, and how many it's allowed, most nodes first.
+    pub chains: Vec<ChainUsageSnapshot>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ChainQuotaSnapshot {
+    pub genesis_hash: BlockHash,
+    pub max_nodes: usize,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ChainUsageSnapshot {
+    pub genesis_hash: BlockHash,
+    pub label: Box<str>,
+    pub node_count: usize,
+    /// The most nodes the chain can have, if there's a limit.
+    pub max_nodes: Option<usize>,
+    /// How many nodes have been turned away because the chain was at its limit.
+    pub nodes_over_quota: u64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct ChainSnapshot {
+    pub genesis_hash: BlockHash,
+    pub label: Box<str>,
+    pub node_count: usize,
+    pub best_block: Block,
+    pub finalized_block: Block,
+    /// When the first node for the chain connected, as a unix timestamp in ms.
+    pub first_seen: Timestamp,
+    pub nodes: Vec<NodeSnapshot>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct NodeSnapshot {
+    /// The ID of the node, as given to feeds subscribed to its chain.
+    pub id: usize,
+    pub name: Box<str>,
+    pub version: Box<str>,
+    pub spec_version: Option<u32>,
+    pub operator: Option<Box<str>>,
+    pub sync_method: Option<SyncMethod>,
+    pub best_block: Block,
+    pub finalized_block: Block,
+    pub location: Option<LocationSnapshot>,
+    /// The shard connection that the node's messages arrive on. This is only filled in for
+    /// operators, who can use it to work out how nodes are being routed to us.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shard: Option<ShardSnapshot>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ShardSnapshot {
+    /// The ID of the shard connection, as logged when it opens and as used to disconnect it.
+    pub id: u64,
+    /// The address that the shard connected from, if known. Nodes mirrored from an upstream
+    /// core arrive on a connection of their own, which has no address.
+    pub addr: Option<SocketAddr>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct LocationSnapshot {
+    pub latitude: f32,
+    pub longitude: f32,
+    pub city: Box<str>,
+    pub asn: Option<u32>,
+    pub network: Option<Box<str>>,
+    /// The geolocation provider (or override) that placed the node here, if known.
+    pub source: Option<Box<str>>,
+}
+
+impl StateSnapshot {
+    /// Take a snapshot of the state, optionally only including the chain with the
+    /// genesis hash given.
+    pub fn new(state: &State, genesis_hash: Option<&BlockHash>) -> Self {
+        let chains = match genesis_hash {
+            Some(genesis_hash) => state
+                .get_chain_by_genesis_hash(genesis_hash)
+                .into_iter()
+                .map(ChainSnapshot::new)
+                .collect(),
+            None => state
+                .iter_chains_by_node_count()
+                .map(ChainSnapshot::new)
+                .collect(),
+        };
+        StateSnapshot { chains }
+    }
+}
+
+impl ChainListsSnapshot {
+    pub fn new(state: &State) -> Self {
+        let mut denylist: Vec<String> = state.denylist().cloned().collect();
+        denylist.sort();
+        let allowlist = state.allowlist().map(|allowlist| {
+            let mut allowlist: Vec<BlockHash> = allowlist.copied().collect();
+            allowlist.sort();
+            allowlist
+        });
+        ChainListsSnapshot {
+            denylist,
+            allowlist,
+        }
+    }
+}
+
+impl QuotasSnapshot {
+    pub fn new(state: &State) -> Self {
+        let mut chain_quotas: Vec<ChainQuotaSnapshot> = state
+            .chain_quotas()
+            .map(|(&genesis_hash, &max_nodes)| ChainQuotaSnapshot {
+                genesis_hash,
+                max_nodes,
+            })
+            .collect();
+        chain_quotas.sort_by_key(|quota| quota.genesis_hash);
+        let chains = state
+            .iter_chains_by_node_count()
+            .map(|chain| ChainUsageSnapshot {
+                genesis_hash: chain.genesis_hash(),
+                label: chain.label().into(),
+                node_count: chain.node_count(),
+                max_nodes: Some(chain.max_nodes()).filter(|&max| max != usize::MAX),
+                nodes_over_quota: chain.nodes_over_quota(),
+            })
+            .collect();
+        QuotasSnapshot {
+            max_third_party_nodes: state.max_third_party_nodes(),
+            chain_quotas,
+            max_chains: state.max_chains(),
+            chain_count: state.chain_count(),
+            nodes_over_chain_limit: state.nodes_over_chain_limit(),
+            chains,
+        }
+    }
+}
+
+impl ChainSnapshot {
+    fn new(chain: StateChain<'_>) -> Self {
+        let nodes = chain
+            .nodes_slice()
+            .iter()
+            .enumerate()
+            .filter_map(|(id, node)| {
+                let node = node.as_ref()?;
+                let details = node.details();
+                Some(NodeSnapshot {
+                    id,
+                    name: details.name.clone(),
+                    version: details.version.clone(),
+                    spec_version: details.spec_version,
+                    operator: details.operator.clone(),
+                    sync_method: details.sync_method,
+                    best_block: *node.best(),
+                    finalized_block: *node.finalized(),
+                    location: node.location().map(|loc| LocationSnapshot {
+                        latitude: loc.latitude,
+                        longitude: loc.longitude,
+                        city: loc.city.clone(),
+                        asn: loc.asn,
+                        network: loc.network.clone(),
+                        source: loc.source.clone(),
+                    }),
+                    shard: None,
+                })
+            })
+            .collect();
+
+        ChainSnapshot {
+            genesis_hash: chain.genesis_hash(),
+            label: chain.label().into(),
+            node_count: chain.node_count(),
+            best_block: *chain.best_block(),
+            finalized_block: *chain.finalized_block(),
+            first_seen: chain.first_seen(),
+            nodes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::state::StateOpts;
+    use crate::test_helpers::node_details;
+    use common::node_types::NodeDetails;
+
+    fn node(name: &str, chain: &str) -> NodeDetails {
+        NodeDetails {
+            chain: chain.into(),
+            ..node_details(name)
+        }
+    }
+
+    #[test]
+    fn snapshot_can_be_filtered_by_chain() {
+        let mut state = State::new(StateOpts::default());
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let chain2_genesis = BlockHash::from_low_u64_be(2);
+        state.add_node(chain1_genesis, node("A", "Chain One"));
+        state.add_node(chain1_genesis, node("B", "Chain One"));
+        state.add_node(chain2_genesis, node("C", "Chain Two"));
+
+        assert_eq!(StateSnapshot::new(&state, None).chains.len(), 2);
+
+        let snapshot = StateSnapshot::new(&state, Some(&chain1_genesis));
+        assert_eq!(snapshot.chains.len(), 1);
+        assert_eq!(&*snapshot.chains[0].label, "Chain One");
+        assert_eq!(snapshot.chains[0].node_count, 2);
+        let names: Vec<&str> = snapshot.chains[0].nodes.iter().map(|n| &*n.name).collect();
+        assert_eq!(names, vec!["A", "B"]);
+
+        let unknown = BlockHash::from_low_u64_be(3);
+        assert!(StateSnapshot::new(&state, Some(&unknown)).chains.is_empty());
+    }
+
+    #[test]
+    fn chain_lists_reflect_the_latest_denylist() {
+        let genesis = BlockHash::from_low_u64_be;
+        let mut state = State::new(StateOpts {
+            denylist: vec!["B".into(), "A".into()],
+            allowlist: Some(vec![genesis(2), genesis(1)]),
+            ..Default::default()
+        });
+        assert_eq!(
+            ChainListsSnapshot::new(&state),
+            ChainListsSnapshot {
+                denylist: vec!["A".into(), "B".into()],
+                allowlist: Some(vec![genesis(1), genesis(2)]),
+            }
+        );
+
+        state.set_denylist(vec!["C".to_owned()]);
+        assert_eq!(ChainListsSnapshot::new(&state).denylist, vec!["C"]);
+    }
+
+    #[test]
+    fn quotas_show_what_each_chain_is_allowed() {
+        let genesis = BlockHash::from_low_u64_be;
+        let mut state = State::new(StateOpts {
+            max_third_party_nodes: 10,
+            chain_quotas: [(genesis(2), 1), (genesis(1), 5)].into_iter().collect(),
+            max_chains: Some(2),
+            ..Default::default()
+        });
+        state.add_node(genesis(1), node("A", "Chain One"));
+        state.add_node(genesis(1), node("B", "Chain One"));
+        state.add_node(genesis(3), node("C", "Chain Three"));
+        state.add_node(genesis(4), node("D", "Chain Four"));
+
+        assert_eq!(
+            QuotasSnapshot::new(&state),
+            QuotasSnapshot {
+                max_third_party_nodes: 10,
+                chain_quotas: vec![
+                    ChainQuotaSnapshot {
+                        genesis_hash: genesis(1),
+                        max_nodes: 5
+                    },
+                    ChainQuotaSnapshot {
+                        genesis_hash: genesis(2),
+                        max_nodes: 1
+                    },
+                ],
+                max_chains: Some(2),
+                chain_count: 2,
+                nodes_over_chain_limit: 1,
+                chains: vec![
+                    ChainUsageSnapshot {
+                        genesis_hash: genesis(1),
+                        label: "Chain One".into(),
+                        node_count: 2,
+                        max_nodes: Some(5),
+                        nodes_over_quota: 0,
+                    },
+                    ChainUsageSnapshot {
+                        genesis_hash: genesis(3),
+                        label: "Chain Three".into(),
+                        node_count: 1,
+                        max_nodes: Some(10),
+                        nodes_over_quota: 0,
+                    },
+                ],
+            }
+        );
+    }
+}
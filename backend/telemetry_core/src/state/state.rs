@@ -18,12 +18,17 @@ use super::node::Node;
 use crate::feed_message::{ChainStats, FeedMessageSerializer};
 use crate::find_location;
 use common::node_message::Payload;
-use common::node_types::{Block, BlockHash, NodeDetails, Timestamp};
+use common::node_types::{Block, BlockHash, BlockNumber, NodeDetails, Timestamp};
 use common::{id_type, DenseMap};
 use std::collections::{HashMap, HashSet};
 use std::iter::IntoIterator;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 use super::chain::{self, Chain, ChainNodeId};
+use super::{
+    FinalizationStallOpts, ForkDetectionOpts, NodeClusterOpts, NodeGrouper, PersistedState,
+};
 
 id_type! {
     /// A globally unique Chain ID.
@@ -51,17 +56,222 @@ pub struct State {
     /// Chain labels that we do not want to allow connecting.
     denylist: HashSet<String>,
 
+    /// If given, only nodes on chains with these genesis hashes are allowed to connect.
+    allowlist: Option<HashSet<BlockHash>>,
+
     /// How many nodes from third party chains are allowed to connect
     /// before we prevent connections from them.
     max_third_party_nodes: usize,
+
+    /// The maximum number of nodes allowed on specific chains, overriding the default.
+    chain_quotas: HashMap<BlockHash, usize>,
+
+    /// The most chains we'll keep track of at once, if there's a limit.
+    max_chains: Option<usize>,
+
+    /// How many nodes have been turned away because they'd have needed a new chain and
+    /// we already had `max_chains` of them.
+    nodes_over_chain_limit: u64,
+
+    /// Chains that are kept around (with no nodes) once their last node is removed.
+    pinned_chains: HashSet<BlockHash>,
+
+    /// Feeds aren't told about chains with fewer nodes than this, unless they're pinned.
+    min_chain_nodes: usize,
+
+    /// How long chains can go without any nodes before we remove them anyway, if at all.
+    empty_chain_ttl: Option<Duration>,
+
+    /// Labels to show specific chains with, regardless of what their nodes call them.
+    chain_labels: HashMap<BlockHash, Box<str>>,
+
+    /// How each chain looks out for forks.
+    fork_detection: ForkDetectionOpts,
+
+    /// How each chain looks out for finalization stalling.
+    finalization_stall: FinalizationStallOpts,
+
+    /// How far a node's best block can be ahead of the rest of its chain before we ignore it.
+    max_height_deviation: BlockNumber,
+
+    /// If set, chains show the block finalized by this fraction of their nodes as their best block.
+    finalized_quorum: Option<f64>,
+
+    /// Node names and operator labels longer than this many characters are truncated.
+    max_node_name_length: usize,
+
+    /// The most nodes we describe in a single message when a feed subscribes to a chain.
+    feed_chunk_size: usize,
+    /// How to group nodes located close together when telling feeds where they are, if at all.
+    node_clusters: Option<NodeClusterOpts>,
+    /// Work out which group each node belongs to; the first to find one wins.
+    node_groupers: Vec<Arc<dyn NodeGrouper>>,
+
+    /// How long we wait for disconnected nodes to reconnect before removing them, if at all.
+    reconnect_grace_period: Option<Duration>,
+
+    /// How long nodes can go without sending us anything before we remove them, if at all.
+    node_idle_timeout: Option<Duration>,
+
+    /// The least time between processing updates of the same kind from a node, if any.
+    min_update_interval: Option<Duration>,
+
+    /// Helps us find nodes which have reconnected, so that they can be replaced.
+    node_index: NodeIndex,
+}
+
+/// Options to configure which nodes the [`State`] will accept.
+#[derive(Debug, Clone)]
+pub struct StateOpts {
+    /// Chain labels that we do not want to allow connecting.
+    pub denylist: Vec<String>,
+    /// If given, only nodes on chains with these genesis hashes are allowed to
+    /// connect. This can't be used alongside a denylist.
+    pub allowlist: Option<Vec<BlockHash>>,
+    /// How many nodes from third party chains are allowed to connect
+    /// before we prevent connections from them.
+    pub max_third_party_nodes: usize,
+    /// The maximum number of nodes allowed to connect to specific chains. These take
+    /// precedence over `max_third_party_nodes` (and the lack of a limit for first party chains).
+    pub chain_quotas: HashMap<BlockHash, usize>,
+    /// If given, nodes won't be added to new chains once there are this many chains. First
+    /// party chains and chains with a quota of their own can always be added, and nodes on
+    /// chains that we already know about are unaffected.
+    pub max_chains: Option<usize>,
+    /// Chains that we always expect to exist. These aren't removed when their last node is,
+    /// so that they don't disappear from feeds if every node drops off for a moment.
+    pub pinned_chains: Vec<BlockHash>,
+    /// Chains with fewer nodes than this aren't advertised to feeds, so that chains with a
+    /// single test node or two don't clutter the chain list. Feeds are told about a chain once
+    /// it has enough nodes, and that it's gone if it drops below this again. Pinned chains are
+    /// always advertised.
+    pub min_chain_nodes: usize,
+    /// If given, chains that have had no nodes for this long are removed, even if they're
+    /// pinned. Chains can also be left empty when the nodes they were created for are
+    /// turned away for being over quota, and this removes those too.
+    pub empty_chain_ttl: Option<Duration>,
+    /// Labels that specific chains are shown with, in place of the most common label that
+    /// their nodes report.
+    pub chain_labels: HashMap<BlockHash, Box<str>>,
+    /// If a node being added to a chain has the same key as one that's already on it,
+    /// the existing node is replaced rather than shown twice.
+    pub dedup_key: NodeDedupKey,
+    /// How we look out for nodes on a chain disagreeing about which block is at some height.
+    pub fork_detection: ForkDetectionOpts,
+    /// How we look out for finalization falling too far behind the best block of a chain.
+    pub finalization_stall: FinalizationStallOpts,
+    /// If a node's best block is more than this many blocks ahead of the median best block
+    /// of the other nodes on its chain, it's flagged to feeds and not allowed to become the
+    /// best block of the chain. If 0, nodes aren't checked.
+    pub max_height_deviation: BlockNumber,
+    /// If given, the best block shown for each chain is the highest block that at least this
+    /// fraction of its nodes have finalized, rather than the highest block that any of them
+    /// have imported. This is steadier on chains that fork a lot. Should be in `(0, 1]`.
+    pub finalized_quorum: Option<f64>,
+    /// Node names (and operator labels) are truncated to this many characters, once any
+    /// control or zero width characters have been removed from them.
+    pub max_node_name_length: usize,
+    /// How many nodes are described in each message sent to a feed when it subscribes
+    /// to a chain. This is clamped to at least 1.
+    pub feed_chunk_size: usize,
+    /// If given, nodes located close together at around the same time are described to
+    /// feeds as a single cluster, rather than one at a time.
+    pub node_clusters: Option<NodeClusterOpts>,
+    /// Ways to work out which group (eg a fleet run by one operator) each node belongs to,
+    /// which feeds are told when the node is added. The first to find a group for a node
+    /// wins. If there are none, nodes aren't grouped.
+    pub node_groupers: Vec<Arc<dyn NodeGrouper>>,
+    /// If given, nodes whose connection goes away are shown as stale for this long rather
+    /// than being removed straight away. If they reconnect with the same network ID in
+    /// the meantime, they replace themselves.
+    pub reconnect_grace_period: Option<Duration>,
+    /// If given, nodes which haven't sent us anything for this long are removed, even though
+    /// their connection is still open. Unlike nodes going stale, which feeds are only told
+    /// about, this frees up everything that we're keeping hold of for them.
+    pub node_idle_timeout: Option<Duration>,
+    /// If given, we process at most one update of each kind (eg "system.interval") from a
+    /// node within this long. Any others that arrive sooner are held back, each replacing
+    /// the last, and the latest is processed once the interval has passed (see
+    /// [`State::take_sampled_updates`]). Adding and removing nodes and chains is unaffected.
+    pub min_update_interval: Option<Duration>,
+}
+
+/// What identifies two nodes as being the same one?
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeDedupKey {
+    /// Never treat two nodes as being the same.
+    None,
+    /// The node's network (libp2p peer) ID.
+    NetworkId,
+    /// The name of the node.
+    Name,
+}
+
+impl NodeDedupKey {
+    fn key<'a>(&self, details: &'a NodeDetails) -> Option<&'a str> {
+        let key = match self {
+            NodeDedupKey::None => return None,
+            NodeDedupKey::NetworkId => &*details.network_id,
+            NodeDedupKey::Name => &*details.name,
+        };
+        // An empty key doesn't tell us anything about which node this is.
+        Some(key).filter(|key| !key.is_empty())
+    }
+}
+
+impl std::str::FromStr for NodeDedupKey {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(NodeDedupKey::None),
+            "network-id" => Ok(NodeDedupKey::NetworkId),
+            "name" => Ok(NodeDedupKey::Name),
+            _ => Err(anyhow::anyhow!(
+                "'{}' is not one of 'none', 'network-id' or 'name'",
+                s
+            )),
+        }
+    }
+}
+
+impl Default for StateOpts {
+    fn default() -> Self {
+        StateOpts {
+            denylist: Vec::new(),
+            allowlist: None,
+            max_third_party_nodes: 1000,
+            chain_quotas: HashMap::new(),
+            max_chains: None,
+            pinned_chains: Vec::new(),
+            min_chain_nodes: 0,
+            empty_chain_ttl: None,
+            chain_labels: HashMap::new(),
+            dedup_key: NodeDedupKey::None,
+            fork_detection: ForkDetectionOpts::default(),
+            finalization_stall: FinalizationStallOpts::default(),
+            max_height_deviation: 0,
+            finalized_quorum: None,
+            max_node_name_length: 64,
+            feed_chunk_size: 64,
+            node_clusters: None,
+            node_groupers: Vec::new(),
+            reconnect_grace_period: None,
+            node_idle_timeout: None,
+            min_update_interval: None,
+        }
+    }
 }
 
 /// Adding a node to a chain leads to this node_idult
 pub enum AddNodeResult<'a> {
     /// The chain is on the "deny list", so we can't add the node
     ChainOnDenyList,
+    /// We have an "allow list" and the chain isn't on it, so we can't add the node
+    ChainNotAllowed,
     /// The chain is over quota (too many nodes connected), so can't add the node
     ChainOverQuota,
+    /// The node would need a new chain, but we already have as many chains as we allow
+    TooManyChains,
     /// The node was added to the chain
     NodeAddedToChain(NodeAddedToChain<'a>),
 }
@@ -89,6 +299,9 @@ pub struct NodeAddedToChain<'a> {
     pub chain_node_count: usize,
     /// Has the chain label been updated?
     pub has_chain_label_changed: bool,
+    /// If this node replaced an existing one on the chain (because they look like
+    /// the same node), this is the ID of the existing node, which is now gone.
+    pub replaced_node_id: Option<NodeId>,
 }
 
 /// if removing a node is successful, we get this information back.
@@ -99,28 +312,144 @@ pub struct RemovedNode {
     pub has_chain_label_changed: bool,
     /// The old label of the chain.
     pub old_chain_label: Box<str>,
-    /// Genesis hash of the chain to be updated.
-    pub chain_genesis_hash: BlockHash,
-    /// The new label of the chain.
-    pub new_chain_label: Box<str>,
 }
 
 impl State {
-    pub fn new<T: IntoIterator<Item = String>>(denylist: T, max_third_party_nodes: usize) -> State {
+    pub fn new(opts: StateOpts) -> State {
         State {
             chains: DenseMap::new(),
             chains_by_genesis_hash: HashMap::new(),
-            denylist: denylist.into_iter().collect(),
-            max_third_party_nodes,
+            denylist: opts.denylist.into_iter().collect(),
+            allowlist: opts.allowlist.map(|hashes| hashes.into_iter().collect()),
+            max_third_party_nodes: opts.max_third_party_nodes,
+            chain_quotas: opts.chain_quotas,
+            max_chains: opts.max_chains,
+            nodes_over_chain_limit: 0,
+            pinned_chains: opts.pinned_chains.into_iter().collect(),
+            min_chain_nodes: opts.min_chain_nodes,
+            empty_chain_ttl: opts.empty_chain_ttl,
+            chain_labels: opts.chain_labels,
+            fork_detection: opts.fork_detection,
+            finalization_stall: opts.finalization_stall,
+            max_height_deviation: opts.max_height_deviation,
+            finalized_quorum: opts.finalized_quorum,
+            max_node_name_length: opts.max_node_name_length,
+            feed_chunk_size: opts.feed_chunk_size.max(1),
+            node_clusters: opts.node_clusters,
+            node_groupers: opts.node_groupers,
+            reconnect_grace_period: opts.reconnect_grace_period,
+            node_idle_timeout: opts.node_idle_timeout,
+            min_update_interval: opts.min_update_interval,
+            node_index: NodeIndex::new(opts.dedup_key),
         }
     }
 
+    /// Replace the list of chain labels that we do not want to allow connecting. The
+    /// IDs of any nodes already connected to a chain that's now denied are returned, so
+    /// that they can be removed.
+    pub fn set_denylist<T: IntoIterator<Item = String>>(&mut self, denylist: T) -> Vec<NodeId> {
+        self.denylist = denylist.into_iter().collect();
+
+        let mut denied_node_ids = Vec::new();
+        for (chain_id, chain) in self.chains.iter() {
+            for (idx, node) in chain.nodes_slice().iter().enumerate() {
+                let is_denied = node
+                    .as_ref()
+                    .map(|node| self.denylist.contains(&*node.details().chain))
+                    .unwrap_or(false);
+                if is_denied {
+                    denied_node_ids.push(NodeId(chain_id, idx.into()));
+                }
+            }
+        }
+        denied_node_ids
+    }
+
+    /// The labels of chains that nodes aren't allowed to connect to.
+    pub fn denylist(&self) -> impl Iterator<Item = &String> {
+        self.denylist.iter()
+    }
+
+    /// The genesis hashes of the only chains that nodes can connect to, if there's an allowlist.
+    pub fn allowlist(&self) -> Option<impl Iterator<Item = &BlockHash>> {
+        self.allowlist.as_ref().map(|allowlist| allowlist.iter())
+    }
+
+    /// How many nodes there are, across every chain.
+    pub fn node_count(&self) -> usize {
+        self.chains
+            .iter()
+            .map(|(_, chain)| chain.node_count())
+            .sum()
+    }
+
+    pub fn chain_count(&self) -> usize {
+        self.chains.len()
+    }
+
+    /// How many nodes each third party chain can have, unless it's been given a quota.
+    pub fn max_third_party_nodes(&self) -> usize {
+        self.max_third_party_nodes
+    }
+
+    /// The most nodes that specific chains can have, by genesis hash.
+    pub fn chain_quotas(&self) -> impl Iterator<Item = (&BlockHash, &usize)> {
+        self.chain_quotas.iter()
+    }
+
+    /// The most chains we'll keep track of at once, if there's a limit.
+    pub fn max_chains(&self) -> Option<usize> {
+        self.max_chains
+    }
+
+    /// How many nodes have been turned away because adding them would have taken us
+    /// over the maximum number of chains.
+    pub fn nodes_over_chain_limit(&self) -> u64 {
+        self.nodes_over_chain_limit
+    }
+
+    /// A rough estimate of how many bytes the chains and nodes we're holding take up. This
+    /// only counts the space reserved for each chain and node slot, and not anything that
+    /// they've allocated on the heap, so it's a lower bound, but it grows with the state.
+    pub fn estimated_size_bytes(&self) -> usize {
+        self.chains
+            .iter()
+            .map(|(_, chain)| {
+                std::mem::size_of::<Chain>() + std::mem::size_of_val(chain.nodes_slice())
+            })
+            .sum()
+    }
+
     pub fn iter_chains(&self) -> impl Iterator<Item = StateChain<'_>> {
         self.chains
             .iter()
             .map(move |(_, chain)| StateChain { chain })
     }
 
+    /// The most nodes that we should describe in a single message to a feed.
+    pub fn feed_chunk_size(&self) -> usize {
+        self.feed_chunk_size
+    }
+
+    /// How to group nodes located close together when telling feeds where they are, if at all.
+    pub fn node_clusters(&self) -> Option<NodeClusterOpts> {
+        self.node_clusters
+    }
+
+    /// Should feeds be told about the chain with this genesis hash, were it to have this
+    /// many nodes?
+    pub fn is_chain_advertised(&self, genesis_hash: &BlockHash, node_count: usize) -> bool {
+        node_count >= self.min_chain_nodes || self.pinned_chains.contains(genesis_hash)
+    }
+
+    /// Iterate over the chains in the order that we show them to feeds: those with the
+    /// most nodes first, and then by genesis hash so that the order is stable.
+    pub fn iter_chains_by_node_count(&self) -> impl Iterator<Item = StateChain<'_>> {
+        let mut chains: Vec<_> = self.iter_chains().collect();
+        chains.sort_by_key(|chain| (std::cmp::Reverse(chain.node_count()), chain.genesis_hash()));
+        chains.into_iter()
+    }
+
     pub fn get_chain_by_node_id(&self, node_id: NodeId) -> Option<StateChain<'_>> {
         self.chains.get(node_id.0).map(|chain| StateChain { chain })
     }
@@ -132,14 +461,35 @@ impl State {
             .map(|chain| StateChain { chain })
     }
 
+    /// Find the node known to feeds subscribed to the chain with this genesis hash as
+    /// `feed_node_id`.
+    pub fn find_node_id(&self, genesis_hash: &BlockHash, feed_node_id: usize) -> Option<NodeId> {
+        let &chain_id = self.chains_by_genesis_hash.get(genesis_hash)?;
+        let chain_node_id = ChainNodeId::from(feed_node_id);
+        self.chains.get(chain_id)?.get_node(chain_node_id)?;
+        Some(NodeId(chain_id, chain_node_id))
+    }
+
     pub fn add_node(
         &mut self,
         genesis_hash: BlockHash,
-        node_details: NodeDetails,
+        mut node_details: NodeDetails,
     ) -> AddNodeResult<'_> {
+        node_details.name = sanitize_node_name(&node_details.name, self.max_node_name_length);
+        node_details.operator = node_details
+            .operator
+            .as_deref()
+            .map(|operator| sanitize_node_name(operator, self.max_node_name_length))
+            .filter(|operator| !operator.is_empty());
+
         if self.denylist.contains(&*node_details.chain) {
             return AddNodeResult::ChainOnDenyList;
         }
+        if let Some(allowlist) = &self.allowlist {
+            if !allowlist.contains(&genesis_hash) {
+                return AddNodeResult::ChainNotAllowed;
+            }
+        }
 
         // Get the chain ID, creating a new empty chain if one doesn't exist.
         // If we create a chain here, we are expecting that it will allow at
@@ -148,11 +498,27 @@ impl State {
         let chain_id = match self.chains_by_genesis_hash.get(&genesis_hash) {
             Some(id) => *id,
             None => {
-                let max_nodes = match chain::is_first_party_network(&genesis_hash) {
-                    true => usize::MAX,
-                    false => self.max_third_party_nodes,
+                let is_exempt = chain::is_first_party_network(&genesis_hash)
+                    || self.chain_quotas.contains_key(&genesis_hash);
+                if matches!(self.max_chains, Some(max) if !is_exempt && self.chains.len() >= max) {
+                    self.nodes_over_chain_limit += 1;
+                    return AddNodeResult::TooManyChains;
+                }
+
+                let max_nodes = match self.chain_quotas.get(&genesis_hash) {
+                    Some(&quota) => quota,
+                    None if chain::is_first_party_network(&genesis_hash) => usize::MAX,
+                    None => self.max_third_party_nodes,
                 };
-                let chain_id = self.chains.add(Chain::new(genesis_hash, max_nodes));
+                let chain_id = self.chains.add(Chain::new(
+                    genesis_hash,
+                    max_nodes,
+                    self.fork_detection,
+                    self.max_height_deviation,
+                    self.chain_labels.get(&genesis_hash).cloned(),
+                    self.finalized_quorum,
+                    self.finalization_stall,
+                ));
                 self.chains_by_genesis_hash.insert(genesis_hash, chain_id);
                 chain_id
             }
@@ -163,51 +529,197 @@ impl State {
             "should be known to exist after the above (unless chains_by_genesis_hash out of sync)",
         );
 
-        let node = Node::new(node_details);
         let old_chain_label = chain.label().into();
 
+        // If this looks like a node that we already know about (ie it's reconnected before
+        // we noticed the old connection going away), replace the old one. This also frees
+        // up space for it if the chain is at its quota. Nodes restored from disk are always
+        // replaced by the node with the same network ID when it reconnects.
+        let mut replaced_node_id = None;
+        let mut chain_renamed_by_removal = false;
+        let existing = self.node_index.find(chain_id, &node_details);
+        if let Some(existing) = existing.and_then(|id| chain.get_node(id).map(|n| (id, n))) {
+            let (existing_id, existing_node) = existing;
+            self.node_index
+                .remove(NodeId(chain_id, existing_id), existing_node.details());
+            chain_renamed_by_removal = chain.remove_node(existing_id).chain_renamed;
+            replaced_node_id = Some(NodeId(chain_id, existing_id));
+        }
+
+        let group = self
+            .node_groupers
+            .iter()
+            .find_map(|grouper| grouper.group(&node_details));
+        let mut node = Node::new(node_details);
+        node.set_group(group);
         match chain.add_node(node) {
             chain::AddNodeResult::Overquota => AddNodeResult::ChainOverQuota,
             chain::AddNodeResult::Added { id, chain_renamed } => {
                 let chain = &*chain;
+                let node = chain.get_node(id).expect("node added above");
+                self.node_index.insert(NodeId(chain_id, id), node.details());
 
                 AddNodeResult::NodeAddedToChain(NodeAddedToChain {
                     id: NodeId(chain_id, id),
-                    node: chain.get_node(id).expect("node added above"),
+                    node,
                     old_chain_label,
                     new_chain_label: chain.label(),
                     chain_node_count: chain.node_count(),
-                    has_chain_label_changed: chain_renamed,
+                    has_chain_label_changed: chain_renamed || chain_renamed_by_removal,
+                    replaced_node_id,
                 })
             }
         }
     }
 
+    /// Add nodes that were previously written to disk. They're marked as stale until they
+    /// reconnect and replace themselves, and the IDs they're given are handed back.
+    pub fn restore(&mut self, persisted: PersistedState) -> Vec<NodeId> {
+        let mut restored_ids = Vec::new();
+        for persisted_chain in persisted.chains {
+            let genesis_hash = persisted_chain.genesis_hash;
+            for persisted_node in persisted_chain.nodes {
+                let node_id = match self.add_node(genesis_hash, persisted_node.details) {
+                    AddNodeResult::NodeAddedToChain(details) => details.id,
+                    _ => continue,
+                };
+                let NodeId(chain_id, chain_node_id) = node_id;
+                if let Some(chain) = self.chains.get_mut(chain_id) {
+                    chain.restore_node(
+                        chain_node_id,
+                        persisted_node.best,
+                        persisted_node.finalized,
+                        persisted_node.location.map(Arc::new),
+                        persisted_node.last_seen,
+                    );
+                    self.node_index
+                        .mark_restored(node_id, chain.get_node(chain_node_id));
+                }
+                restored_ids.push(node_id);
+            }
+            let chain_id = self.chains_by_genesis_hash.get(&genesis_hash);
+            let chain = chain_id.and_then(|&chain_id| self.chains.get_mut(chain_id));
+            if let (Some(chain), Some(first_seen)) = (chain, persisted_chain.first_seen) {
+                chain.restore_first_seen(first_seen);
+            }
+        }
+        restored_ids
+    }
+
+    /// Hand back the IDs of any restored nodes that haven't been replaced by a reconnecting
+    /// node yet; we'll stop caring about whether they are restored after this.
+    pub fn take_restored(&mut self) -> Vec<NodeId> {
+        self.node_index.take_restored()
+    }
+
+    /// A node's connection has gone away. If we've been given a grace period to wait for it
+    /// to reconnect in, it's marked as stale and we return true; it'll be replaced by the
+    /// next node with its network ID, or handed back from [`State::take_expired_disconnected`]
+    /// once the grace period is over. Otherwise it's up to the caller to remove it.
+    pub fn mark_disconnected(&mut self, node_id: NodeId, now: Instant) -> bool {
+        let grace_period = match self.reconnect_grace_period {
+            Some(grace_period) => grace_period,
+            None => return false,
+        };
+        let NodeId(chain_id, chain_node_id) = node_id;
+        let chain = match self.chains.get_mut(chain_id) {
+            Some(chain) => chain,
+            None => return false,
+        };
+        if !chain.mark_node_stale(chain_node_id) {
+            return false;
+        }
+        self.node_index.mark_disconnected(
+            node_id,
+            chain.get_node(chain_node_id),
+            now + grace_period,
+        );
+        true
+    }
+
+    /// Hand back the IDs of any disconnected nodes whose grace period is over, without
+    /// having been replaced by a reconnecting node.
+    pub fn take_expired_disconnected(&mut self, now: Instant) -> Vec<NodeId> {
+        self.node_index.take_expired_disconnected(now)
+    }
+
+    /// Hand back the IDs of any nodes which haven't sent us anything for at least the
+    /// `node_idle_timeout` that we were given. `now` is a unix timestamp in ms.
+    pub fn idle_nodes(&self, now: Timestamp) -> Vec<NodeId> {
+        let timeout = match self.node_idle_timeout {
+            Some(timeout) => timeout.as_millis() as u64,
+            None => return Vec::new(),
+        };
+        self.chains
+            .iter()
+            .flat_map(|(chain_id, chain)| {
+                chain
+                    .nodes_slice()
+                    .iter()
+                    .enumerate()
+                    .filter_map(move |(idx, node)| {
+                        let node = node.as_ref()?;
+                        let idle = now.saturating_sub(node.last_seen()) >= timeout;
+                        idle.then(|| NodeId(chain_id, idx.into()))
+                    })
+            })
+            .collect()
+    }
+
+    /// Remove any chains which have had no nodes for at least the `empty_chain_ttl` that
+    /// we were given, handing back their genesis hashes.
+    pub fn remove_empty_chains(&mut self, now: Instant) -> Vec<BlockHash> {
+        let ttl = match self.empty_chain_ttl {
+            Some(ttl) => ttl,
+            None => return Vec::new(),
+        };
+        let expired: Vec<ChainId> = self
+            .chains
+            .iter()
+            .filter(|(_, chain)| {
+                chain
+                    .empty_since()
+                    .is_some_and(|since| now.saturating_duration_since(since) >= ttl)
+            })
+            .map(|(chain_id, _)| chain_id)
+            .collect();
+
+        let mut removed = Vec::with_capacity(expired.len());
+        for chain_id in expired {
+            if let Some(chain) = self.chains.remove(chain_id) {
+                self.chains_by_genesis_hash.remove(&chain.genesis_hash());
+                removed.push(chain.genesis_hash());
+            }
+        }
+        removed
+    }
+
     /// Remove a node
     pub fn remove_node(&mut self, NodeId(chain_id, chain_node_id): NodeId) -> Option<RemovedNode> {
         let chain = self.chains.get_mut(chain_id)?;
         let old_chain_label = chain.label().into();
 
+        if let Some(node) = chain.get_node(chain_node_id) {
+            self.node_index
+                .remove(NodeId(chain_id, chain_node_id), node.details());
+        }
+
         // Actually remove the node
         let remove_result = chain.remove_node(chain_node_id);
 
         // Get updated chain details.
-        let new_chain_label: Box<str> = chain.label().into();
         let chain_node_count = chain.node_count();
-        let chain_genesis_hash = chain.genesis_hash();
 
-        // Is the chain empty? Remove if so and clean up indexes to it
-        if chain_node_count == 0 {
-            let genesis_hash = chain.genesis_hash();
+        // Is the chain empty? Remove if so (unless it's pinned) and clean up indexes to it
+        let genesis_hash = chain.genesis_hash();
+        if chain_node_count == 0 && !self.pinned_chains.contains(&genesis_hash) {
             self.chains_by_genesis_hash.remove(&genesis_hash);
             self.chains.remove(chain_id);
         }
 
         Some(RemovedNode {
             old_chain_label,
-            new_chain_label,
             chain_node_count,
-            chain_genesis_hash,
             has_chain_label_changed: remove_result.chain_renamed,
         })
     }
@@ -218,6 +730,7 @@ impl State {
         NodeId(chain_id, chain_node_id): NodeId,
         payload: Payload,
         feed: &mut FeedMessageSerializer,
+        now: Instant,
     ) {
         let chain = match self.chains.get_mut(chain_id) {
             Some(chain) => chain,
@@ -227,9 +740,37 @@ impl State {
             }
         };
 
+        // Nodes sending us updates too often have all but the latest held back:
+        let payload = match self.min_update_interval {
+            Some(interval) => match chain.sample_update(chain_node_id, payload, interval, now) {
+                Some(payload) => payload,
+                None => return,
+            },
+            None => payload,
+        };
+
         chain.update_node(chain_node_id, payload, feed)
     }
 
+    /// Hand back any updates that were held back by [`State::update_node`] for at least the
+    /// `min_update_interval` that we were given, along with the nodes that they're from.
+    /// These should be given to [`State::update_node`] again, which will now process them.
+    pub fn take_sampled_updates(&mut self, now: Instant) -> Vec<(NodeId, Payload)> {
+        let interval = match self.min_update_interval {
+            Some(interval) => interval,
+            None => return Vec::new(),
+        };
+        self.chains
+            .iter_mut()
+            .flat_map(|(chain_id, chain)| {
+                chain
+                    .take_sampled_updates(interval, now)
+                    .into_iter()
+                    .map(move |(chain_node_id, payload)| (NodeId(chain_id, chain_node_id), payload))
+            })
+            .collect()
+    }
+
     /// Update the location for a node. Return `false` if the node was not found.
     pub fn update_node_location(
         &mut self,
@@ -244,6 +785,142 @@ impl State {
     }
 }
 
+/// Node names (and operator labels) can be anything that the operator likes. Remove any
+/// characters which would mess up how the name is displayed, and keep it to a sensible length.
+fn sanitize_node_name(name: &str, max_len: usize) -> Box<str> {
+    let is_invisible = |c: char| {
+        c.is_control()
+            || matches!(c,
+                '\u{200B}'..='\u{200F}' // Zero width spaces and joiners, direction marks
+                | '\u{202A}'..='\u{202E}' // Direction embeddings and overrides
+                | '\u{2060}'..='\u{2064}' // Word joiner and invisible operators
+                | '\u{2066}'..='\u{2069}' // Direction isolates
+                | '\u{FEFF}' // Zero width no-break space
+            )
+    };
+    let needs_sanitizing = name.chars().count() > max_len || name.chars().any(is_invisible);
+    if !needs_sanitizing {
+        return name.into();
+    }
+    name.chars()
+        .filter(|&c| !is_invisible(c))
+        .take(max_len)
+        .collect()
+}
+
+/// Keeps track of nodes by the keys that we use to find them again when they
+/// reconnect. Nodes restored from disk, or waiting to reconnect, are always found by their
+/// network ID.
+struct NodeIndex {
+    dedup_key: NodeDedupKey,
+    by_dedup_key: HashMap<(ChainId, Box<str>), ChainNodeId>,
+    /// Nodes that were restored from disk, and haven't been replaced yet.
+    restored: HashSet<NodeId>,
+    /// Nodes that have disconnected, and when we'll stop waiting for them to reconnect.
+    disconnected: HashMap<NodeId, Instant>,
+    /// Restored and disconnected nodes, which are replaced by the next node to be
+    /// added with the same network ID regardless of the dedup key.
+    detached_by_network_id: HashMap<(ChainId, Box<str>), ChainNodeId>,
+}
+
+impl NodeIndex {
+    fn new(dedup_key: NodeDedupKey) -> Self {
+        NodeIndex {
+            dedup_key,
+            by_dedup_key: HashMap::new(),
+            restored: HashSet::new(),
+            disconnected: HashMap::new(),
+            detached_by_network_id: HashMap::new(),
+        }
+    }
+
+    /// Find an existing node on the chain that a node with these details should replace.
+    fn find(&self, chain_id: ChainId, details: &NodeDetails) -> Option<ChainNodeId> {
+        let existing = self
+            .dedup_key
+            .key(details)
+            .and_then(|key| self.by_dedup_key.get(&(chain_id, key.into())));
+        if existing.is_some() || self.detached_by_network_id.is_empty() {
+            return existing.copied();
+        }
+        NodeDedupKey::NetworkId
+            .key(details)
+            .and_then(|key| self.detached_by_network_id.get(&(chain_id, key.into())))
+            .copied()
+    }
+
+    fn insert(&mut self, NodeId(chain_id, id): NodeId, details: &NodeDetails) {
+        if let Some(key) = self.dedup_key.key(details) {
+            self.by_dedup_key.insert((chain_id, key.into()), id);
+        }
+    }
+
+    fn mark_restored(&mut self, node_id: NodeId, node: Option<&Node>) {
+        self.restored.insert(node_id);
+        self.mark_detached(node_id, node);
+    }
+
+    fn mark_disconnected(&mut self, node_id: NodeId, node: Option<&Node>, deadline: Instant) {
+        self.disconnected.insert(node_id, deadline);
+        self.mark_detached(node_id, node);
+    }
+
+    fn mark_detached(&mut self, NodeId(chain_id, id): NodeId, node: Option<&Node>) {
+        if let Some(key) = node.and_then(|n| NodeDedupKey::NetworkId.key(n.details())) {
+            self.detached_by_network_id
+                .insert((chain_id, key.into()), id);
+        }
+    }
+
+    fn remove(&mut self, node_id: NodeId, details: &NodeDetails) {
+        let NodeId(chain_id, id) = node_id;
+        // Only remove entries that still point at this node; a newer node may have replaced it.
+        if let Some(key) = self.dedup_key.key(details) {
+            let key = (chain_id, key.into());
+            if self.by_dedup_key.get(&key) == Some(&id) {
+                self.by_dedup_key.remove(&key);
+            }
+        }
+        let was_restored = self.restored.remove(&node_id);
+        let was_disconnected = self.disconnected.remove(&node_id).is_some();
+        if was_restored || was_disconnected {
+            if let Some(key) = NodeDedupKey::NetworkId.key(details) {
+                let key = (chain_id, key.into());
+                if self.detached_by_network_id.get(&key) == Some(&id) {
+                    self.detached_by_network_id.remove(&key);
+                }
+            }
+        }
+    }
+
+    fn take_restored(&mut self) -> Vec<NodeId> {
+        let restored = std::mem::take(&mut self.restored);
+        self.forget_detached(&restored);
+        restored.into_iter().collect()
+    }
+
+    fn take_expired_disconnected(&mut self, now: Instant) -> Vec<NodeId> {
+        let expired: HashSet<NodeId> = self
+            .disconnected
+            .iter()
+            .filter(|(_, &deadline)| deadline <= now)
+            .map(|(&node_id, _)| node_id)
+            .collect();
+        for node_id in &expired {
+            self.disconnected.remove(node_id);
+        }
+        self.forget_detached(&expired);
+        expired.into_iter().collect()
+    }
+
+    fn forget_detached(&mut self, node_ids: &HashSet<NodeId>) {
+        if !node_ids.is_empty() {
+            self.detached_by_network_id
+                .retain(|&(chain_id, _), &mut id| !node_ids.contains(&NodeId(chain_id, id)));
+        }
+    }
+}
+
 /// When we ask for a chain, we get this struct back. This ensures that we have
 /// a consistent public interface, and don't expose methods on [`Chain`] that
 /// aren't really intended for use outside of [`State`] methods. Any modification
@@ -280,32 +957,49 @@ impl<'a> StateChain<'a> {
     pub fn stats(&self) -> &ChainStats {
         self.chain.stats()
     }
+    pub fn propagation_update(&self) -> crate::feed_message::BlockPropagationUpdate {
+        self.chain.propagation_update()
+    }
+    pub fn finalization_stall(&self) -> Option<BlockNumber> {
+        self.chain.finalization_stall()
+    }
+    pub fn nodes_added(&self) -> u64 {
+        self.chain.nodes_added()
+    }
+    pub fn nodes_removed(&self) -> u64 {
+        self.chain.nodes_removed()
+    }
+    pub fn nodes_over_quota(&self) -> u64 {
+        self.chain.nodes_over_quota()
+    }
+    pub fn max_nodes(&self) -> usize {
+        self.chain.max_nodes()
+    }
+    pub fn first_seen(&self) -> Timestamp {
+        self.chain.first_seen()
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::state::{NetworkIdMapping, NetworkIdPrefix};
+    use crate::test_helpers::node_details;
     use common::node_types::NetworkId;
 
     fn node(name: &str, chain: &str) -> NodeDetails {
         NodeDetails {
             chain: chain.into(),
-            name: name.into(),
-            implementation: "Bar".into(),
             target_arch: Some("x86_64".into()),
             target_os: Some("linux".into()),
             target_env: Some("env".into()),
-            version: "0.1".into(),
-            validator: None,
-            network_id: NetworkId::new(),
-            startup_time: None,
-            sysinfo: None,
+            ..node_details(name)
         }
     }
 
     #[test]
     fn adding_a_node_returns_expected_response() {
-        let mut state = State::new(None, 1000);
+        let mut state = State::new(StateOpts::default());
 
         let chain1_genesis = BlockHash::from_low_u64_be(1);
 
@@ -313,7 +1007,9 @@ mod test {
 
         let add_node_result = match add_result {
             AddNodeResult::ChainOnDenyList => panic!("Chain not on deny list"),
+            AddNodeResult::ChainNotAllowed => panic!("Chain not disallowed"),
             AddNodeResult::ChainOverQuota => panic!("Chain not Overquota"),
+            AddNodeResult::TooManyChains => panic!("Not too many chains"),
             AddNodeResult::NodeAddedToChain(details) => details,
         };
 
@@ -327,7 +1023,9 @@ mod test {
 
         let add_node_result = match add_result {
             AddNodeResult::ChainOnDenyList => panic!("Chain not on deny list"),
+            AddNodeResult::ChainNotAllowed => panic!("Chain not disallowed"),
             AddNodeResult::ChainOverQuota => panic!("Chain not Overquota"),
+            AddNodeResult::TooManyChains => panic!("Not too many chains"),
             AddNodeResult::NodeAddedToChain(details) => details,
         };
 
@@ -340,7 +1038,7 @@ mod test {
 
     #[test]
     fn adding_and_removing_nodes_updates_chain_label_mapping() {
-        let mut state = State::new(None, 1000);
+        let mut state = State::new(StateOpts::default());
 
         let chain1_genesis = BlockHash::from_low_u64_be(1);
         let node_id0 = state
@@ -400,7 +1098,7 @@ mod test {
 
     #[test]
     fn chain_removed_when_last_node_is() {
-        let mut state = State::new(None, 1000);
+        let mut state = State::new(StateOpts::default());
 
         let chain1_genesis = BlockHash::from_low_u64_be(1);
         let node_id = state
@@ -415,4 +1113,483 @@ mod test {
         assert!(state.get_chain_by_genesis_hash(&chain1_genesis).is_none());
         assert_eq!(state.iter_chains().count(), 0);
     }
+
+    #[test]
+    fn estimated_size_grows_with_chains_and_nodes() {
+        let mut state = State::new(StateOpts::default());
+        assert_eq!(state.estimated_size_bytes(), 0);
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let chain2_genesis = BlockHash::from_low_u64_be(2);
+        let a = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+        let one_node = state.estimated_size_bytes();
+        assert!(one_node > 0);
+
+        let b = state
+            .add_node(chain1_genesis, node("B", "Chain One"))
+            .unwrap_id();
+        let two_nodes = state.estimated_size_bytes();
+        assert!(two_nodes > one_node);
+
+        let c = state
+            .add_node(chain2_genesis, node("C", "Chain Two"))
+            .unwrap_id();
+        assert!(state.estimated_size_bytes() > two_nodes);
+        assert_eq!(state.chain_count(), 2);
+
+        state.remove_node(a);
+        state.remove_node(b);
+        state.remove_node(c);
+        assert_eq!(state.chain_count(), 0);
+        assert_eq!(state.estimated_size_bytes(), 0);
+    }
+
+    #[test]
+    fn empty_chains_are_removed_once_their_ttl_is_up() {
+        let pinned_genesis = BlockHash::from_low_u64_be(1);
+        let over_quota_genesis = BlockHash::from_low_u64_be(2);
+        let ttl = Duration::from_secs(60);
+        let mut state = State::new(StateOpts {
+            pinned_chains: vec![pinned_genesis],
+            chain_quotas: [(over_quota_genesis, 0)].into_iter().collect(),
+            empty_chain_ttl: Some(ttl),
+            ..StateOpts::default()
+        });
+
+        // A pinned chain sticks around once its last node goes, and a chain whose only
+        // node was turned away is left behind with no nodes at all:
+        let node_id = state
+            .add_node(pinned_genesis, node("A", "Chain One"))
+            .unwrap_id();
+        assert!(matches!(
+            state.add_node(over_quota_genesis, node("B", "Chain Two")),
+            AddNodeResult::ChainOverQuota
+        ));
+        state.remove_node(node_id);
+        assert_eq!(state.chain_count(), 2);
+
+        // Neither is removed until they've been empty for long enough:
+        let start = Instant::now();
+        assert!(state.remove_empty_chains(start).is_empty());
+        assert_eq!(state.chain_count(), 2);
+
+        let mut removed = state.remove_empty_chains(start + ttl);
+        removed.sort();
+        assert_eq!(removed, vec![pinned_genesis, over_quota_genesis]);
+        assert_eq!(state.chain_count(), 0);
+        assert!(state.get_chain_by_genesis_hash(&pinned_genesis).is_none());
+
+        // A chain that gets a node again is no longer empty, and isn't removed:
+        state
+            .add_node(pinned_genesis, node("A", "Chain One"))
+            .unwrap_id();
+        assert!(state
+            .remove_empty_chains(Instant::now() + ttl * 2)
+            .is_empty());
+        assert_eq!(state.chain_count(), 1);
+    }
+
+    #[test]
+    fn updating_denylist_returns_denied_nodes() {
+        let mut state = State::new(StateOpts::default());
+
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let chain2_genesis = BlockHash::from_low_u64_be(2);
+        let node_id0 = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+        state
+            .add_node(chain2_genesis, node("B", "Chain Two"))
+            .unwrap_id();
+
+        let denied = state.set_denylist(vec!["Chain One".to_string()]);
+        assert_eq!(denied, vec![node_id0]);
+
+        // New nodes on the denied chain are turned away, too:
+        assert!(matches!(
+            state.add_node(chain1_genesis, node("C", "Chain One")),
+            AddNodeResult::ChainOnDenyList
+        ));
+
+        assert!(state.set_denylist(None).is_empty());
+    }
+
+    #[test]
+    fn only_allowed_chains_can_be_added() {
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let chain2_genesis = BlockHash::from_low_u64_be(2);
+        let mut state = State::new(StateOpts {
+            allowlist: Some(vec![chain1_genesis]),
+            ..Default::default()
+        });
+
+        assert!(matches!(
+            state.add_node(chain1_genesis, node("A", "Chain One")),
+            AddNodeResult::NodeAddedToChain(_)
+        ));
+        assert!(matches!(
+            state.add_node(chain2_genesis, node("B", "Chain Two")),
+            AddNodeResult::ChainNotAllowed
+        ));
+        assert_eq!(state.iter_chains().count(), 1);
+    }
+
+    #[test]
+    fn chain_quotas_override_the_default() {
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let chain2_genesis = BlockHash::from_low_u64_be(2);
+        let mut state = State::new(StateOpts {
+            max_third_party_nodes: 1,
+            chain_quotas: [(chain1_genesis, 2)].into_iter().collect(),
+            ..Default::default()
+        });
+
+        for name in ["A", "B"] {
+            assert!(matches!(
+                state.add_node(chain1_genesis, node(name, "Chain One")),
+                AddNodeResult::NodeAddedToChain(_)
+            ));
+        }
+        assert!(matches!(
+            state.add_node(chain1_genesis, node("C", "Chain One")),
+            AddNodeResult::ChainOverQuota
+        ));
+
+        // Other chains still use the default:
+        assert!(matches!(
+            state.add_node(chain2_genesis, node("D", "Chain Two")),
+            AddNodeResult::NodeAddedToChain(_)
+        ));
+        assert!(matches!(
+            state.add_node(chain2_genesis, node("E", "Chain Two")),
+            AddNodeResult::ChainOverQuota
+        ));
+
+        // Nodes that were turned away are counted against the chain they tried to join:
+        let chain1 = state.get_chain_by_genesis_hash(&chain1_genesis).unwrap();
+        assert_eq!(chain1.nodes_over_quota(), 1);
+        assert_eq!(chain1.nodes_added(), 2);
+    }
+
+    #[test]
+    fn new_chains_are_turned_away_once_there_are_too_many() {
+        let genesis = BlockHash::from_low_u64_be;
+        let mut state = State::new(StateOpts {
+            max_chains: Some(2),
+            chain_quotas: [(genesis(3), 10)].into_iter().collect(),
+            ..Default::default()
+        });
+
+        state.add_node(genesis(1), node("A", "Chain One"));
+        let b_id = state
+            .add_node(genesis(2), node("B", "Chain Two"))
+            .unwrap_id();
+        assert!(matches!(
+            state.add_node(genesis(4), node("C", "Chain Four")),
+            AddNodeResult::TooManyChains
+        ));
+        assert_eq!(state.nodes_over_chain_limit(), 1);
+
+        // Chains that we already know about, or that have a quota of their own, are fine:
+        assert!(matches!(
+            state.add_node(genesis(1), node("D", "Chain One")),
+            AddNodeResult::NodeAddedToChain(_)
+        ));
+        assert!(matches!(
+            state.add_node(genesis(3), node("E", "Chain Three")),
+            AddNodeResult::NodeAddedToChain(_)
+        ));
+        assert_eq!(state.chain_count(), 3);
+
+        // Room is made once chains go away:
+        state.remove_node(b_id);
+        assert!(matches!(
+            state.add_node(genesis(4), node("C", "Chain Four")),
+            AddNodeResult::TooManyChains
+        ));
+        state.remove_node(state.find_node_id(&genesis(3), 0).unwrap());
+        assert!(matches!(
+            state.add_node(genesis(4), node("C", "Chain Four")),
+            AddNodeResult::NodeAddedToChain(_)
+        ));
+        assert_eq!(state.nodes_over_chain_limit(), 2);
+    }
+
+    #[test]
+    fn nodes_with_the_same_dedup_key_are_replaced() {
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let mut state = State::new(StateOpts {
+            dedup_key: NodeDedupKey::NetworkId,
+            ..Default::default()
+        });
+
+        let with_network_id = |name: &str, network_id: &str| NodeDetails {
+            network_id: NetworkId::from(network_id).unwrap(),
+            ..node(name, "Chain One")
+        };
+
+        let node_id0 = state
+            .add_node(chain1_genesis, with_network_id("A", "peer0"))
+            .unwrap_id();
+        state
+            .add_node(chain1_genesis, with_network_id("B", "peer1"))
+            .unwrap_id();
+
+        // The same peer connects again, so it replaces the first node:
+        match state.add_node(chain1_genesis, with_network_id("A", "peer0")) {
+            AddNodeResult::NodeAddedToChain(details) => {
+                assert_eq!(details.replaced_node_id, Some(node_id0));
+                assert_eq!(details.chain_node_count, 2);
+            }
+            _ => panic!("Node should have been added"),
+        }
+
+        // Nodes without a network ID are never considered the same:
+        state.add_node(chain1_genesis, node("C", "Chain One"));
+        match state.add_node(chain1_genesis, node("C", "Chain One")) {
+            AddNodeResult::NodeAddedToChain(details) => {
+                assert_eq!(details.replaced_node_id, None);
+                assert_eq!(details.chain_node_count, 4);
+            }
+            _ => panic!("Node should have been added"),
+        }
+    }
+
+    #[test]
+    fn disconnected_nodes_are_replaced_or_expire() {
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let mut state = State::new(StateOpts {
+            reconnect_grace_period: Some(Duration::from_secs(10)),
+            ..Default::default()
+        });
+
+        let with_network_id = |name: &str, network_id: &str| NodeDetails {
+            network_id: NetworkId::from(network_id).unwrap(),
+            ..node(name, "Chain One")
+        };
+
+        let now = Instant::now();
+        let node_id0 = state
+            .add_node(chain1_genesis, with_network_id("A", "peer0"))
+            .unwrap_id();
+        let node_id1 = state
+            .add_node(chain1_genesis, with_network_id("B", "peer1"))
+            .unwrap_id();
+        assert!(state.mark_disconnected(node_id0, now));
+        assert!(state.mark_disconnected(node_id1, now));
+        let chain = state.get_chain_by_genesis_hash(&chain1_genesis).unwrap();
+        assert_eq!(chain.node_count(), 2);
+        assert!(chain.nodes_slice().iter().flatten().all(|n| n.stale()));
+
+        // The first node comes back in time, and replaces itself:
+        match state.add_node(chain1_genesis, with_network_id("A", "peer0")) {
+            AddNodeResult::NodeAddedToChain(details) => {
+                assert_eq!(details.replaced_node_id, Some(node_id0));
+                assert_eq!(details.chain_node_count, 2);
+            }
+            _ => panic!("Node should have been added"),
+        }
+
+        // The second doesn't:
+        assert!(state
+            .take_expired_disconnected(now + Duration::from_secs(5))
+            .is_empty());
+        assert_eq!(
+            state.take_expired_disconnected(now + Duration::from_secs(10)),
+            vec![node_id1]
+        );
+        assert!(state
+            .take_expired_disconnected(now + Duration::from_secs(20))
+            .is_empty());
+
+        // Without a grace period, it's up to the caller to remove nodes:
+        let mut state = State::new(StateOpts::default());
+        let node_id = state
+            .add_node(chain1_genesis, node("A", "Chain One"))
+            .unwrap_id();
+        assert!(!state.mark_disconnected(node_id, now));
+    }
+
+    #[test]
+    fn chains_are_ordered_by_node_count_then_genesis_hash() {
+        let mut state = State::new(StateOpts::default());
+        let genesis = BlockHash::from_low_u64_be;
+
+        state.add_node(genesis(3), node("A", "Chain Three"));
+        state.add_node(genesis(2), node("B", "Chain Two"));
+        state.add_node(genesis(1), node("C", "Chain One"));
+        state.add_node(genesis(2), node("D", "Chain Two"));
+
+        let order: Vec<BlockHash> = state
+            .iter_chains_by_node_count()
+            .map(|chain| chain.genesis_hash())
+            .collect();
+        assert_eq!(order, vec![genesis(2), genesis(1), genesis(3)]);
+    }
+
+    #[test]
+    fn nodes_added_and_removed_are_counted_per_chain() {
+        let mut state = State::new(StateOpts::default());
+        let chain1_genesis = BlockHash::from_low_u64_be(1);
+        let chain2_genesis = BlockHash::from_low_u64_be(2);
+
+        let node_id = match state.add_node(chain1_genesis, node("A", "Chain One")) {
+            AddNodeResult::NodeAddedToChain(details) => details.id,
+            _ => panic!("Node should have been added"),
+        };
+        state.add_node(chain1_genesis, node("B", "Chain One"));
+        state.add_node(chain2_genesis, node("C", "Chain Two"));
+        state.remove_node(node_id);
+
+        let chain1 = state.get_chain_by_genesis_hash(&chain1_genesis).unwrap();
+        assert_eq!((chain1.nodes_added(), chain1.nodes_removed()), (2, 1));
+        let chain2 = state.get_chain_by_genesis_hash(&chain2_genesis).unwrap();
+        assert_eq!((chain2.nodes_added(), chain2.nodes_removed()), (1, 0));
+    }
+
+    #[test]
+    fn chain_labels_can_be_overridden() {
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let mut state = State::new(StateOpts {
+            chain_labels: [(genesis_hash, "Curated".into())].into_iter().collect(),
+            ..Default::default()
+        });
+        let mut add =
+            |name: &str, chain: &str| match state.add_node(genesis_hash, node(name, chain)) {
+                AddNodeResult::NodeAddedToChain(details) => (
+                    details.new_chain_label.to_owned(),
+                    details.has_chain_label_changed,
+                ),
+                _ => panic!("Node should have been added"),
+            };
+
+        // Whatever the nodes call the chain, it's never renamed:
+        assert_eq!(add("A", "ugly-chain"), ("Curated".to_owned(), false));
+        assert_eq!(add("B", "Other"), ("Curated".to_owned(), false));
+        assert_eq!(add("C", "Other"), ("Curated".to_owned(), false));
+
+        // Other chains are labelled as usual:
+        match state.add_node(BlockHash::from_low_u64_be(2), node("D", "Chain Two")) {
+            AddNodeResult::NodeAddedToChain(details) => {
+                assert_eq!(details.new_chain_label, "Chain Two");
+                assert!(details.has_chain_label_changed);
+            }
+            _ => panic!("Node should have been added"),
+        }
+    }
+
+    #[test]
+    fn node_names_are_sanitized() {
+        let mut state = State::new(StateOpts {
+            max_node_name_length: 5,
+            ..Default::default()
+        });
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let mut add = |name: &str| match state.add_node(genesis_hash, node(name, "Chain One")) {
+            AddNodeResult::NodeAddedToChain(details) => details.node.details().name.clone(),
+            _ => panic!("Node should have been added"),
+        };
+
+        assert_eq!(&*add("Alice"), "Alice");
+        assert_eq!(&*add("Bob\u{200B}\n\u{202E}"), "Bob");
+        // Truncated by characters rather than bytes:
+        assert_eq!(&*add("ノードの名前"), "ノードの名");
+        assert_eq!(&*add("B\u{FEFF}\u{0007}ob the node"), "Bob t");
+    }
+
+    #[test]
+    fn operator_labels_are_sanitized() {
+        let mut state = State::new(StateOpts {
+            max_node_name_length: 5,
+            ..Default::default()
+        });
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let mut add = |operator: Option<&str>| {
+            let mut details = node("Alice", "Chain One");
+            details.operator = operator.map(Into::into);
+            match state.add_node(genesis_hash, details) {
+                AddNodeResult::NodeAddedToChain(details) => details.node.details().operator.clone(),
+                _ => panic!("Node should have been added"),
+            }
+        };
+
+        assert_eq!(add(None), None);
+        assert_eq!(add(Some("Acme")).as_deref(), Some("Acme"));
+        assert_eq!(
+            add(Some("Acme\u{202E} Validators")).as_deref(),
+            Some("Acme ")
+        );
+        // Labels with nothing visible in them are dropped:
+        assert_eq!(add(Some("\u{200B}\n")), None);
+    }
+    #[test]
+    fn nodes_are_grouped_by_the_first_grouper_that_matches() {
+        let mut state = State::new(StateOpts {
+            node_groupers: vec![
+                Arc::new(NetworkIdMapping::new([("12D3KooWA", "Fleet A")])),
+                Arc::new(NetworkIdPrefix(9)),
+            ],
+            ..Default::default()
+        });
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let mut add = |network_id: &str| {
+            let mut details = node("Alice", "Chain One");
+            details.network_id = NetworkId::from(network_id).unwrap();
+            match state.add_node(genesis_hash, details) {
+                AddNodeResult::NodeAddedToChain(details) => details.node.group().map(Into::into),
+                _ => panic!("Node should have been added"),
+            }
+        };
+
+        assert_eq!(add("12D3KooWAbcdef"), Some("Fleet A".to_owned()));
+        assert_eq!(add("12D3KooWBbcdef"), Some("12D3KooWB".to_owned()));
+        assert_eq!(add("12D3"), None);
+    }
+
+    #[test]
+    fn updates_sent_too_often_are_held_back() {
+        let interval = Duration::from_secs(3600);
+        let mut state = State::new(StateOpts {
+            min_update_interval: Some(interval),
+            ..Default::default()
+        });
+        let genesis_hash = BlockHash::from_low_u64_be(1);
+        let node_id = state
+            .add_node(genesis_hash, node("A", "Chain One"))
+            .unwrap_id();
+        let import = |height| {
+            Payload::BlockImport(Block {
+                hash: BlockHash::from_low_u64_be(height),
+                height,
+            })
+        };
+        let best_height = |state: &State| {
+            let chain = state.get_chain_by_node_id(node_id).unwrap();
+            chain.nodes_slice()[usize::from(node_id.get_chain_node_id())]
+                .as_ref()
+                .unwrap()
+                .best()
+                .height
+        };
+
+        // Only the first of these is processed straight away:
+        let start = Instant::now();
+        let mut feed = FeedMessageSerializer::new();
+        for height in 1..=3 {
+            state.update_node(node_id, import(height), &mut feed, start);
+        }
+        assert_eq!(best_height(&state), 1);
+
+        // The latest is handed back once the interval has passed, and then processed:
+        assert!(state.take_sampled_updates(start).is_empty());
+        let held = state.take_sampled_updates(start + interval);
+        assert_eq!(held.len(), 1);
+        assert_eq!(held[0].0, node_id);
+        for (node_id, payload) in held {
+            state.update_node(node_id, payload, &mut feed, start + interval);
+        }
+        assert_eq!(best_height(&state), 3);
+    }
 }
@@ -0,0 +1,129 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+use common::node_message::Payload;
+use std::time::{Duration, Instant};
+
+/// Holds back updates from a node that arrive more often than we'd like to process them.
+/// Updates of each kind are processed at most once per interval; any that arrive sooner
+/// are held on to, each replacing the last, so that only the latest is processed once the
+/// interval has passed.
+#[derive(Debug, Default)]
+pub struct UpdateSampler {
+    /// For each kind of update, when we last processed one and the latest that we've held
+    /// back since. There are only a handful of kinds, so these are just searched through.
+    kinds: Vec<(&'static str, Instant, Option<Payload>)>,
+}
+
+impl UpdateSampler {
+    /// Hand back the update if it should be processed now, or else hold on to it until
+    /// [`UpdateSampler::take_due`] hands it back.
+    pub fn sample(
+        &mut self,
+        payload: Payload,
+        interval: Duration,
+        now: Instant,
+    ) -> Option<Payload> {
+        let kind = payload.kind();
+        match self.kinds.iter_mut().find(|(k, ..)| *k == kind) {
+            Some((_, last_processed, held)) => {
+                if now.saturating_duration_since(*last_processed) < interval {
+                    *held = Some(payload);
+                    return None;
+                }
+                *last_processed = now;
+                // Anything held back is older than this, so it's no longer needed:
+                *held = None;
+            }
+            None => self.kinds.push((kind, now, None)),
+        }
+        Some(payload)
+    }
+
+    /// Hand back any held updates whose interval has passed. These should be given to
+    /// [`UpdateSampler::sample`] again, which will let them through.
+    pub fn take_due(&mut self, interval: Duration, now: Instant) -> Vec<Payload> {
+        self.kinds
+            .iter_mut()
+            .filter(|(_, last_processed, _)| {
+                now.saturating_duration_since(*last_processed) >= interval
+            })
+            .filter_map(|(_, _, held)| held.take())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use common::node_message::SystemVersion;
+    use common::node_types::{Block, BlockHash, BlockNumber};
+
+    fn import(height: BlockNumber) -> Payload {
+        Payload::BlockImport(Block {
+            hash: BlockHash::from_low_u64_be(height),
+            height,
+        })
+    }
+
+    fn height(payload: &Payload) -> Option<BlockNumber> {
+        payload.best_block().map(|block| block.height)
+    }
+
+    #[test]
+    fn only_the_latest_update_in_each_interval_is_kept() {
+        let mut sampler = UpdateSampler::default();
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+
+        // The first update is let through, and those soon after are held back:
+        assert!(sampler.sample(import(1), second, start).is_some());
+        assert!(sampler.sample(import(2), second, start).is_none());
+        assert!(sampler
+            .sample(import(3), second, start + second / 2)
+            .is_none());
+
+        // Updates of other kinds aren't affected:
+        let version = Payload::SystemVersion(SystemVersion {
+            version: "1.0.0".into(),
+            spec_version: None,
+        });
+        assert!(sampler.sample(version, second, start).is_some());
+
+        // Nothing is due until the interval has passed, and then only the latest update is:
+        assert!(sampler.take_due(second, start + second / 2).is_empty());
+        let due = sampler.take_due(second, start + second);
+        assert_eq!(due.iter().map(height).collect::<Vec<_>>(), vec![Some(3)]);
+        assert!(sampler.take_due(second, start + second).is_empty());
+
+        // Which is then let through, starting the interval again:
+        let due = due.into_iter().next().unwrap();
+        assert!(sampler.sample(due, second, start + second).is_some());
+        assert!(sampler.sample(import(4), second, start + second).is_none());
+    }
+
+    #[test]
+    fn held_updates_are_dropped_once_a_newer_one_is_let_through() {
+        let mut sampler = UpdateSampler::default();
+        let start = Instant::now();
+        let second = Duration::from_secs(1);
+
+        sampler.sample(import(1), second, start);
+        sampler.sample(import(2), second, start);
+        assert!(sampler.sample(import(3), second, start + second).is_some());
+        assert!(sampler.take_due(second, start + second * 2).is_empty());
+    }
+}
@@ -0,0 +1,98 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+//! Helpers shared between the tests in this crate.
+
+use crate::feed_message::FeedMessageSerializer;
+use crate::state::{
+    Chain, ChainAddNodeResult, ChainNodeId, FinalizationStallOpts, ForkDetectionOpts, Node,
+};
+use common::node_types::{BlockHash, NetworkId, NodeDetails};
+use test_utils::feed_message_de::FeedMessage;
+
+/// Details for a node called `name` on "Chain One", with nothing else of note
+/// about it. Tests fill in whatever else matters to them with struct update syntax.
+pub fn node_details(name: &str) -> NodeDetails {
+    NodeDetails {
+        chain: "Chain One".into(),
+        name: name.into(),
+        implementation: "Bar".into(),
+        version: "0.1".into(),
+        spec_version: None,
+        validator: None,
+        authority: false,
+        operator: None,
+        network_id: NetworkId::new(),
+        startup_time: None,
+        target_os: None,
+        target_arch: None,
+        target_env: None,
+        sysinfo: None,
+        sync_method: None,
+    }
+}
+
+/// How to set up a chain in [`chain_with_nodes`]. By default, there's no limit on the nodes
+/// that it can have, and nothing else of note about it.
+pub struct TestChainOpts {
+    pub max_nodes: usize,
+    pub finalized_quorum: Option<f64>,
+    pub finalization_stall: FinalizationStallOpts,
+}
+
+impl Default for TestChainOpts {
+    fn default() -> Self {
+        TestChainOpts {
+            max_nodes: usize::MAX,
+            finalized_quorum: None,
+            finalization_stall: FinalizationStallOpts::default(),
+        }
+    }
+}
+
+/// A chain set up as asked, with `n` nodes called "Node 0", "Node 1" and so on added to it.
+/// The IDs of the nodes are handed back in the order that they were added.
+pub fn chain_with_nodes(n: usize, opts: TestChainOpts) -> (Chain, Vec<ChainNodeId>) {
+    let mut chain = Chain::new(
+        BlockHash::from_low_u64_be(1),
+        opts.max_nodes,
+        ForkDetectionOpts::default(),
+        0,
+        None,
+        opts.finalized_quorum,
+        opts.finalization_stall,
+    );
+    let ids = (0..n)
+        .map(|n| add_node(&mut chain, node_details(&format!("Node {}", n))))
+        .collect();
+    (chain, ids)
+}
+
+/// Add a node with the details given to a chain, which mustn't be full yet.
+pub fn add_node(chain: &mut Chain, details: NodeDetails) -> ChainNodeId {
+    match chain.add_node(Node::new(details)) {
+        ChainAddNodeResult::Added { id, .. } => id,
+        ChainAddNodeResult::Overquota => panic!("chain should not be over quota"),
+    }
+}
+
+/// Everything that's been written to a serializer, decoded as a feed would see it.
+pub fn decode_feed(feed: FeedMessageSerializer) -> Vec<FeedMessage> {
+    match feed.into_finalized() {
+        Some(bytes) => FeedMessage::from_bytes(&bytes).expect("feed messages should decode"),
+        None => Vec::new(),
+    }
+}
@@ -37,7 +37,7 @@ cargo test e2e -- --ignored
 */
 
 use common::node_types::BlockHash;
-use common::ws_client::SentMessage;
+use common::ws_client::{self, SentMessage};
 use serde_json::json;
 use std::{str::FromStr, time::Duration};
 use test_utils::{
@@ -65,11 +65,17 @@ async fn e2e_feed_sent_version_on_connect() {
     // Connect a feed:
     let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
 
-    // Expect a version response of 31:
+    // Expect a version response of 33, and the (empty) network totals:
     let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
     assert_eq!(
         feed_messages,
-        vec![FeedMessage::Version(32)],
+        vec![
+            FeedMessage::Version(33),
+            FeedMessage::NetworkStats {
+                total_nodes: 0,
+                total_chains: 0
+            }
+        ],
         "expecting version"
     );
 
@@ -77,6 +83,30 @@ async fn e2e_feed_sent_version_on_connect() {
     server.shutdown().await;
 }
 
+/// Feeds can ask for an older version of the feed format.
+#[tokio::test]
+async fn e2e_feed_can_ask_for_older_version() {
+    let server = start_server_debug().await;
+
+    let (_feed_tx, mut feed_rx) = server
+        .get_core()
+        .connect_feed_with_version(32)
+        .await
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_eq!(feed_messages, vec![FeedMessage::Version(32)]);
+
+    // Versions we don't know about are rejected:
+    assert!(server
+        .get_core()
+        .connect_feed_with_version(1)
+        .await
+        .is_err());
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
 /// Another very simple test: pings from feeds should be responded to by pongs
 /// with the same message content.
 #[tokio::test]
@@ -123,11 +153,17 @@ async fn e2e_multiple_feeds_sent_version_on_connect() {
         .await
         .expect("we shouldn't hit a timeout waiting for responses");
 
-    // Expect a version response of 31 to all of them:
+    // Expect a version response of 33 to all of them:
     for feed_messages in responses {
         assert_eq!(
             feed_messages.expect("should have messages"),
-            vec![FeedMessage::Version(32)],
+            vec![
+                FeedMessage::Version(33),
+                FeedMessage::NetworkStats {
+                    total_nodes: 0,
+                    total_chains: 0
+                }
+            ],
             "expecting version"
         );
     }
@@ -604,6 +640,443 @@ async fn e2e_node_banned_if_it_sends_too_much_data() {
     );
 }
 
+/// If a node sends a message that's too big, it'll be disconnected.
+#[tokio::test]
+async fn e2e_node_disconnected_if_it_sends_too_big_a_message() {
+    async fn try_send_message(max_message_size: usize, message_size: usize) -> bool {
+        let mut server = start_server(
+            ServerOpts::default(),
+            CoreOpts::default(),
+            ShardOpts {
+                max_node_message_size: Some(max_message_size),
+                ..Default::default()
+            },
+        )
+        .await;
+
+        let shard_id = server.add_shard().await.unwrap();
+        let (node_tx, _node_rx) = server
+            .get_shard(shard_id)
+            .unwrap()
+            .connect_node()
+            .await
+            .unwrap();
+
+        node_tx
+            .unbounded_send(SentMessage::Binary(vec![1; message_size]))
+            .unwrap();
+
+        // Wait a little for the shard to react and cut off the connection (or not):
+        tokio::time::sleep(Duration::from_millis(250)).await;
+
+        // Has the connection been closed?
+        node_tx.is_closed()
+    }
+
+    assert_eq!(
+        try_send_message(1000, 1000).await,
+        false,
+        "shouldn't be closed; the message wasn't too big"
+    );
+    assert_eq!(
+        try_send_message(1000, 1001).await,
+        true,
+        "should be closed; the message was too big"
+    );
+}
+
+/// Feeds which don't respond to pings will be disconnected.
+#[tokio::test]
+async fn e2e_unresponsive_feeds_are_disconnected() {
+    let server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            feed_ping_interval: Some(1),
+            feed_ping_timeout: Some(1),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+
+    // A raw feed only answers pings when we receive from it, so if we wait
+    // without receiving anything, it'll miss the first ping and be booted.
+    let (_raw_feed_tx, mut raw_feed_rx) = server.get_core().connect_feed_raw().await.unwrap();
+    tokio::time::sleep(Duration::from_secs(4)).await;
+
+    // Drain anything out and expect to hit a "closed" error:
+    loop {
+        let mut v = Vec::new();
+        let data =
+            tokio::time::timeout(Duration::from_secs(2), raw_feed_rx.receive_data(&mut v)).await;
+
+        match data {
+            Ok(Ok(_)) => continue,
+            Ok(Err(_)) => break,
+            Err(_) => {
+                panic!("recv should be closed but seems to be happy waiting for more data");
+            }
+        }
+    }
+
+    // A feed that keeps receiving responds to pings, and so is kept around:
+    let (feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    feed_rx.recv_feed_messages().await.unwrap();
+    tokio::time::sleep(Duration::from_secs(4)).await;
+    feed_tx.send_command("ping", "still here?").unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::Pong {
+        msg: "still here?".to_owned()
+    }));
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// If the core is given a shard token, only shards which know it can connect and
+/// tell it about nodes.
+#[tokio::test]
+async fn e2e_shards_need_the_right_token() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            shard_token: Some("secret".to_owned()),
+            ..Default::default()
+        },
+        ShardOpts {
+            core_token: Some("secret".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // A shard with the right token can tell the core about nodes:
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+
+    // Wait a little for this message to propagate to the core:
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::AddedChain {
+        name: "Local Testnet".to_owned(),
+        genesis_hash: ghash(1),
+        node_count: 1,
+    }));
+
+    // Anything else trying to connect as a shard is turned away:
+    for path in ["/shard_submit", "/shard_submit?token=wrong"] {
+        let uri = format!("http://{}{}", server.get_core().host(), path)
+            .parse()
+            .unwrap();
+        match ws_client::connect(&uri).await {
+            Err(ws_client::ConnectError::ConnectionFailedRejected { status_code }) => {
+                assert_eq!(status_code, 401)
+            }
+            Err(e) => panic!("unexpected error connecting to {}: {}", path, e),
+            Ok(_) => panic!("shouldn't be able to connect to {}", path),
+        }
+    }
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Shards can also connect to any other addresses that the core is told to listen on, each
+/// with a shard token of its own. Nothing but shards (and health checks) is served there.
+#[tokio::test]
+async fn e2e_shards_can_connect_to_other_listeners() {
+    // Find a free port for the other listener:
+    let other_addr = std::net::TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap();
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            shard_token: Some("secret".to_owned()),
+            shard_listeners: vec![format!("{},token=other", other_addr)],
+            ..Default::default()
+        },
+        ShardOpts {
+            core_token: Some("secret".to_owned()),
+            ..Default::default()
+        },
+    )
+    .await;
+
+    // Wait for the other listener to start, too:
+    for _ in 0..50 {
+        if tokio::net::TcpStream::connect(other_addr).await.is_ok() {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+
+    // Shards with the other listener's token can connect to it, but not with the main one:
+    let connect = |path: &str| {
+        let uri: http::Uri = format!("http://{}{}", other_addr, path).parse().unwrap();
+        async move { ws_client::connect(&uri).await }
+    };
+    assert!(connect("/shard_submit?token=other").await.is_ok());
+    match connect("/shard_submit?token=secret").await {
+        Err(ws_client::ConnectError::ConnectionFailedRejected { status_code }) => {
+            assert_eq!(status_code, 401)
+        }
+        Err(e) => panic!("unexpected error connecting: {}", e),
+        Ok(_) => panic!("shouldn't be able to connect with the main shard token"),
+    }
+    match connect("/feed").await {
+        Err(ws_client::ConnectError::ConnectionFailedRejected { status_code }) => {
+            assert_eq!(status_code, 404)
+        }
+        Err(e) => panic!("unexpected error connecting: {}", e),
+        Ok(_) => panic!("feeds shouldn't be served on the other listener"),
+    }
+
+    // The main listener still accepts shards with its own token:
+    let shard_id = server.add_shard().await.unwrap();
+    assert!(server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .is_ok());
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// If the core is given a feed token, only feeds which offer it as a subprotocol
+/// can connect, and the core accepts that subprotocol in its response.
+#[tokio::test]
+async fn e2e_feeds_need_the_right_token() {
+    let server = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            feed_token: Some("secret".to_owned()),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+
+    let uri: http::Uri = format!("http://{}/feed", server.get_core().host())
+        .parse()
+        .unwrap();
+    let connect = |protocols: &[&str]| {
+        let opts = ws_client::ConnectOpts {
+            protocols: protocols.iter().map(|p| p.to_string()).collect(),
+            ..Default::default()
+        };
+        ws_client::connect_with_opts(&uri, opts)
+    };
+
+    // A feed with the right token is accepted, and told about the version of the feed:
+    let connection = connect(&["other", "feed-token.secret"]).await.unwrap();
+    assert_eq!(connection.protocol(), Some("feed-token.secret"));
+    let (_feed_tx, feed_rx) = connection.into_channels();
+    let mut feed_rx: test_utils::server::channels::FeedReceiver = feed_rx.into();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages
+        .iter()
+        .any(|m| matches!(m, FeedMessage::Version(..))));
+
+    // Anything else is turned away:
+    for protocols in [&[][..], &["feed-token.wrong"], &["secret"]] {
+        match connect(protocols).await {
+            Err(ws_client::ConnectError::ConnectionFailedRejected { status_code }) => {
+                assert_eq!(status_code, 401)
+            }
+            Err(e) => panic!("unexpected error offering {:?}: {}", protocols, e),
+            Ok(_) => panic!("shouldn't be able to connect offering {:?}", protocols),
+        }
+    }
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// Once they're up and running, the core and shards say that they're alive and ready for
+/// traffic, without going anywhere near the aggregator.
+#[tokio::test]
+async fn e2e_health_and_readiness_can_be_checked() {
+    let mut server = start_server_debug().await;
+    let shard_id = server.add_shard().await.unwrap();
+    let hosts = [
+        server.get_core().host().to_owned(),
+        server.get_shard(shard_id).unwrap().host().to_owned(),
+    ];
+
+    let client = hyper::Client::new();
+    for host in hosts {
+        for path in ["/health", "/healthz", "/readyz"] {
+            let uri: http::Uri = format!("http://{}{}", host, path).parse().unwrap();
+            let res = client.get(uri).await.unwrap();
+            assert_eq!(res.status(), 200, "{}{} should be OK", host, path);
+        }
+    }
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
+/// A core can mirror the chains and nodes of another core, by connecting to it as a feed,
+/// and its own feeds are told about them as if they'd connected to it directly.
+#[tokio::test]
+async fn e2e_core_can_mirror_an_upstream_core() {
+    let mut upstream = start_server_debug().await;
+    let shard_id = upstream.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = upstream
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+
+    let replica = start_server(
+        ServerOpts::default(),
+        CoreOpts {
+            upstream_feed: Some(format!("ws://{}/feed", upstream.get_core().host())),
+            ..Default::default()
+        },
+        ShardOpts::default(),
+    )
+    .await;
+
+    // Wait a little for the replica to hear about the chain and its node:
+    tokio::time::sleep(Duration::from_secs(1)).await;
+
+    let (feed_tx, mut feed_rx) = replica.get_core().connect_feed().await.unwrap();
+    feed_tx
+        .send_command("subscribe", &format!("{:?}", ghash(1)))
+        .unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert_contains_matches!(
+        feed_messages,
+        FeedMessage::AddedChain { name, genesis_hash, node_count: 1 } if name == "Local Testnet" && genesis_hash == ghash(1),
+        FeedMessage::SubscribedTo { genesis_hash } if genesis_hash == ghash(1),
+        FeedMessage::AddedNode { node: NodeDetails { name, authority: true, .. }, ..} if name == "Alice",
+    );
+
+    // When the node goes away upstream, it goes away here too:
+    node_tx.close().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::RemovedChain {
+        genesis_hash: ghash(1),
+    }));
+
+    // Tidy up:
+    replica.shutdown().await;
+    upstream.shutdown().await;
+}
+
+/// Shards can ask for their connection to the core to be compressed, and the
+/// core still hears about their nodes.
+#[tokio::test]
+async fn e2e_shard_connections_can_be_compressed() {
+    let mut server = start_server(
+        ServerOpts::default(),
+        CoreOpts::default(),
+        ShardOpts {
+            compress_core_connection: true,
+            ..Default::default()
+        },
+    )
+    .await;
+
+    let shard_id = server.add_shard().await.unwrap();
+    let (mut node_tx, _node_rx) = server
+        .get_shard(shard_id)
+        .unwrap()
+        .connect_node()
+        .await
+        .expect("can connect to shard");
+
+    node_tx
+        .send_json_text(json!(
+            {
+                "id":1,
+                "ts":"2021-07-12T10:37:47.714666+01:00",
+                "payload": {
+                    "authority":true,
+                    "chain":"Local Testnet",
+                    "config":"",
+                    "genesis_hash": ghash(1),
+                    "implementation":"Substrate Node",
+                    "msg":"system.connected",
+                    "name":"Alice",
+                    "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+                    "startup_time":"1625565542717",
+                    "version":"2.0.0-07a1af348-aarch64-macos"
+                },
+            }
+        ))
+        .unwrap();
+
+    // Wait a little for this message to propagate to the core:
+    tokio::time::sleep(Duration::from_millis(500)).await;
+
+    let (_feed_tx, mut feed_rx) = server.get_core().connect_feed().await.unwrap();
+    let feed_messages = feed_rx.recv_feed_messages().await.unwrap();
+    assert!(feed_messages.contains(&FeedMessage::AddedChain {
+        name: "Local Testnet".to_owned(),
+        genesis_hash: ghash(1),
+        node_count: 1,
+    }));
+
+    // Tidy up:
+    server.shutdown().await;
+}
+
 /// Feeds will be disconnected if they can't receive messages quickly enough.
 #[tokio::test]
 async fn e2e_slow_feeds_are_disconnected() {
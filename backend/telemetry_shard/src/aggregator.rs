@@ -19,7 +19,7 @@ use common::{
     internal_messages::{self, ShardNodeId},
     node_message,
     node_types::BlockHash,
-    AssignId,
+    tls, ws_client, AssignId,
 };
 use futures::{Sink, SinkExt};
 use std::collections::{HashMap, HashSet};
@@ -83,6 +83,25 @@ pub enum FromWebsocket {
 
 pub type FromAggregator = internal_messages::FromShardAggregator;
 
+/// Options to configure the aggregator loop.
+#[derive(Debug, Clone)]
+pub struct AggregatorOpts {
+    /// The URL of the telemetry core.
+    pub telemetry_uri: http::Uri,
+    /// How many messages can be waiting for the aggregator to handle them
+    /// before anything trying to send more has to wait.
+    pub node_queue_len: usize,
+    /// How many messages can be waiting to be sent to, or handled from, the
+    /// telemetry core.
+    pub core_queue_len: usize,
+    /// Ask the telemetry core to compress messages sent over our connection to it.
+    pub compress_core_connection: bool,
+    /// How to connect to the telemetry core if its URL is `wss://`.
+    pub core_tls: Option<Arc<tls::ClientConfig>>,
+    /// The longest we'll wait between attempts to connect to the telemetry core.
+    pub max_core_reconnect_delay: std::time::Duration,
+}
+
 /// The aggregator loop handles incoming messages from nodes, or from the telemetry core.
 /// this is where we decide what effect messages will have.
 #[derive(Clone)]
@@ -102,12 +121,21 @@ struct AggregatorInternal {
 
 impl Aggregator {
     /// Spawn a new Aggregator. This connects to the telemetry backend
-    pub async fn spawn(telemetry_uri: http::Uri) -> anyhow::Result<Aggregator> {
-        let (tx_to_aggregator, rx_from_external) = flume::bounded(10);
+    pub async fn spawn(opts: AggregatorOpts) -> anyhow::Result<Aggregator> {
+        let (tx_to_aggregator, rx_from_external) = flume::bounded(opts.node_queue_len);
 
         // Establish a resiliant connection to the core (this retries as needed):
-        let (tx_to_telemetry_core, rx_from_telemetry_core) =
-            create_ws_connection_to_core(telemetry_uri).await;
+        let (tx_to_telemetry_core, rx_from_telemetry_core) = create_ws_connection_to_core(
+            opts.telemetry_uri,
+            opts.core_queue_len,
+            ws_client::ConnectOpts {
+                compress: opts.compress_core_connection,
+                tls: opts.core_tls,
+                ..Default::default()
+            },
+            opts.max_core_reconnect_delay,
+        )
+        .await;
 
         // Forward messages from the telemetry core into the aggregator:
         let tx_to_aggregator2 = tx_to_aggregator.clone();
@@ -17,6 +17,10 @@
 use bincode::Options;
 use common::ws_client;
 use futures::StreamExt;
+use std::time::Duration;
+
+/// How long to wait before trying to connect again after losing the connection to the core.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
 
 #[derive(Clone, Debug)]
 pub enum Message<Out> {
@@ -28,6 +32,9 @@ pub enum Message<Out> {
 /// Connect to the telemetry core, retrying the connection if we're disconnected.
 /// - Sends `Message::Connected` and `Message::Disconnected` when the connection goes up/down.
 /// - Returns a channel that allows you to send messages to the connection.
+/// - Waits longer after each failed attempt to connect, up to `max_reconnect_delay`, and
+///   jitters each wait so that shards which all lost their connection at once (for instance
+///   because the core restarted) don't all try to reconnect at once too.
 /// - Messages are all encoded/decoded to/from bincode, and so need to support being (de)serialized from
 ///   a non self-describing encoding.
 ///
@@ -35,15 +42,19 @@ pub enum Message<Out> {
 /// between aggregator and core.
 pub async fn create_ws_connection_to_core<In, Out>(
     telemetry_uri: http::Uri,
+    queue_len: usize,
+    connect_opts: ws_client::ConnectOpts,
+    max_reconnect_delay: Duration,
 ) -> (flume::Sender<In>, flume::Receiver<Message<Out>>)
 where
     In: serde::Serialize + Send + 'static,
     Out: serde::de::DeserializeOwned + Send + 'static,
 {
-    let (tx_in, rx_in) = flume::bounded::<In>(10);
-    let (tx_out, rx_out) = flume::bounded(10);
+    let (tx_in, rx_in) = flume::bounded::<In>(queue_len);
+    let (tx_out, rx_out) = flume::bounded(queue_len);
 
     let mut is_connected = false;
+    let mut failed_attempts = 0;
 
     tokio::spawn(async move {
         loop {
@@ -55,10 +66,11 @@ where
             // Try to connect. If connection established, we serialize and forward messages
             // to/from the core. If the external channels break, we end for good. If the internal
             // channels break, we loop around and try connecting again.
-            match ws_client::connect(&telemetry_uri).await {
+            match ws_client::connect_with_opts(&telemetry_uri, connect_opts.clone()).await {
                 Ok(connection) => {
                     let (tx_to_core, mut rx_from_core) = connection.into_channels();
                     is_connected = true;
+                    failed_attempts = 0;
                     let tx_out = tx_out.clone();
 
                     if let Err(e) = tx_out.send_async(Message::Connected).await {
@@ -116,7 +128,10 @@ where
                     }
                 }
                 Err(connect_err) => {
-                    // Issue connecting? Wait and try again on the next loop iteration.
+                    // Issue connecting? Wait and try again on the next loop iteration. If the
+                    // core is turning us away because lots of shards are connecting, it'll
+                    // respond with a 503, and waiting longer each time helps it out.
+                    failed_attempts += 1;
                     log::error!(
                         "Error connecting to websocker server (will reconnect): {}",
                         connect_err
@@ -133,9 +148,43 @@ where
             }
 
             // Wait a little before we try to connect again.
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+            tokio::time::sleep(reconnect_delay(failed_attempts, max_reconnect_delay)).await;
         }
     });
 
     (tx_in, rx_out)
 }
+
+/// How long to wait before trying to connect to the core again, given how many attempts in
+/// a row have failed. This doubles with each failure up to `max_delay`, and then we wait for
+/// somewhere between half and all of that.
+fn reconnect_delay(failed_attempts: u32, max_delay: Duration) -> Duration {
+    let delay = MIN_RECONNECT_DELAY
+        .saturating_mul(1 << failed_attempts.min(16))
+        .min(max_delay)
+        .max(MIN_RECONNECT_DELAY);
+    delay.mul_f64(0.5 + rand::random::<f64>() / 2.0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn reconnect_delay_backs_off_with_jitter() {
+        let max_delay = Duration::from_secs(10);
+        let bounds = [
+            (0, 500, 1000),
+            (1, 1000, 2000),
+            (3, 4000, 8000),
+            (20, 5000, 10000),
+        ];
+        for (failed_attempts, min_ms, max_ms) in bounds {
+            for _ in 0..100 {
+                let delay = reconnect_delay(failed_attempts, max_delay);
+                assert!(delay >= Duration::from_millis(min_ms), "{:?}", delay);
+                assert!(delay <= Duration::from_millis(max_ms), "{:?}", delay);
+            }
+        }
+    }
+}
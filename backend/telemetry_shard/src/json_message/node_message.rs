@@ -71,13 +71,15 @@ pub enum Payload {
     #[serde(rename = "system.interval")]
     SystemInterval(SystemInterval),
     #[serde(rename = "block.import")]
-    BlockImport(Block),
+    BlockImport(BlockImport),
     #[serde(rename = "notify.finalized")]
     NotifyFinalized(Finalized),
     #[serde(rename = "afg.authority_set")]
     AfgAuthoritySet(AfgAuthoritySet),
     #[serde(rename = "sysinfo.hwbench")]
     HwBench(NodeHwBench),
+    #[serde(rename = "system.version")]
+    SystemVersion(SystemVersion),
 }
 
 impl From<Payload> for internal::Payload {
@@ -85,10 +87,11 @@ impl From<Payload> for internal::Payload {
         match msg {
             Payload::SystemConnected(m) => internal::Payload::SystemConnected(m.into()),
             Payload::SystemInterval(m) => internal::Payload::SystemInterval(m.into()),
-            Payload::BlockImport(m) => internal::Payload::BlockImport(m.into()),
+            Payload::BlockImport(m) => m.into(),
             Payload::NotifyFinalized(m) => internal::Payload::NotifyFinalized(m.into()),
             Payload::AfgAuthoritySet(m) => internal::Payload::AfgAuthoritySet(m.into()),
             Payload::HwBench(m) => internal::Payload::HwBench(m.into()),
+            Payload::SystemVersion(m) => internal::Payload::SystemVersion(m.into()),
         }
     }
 }
@@ -120,6 +123,19 @@ pub struct SystemInterval {
     #[serde(flatten)]
     pub block: Option<Block>,
     pub used_state_cache_size: Option<f32>,
+    /// CPU usage, as a percentage.
+    pub cpu: Option<f32>,
+    /// Memory used, in bytes. Older nodes sent other (fractional) values in this
+    /// field, so we accept any number rather than fail to parse the whole message.
+    pub memory: Option<f64>,
+    /// Disk space used, in bytes.
+    pub disk_usage: Option<f64>,
+    /// The height of the best block that the node knows about from its peers,
+    /// which it's syncing towards.
+    pub target_height: Option<BlockNumber>,
+    /// How the node is syncing (for example "full" or "warp"), if it's changed since
+    /// it connected.
+    pub sync_mode: Option<Box<str>>,
 }
 
 impl From<SystemInterval> for internal::SystemInterval {
@@ -133,6 +149,28 @@ impl From<SystemInterval> for internal::SystemInterval {
             finalized_hash: msg.finalized_hash.map(|h| h.into()),
             block: msg.block.map(|b| b.into()),
             used_state_cache_size: msg.used_state_cache_size,
+            cpu: msg.cpu,
+            memory: msg.memory.map(|bytes| bytes as u64),
+            disk_usage: msg.disk_usage.map(|bytes| bytes as u64),
+            target_height: msg.target_height,
+            sync_method: msg
+                .sync_mode
+                .and_then(|mode| node_types::SyncMethod::from_name(&mode)),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SystemVersion {
+    pub version: Box<str>,
+    pub spec_version: Option<u32>,
+}
+
+impl From<SystemVersion> for internal::SystemVersion {
+    fn from(msg: SystemVersion) -> Self {
+        internal::SystemVersion {
+            version: msg.version,
+            spec_version: msg.spec_version,
         }
     }
 }
@@ -186,6 +224,31 @@ impl From<Block> for node_types::Block {
     }
 }
 
+#[derive(Deserialize, Debug, Clone, Copy)]
+pub struct BlockImport {
+    #[serde(rename = "best")]
+    pub hash: Hash,
+    pub height: BlockNumber,
+    /// How long the node took to import the block, in ms, if it tells us.
+    pub import_time: Option<u64>,
+}
+
+impl From<BlockImport> for internal::Payload {
+    fn from(msg: BlockImport) -> Self {
+        let block = node_types::Block {
+            hash: msg.hash.into(),
+            height: msg.height,
+        };
+        match msg.import_time {
+            Some(import_time) => internal::Payload::TimedBlockImport(internal::TimedBlockImport {
+                block,
+                import_time,
+            }),
+            None => internal::Payload::BlockImport(block),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 pub struct NodeSysInfo {
     pub cpu: Option<Box<str>>,
@@ -245,13 +308,22 @@ pub struct NodeDetails {
     pub name: Box<str>,
     pub implementation: Box<str>,
     pub version: Box<str>,
+    /// The spec version of the node's runtime. Not all nodes send this.
+    pub spec_version: Option<u32>,
     pub validator: Option<Box<str>>,
+    /// Nodes which aren't authorities may not tell us either way.
+    #[serde(default)]
+    pub authority: bool,
+    /// An optional label for the node's operator, so that nodes can be grouped by who runs them.
+    pub operator: Option<Box<str>>,
     pub network_id: node_types::NetworkId,
     pub startup_time: Option<Box<str>>,
     pub target_os: Option<Box<str>>,
     pub target_arch: Option<Box<str>>,
     pub target_env: Option<Box<str>>,
     pub sysinfo: Option<NodeSysInfo>,
+    /// How the node is syncing: "full", "fast" or "warp". Anything else is ignored.
+    pub sync_mode: Option<Box<str>>,
 }
 
 impl From<NodeDetails> for node_types::NodeDetails {
@@ -277,13 +349,19 @@ impl From<NodeDetails> for node_types::NodeDetails {
             name: details.name,
             implementation: details.implementation,
             version: details.version,
+            spec_version: details.spec_version,
             validator: details.validator,
+            authority: details.authority,
+            operator: details.operator,
             network_id: details.network_id,
             startup_time: details.startup_time,
             target_os: details.target_os,
             target_arch: details.target_arch,
             target_env: details.target_env,
             sysinfo: details.sysinfo.map(|sysinfo| sysinfo.into()),
+            sync_method: details
+                .sync_mode
+                .and_then(|mode| node_types::SyncMethod::from_name(&mode)),
         }
     }
 }
@@ -359,6 +437,150 @@ mod tests {
         );
     }
 
+    #[test]
+    fn system_interval_with_resource_usage() {
+        let json = r#"{
+            "msg":"system.interval",
+            "level":"INFO",
+            "ts":"2021-01-13T12:38:25.410794650+01:00",
+            "peers":5,
+            "cpu":12.5,
+            "memory":2000000000,
+            "disk_usage":30000000000.0,
+            "target_height":1000
+        }"#;
+        let msg: internal::Payload = match serde_json::from_str::<NodeMessage>(json).unwrap() {
+            NodeMessage::V1 { payload } => payload.into(),
+            _ => panic!("message did not match variant V1"),
+        };
+        match msg {
+            internal::Payload::SystemInterval(interval) => {
+                assert_eq!(interval.peers, Some(5));
+                assert_eq!(interval.cpu, Some(12.5));
+                assert_eq!(interval.memory, Some(2_000_000_000));
+                assert_eq!(interval.disk_usage, Some(30_000_000_000));
+                assert_eq!(interval.target_height, Some(1000));
+            }
+            _ => panic!("message should be a system interval"),
+        }
+    }
+
+    #[test]
+    fn system_connected_with_authority() {
+        let json = r#"{
+            "msg":"system.connected",
+            "genesis_hash":"0x91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3",
+            "chain":"Polkadot",
+            "name":"Alice",
+            "implementation":"Parity Polkadot",
+            "version":"0.9.17",
+            "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+            "authority":true
+        }"#;
+        let is_authority = |json: &str| {
+            let msg: internal::Payload = match serde_json::from_str::<NodeMessage>(json).unwrap() {
+                NodeMessage::V1 { payload } => payload.into(),
+                _ => panic!("message did not match variant V1"),
+            };
+            match msg {
+                internal::Payload::SystemConnected(connected) => connected.node.authority,
+                _ => panic!("message should be a system connected"),
+            }
+        };
+        assert!(is_authority(json));
+        // Nodes that don't say are assumed not to be authorities:
+        assert!(!is_authority(
+            &json.replace(r#""authority":true"#, r#""config":"""#)
+        ));
+    }
+
+    #[test]
+    fn system_connected_with_operator() {
+        let json = r#"{
+            "msg":"system.connected",
+            "genesis_hash":"0x91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3",
+            "chain":"Polkadot",
+            "name":"Alice",
+            "implementation":"Parity Polkadot",
+            "version":"0.9.17",
+            "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+            "operator":"Acme Validators"
+        }"#;
+        let operator = |json: &str| {
+            let msg: internal::Payload = match serde_json::from_str::<NodeMessage>(json).unwrap() {
+                NodeMessage::V1 { payload } => payload.into(),
+                _ => panic!("message did not match variant V1"),
+            };
+            match msg {
+                internal::Payload::SystemConnected(connected) => connected.node.operator,
+                _ => panic!("message should be a system connected"),
+            }
+        };
+        assert_eq!(operator(json).as_deref(), Some("Acme Validators"));
+        assert_eq!(
+            operator(&json.replace(r#""operator":"Acme Validators""#, r#""config":"""#)),
+            None
+        );
+    }
+
+    #[test]
+    fn system_connected_with_sync_mode() {
+        let json = r#"{
+            "msg":"system.connected",
+            "genesis_hash":"0x91b171bb158e2d3848fa23a9f1c25182fb8e20313b2c1eb49219da7a70ce90c3",
+            "chain":"Polkadot",
+            "name":"Alice",
+            "implementation":"Parity Polkadot",
+            "version":"0.9.17",
+            "network_id":"12D3KooWEyoppNCUx8Yx66oV9fJnriXwCcXwDDUA2kj6vnc6iDEp",
+            "sync_mode":"warp"
+        }"#;
+        let sync_method = |json: &str| {
+            let msg: internal::Payload = match serde_json::from_str::<NodeMessage>(json).unwrap() {
+                NodeMessage::V1 { payload } => payload.into(),
+                _ => panic!("message did not match variant V1"),
+            };
+            match msg {
+                internal::Payload::SystemConnected(connected) => connected.node.sync_method,
+                _ => panic!("message should be a system connected"),
+            }
+        };
+        assert_eq!(sync_method(json), Some(node_types::SyncMethod::Warp));
+        // Nodes that don't say, or sync in a way we don't know about, are left unknown:
+        assert_eq!(
+            sync_method(&json.replace(r#""sync_mode":"warp""#, r#""config":"""#)),
+            None
+        );
+        assert_eq!(
+            sync_method(&json.replace(r#""sync_mode":"warp""#, r#""sync_mode":"magic""#)),
+            None
+        );
+    }
+
+    #[test]
+    fn system_version() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "msg":"system.version",
+                "version":"0.9.18",
+                "spec_version":9180
+            }
+        }"#;
+        let msg: internal::Payload = match serde_json::from_str::<NodeMessage>(json).unwrap() {
+            NodeMessage::V2 { payload, .. } => payload.into(),
+            _ => panic!("message did not match variant V2"),
+        };
+        match msg {
+            internal::Payload::SystemVersion(version) => {
+                assert_eq!(&*version.version, "0.9.18");
+                assert_eq!(version.spec_version, Some(9180));
+            }
+            _ => panic!("message should be a system version"),
+        }
+    }
+
     #[test]
     fn message_v2() {
         let json = r#"{
@@ -398,7 +620,10 @@ mod tests {
             matches!(
                 serde_json::from_str::<NodeMessage>(json).unwrap(),
                 NodeMessage::V2 {
-                    payload: Payload::BlockImport(Block { .. }),
+                    payload: Payload::BlockImport(BlockImport {
+                        import_time: None,
+                        ..
+                    }),
                     ..
                 },
             ),
@@ -406,6 +631,31 @@ mod tests {
         );
     }
 
+    #[test]
+    fn message_v2_block_import_with_import_time() {
+        let json = r#"{
+            "id":1,
+            "ts":"2021-01-13T12:22:20.053527101+01:00",
+            "payload":{
+                "msg":"block.import",
+                "best":"0xcc41708573f2acaded9dd75e07dac2d4163d136ca35b3061c558d7a35a09dd8d",
+                "height": 1234,
+                "import_time": 250
+            }
+        }"#;
+        let msg: internal::NodeMessage = serde_json::from_str::<NodeMessage>(json).unwrap().into();
+        assert!(
+            matches!(
+                msg.into_payload(),
+                internal::Payload::TimedBlockImport(internal::TimedBlockImport {
+                    block: node_types::Block { height: 1234, .. },
+                    import_time: 250,
+                }),
+            ),
+            "message did not match the expected output",
+        );
+    }
+
     #[test]
     fn split_old_style_version_works() {
         let (version, target_arch, target_os, target_env) =
@@ -19,7 +19,6 @@ mod aggregator;
 mod blocked_addrs;
 mod connection;
 mod json_message;
-mod real_ip;
 
 use std::{
     collections::HashMap,
@@ -27,13 +26,18 @@ use std::{
     time::{Duration, Instant},
 };
 
-use aggregator::{Aggregator, FromWebsocket};
+use aggregator::{Aggregator, AggregatorOpts, FromWebsocket};
 use blocked_addrs::BlockedAddrs;
 use common::byte_size::ByteSize;
-use common::http_utils;
+use common::http_utils::{self, BasePath};
+use common::ip_range::IpRange;
+use common::logging::{self, JsonLogger, LogFormat};
 use common::node_message;
 use common::node_message::NodeMessageId;
+use common::real_ip;
 use common::rolling_total::RollingTotalBuilder;
+use common::secret_token::SecretToken;
+use common::tls;
 use futures::{SinkExt, StreamExt};
 use http::Uri;
 use hyper::{Method, Response};
@@ -61,10 +65,20 @@ struct Opts {
     /// you are using Telemetry in a container, you likely want to set this to '0.0.0.0:8000'
     #[structopt(short = "l", long = "listen", default_value = "127.0.0.1:8001")]
     socket: std::net::SocketAddr,
+    /// A path to serve every endpoint under (eg "/telemetry"), including the endpoint that nodes submit telemetry to. This
+    /// is for running behind a reverse proxy which doesn't strip the path from requests.
+    /// Health checks are answered at the root as well.
+    #[structopt(long, default_value = "/")]
+    base_path: BasePath,
     /// The desired log level; one of 'error', 'warn', 'info', 'debug' or 'trace', where
     /// 'error' only logs errors and 'trace' logs everything.
     #[structopt(long = "log", default_value = "info")]
     log_level: log::LevelFilter,
+    /// How to write out logs; either 'text' for human readable lines, or 'json' to write
+    /// one JSON object per line, including extra fields like the IP address of the
+    /// connection being logged about where we know them.
+    #[structopt(long, default_value = "text")]
+    log_format: LogFormat,
     /// Url to the Backend Core endpoint accepting shard connections
     #[structopt(
         short = "c",
@@ -72,6 +86,10 @@ struct Opts {
         default_value = "ws://127.0.0.1:8000/shard_submit/"
     )]
     core_url: Uri,
+    /// The secret that the Backend Core expects shards to provide when they connect to it
+    /// (see its '--shard-token' option), if any.
+    #[structopt(long)]
+    core_token: Option<SecretToken>,
     /// How many different nodes is a given connection to the /submit endpoint allowed to
     /// tell us about before we ignore the rest?
     ///
@@ -88,6 +106,11 @@ struct Opts {
     /// traffic on average (at least initially).
     #[structopt(long, default_value = "256k")]
     max_node_data_per_second: ByteSize,
+    /// The largest message that a node can send us. Nodes sending anything bigger are
+    /// disconnected rather than having it buffered. The largest messages that nodes
+    /// normally send are the details they send when first connecting, which are a few KB.
+    #[structopt(long, default_value = "1MiB")]
+    max_node_message_size: ByteSize,
     /// How many seconds is a "/feed" connection that violates the '--max-node-data-per-second'
     /// value prevented from reconnecting to this shard for, in seconds.
     #[structopt(long, default_value = "600")]
@@ -102,15 +125,58 @@ struct Opts {
     /// dropped.
     #[structopt(long, default_value = "60")]
     stale_node_timeout: u64,
+    /// How many decoded node messages can be waiting for the shard to handle them. When this
+    /// many are waiting, every node connection stops reading until there's room again, so a
+    /// longer queue rides out bursts of node traffic without slowing nodes down. Nothing in it
+    /// is bigger than '--max-node-message-size'.
+    #[structopt(long, default_value = "10")]
+    node_queue_len: usize,
+    /// How many messages can be queued in each direction on our connection to the Backend
+    /// Core: waiting to be sent to it, and received from it but not yet handled. While the
+    /// outgoing queue is full, node messages wait in the '--node-queue-len' queue instead.
+    /// The core only ever sends us small messages asking for a node to be muted.
+    #[structopt(long, default_value = "10")]
+    core_queue_len: usize,
+    /// Compress node messages on the way to the Backend Core, using the "permessage-deflate"
+    /// websocket extension. This is worth having if bandwidth to the core is limited or
+    /// expensive, at the cost of some CPU time on both ends to compress and decompress
+    /// messages. The core logs how much was saved when each shard connection closes.
+    #[structopt(long)]
+    compress_core_connection: bool,
+    /// If the Backend Core is serving TLS (ie '--core' is a 'wss://' URL) with a certificate
+    /// that isn't signed by one of the usual web authorities, this is a PEM encoded file of
+    /// certificates to trust as well, such as the core's own self-signed certificate.
+    #[structopt(long)]
+    core_ca_cert: Option<std::path::PathBuf>,
+    /// The longest to wait in seconds between attempts to connect to the Backend Core. We wait
+    /// about a second after losing the connection, and twice as long after each failed attempt
+    /// up to this, with some jitter so that shards don't all retry together when the core
+    /// restarts. Cores limiting how quickly shards connect (see its
+    /// '--max-shard-connections-per-second' option) turn the rest away until they retry.
+    #[structopt(long, default_value = "30")]
+    max_core_reconnect_delay: u64,
+    /// The header that proxies in front of this shard put the real IP address of nodes in,
+    /// such as 'X-Forwarded-For' or 'X-Real-IP'. This is the address that nodes are located
+    /// with. If not given, the 'Forwarded', 'X-Forwarded-For' and 'X-Real-IP' headers are
+    /// each tried in turn.
+    #[structopt(long)]
+    real_ip_header: Option<hyper::header::HeaderName>,
+    /// Space delimited list of the CIDR ranges (eg "10.0.0.0/8") of proxies that we trust to
+    /// tell us the real IP address of nodes. If given, headers are ignored on connections
+    /// from anywhere else, and these proxies are skipped over when they've added themselves
+    /// to them. If not given, the headers are believed whoever sends them.
+    #[structopt(long, required = false)]
+    trusted_proxy: Vec<IpRange>,
 }
 
 fn main() {
     let opts = Opts::from_args();
 
-    SimpleLogger::new()
-        .with_level(opts.log_level)
-        .init()
-        .expect("Must be able to start a logger");
+    match opts.log_format {
+        LogFormat::Text => SimpleLogger::new().with_level(opts.log_level).init(),
+        LogFormat::Json => JsonLogger::new(opts.log_level).init(),
+    }
+    .expect("Must be able to start a logger");
 
     log::info!("Starting Telemetry Shard version: {}", VERSION);
 
@@ -135,25 +201,71 @@ fn main() {
         });
 }
 
+/// The core is handed the shard token in the query string of the URL that we connect to.
+fn core_url_with_token(core_url: Uri, token: Option<&SecretToken>) -> anyhow::Result<Uri> {
+    let token = match token {
+        Some(token) => token,
+        None => return Ok(core_url),
+    };
+    let mut parts = core_url.into_parts();
+    let path_and_query = match &parts.path_and_query {
+        Some(pq) => match pq.query() {
+            Some(query) => format!("{}?{}&token={}", pq.path(), query, token.as_str()),
+            None => format!("{}?token={}", pq.path(), token.as_str()),
+        },
+        None => format!("/?token={}", token.as_str()),
+    };
+    parts.path_and_query = Some(path_and_query.parse()?);
+    Ok(Uri::from_parts(parts)?)
+}
+
 /// Declare our routes and start the server.
 async fn start_server(opts: Opts) -> anyhow::Result<()> {
     let block_list = BlockedAddrs::new(Duration::from_secs(opts.node_block_seconds));
-    let aggregator = Aggregator::spawn(opts.core_url).await?;
+    let core_url = core_url_with_token(opts.core_url, opts.core_token.as_ref())?;
+    let aggregator = Aggregator::spawn(AggregatorOpts {
+        telemetry_uri: core_url,
+        node_queue_len: opts.node_queue_len,
+        core_queue_len: opts.core_queue_len,
+        compress_core_connection: opts.compress_core_connection,
+        core_tls: match &opts.core_ca_cert {
+            Some(path) => Some(tls::client_config(Some(path))?),
+            None => None,
+        },
+        max_core_reconnect_delay: Duration::from_secs(opts.max_core_reconnect_delay),
+    })
+    .await?;
     let socket_addr = opts.socket;
+    let base_path = opts.base_path;
     let max_nodes_per_connection = opts.max_nodes_per_connection;
     let bytes_per_second = opts.max_node_data_per_second;
+    let node_ws_opts = http_utils::WsOpts {
+        max_message_size: opts.max_node_message_size.num_bytes(),
+    };
     let stale_node_timeout = Duration::from_secs(opts.stale_node_timeout);
+    let real_ip_opts = real_ip::RealIpOpts {
+        header: opts.real_ip_header,
+        trusted_proxies: opts.trusted_proxy,
+    };
+
+    let ready = http_utils::Readiness::new();
 
-    let server = http_utils::start_server(socket_addr, move |addr, req| {
+    let server = http_utils::start_server(socket_addr, None, ready.clone(), move |addr, req| {
         let aggregator = aggregator.clone();
+        let ready = ready.clone();
         let block_list = block_list.clone();
+        let real_ip_opts = real_ip_opts.clone();
+        let base_path = base_path.clone();
         async move {
-            match (req.method(), req.uri().path().trim_end_matches('/')) {
+            match (req.method(), base_path.route(req.uri().path())) {
                 // Check that the server is up and running:
-                (&Method::GET, "/health") => Ok(Response::new("OK".into())),
+                (&Method::GET, "/health" | "/healthz") => Ok(Response::new("OK".into())),
+                // Check that we're accepting nodes:
+                (&Method::GET, "/readyz") => Ok(ready.response()),
                 // Nodes send messages here:
                 (&Method::GET, "/submit") => {
-                    let (real_addr, real_addr_source) = real_ip::real_ip(addr, req.headers());
+                    let (real_addr, real_addr_source) =
+                        real_ip::real_ip_with_opts(addr, req.headers(), &real_ip_opts);
 
                     if let Some(reason) = block_list.blocked_reason(&real_addr) {
                         return Ok(Response::builder().status(403).body(reason.into()).unwrap());
@@ -161,33 +273,36 @@ async fn start_server(opts: Opts) -> anyhow::Result<()> {
 
                     Ok(http_utils::upgrade_to_websocket(
                         req,
-                        move |ws_send, ws_recv| async move {
-                            log::info!(
-                                "Opening /submit connection from {:?} (address source: {})",
-                                real_addr,
-                                real_addr_source
-                            );
-                            let tx_to_aggregator = aggregator.subscribe_node();
-                            let (mut tx_to_aggregator, mut ws_send) =
-                                handle_node_websocket_connection(
+                        node_ws_opts,
+                        move |ws_send, ws_recv| {
+                            logging::scope([("ip", real_addr.to_string())], async move {
+                                log::info!(
+                                    "Opening /submit connection from {:?} (address source: {})",
+                                    real_addr,
+                                    real_addr_source
+                                );
+                                let tx_to_aggregator = aggregator.subscribe_node();
+                                let (mut tx_to_aggregator, mut ws_send) =
+                                    handle_node_websocket_connection(
+                                        real_addr,
+                                        ws_send,
+                                        ws_recv,
+                                        tx_to_aggregator,
+                                        max_nodes_per_connection,
+                                        bytes_per_second,
+                                        block_list,
+                                        stale_node_timeout,
+                                    )
+                                    .await;
+                                log::info!(
+                                    "Closing /submit connection from {:?} (address source: {})",
                                     real_addr,
-                                    ws_send,
-                                    ws_recv,
-                                    tx_to_aggregator,
-                                    max_nodes_per_connection,
-                                    bytes_per_second,
-                                    block_list,
-                                    stale_node_timeout,
-                                )
-                                .await;
-                            log::info!(
-                                "Closing /submit connection from {:?} (address source: {})",
-                                real_addr,
-                                real_addr_source
-                            );
-                            // Tell the aggregator that this connection has closed, so it can tidy up.
-                            let _ = tx_to_aggregator.send(FromWebsocket::Disconnected).await;
-                            let _ = ws_send.close().await;
+                                    real_addr_source
+                                );
+                                // Tell the aggregator that this connection has closed, so it can tidy up.
+                                let _ = tx_to_aggregator.send(FromWebsocket::Disconnected).await;
+                                let _ = ws_send.close().await;
+                            })
                         },
                     ))
                 }
@@ -16,10 +16,13 @@
 
 use anyhow::Context;
 use common::node_types::{
-    BlockDetails, BlockHash, BlockNumber, NodeLocation, NodeStats, Timestamp,
+    BlockDetails, BlockHash, BlockNumber, NodeLocation, NodeResourceUsage, NodeStats, SyncMethod,
+    Timestamp,
 };
 use serde_json::value::RawValue;
 
+// Node details are kept inline so that tests can match on them:
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug, PartialEq)]
 pub enum FeedMessage {
     Version(usize),
@@ -39,8 +42,10 @@ pub enum FeedMessage {
         // io: NodeIO, // can't losslessly deserialize
         // hardware: NodeHardware, // can't losslessly deserialize
         block_details: BlockDetails,
-        location: Option<NodeLocation>,
+        location: Option<Box<NodeLocation>>,
         startup_time: Option<Timestamp>,
+        connected_at: Timestamp,
+        last_seen: Timestamp,
     },
     RemovedNode {
         node_id: usize,
@@ -50,6 +55,8 @@ pub enum FeedMessage {
         lat: f32,
         long: f32,
         city: String,
+        asn: Option<u32>,
+        network: Option<String>,
     },
     ImportedBlock {
         node_id: usize,
@@ -120,6 +127,75 @@ pub enum FeedMessage {
         node_id: usize,
         // details: NodeIO, // can't losslessly deserialize
     },
+    ForkDetected {
+        block_number: BlockNumber,
+        block_hashes: Vec<BlockHash>,
+    },
+    NodeResourceUsageUpdate {
+        node_id: usize,
+        usage: NodeResourceUsage,
+    },
+    BlockPropagationUpdate {
+        block_number: BlockNumber,
+        p50: Option<u64>,
+        p90: Option<u64>,
+    },
+    NodeAuthorityStatus {
+        node_id: usize,
+        authority: bool,
+    },
+    NodeSyncState {
+        node_id: usize,
+        current: BlockNumber,
+        target: BlockNumber,
+    },
+    NodeAnomaly {
+        node_id: usize,
+        implausible_height: bool,
+    },
+    NetworkStats {
+        total_nodes: usize,
+        total_chains: usize,
+    },
+    NodeLastSeen {
+        node_id: usize,
+        last_seen: Timestamp,
+    },
+    NodeVersionInfo {
+        node_id: usize,
+        version: String,
+        spec_version: Option<u32>,
+    },
+    ChainBlockTime {
+        genesis_hash: BlockHash,
+        average_block_time: u64,
+    },
+    ChainFirstSeen {
+        genesis_hash: BlockHash,
+        first_seen: Timestamp,
+    },
+    FinalizationStall {
+        stalled: bool,
+        gap: BlockNumber,
+    },
+    NodeCluster {
+        lat: f32,
+        long: f32,
+        city: String,
+        count: usize,
+        node_ids: Vec<usize>,
+    },
+    NodeBlockImportTime {
+        node_id: usize,
+        import_time: u64,
+    },
+    NodeRecovered {
+        node_id: usize,
+    },
+    NodeSyncMethod {
+        node_id: usize,
+        sync_method: SyncMethod,
+    },
     /// A "special" case when we don't know how to decode an action:
     UnknownValue {
         action: u8,
@@ -134,6 +210,10 @@ pub struct NodeDetails {
     pub version: String,
     pub validator: Option<String>,
     pub network_id: Option<String>,
+    pub authority: bool,
+    pub spec_version: Option<u32>,
+    pub operator: Option<String>,
+    pub group: Option<String>,
 }
 
 impl FeedMessage {
@@ -185,13 +265,25 @@ impl FeedMessage {
             3 => {
                 let (
                     node_id,
-                    (name, implementation, version, validator, network_id),
+                    (
+                        name,
+                        implementation,
+                        version,
+                        validator,
+                        network_id,
+                        authority,
+                        spec_version,
+                        operator,
+                        group,
+                    ),
                     stats,
                     io,
                     hardware,
                     block_details,
                     location,
                     startup_time,
+                    connected_at,
+                    last_seen,
                 ) = serde_json::from_str(raw_val.get())?;
 
                 // Give these two types but don't use the results:
@@ -205,11 +297,17 @@ impl FeedMessage {
                         version,
                         validator,
                         network_id,
+                        authority,
+                        spec_version,
+                        operator,
+                        group,
                     },
                     stats,
                     block_details,
                     location,
                     startup_time,
+                    connected_at,
+                    last_seen,
                 }
             }
             // RemoveNode
@@ -219,12 +317,14 @@ impl FeedMessage {
             }
             // LocatedNode
             5 => {
-                let (node_id, lat, long, city) = serde_json::from_str(raw_val.get())?;
+                let (node_id, lat, long, city, asn, network) = serde_json::from_str(raw_val.get())?;
                 FeedMessage::LocatedNode {
                     node_id,
                     lat,
                     long,
                     city,
+                    asn,
+                    network,
                 }
             }
             // ImportedBlock
@@ -341,6 +441,125 @@ impl FeedMessage {
                 let (node_id, _node_io): (_, &RawValue) = serde_json::from_str(raw_val.get())?;
                 FeedMessage::NodeIOUpdate { node_id }
             }
+            // ForkDetected
+            23 => {
+                let (block_number, block_hashes) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::ForkDetected {
+                    block_number,
+                    block_hashes,
+                }
+            }
+            // NodeResourceUsageUpdate
+            24 => {
+                let (node_id, usage) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeResourceUsageUpdate { node_id, usage }
+            }
+            // BlockPropagationUpdate
+            25 => {
+                let (block_number, p50, p90) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::BlockPropagationUpdate {
+                    block_number,
+                    p50,
+                    p90,
+                }
+            }
+            // NodeAuthorityStatus
+            26 => {
+                let (node_id, authority) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeAuthorityStatus { node_id, authority }
+            }
+            // NodeSyncState
+            27 => {
+                let (node_id, current, target) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeSyncState {
+                    node_id,
+                    current,
+                    target,
+                }
+            }
+            // NodeAnomaly
+            28 => {
+                let (node_id, implausible_height) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeAnomaly {
+                    node_id,
+                    implausible_height,
+                }
+            }
+            // NetworkStats
+            29 => {
+                let (total_nodes, total_chains) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NetworkStats {
+                    total_nodes,
+                    total_chains,
+                }
+            }
+            // NodeLastSeen
+            30 => {
+                let (node_id, last_seen) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeLastSeen { node_id, last_seen }
+            }
+            // NodeVersionInfo
+            31 => {
+                let (node_id, version, spec_version) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeVersionInfo {
+                    node_id,
+                    version,
+                    spec_version,
+                }
+            }
+            // ChainBlockTime
+            32 => {
+                let (genesis_hash, average_block_time) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::ChainBlockTime {
+                    genesis_hash,
+                    average_block_time,
+                }
+            }
+            // ChainFirstSeen
+            33 => {
+                let (genesis_hash, first_seen) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::ChainFirstSeen {
+                    genesis_hash,
+                    first_seen,
+                }
+            }
+            // FinalizationStall
+            34 => {
+                let (stalled, gap) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::FinalizationStall { stalled, gap }
+            }
+            // NodeCluster
+            35 => {
+                let (lat, long, city, count, node_ids) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeCluster {
+                    lat,
+                    long,
+                    city,
+                    count,
+                    node_ids,
+                }
+            }
+            // NodeBlockImportTime
+            36 => {
+                let (node_id, import_time) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeBlockImportTime {
+                    node_id,
+                    import_time,
+                }
+            }
+            // NodeRecovered
+            37 => {
+                let node_id = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeRecovered { node_id }
+            }
+            // NodeSyncMethod
+            38 => {
+                let (node_id, sync_method) = serde_json::from_str(raw_val.get())?;
+                FeedMessage::NodeSyncMethod {
+                    node_id,
+                    sync_method,
+                }
+            }
             // A catchall for messages we don't know/care about yet:
             _ => {
                 let value = raw_val.to_string();
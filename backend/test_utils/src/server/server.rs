@@ -469,6 +469,15 @@ impl CoreProcess {
         Process::connect_to_uri(&uri).await
     }
 
+    /// Establish a connection to the process, asking for a specific version of the feed.
+    pub async fn connect_feed_with_version(
+        &self,
+        version: usize,
+    ) -> Result<(channels::FeedSender, channels::FeedReceiver), Error> {
+        let uri = format!("http://{}/feed?version={}", self.host, version).parse()?;
+        Process::connect_to_uri(&uri).await
+    }
+
     /// Establish multiple connections to the process
     pub async fn connect_multiple_feeds(
         &self,
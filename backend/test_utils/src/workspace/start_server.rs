@@ -35,16 +35,28 @@ impl Default for ServerOpts {
 /// Additional options to pass to the core command.
 pub struct CoreOpts {
     pub feed_timeout: Option<u64>,
+    pub feed_ping_interval: Option<u64>,
+    pub feed_ping_timeout: Option<u64>,
     pub worker_threads: Option<usize>,
     pub num_aggregators: Option<usize>,
+    pub shard_token: Option<String>,
+    pub feed_token: Option<String>,
+    pub upstream_feed: Option<String>,
+    pub shard_listeners: Vec<String>,
 }
 
 impl Default for CoreOpts {
     fn default() -> Self {
         Self {
             feed_timeout: None,
+            feed_ping_interval: None,
+            feed_ping_timeout: None,
             worker_threads: None,
             num_aggregators: None,
+            shard_token: None,
+            feed_token: None,
+            upstream_feed: None,
+            shard_listeners: Vec::new(),
         }
     }
 }
@@ -53,8 +65,11 @@ impl Default for CoreOpts {
 pub struct ShardOpts {
     pub max_nodes_per_connection: Option<usize>,
     pub max_node_data_per_second: Option<usize>,
+    pub max_node_message_size: Option<usize>,
     pub node_block_seconds: Option<u64>,
     pub worker_threads: Option<usize>,
+    pub core_token: Option<String>,
+    pub compress_core_connection: bool,
 }
 
 impl Default for ShardOpts {
@@ -62,8 +77,11 @@ impl Default for ShardOpts {
         Self {
             max_nodes_per_connection: None,
             max_node_data_per_second: None,
+            max_node_message_size: None,
             node_block_seconds: None,
             worker_threads: None,
+            core_token: None,
+            compress_core_connection: false,
         }
     }
 }
@@ -134,6 +152,11 @@ pub async fn start_server(
             .arg("--max-node-data-per-second")
             .arg(val.to_string());
     }
+    if let Some(val) = shard_opts.max_node_message_size {
+        shard_command = shard_command
+            .arg("--max-node-message-size")
+            .arg(val.to_string());
+    }
     if let Some(val) = shard_opts.node_block_seconds {
         shard_command = shard_command
             .arg("--node-block-seconds")
@@ -142,6 +165,12 @@ pub async fn start_server(
     if let Some(val) = shard_opts.worker_threads {
         shard_command = shard_command.arg("--worker-threads").arg(val.to_string());
     }
+    if let Some(val) = shard_opts.core_token {
+        shard_command = shard_command.arg("--core-token").arg(val);
+    }
+    if shard_opts.compress_core_connection {
+        shard_command = shard_command.arg("--compress-core-connection");
+    }
 
     // Build the core command
     let mut core_command = std::env::var("TELEMETRY_CORE_BIN")
@@ -155,12 +184,32 @@ pub async fn start_server(
     if let Some(val) = core_opts.feed_timeout {
         core_command = core_command.arg("--feed-timeout").arg(val.to_string());
     }
+    if let Some(val) = core_opts.feed_ping_interval {
+        core_command = core_command
+            .arg("--feed-ping-interval")
+            .arg(val.to_string());
+    }
+    if let Some(val) = core_opts.feed_ping_timeout {
+        core_command = core_command.arg("--feed-ping-timeout").arg(val.to_string());
+    }
     if let Some(val) = core_opts.worker_threads {
         core_command = core_command.arg("--worker-threads").arg(val.to_string());
     }
     if let Some(val) = core_opts.num_aggregators {
         core_command = core_command.arg("--num-aggregators").arg(val.to_string());
     }
+    if let Some(val) = core_opts.shard_token {
+        core_command = core_command.arg("--shard-token").arg(val);
+    }
+    if let Some(val) = core_opts.feed_token {
+        core_command = core_command.arg("--feed-token").arg(val);
+    }
+    if let Some(val) = core_opts.upstream_feed {
+        core_command = core_command.arg("--upstream-feed").arg(val);
+    }
+    for val in core_opts.shard_listeners {
+        core_command = core_command.arg("--shard-listener").arg(val);
+    }
 
     // Start the server
     Server::start(server::StartOpts::ShardAndCore {
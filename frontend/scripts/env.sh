@@ -13,6 +13,7 @@ echo -n > $TARGET
 declare -a vars=(
   "SUBSTRATE_TELEMETRY_URL"
   "SUBSTRATE_TELEMETRY_SAMPLE"
+  "SUBSTRATE_TELEMETRY_FEED_TOKEN"
 )
 
 echo "window.process_env = {" >> $TARGET
@@ -71,6 +71,8 @@ export default class App extends React.Component<{}, {}> {
         blockpropagation: true,
         blocklasttime: false,
         uptime: false,
+        connected: false,
+        lastseen: false,
       },
       (settings) => {
         const selectedColumns = this.selectedColumns(settings);
@@ -29,6 +29,8 @@ import {
   UploadColumn,
   DownloadColumn,
   StateCacheColumn,
+  LastSeenColumn,
+  ImplementationColumn,
 } from './components/List';
 
 const CONNECTION_TIMEOUT_BASE = (1000 * 5) as Types.Milliseconds; // 5 seconds
@@ -52,6 +54,7 @@ export class Connection {
 
   private static readonly utf8decoder = new TextDecoder('utf-8');
   private static readonly address = Connection.getAddress();
+  private static readonly protocols = Connection.getProtocols();
 
   private static getAddress(): string {
     const ENV_URL = 'SUBSTRATE_TELEMETRY_URL';
@@ -71,6 +74,22 @@ export class Connection {
     return `ws://127.0.0.1:8000/feed`;
   }
 
+  // Browsers can't set headers on websockets, so if the backend wants a token
+  // from us, we hand it over by offering to speak a subprotocol containing it:
+  private static getProtocols(): string[] {
+    const ENV_TOKEN = 'SUBSTRATE_TELEMETRY_FEED_TOKEN';
+
+    if (process.env && process.env[ENV_TOKEN]) {
+      return [`feed-token.${process.env[ENV_TOKEN]}`];
+    }
+
+    if (window.process_env && window.process_env[ENV_TOKEN]) {
+      return [`feed-token.${window.process_env[ENV_TOKEN]}`];
+    }
+
+    return [];
+  }
+
   private static async socket(): Promise<WebSocket> {
     let socket = await Connection.trySocket();
     let timeout = CONNECTION_TIMEOUT_BASE;
@@ -105,7 +124,10 @@ export class Connection {
         clean();
         resolve(null);
       }
-      const socket = new WebSocket(Connection.address);
+      const socket = new WebSocket(
+        Connection.address,
+        Connection.protocols
+      );
 
       socket.binaryType = 'arraybuffer';
       socket.addEventListener('open', onSuccess);
@@ -200,6 +222,8 @@ export class Connection {
             blockDetails,
             location,
             startupTime,
+            connectedAt,
+            lastSeen,
           ] = message.payload;
           const pinned = this.pins.has(nodeDetails[0]);
           const node = new Node(
@@ -211,7 +235,9 @@ export class Connection {
             nodeHardware,
             blockDetails,
             location,
-            startupTime
+            startupTime,
+            connectedAt,
+            lastSeen
           );
 
           nodes.add(node);
@@ -235,6 +261,62 @@ export class Connection {
           break;
         }
 
+        case ACTIONS.NodeRecovered: {
+          const id = message.payload;
+
+          nodes.mutAndSort(id, (node) => node.setStale(false));
+
+          break;
+        }
+
+        case ACTIONS.NodeSyncMethod: {
+          const [id, syncMethod] = message.payload;
+
+          nodes.mutAndMaybeSort(
+            id,
+            (node) => node.updateSyncMethod(syncMethod),
+            false
+          );
+
+          break;
+        }
+
+        case ACTIONS.NodeLastSeen: {
+          const [id, lastSeen] = message.payload;
+
+          nodes.mutAndMaybeSort(
+            id,
+            (node) => node.updateLastSeen(lastSeen),
+            sortByColumn === LastSeenColumn
+          );
+
+          break;
+        }
+
+        case ACTIONS.NodeBlockImportTime: {
+          const [id, blockImportTime] = message.payload;
+
+          nodes.mutAndMaybeSort(
+            id,
+            (node) => node.updateBlockImportTime(blockImportTime),
+            false
+          );
+
+          break;
+        }
+
+        case ACTIONS.NodeVersionInfo: {
+          const [id, version, specVersion] = message.payload;
+
+          nodes.mutAndMaybeSort(
+            id,
+            (node) => node.updateVersion(version, specVersion),
+            sortByColumn === ImplementationColumn
+          );
+
+          break;
+        }
+
         case ACTIONS.LocatedNode: {
           const [id, lat, lon, city] = message.payload;
 
@@ -247,6 +329,20 @@ export class Connection {
           break;
         }
 
+        case ACTIONS.NodeCluster: {
+          const [lat, lon, city, , ids] = message.payload;
+
+          for (const id of ids) {
+            nodes.mutAndMaybeSort(
+              id,
+              (node) => node.updateLocation([lat, lon, city]),
+              sortByColumn === LocationColumn
+            );
+          }
+
+          break;
+        }
+
         case ACTIONS.ImportedBlock: {
           const [id, blockDetails] = message.payload;
 
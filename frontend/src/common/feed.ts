@@ -24,9 +24,14 @@ import {
   City,
   NodeId,
   NodeCount,
+  IsAuthority,
+  NodeVersion,
+  SpecVersion,
+  SyncMethod,
   NodeDetails,
   NodeStats,
   NodeIO,
+  NodeResourceUsage,
   NodeHardware,
   NodeLocation,
   BlockNumber,
@@ -34,6 +39,7 @@ import {
   BlockDetails,
   Timestamp,
   Milliseconds,
+  PropagationTime,
   ChainLabel,
   GenesisHash,
   AuthoritySetInfo,
@@ -64,6 +70,22 @@ export const ACTIONS = {
   StaleNode: 0x14 as 0x14,
   NodeIO: 0x15 as 0x15,
   ChainStatsUpdate: 0x16 as 0x16,
+  ForkDetected: 0x17 as 0x17,
+  NodeResourceUsageUpdate: 0x18 as 0x18,
+  BlockPropagationUpdate: 0x19 as 0x19,
+  NodeAuthorityStatus: 0x1a as 0x1a,
+  NodeSyncState: 0x1b as 0x1b,
+  NodeAnomaly: 0x1c as 0x1c,
+  NetworkStats: 0x1d as 0x1d,
+  NodeLastSeen: 0x1e as 0x1e,
+  NodeVersionInfo: 0x1f as 0x1f,
+  ChainBlockTime: 0x20 as 0x20,
+  ChainFirstSeen: 0x21 as 0x21,
+  FinalizationStall: 0x22 as 0x22,
+  NodeCluster: 0x23 as 0x23,
+  NodeBlockImportTime: 0x24 as 0x24,
+  NodeRecovered: 0x25 as 0x25,
+  NodeSyncMethod: 0x26 as 0x26,
 };
 
 export type Action = typeof ACTIONS[keyof typeof ACTIONS];
@@ -99,7 +121,9 @@ export namespace Variants {
       NodeHardware,
       BlockDetails,
       Maybe<NodeLocation>,
-      Maybe<Timestamp>
+      Maybe<Timestamp>,
+      Timestamp,
+      Timestamp
     ];
   }
 
@@ -197,6 +221,86 @@ export namespace Variants {
     action: typeof ACTIONS.ChainStatsUpdate;
     payload: ChainStats;
   }
+
+  export interface ForkDetectedMessage extends MessageBase {
+    action: typeof ACTIONS.ForkDetected;
+    payload: [BlockNumber, BlockHash[]];
+  }
+
+  export interface NodeResourceUsageUpdateMessage extends MessageBase {
+    action: typeof ACTIONS.NodeResourceUsageUpdate;
+    payload: [NodeId, NodeResourceUsage];
+  }
+
+  export interface BlockPropagationUpdateMessage extends MessageBase {
+    action: typeof ACTIONS.BlockPropagationUpdate;
+    payload: [BlockNumber, Maybe<PropagationTime>, Maybe<PropagationTime>];
+  }
+
+  export interface NodeAuthorityStatusMessage extends MessageBase {
+    action: typeof ACTIONS.NodeAuthorityStatus;
+    payload: [NodeId, IsAuthority];
+  }
+
+  export interface NodeSyncStateMessage extends MessageBase {
+    action: typeof ACTIONS.NodeSyncState;
+    payload: [NodeId, BlockNumber, BlockNumber];
+  }
+
+  export interface NodeAnomalyMessage extends MessageBase {
+    action: typeof ACTIONS.NodeAnomaly;
+    payload: [NodeId, boolean];
+  }
+
+  export interface NetworkStatsMessage extends MessageBase {
+    action: typeof ACTIONS.NetworkStats;
+    payload: [NodeCount, number];
+  }
+
+  export interface NodeLastSeenMessage extends MessageBase {
+    action: typeof ACTIONS.NodeLastSeen;
+    payload: [NodeId, Timestamp];
+  }
+
+  export interface NodeVersionInfoMessage extends MessageBase {
+    action: typeof ACTIONS.NodeVersionInfo;
+    payload: [NodeId, NodeVersion, Maybe<SpecVersion>];
+  }
+
+  export interface ChainBlockTimeMessage extends MessageBase {
+    action: typeof ACTIONS.ChainBlockTime;
+    payload: [GenesisHash, Milliseconds];
+  }
+
+  export interface ChainFirstSeenMessage extends MessageBase {
+    action: typeof ACTIONS.ChainFirstSeen;
+    payload: [GenesisHash, Timestamp];
+  }
+
+  export interface FinalizationStallMessage extends MessageBase {
+    action: typeof ACTIONS.FinalizationStall;
+    payload: [boolean, BlockNumber];
+  }
+
+  export interface NodeClusterMessage extends MessageBase {
+    action: typeof ACTIONS.NodeCluster;
+    payload: [Latitude, Longitude, City, NodeCount, NodeId[]];
+  }
+
+  export interface NodeBlockImportTimeMessage extends MessageBase {
+    action: typeof ACTIONS.NodeBlockImportTime;
+    payload: [NodeId, Milliseconds];
+  }
+
+  export interface NodeRecoveredMessage extends MessageBase {
+    action: typeof ACTIONS.NodeRecovered;
+    payload: NodeId;
+  }
+
+  export interface NodeSyncMethodMessage extends MessageBase {
+    action: typeof ACTIONS.NodeSyncMethod;
+    payload: [NodeId, SyncMethod];
+  }
 }
 
 export type Message =
@@ -222,7 +326,23 @@ export type Message =
   | Variants.StaleNodeMessage
   | Variants.PongMessage
   | Variants.NodeIOMessage
-  | Variants.ChainStatsUpdate;
+  | Variants.ChainStatsUpdate
+  | Variants.ForkDetectedMessage
+  | Variants.NodeResourceUsageUpdateMessage
+  | Variants.BlockPropagationUpdateMessage
+  | Variants.NodeAuthorityStatusMessage
+  | Variants.NodeSyncStateMessage
+  | Variants.NodeAnomalyMessage
+  | Variants.NetworkStatsMessage
+  | Variants.NodeLastSeenMessage
+  | Variants.NodeVersionInfoMessage
+  | Variants.ChainBlockTimeMessage
+  | Variants.ChainFirstSeenMessage
+  | Variants.FinalizationStallMessage
+  | Variants.NodeClusterMessage
+  | Variants.NodeBlockImportTimeMessage
+  | Variants.NodeRecoveredMessage
+  | Variants.NodeSyncMethodMessage;
 
 /**
  * Data type to be sent to the feed. Passing through strings means we can only serialize once,
@@ -25,4 +25,4 @@ import * as FeedMessage from './feed';
 export { Types, FeedMessage };
 
 // Increment this if breaking changes were made to types in `feed.ts`
-export const VERSION: Types.FeedVersion = 32 as Types.FeedVersion;
+export const VERSION: Types.FeedVersion = 33 as Types.FeedVersion;
@@ -25,6 +25,10 @@ export type NodeId = Id<'Node'>;
 export type NodeName = Opaque<string, 'NodeName'>;
 export type NodeImplementation = Opaque<string, 'NodeImplementation'>;
 export type NodeVersion = Opaque<string, 'NodeVersion'>;
+export type SpecVersion = Opaque<number, 'SpecVersion'>;
+export type NodeOperator = Opaque<string, 'NodeOperator'>;
+export type NodeGroup = Opaque<string, 'NodeGroup'>;
+export type SyncMethod = 'full' | 'fast' | 'warp';
 export type BlockNumber = Opaque<number, 'BlockNumber'>;
 export type BlockHash = Opaque<string, 'BlockHash'>;
 export type Address = Opaque<string, 'Address'>;
@@ -32,6 +36,7 @@ export type Milliseconds = Opaque<number, 'Milliseconds'>;
 export type Timestamp = Opaque<Milliseconds, 'Timestamp'>;
 export type PropagationTime = Opaque<Milliseconds, 'PropagationTime'>;
 export type NodeCount = Opaque<number, 'NodeCount'>;
+export type IsAuthority = Opaque<boolean, 'IsAuthority'>;
 export type PeerCount = Opaque<number, 'PeerCount'>;
 export type TransactionCount = Opaque<number, 'TransactionCount'>;
 export type Latitude = Opaque<number, 'Latitude'>;
@@ -55,10 +60,15 @@ export type NodeDetails = [
   NodeImplementation,
   NodeVersion,
   Maybe<Address>,
-  Maybe<NetworkId>
+  Maybe<NetworkId>,
+  IsAuthority,
+  Maybe<SpecVersion>,
+  Maybe<NodeOperator>,
+  Maybe<NodeGroup>
 ];
 export type NodeStats = [PeerCount, TransactionCount];
 export type NodeIO = [Array<Bytes>];
+export type NodeResourceUsage = [Maybe<number>, Maybe<Bytes>, Maybe<Bytes>];
 export type NodeHardware = [
   Array<BytesPerSecond>,
   Array<BytesPerSecond>,
@@ -39,6 +39,8 @@ import {
   BlockPropagationColumn,
   LastBlockColumn,
   UptimeColumn,
+  ConnectedColumn,
+  LastSeenColumn,
 } from './';
 
 export type Column =
@@ -59,7 +61,9 @@ export type Column =
   | typeof BlockTimeColumn
   | typeof BlockPropagationColumn
   | typeof LastBlockColumn
-  | typeof UptimeColumn;
+  | typeof UptimeColumn
+  | typeof ConnectedColumn
+  | typeof LastSeenColumn;
 
 export namespace Column {
   export interface Props {
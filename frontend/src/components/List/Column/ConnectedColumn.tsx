@@ -0,0 +1,44 @@
+// Source code for the Substrate Telemetry Server.
+// Copyright (C) 2021 Parity Technologies (UK) Ltd.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU General Public License as published by
+// the Free Software Foundation, either version 3 of the License, or
+// (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU General Public License for more details.
+//
+// You should have received a copy of the GNU General Public License
+// along with this program. If not, see <https://www.gnu.org/licenses/>.
+
+import * as React from 'react';
+import { Column } from './';
+import { Node } from '../../../state';
+import { Ago } from '../../';
+import icon from '../../../icons/plug.svg';
+
+export class ConnectedColumn extends React.Component<Column.Props, {}> {
+  public static readonly label = 'Connected For';
+  public static readonly icon = icon;
+  public static readonly width = 58;
+  public static readonly setting = 'connected';
+  public static readonly sortBy = ({ connectedAt }: Node) => connectedAt;
+
+  public shouldComponentUpdate(nextProps: Column.Props) {
+    // Connection time only changes when the node does
+    return this.props.node !== nextProps.node;
+  }
+
+  render() {
+    const { connectedAt } = this.props.node;
+
+    return (
+      <td className="Column">
+        <Ago when={connectedAt} justTime={true} />
+      </td>
+    );
+  }
+}
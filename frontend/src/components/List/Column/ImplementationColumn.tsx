@@ -15,6 +15,7 @@
 // along with this program. If not, see <https://www.gnu.org/licenses/>.
 
 import * as React from 'react';
+import { Maybe } from '../../../common';
 import { Column } from './';
 import { Node } from '../../../state';
 import { Tooltip, Icon } from '../../';
@@ -79,31 +80,33 @@ export class ImplementationColumn extends React.Component<Column.Props, {}> {
 
   private implementation: string;
   private version: string;
+  private specVersion: Maybe<number>;
 
   public shouldComponentUpdate(nextProps: Column.Props) {
-    if (this.props.node === nextProps.node) {
-      // Implementation can't change unless we got a new node
-      return false;
-    }
-
+    // The version can change (say, after a runtime upgrade) without us
+    // getting a new node, so we always have to compare it:
     return (
       this.implementation !== nextProps.node.implementation ||
-      this.version !== nextProps.node.version
+      this.version !== nextProps.node.version ||
+      this.specVersion !== nextProps.node.specVersion
     );
   }
 
   render() {
-    const { implementation, version } = this.props.node;
+    const { implementation, version, specVersion } = this.props.node;
 
     this.implementation = implementation;
     this.version = version;
+    this.specVersion = specVersion;
+
+    const runtime = specVersion != null ? ` (runtime ${specVersion})` : '';
 
     const [semver] = version.match(SEMVER_PATTERN) || ['?.?.?'];
     const implIcon = ICONS[implementation] || paritySubstrateIcon;
 
     return (
       <td className="Column">
-        <Tooltip text={`${implementation} v${version}`} />
+        <Tooltip text={`${implementation} v${version}${runtime}`} />
         <Icon src={implIcon} /> {semver}
       </td>
     );
@@ -37,6 +37,9 @@ export class PeersColumn extends React.Component<Column.Props, {}> {
 
     this.data = peers;
 
-    return <td className="Column">{peers}</td>;
+    // A node without any peers is cut off from the rest of its network:
+    const className = peers === 0 ? 'Column Column-isolated' : 'Column';
+
+    return <td className={className}>{peers}</td>;
   }
 }
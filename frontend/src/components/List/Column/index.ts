@@ -33,3 +33,5 @@ export * from './BlockTimeColumn';
 export * from './BlockPropagationColumn';
 export * from './LastBlockColumn';
 export * from './UptimeColumn';
+export * from './ConnectedColumn';
+export * from './LastSeenColumn';
@@ -38,6 +38,8 @@ import {
   BlockPropagationColumn,
   LastBlockColumn,
   UptimeColumn,
+  ConnectedColumn,
+  LastSeenColumn,
 } from './';
 
 import './Row.css';
@@ -79,6 +81,8 @@ export class Row extends React.Component<Row.Props, Row.State> {
     BlockPropagationColumn,
     LastBlockColumn,
     UptimeColumn,
+    ConnectedColumn,
+    LastSeenColumn,
   ];
 
   private renderedChangeRef = 0;
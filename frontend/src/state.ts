@@ -58,13 +58,20 @@ export class Node {
   public readonly id: Types.NodeId;
   public readonly name: Types.NodeName;
   public readonly implementation: Types.NodeImplementation;
-  public readonly version: Types.NodeVersion;
+  public version: Types.NodeVersion;
+  public specVersion: Maybe<Types.SpecVersion>;
   public readonly validator: Maybe<Types.Address>;
   public readonly networkId: Maybe<Types.NetworkId>;
+  public readonly operator: Maybe<Types.NodeOperator>;
+  public readonly group: Maybe<Types.NodeGroup>;
   public readonly startupTime: Maybe<Types.Timestamp>;
+  public readonly connectedAt: Types.Timestamp;
+  public lastSeen: Types.Timestamp;
+  public blockImportTime: Maybe<Types.Milliseconds>;
+  public syncMethod: Maybe<Types.SyncMethod>;
 
   public readonly sortableName: string;
-  public readonly sortableVersion: number;
+  public sortableVersion: number;
 
   public stale: boolean;
   public pinned: boolean;
@@ -100,26 +107,32 @@ export class Node {
     nodeHardware: Types.NodeHardware,
     blockDetails: Types.BlockDetails,
     location: Maybe<Types.NodeLocation>,
-    startupTime: Maybe<Types.Timestamp>
+    startupTime: Maybe<Types.Timestamp>,
+    connectedAt: Types.Timestamp,
+    lastSeen: Types.Timestamp
   ) {
     const [name, implementation, version, validator, networkId] = nodeDetails;
+    const specVersion = nodeDetails[6];
+    const operator = nodeDetails[7];
+    const group = nodeDetails[8];
 
     this.pinned = pinned;
 
     this.id = id;
     this.name = name;
     this.implementation = implementation;
-    this.version = version;
     this.validator = validator;
     this.networkId = networkId;
+    this.operator = operator;
+    this.group = group;
     this.startupTime = startupTime;
-
-    const [major = 0, minor = 0, patch = 0] = (version || '0.0.0')
-      .split('.')
-      .map((n) => parseInt(n, 10) | 0);
+    this.connectedAt = connectedAt;
+    this.lastSeen = lastSeen;
+    this.blockImportTime = null;
+    this.syncMethod = null;
 
     this.sortableName = name.toLocaleLowerCase();
-    this.sortableVersion = (major * 1000 + minor * 100 + patch) | 0;
+    this.setVersion(version, specVersion);
 
     this.updateStats(nodeStats);
     this.updateIO(nodeIO);
@@ -200,6 +213,33 @@ export class Node {
     }
   }
 
+  public updateVersion(
+    version: Types.NodeVersion,
+    specVersion: Maybe<Types.SpecVersion>
+  ) {
+    this.setVersion(version, specVersion);
+
+    this.trigger();
+  }
+
+  public updateLastSeen(lastSeen: Types.Timestamp) {
+    this.lastSeen = lastSeen;
+
+    this.trigger();
+  }
+
+  public updateBlockImportTime(blockImportTime: Types.Milliseconds) {
+    this.blockImportTime = blockImportTime;
+
+    this.trigger();
+  }
+
+  public updateSyncMethod(syncMethod: Types.SyncMethod) {
+    this.syncMethod = syncMethod;
+
+    this.trigger();
+  }
+
   public setStale(stale: boolean) {
     if (this.stale !== stale) {
       this.stale = stale;
@@ -211,6 +251,19 @@ export class Node {
     return this._changeRef;
   }
 
+  private setVersion(
+    version: Types.NodeVersion,
+    specVersion: Maybe<Types.SpecVersion>
+  ) {
+    const [major = 0, minor = 0, patch = 0] = (version || '0.0.0')
+      .split('.')
+      .map((n) => parseInt(n, 10) | 0);
+
+    this.version = version;
+    this.specVersion = specVersion;
+    this.sortableVersion = (major * 1000 + minor * 100 + patch) | 0;
+  }
+
   private trigger() {
     this._changeRef += 1;
   }
@@ -256,6 +309,8 @@ export namespace State {
     blockpropagation: boolean;
     blocklasttime: boolean;
     uptime: boolean;
+    connected: boolean;
+    lastseen: boolean;
   }
 
   export interface SortBy {